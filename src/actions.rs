@@ -0,0 +1,318 @@
+//! Deck actions and the macros that chain them.
+//!
+//! Everything a deck can be asked to do besides pressing its keys runs
+//! through `AppState::run_action`, whether it comes from the touch strip,
+//! MIDI, hotkeys, remote controls or a macro.
+
+use crate::lcd::{flush_device, render_key_label, set_key_image, update_lcd_banner};
+use crate::{AppState, Mode};
+use elgato_streamdeck::AsyncStreamDeck;
+use image::DynamicImage;
+use soundboard::config::{MacroStep, TouchAction};
+use soundboard::error::Result;
+use std::time::Duration;
+use tokio::task::AbortHandle;
+
+/// Something a deck can be asked to do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Play a key, moved by `pitch` semitones and with its volume scaled
+    /// by `volume`, for this playback only.
+    Play {
+        key: u8,
+        pitch: f64,
+        volume: f64,
+    },
+    SetMode(Mode),
+    ToggleMode,
+    CycleRoutes,
+    SetRoute {
+        name: String,
+        enabled: bool,
+    },
+    /// Play keys without routes of their own through exactly these.
+    SetRoutes(Vec<String>),
+    /// Move this many banks forward or back.
+    SwitchBank(isize),
+    /// Switch to a bank, counting from 0.
+    SelectBank(usize),
+    Undo,
+    ResetDial(u8),
+    ToggleSchedules,
+    RunMacro(String),
+    CancelMacro(String),
+}
+
+impl Action {
+    /// Plays a key as it is set up.
+    pub fn play(key: u8) -> Action {
+        Action::Play {
+            key,
+            pitch: 0.0,
+            volume: 1.0,
+        }
+    }
+
+    /// What a touch strip gesture or hotkey does, `dial` being the dial
+    /// under the finger. `None` does nothing.
+    pub fn from_touch(action: TouchAction, dial: u8) -> Option<Action> {
+        Some(match action {
+            TouchAction::None => return None,
+            TouchAction::ResetDial => Action::ResetDial(dial),
+            TouchAction::ToggleMode => Action::ToggleMode,
+            TouchAction::CycleRoutes => Action::CycleRoutes,
+            TouchAction::NextBank => Action::SwitchBank(1),
+            TouchAction::PreviousBank => Action::SwitchBank(-1),
+            TouchAction::Undo => Action::Undo,
+            TouchAction::ToggleSchedules => Action::ToggleSchedules,
+        })
+    }
+
+    /// What a macro step does. Waits are left to the macro itself.
+    fn from_macro_step(step: &MacroStep) -> Option<Action> {
+        match step {
+            MacroStep::Play { key, pitch, volume } => Some(Action::Play {
+                key: key.checked_sub(1)?,
+                pitch: *pitch,
+                volume: *volume,
+            }),
+            MacroStep::Wait { .. } => None,
+            MacroStep::Routes { routes } => Some(Action::SetRoutes(routes.clone())),
+            MacroStep::Route { name, enabled } => Some(Action::SetRoute {
+                name: name.clone(),
+                enabled: *enabled,
+            }),
+            MacroStep::Bank { bank } => Some(Action::SelectBank(bank.checked_sub(1)?)),
+            MacroStep::Action { action } => Action::from_touch(*action, 0),
+        }
+    }
+}
+
+/// A macro that is running, so it can be stopped.
+#[derive(Clone)]
+pub struct RunningMacro {
+    /// Tells this run's steps apart from those of an earlier run that
+    /// were already queued when it was stopped.
+    run: u64,
+    abort: AbortHandle,
+}
+
+/// What a running macro sends the deck it runs on.
+pub enum MacroEvent {
+    /// Run a step now.
+    Step {
+        name: String,
+        run: u64,
+        action: Action,
+    },
+    /// Every step has run.
+    Finished { name: String, run: u64 },
+}
+
+impl AppState {
+    /// Carries out `action` as if done on the deck itself.
+    pub async fn run_action(
+        &mut self,
+        action: Action,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        match action {
+            Action::Play { key, pitch, volume } => {
+                if !self.has_sample(key) {
+                    println!("Key {} has no sample to play.", key);
+                    return Ok(());
+                }
+                self.play_key_adjusted(key, pitch, volume, device).await
+            }
+            Action::SetMode(mode) => self.set_mode(mode, device).await,
+            Action::ToggleMode => self.toggle_mode(device).await,
+            Action::CycleRoutes => {
+                self.cycle_routes();
+                Ok(())
+            }
+            Action::SetRoute { name, enabled } => {
+                self.set_route_enabled(&name, enabled);
+                Ok(())
+            }
+            Action::SetRoutes(routes) => {
+                println!("Output routes set to: {}", routes.join(", "));
+                self.enabled_routes = routes;
+                Ok(())
+            }
+            Action::SwitchBank(delta) => self.switch_bank(delta, device).await,
+            Action::SelectBank(bank) => {
+                if bank >= self.bank_count {
+                    println!("There is no bank {}.", bank + 1);
+                    return Ok(());
+                }
+                self.switch_bank(bank as isize - self.bank as isize, device)
+                    .await
+            }
+            Action::Undo => self.undo_last(device).await,
+            Action::ResetDial(dial) => self.reset_dial(dial, device).await,
+            Action::ToggleSchedules => self.toggle_schedules(device).await,
+            Action::RunMacro(name) => self.start_macro(&name, device).await,
+            Action::CancelMacro(name) => self.cancel_macro(&name, device).await,
+        }
+    }
+
+    /// The macro `key` runs, if it is a macro key.
+    pub fn key_macro(&self, key: u8) -> Option<&str> {
+        self.macros
+            .iter()
+            .find(|config| config.key.and_then(|k| k.checked_sub(1)) == Some(key))
+            .map(|config| config.name.as_str())
+    }
+
+    /// What a macro key shows: lit while its macro runs.
+    pub fn macro_key_image(&self, name: &str) -> DynamicImage {
+        if self.running_macros.contains_key(name) {
+            render_key_label(&self.img_rec_on, &name.to_uppercase())
+        } else {
+            render_key_label(&self.img_play, &name.to_uppercase())
+        }
+    }
+
+    /// Redraws the key of macro `name`, if it has one.
+    async fn redraw_macro_key(&self, name: &str, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let key = self
+            .macros
+            .iter()
+            .find(|config| config.name == name)
+            .and_then(|config| config.key?.checked_sub(1));
+        if let Some(key) = key {
+            set_key_image(device, key, self.key_image(key)).await?;
+            flush_device(device).await?;
+        }
+        Ok(())
+    }
+
+    /// Starts the macro `name`. Its steps are sent back to this deck as
+    /// they come due, so waits never hold up the deck.
+    pub async fn start_macro(
+        &mut self,
+        name: &str,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let Some(config) = self.macros.iter().find(|config| config.name == name) else {
+            println!("There is no macro called '{}'.", name);
+            return Ok(());
+        };
+        if self.running_macros.contains_key(name) {
+            println!("Macro '{}' is already running.", name);
+            return Ok(());
+        }
+        let Some(macro_tx) = self.macro_tx.clone() else {
+            return Ok(());
+        };
+        self.next_macro_run += 1;
+        let run = self.next_macro_run;
+        let steps = config.steps.clone();
+        let task_name = name.to_string();
+        let task = tokio::spawn(async move {
+            for step in &steps {
+                if let MacroStep::Wait { ms } = step {
+                    tokio::time::sleep(Duration::from_millis(*ms)).await;
+                    continue;
+                }
+                let Some(action) = Action::from_macro_step(step) else {
+                    eprintln!("Skipping invalid step in macro '{}': {:?}", task_name, step);
+                    continue;
+                };
+                let step = MacroEvent::Step {
+                    name: task_name.clone(),
+                    run,
+                    action,
+                };
+                if macro_tx.send(step).is_err() {
+                    return;
+                }
+            }
+            let _ = macro_tx.send(MacroEvent::Finished {
+                name: task_name,
+                run,
+            });
+        });
+        println!("Running macro '{}'.", name);
+        self.running_macros.insert(
+            name.to_string(),
+            RunningMacro {
+                run,
+                abort: task.abort_handle(),
+            },
+        );
+        self.redraw_macro_key(name, device).await?;
+        update_lcd_banner(
+            device,
+            self.lcd_image(),
+            &format!("MACRO {}", name.to_uppercase()),
+        )
+        .await?;
+        flush_device(device).await
+    }
+
+    /// Stops the macro `name` before its remaining steps run.
+    pub async fn cancel_macro(
+        &mut self,
+        name: &str,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let Some(running) = self.running_macros.remove(name) else {
+            println!("Macro '{}' is not running.", name);
+            return Ok(());
+        };
+        running.abort.abort();
+        println!("Cancelled macro '{}'.", name);
+        self.redraw_macro_key(name, device).await?;
+        self.update_lcd_status(device).await?;
+        flush_device(device).await
+    }
+
+    /// Runs a macro key: starts its macro, or cancels it while it runs.
+    pub async fn toggle_macro(&mut self, key: u8, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(name) = self.key_macro(key).map(str::to_string) else {
+            return Ok(());
+        };
+        if self.running_macros.contains_key(&name) {
+            self.cancel_macro(&name, device).await
+        } else {
+            self.start_macro(&name, device).await
+        }
+    }
+
+    /// Carries out a step or the end of a running macro. Steps of a run
+    /// that has since been cancelled are dropped.
+    pub async fn handle_macro_event(
+        &mut self,
+        event: MacroEvent,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        match event {
+            MacroEvent::Step { name, run, action } => {
+                if self.running_macros.get(&name).map(|running| running.run) != Some(run) {
+                    return Ok(());
+                }
+                println!("Macro '{}': {:?}", name, action);
+                self.run_action(action, device).await
+            }
+            MacroEvent::Finished { name, run } => {
+                if self.running_macros.get(&name).map(|running| running.run) != Some(run) {
+                    return Ok(());
+                }
+                self.running_macros.remove(&name);
+                println!("Macro '{}' finished.", name);
+                self.redraw_macro_key(&name, device).await?;
+                self.update_lcd_status(device).await?;
+                flush_device(device).await
+            }
+        }
+    }
+
+    /// Stops every running macro, e.g. when the deck goes away.
+    pub fn cancel_all_macros(&mut self) {
+        for (name, running) in self.running_macros.drain() {
+            running.abort.abort();
+            println!("Stopped macro '{}'.", name);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::amplitude_to_dbfs;
+use hound::{SampleFormat, WavReader};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Samples at or above this amplitude count as clipped.
+const CLIP_THRESHOLD: f32 = 0.999;
+/// Samples below this level count as silence when measuring the quiet
+/// lead-in and tail of a take.
+const SILENCE_DB: f32 = -50.0;
+
+/// Levels and timing of a recorded sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub duration: Duration,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Loudest sample, in dBFS.
+    pub peak_db: f32,
+    /// Average level, in dBFS.
+    pub rms_db: f32,
+    /// Samples at full scale, likely distorted.
+    pub clipped_samples: usize,
+    /// Silence before the first sound.
+    pub leading_silence: Duration,
+    /// Silence after the last sound.
+    pub trailing_silence: Duration,
+}
+
+/// Reads the length of a WAV file from its header, without decoding it.
+pub fn wav_duration(path: &Path) -> io::Result<Duration> {
+    let reader = WavReader::open(path).map_err(io::Error::other)?;
+    let spec = reader.spec();
+    Ok(Duration::from_secs_f64(
+        reader.duration() as f64 / spec.sample_rate.max(1) as f64,
+    ))
+}
+
+/// Decodes a WAV file and measures its levels, clipping and silence.
+pub fn analyze_wav(path: &Path) -> io::Result<Analysis> {
+    let mut reader = WavReader::open(path).map_err(io::Error::other)?;
+    let spec = reader.spec();
+    // Normalise everything to -1.0..=1.0
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader
+            .samples::<f32>()
+            .collect::<Result<_, _>>()
+            .map_err(io::Error::other)?,
+        SampleFormat::Int => {
+            let full_scale = (1i64 << (spec.bits_per_sample.max(1) - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 / full_scale))
+                .collect::<Result<_, _>>()
+                .map_err(io::Error::other)?
+        }
+    };
+
+    let channels = spec.channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let frame_duration =
+        |frames: usize| Duration::from_secs_f64(frames as f64 / spec.sample_rate.max(1) as f64);
+
+    let mut peak = 0.0f32;
+    let mut sum_squares = 0.0f64;
+    let mut clipped_samples = 0;
+    for &sample in &samples {
+        let amplitude = sample.abs();
+        peak = peak.max(amplitude);
+        sum_squares += (sample as f64) * (sample as f64);
+        if amplitude >= CLIP_THRESHOLD {
+            clipped_samples += 1;
+        }
+    }
+    let rms = if samples.is_empty() {
+        0.0
+    } else {
+        (sum_squares / samples.len() as f64).sqrt() as f32
+    };
+
+    // A frame is sound if any of its channels is above the silence level
+    let is_sound = |frame: &[f32]| {
+        frame
+            .iter()
+            .any(|sample| amplitude_to_dbfs(sample.abs()) > SILENCE_DB)
+    };
+    let leading = samples
+        .chunks_exact(channels)
+        .position(is_sound)
+        .unwrap_or(frames);
+    let trailing = samples
+        .chunks_exact(channels)
+        .rev()
+        .position(is_sound)
+        .unwrap_or(0);
+
+    Ok(Analysis {
+        duration: frame_duration(frames),
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        peak_db: amplitude_to_dbfs(peak),
+        rms_db: amplitude_to_dbfs(rms),
+        clipped_samples,
+        leading_silence: frame_duration(leading),
+        trailing_silence: frame_duration(trailing),
+    })
+}
@@ -0,0 +1,545 @@
+//! Bank archives: a bank's samples, icons, key settings and pattern
+//! packed into a gzipped tar with a `manifest.toml`, to move banks between
+//! machines.
+//!
+//! Key routes are kept by name. They play where they should only if the
+//! importing machine has routes of the same names, since an unknown name is
+//! taken as a PipeWire node name, as it is in `config.toml`.
+
+use crate::bank::{
+    KeySettings, MAX_BPM, MAX_SWING, MIN_BPM, PATTERN_STEP_COUNTS, Pattern, key_name,
+    load_bank_manifest, save_bank_manifest,
+};
+use crate::error::{Error, Result};
+use crate::history::{self, ArchiveReason};
+use crate::{KEY_COUNT, MAX_VOLUME, get_bank_path, get_key_file_path};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Format version written to new archives. Archives from a newer version
+/// are refused rather than half understood.
+pub const ARCHIVE_VERSION: u32 = 1;
+/// Name of the manifest at the root of an archive.
+const MANIFEST_NAME: &str = "manifest.toml";
+/// Directory inside a bank holding imported files that are not recordings.
+const IMPORTED_DIR: &str = "imported";
+
+/// One key of an archived bank. Paths are relative to the archive root.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ArchivedKey {
+    pub audio: Option<String>,
+    /// The audio was a library file assigned to the key rather than its
+    /// own recording.
+    pub assigned: bool,
+    pub volume: Option<f64>,
+    pub pitch: Option<f64>,
+    pub label: Option<String>,
+    pub icon: Option<String>,
+    /// Output routes the key plays through instead of the enabled ones.
+    pub routes: Option<Vec<String>>,
+}
+
+/// The `manifest.toml` of a bank archive, keyed by key letter.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchiveManifest {
+    pub version: u32,
+    #[serde(default)]
+    pub keys: BTreeMap<String, ArchivedKey>,
+    /// The bank's step sequencer pattern, if it has one.
+    #[serde(default)]
+    pub pattern: Option<Pattern>,
+}
+
+/// What to do with keys of the target bank that already have a sample or
+/// settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Conflict {
+    /// Import nothing if any key is taken.
+    #[default]
+    Abort,
+    /// Leave taken keys as they are and import the rest.
+    Skip,
+    /// Overwrite taken keys. Their old recordings go to the history.
+    Replace,
+}
+
+impl FromStr for Conflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(Conflict::Abort),
+            "skip" => Ok(Conflict::Skip),
+            "replace" => Ok(Conflict::Replace),
+            other => Err(format!(
+                "unknown conflict policy '{}', expected abort, skip or replace",
+                other
+            )),
+        }
+    }
+}
+
+/// The keys an import wrote and the ones it left alone.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: Vec<String>,
+    pub skipped: Vec<String>,
+    /// Whether the archive's pattern was imported too.
+    pub pattern: bool,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "imported {} keys", self.imported.len())?;
+        if self.pattern {
+            write!(f, " and the pattern")?;
+        }
+        if !self.skipped.is_empty() {
+            write!(
+                f,
+                ", skipped {} ({})",
+                self.skipped.len(),
+                self.skipped.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// An error for an archive that cannot be used, naming the archive.
+fn invalid(archive_path: &Path, message: impl Into<String>) -> Error {
+    Error::io(
+        format!("validating {}", archive_path.display()),
+        io::Error::new(io::ErrorKind::InvalidData, message.into()),
+    )
+}
+
+/// The extension of `path` if it is a plain one, used to name files
+/// taken out of an archive.
+fn safe_extension(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    let plain = !extension.is_empty() && extension.chars().all(|c| c.is_ascii_alphanumeric());
+    plain.then_some(extension)
+}
+
+/// Adds the file at `path` to the archive as `{dir}/{key}.{ext}`,
+/// returning the name it was stored under.
+fn append_key_file<W: io::Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    dir: &str,
+    key: &str,
+) -> Result<String> {
+    let extension = safe_extension(path).unwrap_or_else(|| "bin".to_string());
+    let name = format!("{}/{}.{}", dir, key, extension);
+    builder
+        .append_path_with_name(path, &name)
+        .map_err(|e| Error::io(format!("adding {} to archive", path.display()), e))?;
+    Ok(name)
+}
+
+/// Packs `bank` into a gzipped tar at `archive_path`: a manifest with
+/// every key's settings and the bank's pattern, plus each key's audio and
+/// icon. Returns how many keys were exported.
+pub fn export_bank(storage_path: &Path, bank: usize, archive_path: &Path) -> Result<usize> {
+    let manifest = load_bank_manifest(storage_path, bank)?;
+    let file = File::create(archive_path)
+        .map_err(|e| Error::io(format!("creating {}", archive_path.display()), e))?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    // 1. Add each key's files, noting where they went
+    let mut archived = ArchiveManifest {
+        version: ARCHIVE_VERSION,
+        keys: BTreeMap::new(),
+        pattern: manifest.pattern.clone(),
+    };
+    for key in 0..KEY_COUNT {
+        let name = key_name(key);
+        let settings = manifest.key(key).cloned().unwrap_or_default();
+        let audio_path = settings
+            .file
+            .clone()
+            .unwrap_or_else(|| get_key_file_path(storage_path, bank, key));
+        let mut entry = ArchivedKey {
+            assigned: settings.file.is_some(),
+            volume: settings.volume,
+            pitch: settings.pitch,
+            label: settings.label.clone(),
+            routes: settings.routes.clone(),
+            ..Default::default()
+        };
+        if audio_path.exists() {
+            entry.audio = Some(append_key_file(&mut builder, &audio_path, "audio", &name)?);
+        } else if settings.file.is_some() {
+            eprintln!(
+                "Key {} is assigned {}, which no longer exists. Leaving it out.",
+                name,
+                audio_path.display()
+            );
+        }
+        if let Some(icon) = settings.icon.as_ref().filter(|icon| icon.exists()) {
+            entry.icon = Some(append_key_file(&mut builder, icon, "icons", &name)?);
+        }
+        if entry.audio.is_some() || entry.icon.is_some() || !settings.is_empty() {
+            archived.keys.insert(name, entry);
+        }
+    }
+
+    // 2. Add the manifest describing them
+    let contents = toml::to_string_pretty(&archived).map_err(|e| {
+        Error::io(
+            format!("serializing {}", MANIFEST_NAME),
+            io::Error::other(e),
+        )
+    })?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, MANIFEST_NAME, contents.as_bytes())
+        .map_err(|e| Error::io(format!("adding {} to archive", MANIFEST_NAME), e))?;
+
+    // 3. Finish both the tar and the gzip stream
+    builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .map_err(|e| Error::io(format!("writing {}", archive_path.display()), e))?;
+    Ok(archived.keys.len())
+}
+
+/// Reads every regular file in the archive into memory, by name.
+fn read_archive(archive_path: &Path) -> Result<HashMap<String, Vec<u8>>> {
+    let context = || format!("reading {}", archive_path.display());
+    let file = File::open(archive_path).map_err(|e| Error::io(context(), e))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    let mut files = HashMap::new();
+    for entry in archive.entries().map_err(|e| Error::io(context(), e))? {
+        let mut entry = entry.map_err(|e| Error::io(context(), e))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .map_err(|e| Error::io(context(), e))?
+            .to_string_lossy()
+            .into_owned();
+        let mut contents = Vec::new();
+        entry
+            .read_to_end(&mut contents)
+            .map_err(|e| Error::io(context(), e))?;
+        files.insert(name, contents);
+    }
+    Ok(files)
+}
+
+/// Checks that an archived pattern has a step count the sequencer plays,
+/// a tempo and swing in range and tracks only for keys the deck has.
+fn validate_pattern(archive_path: &Path, pattern: &Pattern) -> Result<()> {
+    if !PATTERN_STEP_COUNTS.contains(&pattern.steps) {
+        return Err(invalid(
+            archive_path,
+            format!("pattern has {} steps, expected 8 or 16", pattern.steps),
+        ));
+    }
+    if !(MIN_BPM..=MAX_BPM).contains(&pattern.bpm) {
+        return Err(invalid(
+            archive_path,
+            format!("pattern tempo {} is out of range", pattern.bpm),
+        ));
+    }
+    if !(0.0..=MAX_SWING).contains(&pattern.swing) {
+        return Err(invalid(
+            archive_path,
+            format!("pattern swing {} is out of range", pattern.swing),
+        ));
+    }
+    for (name, steps) in &pattern.tracks {
+        if !(0..KEY_COUNT).any(|k| key_name(k) == *name) {
+            return Err(invalid(
+                archive_path,
+                format!("pattern has a track for unknown key '{}'", name),
+            ));
+        }
+        if steps.chars().any(|step| step != 'x' && step != '.') {
+            return Err(invalid(
+                archive_path,
+                format!("pattern track {} has steps other than 'x' and '.'", name),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the manifest is a version we understand, names only keys
+/// the deck has, keeps its values and pattern in range and only refers to
+/// files that are in the archive.
+fn validate(
+    archive_path: &Path,
+    manifest: &ArchiveManifest,
+    files: &HashMap<String, Vec<u8>>,
+) -> Result<()> {
+    if manifest.version == 0 || manifest.version > ARCHIVE_VERSION {
+        return Err(invalid(
+            archive_path,
+            format!(
+                "archive version {} is not supported (this build reads up to {})",
+                manifest.version, ARCHIVE_VERSION
+            ),
+        ));
+    }
+    if let Some(pattern) = &manifest.pattern {
+        validate_pattern(archive_path, pattern)?;
+    }
+    for (name, key) in &manifest.keys {
+        if !(0..KEY_COUNT).any(|k| key_name(k) == *name) {
+            return Err(invalid(archive_path, format!("unknown key '{}'", name)));
+        }
+        if let Some(volume) = key.volume
+            && !(0.0..=MAX_VOLUME).contains(&volume)
+        {
+            return Err(invalid(
+                archive_path,
+                format!("key {} has volume {} out of range", name, volume),
+            ));
+        }
+        if let Some(pitch) = key.pitch
+            && !pitch.is_finite()
+        {
+            return Err(invalid(
+                archive_path,
+                format!("key {} has an invalid pitch", name),
+            ));
+        }
+        for file in [&key.audio, &key.icon].into_iter().flatten() {
+            if !files.contains_key(file) {
+                return Err(invalid(
+                    archive_path,
+                    format!("key {} refers to {}, which is missing", name, file),
+                ));
+            }
+            if safe_extension(Path::new(file)).is_none() {
+                return Err(invalid(
+                    archive_path,
+                    format!(
+                        "key {} refers to {}, which has no usable extension",
+                        name, file
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `contents` to `path`, moving any file already there into the
+/// history first.
+fn write_key_file(storage_path: &Path, path: &Path, contents: &[u8]) -> Result<()> {
+    history::archive(storage_path, path, ArchiveReason::Replaced)
+        .map_err(|e| Error::io(format!("archiving {}", path.display()), e))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| Error::io(format!("creating {}", parent.display()), e))?;
+    }
+    fs::write(path, contents).map_err(|e| Error::io(format!("writing {}", path.display()), e))
+}
+
+/// Unpacks an archive made by [`export_bank`] into `bank`. The whole
+/// archive is validated before anything is written, and keys that already
+/// have a sample or settings are handled according to `conflict`.
+pub fn import_bank(
+    storage_path: &Path,
+    bank: usize,
+    archive_path: &Path,
+    conflict: Conflict,
+) -> Result<ImportSummary> {
+    // 1. Read and validate the archive
+    let files = read_archive(archive_path)?;
+    let contents = files.get(MANIFEST_NAME).ok_or_else(|| {
+        invalid(
+            archive_path,
+            format!("no {}, not a bank archive", MANIFEST_NAME),
+        )
+    })?;
+    let contents = String::from_utf8_lossy(contents);
+    let archived: ArchiveManifest = toml::from_str(&contents)
+        .map_err(|e| Error::config(format!("parsing {}", MANIFEST_NAME), e))?;
+    validate(archive_path, &archived, &files)?;
+
+    // 2. Find the keys that are already taken
+    let mut manifest = load_bank_manifest(storage_path, bank)?;
+    let taken: Vec<String> = (0..KEY_COUNT)
+        .filter(|&key| {
+            let settings = manifest.key(key).cloned().unwrap_or_default();
+            !settings.is_empty() || get_key_file_path(storage_path, bank, key).exists()
+        })
+        .map(key_name)
+        .filter(|name| archived.keys.contains_key(name))
+        .collect();
+    let pattern_taken = archived.pattern.is_some() && manifest.pattern.is_some();
+    if conflict == Conflict::Abort && !taken.is_empty() {
+        return Err(invalid(
+            archive_path,
+            format!(
+                "bank {} already uses keys {}; import with skip or replace",
+                bank + 1,
+                taken.join(", ")
+            ),
+        ));
+    }
+    if conflict == Conflict::Abort && pattern_taken {
+        return Err(invalid(
+            archive_path,
+            format!(
+                "bank {} already has a pattern; import with skip or replace",
+                bank + 1
+            ),
+        ));
+    }
+
+    // 3. Write each key's files and settings
+    let mut summary = ImportSummary::default();
+    for key in 0..KEY_COUNT {
+        let name = key_name(key);
+        let Some(entry) = archived.keys.get(&name) else {
+            continue;
+        };
+        if conflict == Conflict::Skip && taken.contains(&name) {
+            summary.skipped.push(name);
+            continue;
+        }
+        let recording = get_key_file_path(storage_path, bank, key);
+        let mut settings = KeySettings {
+            volume: entry.volume,
+            pitch: entry.pitch,
+            label: entry.label.clone(),
+            routes: entry.routes.clone(),
+            ..Default::default()
+        };
+        // The key's own recording goes to the history when replaced
+        history::archive(storage_path, &recording, ArchiveReason::Replaced)
+            .map_err(|e| Error::io(format!("archiving {}", recording.display()), e))?;
+        if let Some(audio) = &entry.audio {
+            let extension = safe_extension(Path::new(audio)).unwrap_or_default();
+            // Recordings are always WAV; anything else was a library file
+            if entry.assigned || extension != "wav" {
+                let path = imported_path(storage_path, bank, "audio", &name, &extension);
+                write_key_file(storage_path, &path, &files[audio])?;
+                settings.file = Some(path);
+            } else {
+                write_key_file(storage_path, &recording, &files[audio])?;
+            }
+        }
+        if let Some(icon) = &entry.icon {
+            let extension = safe_extension(Path::new(icon)).unwrap_or_default();
+            let path = imported_path(storage_path, bank, "icon", &name, &extension);
+            write_key_file(storage_path, &path, &files[icon])?;
+            settings.icon = Some(path);
+        }
+        *manifest.key_mut(key) = settings;
+        summary.imported.push(name);
+    }
+    if archived.pattern.is_some() && !(conflict == Conflict::Skip && pattern_taken) {
+        manifest.pattern = archived.pattern.clone();
+        summary.pattern = true;
+    }
+    save_bank_manifest(storage_path, bank, &manifest)?;
+    Ok(summary)
+}
+
+/// Where an imported file that is not a recording is kept, e.g.
+/// `bank_2/imported/icon_A.png`.
+fn imported_path(
+    storage_path: &Path,
+    bank: usize,
+    kind: &str,
+    key: &str,
+    extension: &str,
+) -> PathBuf {
+    get_bank_path(storage_path, bank)
+        .join(IMPORTED_DIR)
+        .join(format!("{}_{}.{}", kind, key, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(pattern: Pattern) -> Result<()> {
+        validate_pattern(Path::new("bank.tar.gz"), &pattern)
+    }
+
+    #[test]
+    fn validates_archived_patterns() {
+        let mut pattern = Pattern::default();
+        pattern.set_step(0, 0, true);
+        assert!(check(pattern.clone()).is_ok());
+        for steps in [0, 12, 32] {
+            assert!(
+                check(Pattern {
+                    steps,
+                    ..pattern.clone()
+                })
+                .is_err()
+            );
+        }
+        for bpm in [f64::NAN, f64::INFINITY, 0.0, MAX_BPM + 1.0] {
+            assert!(
+                check(Pattern {
+                    bpm,
+                    ..pattern.clone()
+                })
+                .is_err()
+            );
+        }
+        for swing in [f64::NAN, -0.1, MAX_SWING + 0.1] {
+            assert!(
+                check(Pattern {
+                    swing,
+                    ..pattern.clone()
+                })
+                .is_err()
+            );
+        }
+        let mut tracks = BTreeMap::new();
+        tracks.insert("Z".to_string(), "x...".to_string());
+        assert!(
+            check(Pattern {
+                tracks,
+                ..pattern.clone()
+            })
+            .is_err()
+        );
+        let mut tracks = BTreeMap::new();
+        tracks.insert("A".to_string(), "x.o.".to_string());
+        assert!(check(Pattern { tracks, ..pattern }).is_err());
+    }
+
+    #[test]
+    fn manifest_keeps_the_pattern() {
+        let mut pattern = Pattern::default();
+        pattern.set_step(1, 4, true);
+        let manifest = ArchiveManifest {
+            version: ARCHIVE_VERSION,
+            keys: BTreeMap::new(),
+            pattern: Some(pattern),
+        };
+        let contents = toml::to_string_pretty(&manifest).unwrap();
+        assert_eq!(
+            toml::from_str::<ArchiveManifest>(&contents).unwrap(),
+            manifest
+        );
+        // Archives from before patterns were exported still read
+        let old: ArchiveManifest = toml::from_str("version = 1").unwrap();
+        assert_eq!(old.pattern, None);
+    }
+}
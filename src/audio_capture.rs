@@ -1,44 +1,148 @@
-use crate::AudioCommand;
 use hound::{SampleFormat, WavSpec, WavWriter};
 use pipewire as pw;
 use pw::{properties::properties, spa};
+use soundboard::config::HistoryConfig;
+use soundboard::error::{Error, Result};
+use soundboard::history::{self, ArchiveReason};
+use soundboard::{
+    AudioCommand, AudioRequest, AudioResponse, CaptureHealth, InputLevel, TakeLevels,
+};
 use spa::param::format::{MediaSubtype, MediaType};
 use spa::param::format_utils;
 use spa::pod::Pod;
-use std::convert::TryInto;
+use std::cell::RefCell;
 use std::fs;
 use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, mpsc::Receiver};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, mpsc::Receiver};
 use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
-#[derive(Debug, PartialEq, Clone)]
-enum State {
-    Listening,
-    Recording(PathBuf),
+/// Delay before the first attempt to restart a failed capture stream.
+const RESTART_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+/// The delay doubles after every failed attempt, up to this limit.
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// A capture stream that stayed up this long counts as recovered, so the
+/// next failure starts again from the initial delay.
+const RESTART_BACKOFF_RESET: Duration = Duration::from_secs(60);
+/// How often a running capture stream checks whether the command channel
+/// has closed.
+const COMMANDS_CLOSED_CHECK: Duration = Duration::from_millis(500);
+
+/// A recording in progress. Every take shares the capture buffer and
+/// remembers where in it the take began.
+struct Take {
+    path: PathBuf,
+    /// Offset of the take's first sample in `UserData::buffer`.
+    start: usize,
+    clipped: bool,
 }
 
 struct UserData {
     format: Option<spa::param::audio::AudioInfoRaw>,
-    state: State,
+    /// Takes in the order they were started. Capture only runs while this
+    /// is non-empty.
+    takes: Vec<Take>,
+    /// Input captured since the earliest take in progress started.
     buffer: Vec<f32>,
+    level_tx: watch::Sender<TakeLevels>,
+    health_tx: watch::Sender<CaptureHealth>,
+    /// Where recordings live, so replaced files can be moved to the history.
+    storage_path: PathBuf,
+    /// How many replaced takes the history keeps.
+    history: HistoryConfig,
+}
+
+impl UserData {
+    /// Drops captured samples that no take in progress reaches back to,
+    /// so the buffer only grows while some take needs it.
+    fn trim_buffer(&mut self) {
+        let earliest = self
+            .takes
+            .iter()
+            .map(|take| take.start)
+            .min()
+            .unwrap_or(self.buffer.len());
+        self.buffer.drain(..earliest);
+        for take in &mut self.takes {
+            take.start -= earliest;
+        }
+    }
+}
+
+/// Locks state shared with a PipeWire thread. A poisoned lock only means
+/// another thread panicked while holding it; the buffers and state behind
+/// it are still consistent enough to keep the audio going, so the poison
+/// is ignored.
+pub fn lock_ignoring_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// How long `samples` interleaved samples last in `format`.
+fn take_duration(samples: usize, format: &spa::param::audio::AudioInfoRaw) -> Duration {
+    let samples_per_second = (format.channels() * format.rate()).max(1) as f64;
+    Duration::from_secs_f64(samples as f64 / samples_per_second)
+}
+
+/// Measures a block of freshly captured samples and publishes the
+/// result for every take, so the UI can draw a live meter for each.
+fn publish_levels(user_data: &mut UserData, samples: &[f32]) {
+    let Some(format) = user_data.format else {
+        return;
+    };
+    if samples.is_empty() {
+        return;
+    }
+    let mut peak = 0.0_f32;
+    let mut sum_squares = 0.0_f64;
+    for &sample in samples {
+        peak = peak.max(sample.abs());
+        sum_squares += (sample as f64) * (sample as f64);
+    }
+    let rms = (sum_squares / samples.len() as f64).sqrt() as f32;
+    let buffer_len = user_data.buffer.len();
+    let mut levels = TakeLevels::new();
+    for take in &mut user_data.takes {
+        if peak > 1.0 {
+            take.clipped = true;
+        }
+        let level = InputLevel {
+            peak,
+            rms,
+            elapsed: take_duration(buffer_len - take.start, &format),
+            clipped: take.clipped,
+        };
+        levels.insert(take.path.clone(), level);
+    }
+    user_data.level_tx.send_replace(levels);
 }
 
 fn save_recording_from_buffer(
-    buffer: Vec<f32>,
+    buffer: &[f32],
     format: &spa::param::audio::AudioInfoRaw,
     filename: &Path,
-) {
-    if buffer.is_empty() {
-        println!("Buffer is empty, not saving.");
-        return;
+    storage_path: &Path,
+    history_config: &HistoryConfig,
+) -> Result<()> {
+    // Keep the take being recorded over so it can be undone
+    if let Some(target) = history::archive(storage_path, filename, ArchiveReason::Replaced)
+        .map_err(|e| Error::io(format!("archiving {}", filename.display()), e))?
+    {
+        println!("Moved previous take to {}.", target.display());
+        match history::apply_retention(storage_path, history_config) {
+            Ok(0) => {}
+            Ok(removed) => println!("Removed {} old takes from the history.", removed),
+            Err(e) => eprintln!("Failed to prune the take history: {}", e),
+        }
     }
     if let Some(parent) = filename.parent()
         && !parent.exists()
-        && let Err(e) = fs::create_dir_all(parent)
     {
-        eprintln!("Failed to create directory {}: {}", parent.display(), e);
-        return;
+        fs::create_dir_all(parent)
+            .map_err(|e| Error::io(format!("creating directory {}", parent.display()), e))?;
     }
     let spec = WavSpec {
         channels: format.channels() as u16,
@@ -47,89 +151,404 @@ fn save_recording_from_buffer(
         sample_format: SampleFormat::Float,
     };
     println!("Saving recording to {}...", filename.display());
-    match WavWriter::create(filename, spec) {
-        Ok(mut writer) => {
-            for &sample in &buffer {
-                if let Err(e) = writer.write_sample(sample) {
-                    eprintln!("Error writing sample: {}", e);
-                    break;
-                }
-            }
-            if let Err(e) = writer.finalize() {
-                eprintln!("Error finalizing WAV file: {}", e);
-            } else {
-                println!(
-                    "Saved {} samples ({} channels) to {}.",
-                    buffer.len(),
-                    format.channels(),
-                    filename.display()
-                );
-            }
-        }
+    let mut writer = WavWriter::create(filename, spec)
+        .map_err(|e| Error::audio(format!("creating WAV file {}", filename.display()), e))?;
+    for &sample in buffer {
+        writer
+            .write_sample(sample)
+            .map_err(|e| Error::audio("writing sample", e))?;
+    }
+    writer
+        .finalize()
+        .map_err(|e| Error::audio("finalizing WAV file", e))?;
+    println!(
+        "Saved {} samples ({} channels) to {}.",
+        buffer.len(),
+        format.channels(),
+        filename.display()
+    );
+    Ok(())
+}
+
+fn start_recording(data: &Mutex<UserData>, path: PathBuf) -> AudioResponse {
+    let mut user_data = lock_ignoring_poison(data);
+    if user_data.format.is_none() {
+        return AudioResponse::Refused("audio capture is not running".to_string());
+    }
+    if user_data.takes.iter().any(|take| take.path == path) {
+        return AudioResponse::Refused("already recording to this file".to_string());
+    }
+    println!("START recording to {}", path.display());
+    // The new take begins at the current end of the shared buffer
+    let start = user_data.buffer.len();
+    user_data.takes.push(Take {
+        path: path.clone(),
+        start,
+        clipped: false,
+    });
+    user_data.level_tx.send_modify(|levels| {
+        levels.insert(path, InputLevel::default());
+    });
+    AudioResponse::Started
+}
+
+fn stop_recording(data: &Mutex<UserData>, path: PathBuf) -> AudioResponse {
+    let (buffer, format, storage_path, history_config) = {
+        // Scoped MutexGuard
+        let mut user_data = lock_ignoring_poison(data);
+        let Some(index) = user_data.takes.iter().position(|take| take.path == path) else {
+            return AudioResponse::Refused("not recording to this file".to_string());
+        };
+        let take = user_data.takes.remove(index);
+        println!("STOP recording to {}.", path.display());
+        let buffer = if user_data.takes.is_empty() {
+            // The last take can have the buffer rather than a copy
+            let mut buffer = std::mem::take(&mut user_data.buffer);
+            buffer.drain(..take.start);
+            buffer
+        } else {
+            let buffer = user_data.buffer[take.start..].to_vec();
+            user_data.trim_buffer();
+            buffer
+        };
+        user_data.level_tx.send_modify(|levels| {
+            levels.remove(&path);
+        });
+        // START is refused until the format is known, so a recording
+        // always has one.
+        let Some(format) = user_data.format else {
+            return AudioResponse::Failed("audio format unknown".to_string());
+        };
+        (
+            buffer,
+            format,
+            user_data.storage_path.clone(),
+            user_data.history.clone(),
+        )
+    };
+    if buffer.is_empty() {
+        return AudioResponse::Refused("nothing was recorded".to_string());
+    }
+    // Save data *outside* the mutex lock
+    match save_recording_from_buffer(&buffer, &format, &path, &storage_path, &history_config) {
+        Ok(()) => AudioResponse::Saved {
+            duration: take_duration(buffer.len(), &format),
+            samples: buffer.len(),
+            path,
+        },
         Err(e) => {
-            eprintln!("Error creating WAV file: {}", e);
+            // Don't leave a truncated file behind for the key to play
+            let _ = fs::remove_file(&path);
+            AudioResponse::Failed(e.to_string())
         }
     }
 }
 
 /// It runs in a separate thread and blocks on the MPSC channel.
-fn handle_audio_commands(rx: Receiver<AudioCommand>, data: Arc<Mutex<UserData>>) {
+fn handle_audio_commands(rx: Receiver<AudioRequest>, data: Arc<Mutex<UserData>>) {
     // This loop blocks on `rx.recv()`, waiting for commands from the main thread.
     // When the main thread drops its `Sender`, this loop will end.
-    for command in rx {
-        let mut save_data: Option<(Vec<f32>, spa::param::audio::AudioInfoRaw, PathBuf)> = None;
-        {
-            // Scoped MutexGuard
-            let mut user_data = data.lock().unwrap();
-            match command {
-                AudioCommand::Start(path) => {
-                    if user_data.format.is_none() {
-                        eprintln!("Refused START: Audio format not yet known.");
-                    } else {
-                        match user_data.state {
-                            State::Listening => {
-                                println!("START recording to {}", path.display());
-                                user_data.state = State::Recording(path);
-                                user_data.buffer.clear();
-                            }
-                            State::Recording(_) => {
-                                eprintln!("Refused START: Already recording.");
-                            }
-                        }
-                    }
-                }
-                AudioCommand::Stop => {
-                    let old_state = std::mem::replace(&mut user_data.state, State::Listening);
-                    if let State::Recording(save_path) = old_state {
-                        println!("STOP recording.");
-                        let buffer_to_save = std::mem::take(&mut user_data.buffer);
-                        let format_to_save = *user_data.format.as_ref().unwrap();
-                        save_data = Some((buffer_to_save, format_to_save, save_path));
-                    } else {
-                        eprintln!("Refused STOP: Not recording.");
-                    }
-                }
+    for AudioRequest { command, reply } in rx {
+        let response = match command {
+            AudioCommand::Start(path) => start_recording(&data, path),
+            AudioCommand::Stop(path) => stop_recording(&data, path),
+        };
+        match &response {
+            AudioResponse::Refused(_) | AudioResponse::Failed(_) => {
+                eprintln!("Command {}", response)
             }
+            _ => println!("Command {}", response),
         }
-        // Save data *outside* the mutex lock
-        if let Some((buffer, format, path)) = save_data {
-            save_recording_from_buffer(buffer, &format, &path);
-        }
+        // The sender may not be waiting for the outcome
+        let _ = reply.send(response);
     }
     println!("Audio command channel closed. Exiting command loop.");
 }
 
-pub fn run_capture_loop(rx: Receiver<AudioCommand>) -> Result<(), pw::Error> {
-    pw::init();
-    let mainloop = pw::main_loop::MainLoopRc::new(None)?;
-    let context = pw::context::ContextRc::new(&mainloop, None)?;
-    let core = context.connect_rc(None)?;
+/// Saves whatever each take had captured when the capture stream died,
+/// and forgets the stream format so START is refused until it is back.
+fn finish_interrupted_takes(data: &Mutex<UserData>) {
+    let (takes, buffer, format, storage_path, history_config) = {
+        let mut user_data = lock_ignoring_poison(data);
+        let takes = std::mem::take(&mut user_data.takes);
+        let buffer = std::mem::take(&mut user_data.buffer);
+        user_data.level_tx.send_replace(TakeLevels::new());
+        let format = user_data.format.take();
+        (
+            takes,
+            buffer,
+            format,
+            user_data.storage_path.clone(),
+            user_data.history.clone(),
+        )
+    };
+    let Some(format) = format else {
+        return;
+    };
+    for take in takes {
+        let samples = &buffer[take.start..];
+        if samples.is_empty() {
+            continue;
+        }
+        println!(
+            "Capture stopped mid-take. Saving what was recorded to {}.",
+            take.path.display()
+        );
+        if let Err(e) =
+            save_recording_from_buffer(samples, &format, &take.path, &storage_path, &history_config)
+        {
+            eprintln!("Failed to save interrupted take: {}", e);
+        }
+    }
+}
+
+/// Runs the capture stream and keeps it running.
+///
+/// Commands are handled on their own thread for the lifetime of the
+/// process, while the PipeWire stream runs on a thread that is restarted
+/// with exponential backoff whenever it fails, panics or loses its
+/// connection. Every change is published on `health_tx`. Takes replaced
+/// by a recording go to the history under `storage_path`, which is then
+/// pruned to what `history` keeps. Returns once the command channel has
+/// closed, stopping the capture stream if it is still up.
+pub fn run_capture_supervisor(
+    rx: Receiver<AudioRequest>,
+    level_tx: watch::Sender<TakeLevels>,
+    health_tx: watch::Sender<CaptureHealth>,
+    storage_path: PathBuf,
+    history: HistoryConfig,
+) {
     let data = Arc::new(Mutex::new(UserData {
         format: None,
-        state: State::Listening,
+        takes: Vec::new(),
         buffer: Vec::new(),
+        level_tx,
+        health_tx,
+        storage_path,
+        history,
     }));
 
+    let ipc_data = data.clone();
+    let commands_closed = Arc::new(AtomicBool::new(false));
+    let closed = commands_closed.clone();
+    thread::spawn(move || {
+        handle_audio_commands(rx, ipc_data);
+        closed.store(true, Ordering::Relaxed);
+    });
+
+    let mut backoff = RESTART_BACKOFF_INITIAL;
+    let mut attempt = 0;
+    loop {
+        let publish = |health: CaptureHealth| {
+            lock_ignoring_poison(&data).health_tx.send_replace(health);
+        };
+        publish(CaptureHealth::Starting);
+        let started = Instant::now();
+        let loop_data = data.clone();
+        let loop_closed = commands_closed.clone();
+        match thread::spawn(move || run_capture_loop(loop_data, loop_closed)).join() {
+            Ok(Ok(())) => println!("Audio capture loop exited."),
+            Ok(Err(e)) => eprintln!("Audio capture failed: {}", e),
+            Err(_) => eprintln!("Audio capture thread panicked."),
+        }
+        finish_interrupted_takes(&data);
+
+        if commands_closed.load(Ordering::Relaxed) {
+            publish(CaptureHealth::Stopped);
+            println!("Audio command channel closed. Not restarting capture.");
+            return;
+        }
+        if started.elapsed() >= RESTART_BACKOFF_RESET {
+            backoff = RESTART_BACKOFF_INITIAL;
+            attempt = 0;
+        }
+        attempt += 1;
+        publish(CaptureHealth::Restarting {
+            attempt,
+            retry_in: backoff,
+        });
+        eprintln!(
+            "Restarting audio capture in {:?} (attempt {}).",
+            backoff, attempt
+        );
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    }
+}
+
+/// The EnumFormat param for a stream of 32-bit float samples, serialized
+/// for `Pod::from_bytes`. `rate_and_channels` fixes those too, otherwise
+/// PipeWire picks them.
+pub fn f32_format_pod(rate_and_channels: Option<(u32, u32)>) -> Result<Vec<u8>> {
+    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+    audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
+    if let Some((rate, channels)) = rate_and_channels {
+        audio_info.set_rate(rate);
+        audio_info.set_channels(channels);
+    }
+    let obj = pw::spa::pod::Object {
+        type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+        id: pw::spa::param::ParamType::EnumFormat.as_raw(),
+        properties: audio_info.into(),
+    };
+    let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pw::spa::pod::Value::Object(obj),
+    )
+    .map_err(|e| Error::audio("serializing audio format", e))?
+    .0
+    .into_inner();
+    Ok(values)
+}
+
+/// Takes the next buffer of a capture stream of 32-bit float samples.
+/// Returns `None` if PipeWire has none ready.
+pub fn read_f32_buffer(stream: &pw::stream::Stream) -> Option<Vec<f32>> {
+    let mut buffer = stream.dequeue_buffer()?;
+    let datas = buffer.datas_mut();
+    let first = datas.first_mut()?;
+    let n_bytes = first.chunk().size() as usize;
+    let bytes = first.data()?;
+    // Never trust the chunk size beyond the mapped data
+    Some(
+        bytes[..n_bytes.min(bytes.len())]
+            .chunks_exact(mem::size_of::<f32>())
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    )
+}
+
+/// Fills the next buffer of a playback stream of 32-bit float samples.
+/// `fill` is told how many samples fit and returns those to play, which
+/// are cut short if there are more.
+pub fn write_f32_buffer(stream: &pw::stream::Stream, fill: impl FnOnce(usize) -> Vec<f32>) {
+    let Some(mut buffer) = stream.dequeue_buffer() else {
+        return;
+    };
+    let datas = buffer.datas_mut();
+    let Some(first) = datas.first_mut() else {
+        return;
+    };
+    let Some(bytes) = first.data() else {
+        return;
+    };
+    let samples = fill(bytes.len() / mem::size_of::<f32>());
+    let mut count = 0;
+    for (out, sample) in bytes.chunks_exact_mut(mem::size_of::<f32>()).zip(&samples) {
+        out.copy_from_slice(&sample.to_le_bytes());
+        count += 1;
+    }
+    let chunk = first.chunk_mut();
+    *chunk.offset_mut() = 0;
+    *chunk.stride_mut() = mem::size_of::<f32>() as i32;
+    *chunk.size_mut() = (count * mem::size_of::<f32>()) as u32;
+}
+
+/// A connection to PipeWire with a main loop of its own, for one thread's
+/// streams. The loop quits when the connection is lost.
+pub struct PipeWireSession {
+    _core_listener: pw::core::Listener,
+    pub core: pw::core::CoreRc,
+    _context: pw::context::ContextRc,
+    pub mainloop: pw::main_loop::MainLoopRc,
+    /// Why the main loop was stopped, reported once it returns.
+    pub failure: Rc<RefCell<Option<String>>>,
+}
+
+impl PipeWireSession {
+    pub fn connect() -> Result<Self> {
+        pw::init();
+        let mainloop = pw::main_loop::MainLoopRc::new(None)
+            .map_err(|e| Error::audio("creating PipeWire main loop", e))?;
+        let context = pw::context::ContextRc::new(&mainloop, None)
+            .map_err(|e| Error::audio("creating PipeWire context", e))?;
+        let core = context
+            .connect_rc(None)
+            .map_err(|e| Error::audio("connecting to PipeWire", e))?;
+        let failure: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+        let core_listener = core
+            .add_listener_local()
+            .error({
+                let mainloop = mainloop.clone();
+                let failure = failure.clone();
+                move |id, _seq, res, message| {
+                    eprintln!("PipeWire error on object {}: {} ({})", id, message, res);
+                    // An error on the core itself means the connection is gone
+                    if id == pw::core::PW_ID_CORE {
+                        *failure.borrow_mut() =
+                            Some(format!("PipeWire connection lost: {}", message));
+                        mainloop.quit();
+                    }
+                }
+            })
+            .register();
+        Ok(PipeWireSession {
+            _core_listener: core_listener,
+            core,
+            _context: context,
+            mainloop,
+            failure,
+        })
+    }
+
+    /// A stream `state_changed` callback that logs the change and stops
+    /// the main loop if the stream named `name` fails.
+    pub fn report_state<D>(
+        &self,
+        name: &'static str,
+    ) -> impl Fn(&pw::stream::Stream, &mut D, pw::stream::StreamState, pw::stream::StreamState) + 'static
+    {
+        let mainloop = self.mainloop.clone();
+        let failure = self.failure.clone();
+        move |_, _, old, new| {
+            println!("{} stream state: {:?} -> {:?}", name, old, new);
+            if let pw::stream::StreamState::Error(message) = new {
+                *failure.borrow_mut() = Some(format!("{} stream error: {}", name, message));
+                mainloop.quit();
+            }
+        }
+    }
+
+    /// Runs the main loop until the connection or a stream fails, and
+    /// returns why as an error while `doing` that.
+    pub fn run(&self, doing: &str) -> Result<()> {
+        self.mainloop.run();
+        let reason = self
+            .failure
+            .borrow_mut()
+            .take()
+            .unwrap_or_else(|| "main loop stopped".to_string());
+        Err(Error::audio(doing, reason))
+    }
+}
+
+/// Runs `session` over and over for the lifetime of the process, waiting
+/// `delay` after each time it stops. `name` says what stopped in the log.
+pub fn keep_running(name: &str, delay: Duration, mut session: impl FnMut() -> Result<()>) {
+    loop {
+        if let Err(e) = session() {
+            eprintln!("{} stopped: {}", name, e);
+        }
+        thread::sleep(delay);
+    }
+}
+
+/// Connects to PipeWire and captures into `data` until the connection or
+/// the stream fails, or `commands_closed` is set, returning why it stopped.
+fn run_capture_loop(data: Arc<Mutex<UserData>>, commands_closed: Arc<AtomicBool>) -> Result<()> {
+    let session = PipeWireSession::connect()?;
+    // Stop once nothing can ask for recordings any more
+    let commands_check = session.mainloop.loop_().add_timer({
+        let mainloop = session.mainloop.clone();
+        let commands_closed = commands_closed.clone();
+        move |_| {
+            if commands_closed.load(Ordering::Relaxed) {
+                mainloop.quit();
+            }
+        }
+    });
+    let _ = commands_check.update_timer(Some(COMMANDS_CLOSED_CHECK), Some(COMMANDS_CLOSED_CHECK));
+
     // --- PipeWire Stream Setup (Unchanged) ---
     let props = properties! {
         *pw::keys::MEDIA_TYPE => "Audio",
@@ -137,9 +556,11 @@ pub fn run_capture_loop(rx: Receiver<AudioCommand>) -> Result<(), pw::Error> {
         *pw::keys::MEDIA_ROLE => "Music",
         *pw::keys::STREAM_CAPTURE_SINK => "true",
     };
-    let stream = pw::stream::StreamBox::new(&core, "audio-capture", props)?;
+    let stream = pw::stream::StreamBox::new(&session.core, "audio-capture", props)
+        .map_err(|e| Error::audio("creating capture stream", e))?;
     let _listener = stream
         .add_local_listener_with_user_data(data.clone())
+        .state_changed(session.report_state("Capture"))
         .param_changed(|_, user_data_arc, id, param| {
             let Some(param) = param else {
                 return;
@@ -154,83 +575,57 @@ pub fn run_capture_loop(rx: Receiver<AudioCommand>) -> Result<(), pw::Error> {
             if media_type != MediaType::Audio || media_subtype != MediaSubtype::Raw {
                 return;
             }
-            let mut user_data = user_data_arc.lock().unwrap();
+            let mut user_data = lock_ignoring_poison(user_data_arc);
             let mut info = spa::param::audio::AudioInfoRaw::new();
-            info.parse(param)
-                .expect("Failed to parse param changed to AudioInfoRaw");
+            if let Err(e) = info.parse(param) {
+                eprintln!("Failed to parse capture format: {}", e);
+                return;
+            }
             println!(
                 "capturing rate:{} channels:{}",
                 info.rate(),
                 info.channels()
             );
             user_data.format = Some(info);
+            user_data.health_tx.send_replace(CaptureHealth::Running);
         })
         .process(|stream, user_data_arc| {
-            let mut user_data = user_data_arc.lock().unwrap();
+            let mut user_data = lock_ignoring_poison(user_data_arc);
             let Some(_format) = user_data.format.as_ref() else {
                 return;
             };
-            if user_data.state == State::Listening {
+            if user_data.takes.is_empty() {
                 let _ = stream.dequeue_buffer();
                 return;
             }
-            match stream.dequeue_buffer() {
-                None => println!("out of buffers"),
-                Some(mut buffer) => {
-                    let datas = buffer.datas_mut();
-                    if datas.is_empty() {
-                        return;
-                    }
-                    let data = &mut datas[0];
-                    let n_samples = data.chunk().size() / (mem::size_of::<f32>() as u32);
-                    if let Some(samples) = data.data() {
-                        let mut all_samples = Vec::with_capacity(n_samples as usize);
-                        for n in 0..(n_samples as usize) {
-                            let start = n * mem::size_of::<f32>();
-                            let end = start + mem::size_of::<f32>();
-                            let chan = &samples[start..end];
-                            all_samples.push(f32::from_le_bytes(chan.try_into().unwrap()));
-                        }
-                        if let State::Recording(_) = user_data.state {
-                            user_data.buffer.extend_from_slice(&all_samples);
-                        }
-                    }
-                }
-            }
+            let Some(samples) = read_f32_buffer(stream) else {
+                return;
+            };
+            user_data.buffer.extend_from_slice(&samples);
+            publish_levels(&mut user_data, &samples);
         })
-        .register()?;
+        .register()
+        .map_err(|e| Error::audio("registering capture listener", e))?;
 
-    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
-    audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
-    let obj = pw::spa::pod::Object {
-        type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
-        id: pw::spa::param::ParamType::EnumFormat.as_raw(),
-        properties: audio_info.into(),
-    };
-    let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
-        std::io::Cursor::new(Vec::new()),
-        &pw::spa::pod::Value::Object(obj),
-    )
-    .unwrap()
-    .0
-    .into_inner();
-    let mut params = [Pod::from_bytes(&values).unwrap()];
-    stream.connect(
-        spa::utils::Direction::Input,
-        None,
-        pw::stream::StreamFlags::AUTOCONNECT
-            | pw::stream::StreamFlags::MAP_BUFFERS
-            | pw::stream::StreamFlags::RT_PROCESS,
-        &mut params,
-    )?;
+    let values = f32_format_pod(None)?;
+    let pod = Pod::from_bytes(&values)
+        .ok_or_else(|| Error::audio("building capture format", "invalid POD"))?;
+    let mut params = [pod];
+    stream
+        .connect(
+            spa::utils::Direction::Input,
+            None,
+            pw::stream::StreamFlags::AUTOCONNECT
+                | pw::stream::StreamFlags::MAP_BUFFERS
+                | pw::stream::StreamFlags::RT_PROCESS,
+            &mut params,
+        )
+        .map_err(|e| Error::audio("connecting capture stream", e))?;
     // --- End of Stream Setup ---
 
-    let ipc_data = data.clone();
-    thread::spawn(move || {
-        handle_audio_commands(rx, ipc_data);
-    });
-
-    mainloop.run();
-    Ok(())
+    let result = session.run("running audio capture");
+    if commands_closed.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    result
 }
-
@@ -1,95 +1,154 @@
+use crate::audio_processor;
+use soundboard::library::is_wav_file;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use tokio::fs as tokio_fs;
 use tokio::process::Command;
 
-/// Defines where audio should be played back.
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub enum PlaybackSink {
-    Default,
-    Mixer,
-    Both,
+/// Where one copy of a sample plays: into a PipeWire node, or the default
+/// output if `node` is `None`, at `gain` times the sample's volume.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackRoute {
+    /// The route's name, for logs.
+    pub name: String,
+    pub node: Option<String>,
+    pub gain: f64,
 }
 
-/// Asynchronously plays an audio file through PipeWire.
+impl PlaybackRoute {
+    /// The default output at unchanged volume.
+    pub fn default_output() -> Self {
+        PlaybackRoute {
+            name: "default".to_string(),
+            node: None,
+            gain: 1.0,
+        }
+    }
+}
+
+/// Asynchronously plays an audio file through PipeWire, once on every
+/// route at the same time. Fails if playback failed on any of them.
 pub async fn play_audio_file(
     path: &PathBuf,
-    sink_target: PlaybackSink,
+    routes: &[PlaybackRoute],
     volume: f64,
 ) -> io::Result<()> {
     let player = "pw-play";
-    let volume_str = volume.to_string();
+    if routes.is_empty() {
+        return Err(io::Error::other("no output route is enabled"));
+    }
     println!(
         "Attempting to play file with '{}': {}",
         player,
         path.display()
     );
 
-    let mut cmd_default = Command::new(player);
-    cmd_default.arg("--volume");
-    cmd_default.arg(&volume_str);
-    cmd_default.arg(path);
-    cmd_default.stdout(Stdio::null()).stderr(Stdio::null());
+    // 1. Start a player per route so they all play together
+    let mut children = Vec::with_capacity(routes.len());
+    for route in routes {
+        let mut cmd = Command::new(player);
+        cmd.arg("--volume");
+        cmd.arg((volume * route.gain).to_string());
+        if let Some(node) = &route.node {
+            cmd.arg("--target");
+            cmd.arg(node);
+        }
+        cmd.arg(path);
+        cmd.stdout(Stdio::null()).stderr(Stdio::null());
+        println!(
+            "...routing playback to {} ({}).",
+            route.name,
+            route.node.as_deref().unwrap_or("default output")
+        );
+        children.push((route, cmd.spawn()?));
+    }
 
-    let mut cmd_mixer = Command::new(player);
-    cmd_default.arg("--volume");
-    cmd_default.arg(&volume_str);
+    // 2. Wait for all of them, noting the routes that failed
+    let mut failed = Vec::new();
+    for (route, mut child) in children {
+        match child.wait().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                eprintln!("Playback ({}) failed with status: {}", route.name, status);
+                failed.push(route.name.as_str());
+            }
+            Err(e) => {
+                eprintln!("Failed to get command status ({}): {}", route.name, e);
+                failed.push(route.name.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(io::Error::other(format!(
+            "playback failed on {}",
+            failed.join(", ")
+        )));
+    }
 
-    cmd_mixer.arg("--target");
-    cmd_mixer.arg("MyMixer");
-    cmd_mixer.arg(path);
-    cmd_mixer.stdout(Stdio::null()).stderr(Stdio::null());
+    println!("Playback successful for {} route(s).", routes.len());
+    Ok(())
+}
+
+/// Plays a key's sample with its volume and pitch shift. A pitch shift
+/// is applied to a temporary copy, which is removed once it has played.
+pub async fn play_sample(
+    path: &Path,
+    routes: &[PlaybackRoute],
+    volume: f64,
+    pitch_shift: f64,
+) -> io::Result<()> {
+    let mut temp_path: Option<PathBuf> = None;
+    // 1. Check if we need to apply pitch shift
+    // We use an epsilon (0.01) to avoid floating point issues
+    let path_to_play = if pitch_shift.abs() > 0.01 && !is_wav_file(path) {
+        println!(
+            "...Pitch shift only works on WAV files. Playing {} unshifted.",
+            path.display()
+        );
+        path.to_path_buf()
+    } else if pitch_shift.abs() > 0.01 {
+        println!("...Applying pitch shift: {:.2} semitones", pitch_shift);
 
-    match sink_target {
-        PlaybackSink::Default => {
-            println!("...routing playback to Default.");
-            let status = cmd_default.status().await?;
-            if !status.success() {
-                let msg = format!("Playback command (Default) failed with status: {}", status);
-                eprintln!("{}", msg);
-                return Err(io::Error::other(msg));
+        let path_for_blocking = path.to_path_buf();
+        // 2. Run the synchronous file I/O in a blocking thread
+        // This prevents blocking the main async runtime
+        match tokio::task::spawn_blocking(move || {
+            audio_processor::create_pitched_copy_sync(&path_for_blocking, pitch_shift)
+        })
+        .await
+        {
+            Ok(Ok(new_path)) => {
+                // Successfully created temp file
+                temp_path = Some(new_path.to_path_buf());
+                new_path
             }
-        }
-        PlaybackSink::Mixer => {
-            println!("...routing playback to sink: MyMixer");
-            let status = cmd_mixer.status().await?;
-            if !status.success() {
-                let msg = format!("Playback command (MyMixer) failed with status: {}", status);
-                eprintln!("{}", msg);
-                return Err(io::Error::other(msg));
+            Ok(Err(e)) => {
+                // Failed to create, play original
+                eprintln!("Failed to create pitched copy: {}. Playing original.", e);
+
+                path.to_path_buf()
             }
-        }
-        PlaybackSink::Both => {
-            println!("...routing playback to BOTH Default and MyMixer.");
-            let default_handle = tokio::spawn(async move { cmd_default.status().await });
-            let mixer_handle = tokio::spawn(async move { cmd_mixer.status().await });
+            Err(e) => {
+                // Task itself failed, play original
+                eprintln!("Task join error for pitched copy: {}. Playing original.", e);
 
-            match tokio::try_join!(default_handle, mixer_handle) {
-                Ok((Ok(status_default), Ok(status_mixer))) => {
-                    if !status_default.success() {
-                        eprintln!("Playback (Default) failed with status: {}", status_default);
-                    }
-                    if !status_mixer.success() {
-                        eprintln!("Playback (MyMixer) failed with status: {}", status_mixer);
-                    }
-                    if !status_default.success() || !status_mixer.success() {
-                        return Err(io::Error::other("One or more playback commands failed."));
-                    }
-                }
-                Ok((Err(e), _)) | Ok((_, Err(e))) => {
-                    let msg = format!("Failed to get command status: {}", e);
-                    eprintln!("{}", msg);
-                    return Err(io::Error::other(msg));
-                }
-                Err(e) => {
-                    let msg = format!("Task join failed: {}", e);
-                    eprintln!("{}", msg);
-                    return Err(io::Error::other(msg));
-                }
+                path.to_path_buf()
             }
         }
+    } else {
+        // No pitch shift, play original
+        path.to_path_buf()
+    };
+    // 3. Play the chosen file (original or temp)
+    let result = play_audio_file(&path_to_play, routes, volume).await;
+    // 4. Clean up the temp file if one was created
+    if let Some(p) = temp_path {
+        if let Err(e) = tokio_fs::remove_file(&p).await {
+            eprintln!("Failed to clean up temp file {}: {}", p.display(), e);
+        } else {
+            println!("Cleaned up temp file: {}", p.display());
+        }
     }
-
-    println!("Playback successful for sink: {:?}", sink_target);
-    Ok(())
+    result
 }
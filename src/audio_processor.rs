@@ -1,8 +1,13 @@
 use hound::{WavReader, WavSpec, WavWriter};
+use soundboard::config::VoiceEffect;
 use std::env;
 use std::io;
 use std::path::{Path, PathBuf};
 
+/// Longest sample `decode_wav_stereo` produces. Anything longer is cut
+/// off rather than filling memory.
+const MAX_DECODED_SECONDS: usize = 600;
+
 /// Creates a temporary, pitch-shifted copy of a WAV file.
 ///
 /// This is a synchronous function and should be called from a
@@ -87,3 +92,158 @@ pub fn create_pitched_copy_sync(original_path: &Path, semitone_shift: f64) -> io
     Ok(temp_file_path)
 }
 
+/// Decodes a WAV file to interleaved stereo at `sample_rate`, pitch
+/// shifted the way `create_pitched_copy_sync` does it: by reading through
+/// the file faster or slower. Mono files are copied to both channels, and
+/// channels past the second are dropped.
+pub fn decode_wav_stereo(
+    path: &Path,
+    sample_rate: u32,
+    semitone_shift: f64,
+) -> io::Result<Vec<f32>> {
+    let mut reader = WavReader::open(path).map_err(io::Error::other)?;
+    let spec = reader.spec();
+
+    // 1. Read every sample as a float from -1.0 to 1.0
+    let samples: Vec<f32> = match (spec.sample_format, spec.bits_per_sample) {
+        (hound::SampleFormat::Float, 32) => reader
+            .samples::<f32>()
+            .collect::<Result<_, _>>()
+            .map_err(io::Error::other)?,
+        (hound::SampleFormat::Int, bits @ (8 | 16 | 24 | 32)) => {
+            let scale = 1.0 / (1_i64 << (bits - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 * scale))
+                .collect::<Result<_, _>>()
+                .map_err(io::Error::other)?
+        }
+        _ => {
+            return Err(io::Error::other(format!(
+                "Unsupported WAV format: {:?}, {}-bit",
+                spec.sample_format, spec.bits_per_sample
+            )));
+        }
+    };
+
+    // 2. Resample, stepping through the input by the rate and pitch ratio
+    let channels = spec.channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let frame = |index: usize| {
+        let left = samples[index * channels];
+        let right = if channels > 1 {
+            samples[index * channels + 1]
+        } else {
+            left
+        };
+        (left, right)
+    };
+    let pitch_ratio = 2.0_f64.powf(semitone_shift / 12.0);
+    let step = spec.sample_rate as f64 * pitch_ratio / sample_rate.max(1) as f64;
+    if !step.is_normal() || step < 0.0 {
+        return Err(io::Error::other(format!(
+            "Cannot pitch shift by {} semitones",
+            semitone_shift
+        )));
+    }
+    let out_frames =
+        ((frames as f64 / step) as usize).min(MAX_DECODED_SECONDS * sample_rate as usize);
+    let mut out = Vec::with_capacity(out_frames * 2);
+    for n in 0..out_frames {
+        let position = n as f64 * step;
+        let index = (position as usize).min(frames - 1);
+        let fraction = (position - index as f64) as f32;
+        let (left, right) = frame(index);
+        let (next_left, next_right) = frame((index + 1).min(frames - 1));
+        out.push(left + (next_left - left) * fraction);
+        out.push(right + (next_right - right) * fraction);
+    }
+    Ok(out)
+}
+
+/// Length of the pitch shifter's delay line. Longer windows smear less
+/// but make the voice lag further behind.
+const PITCH_WINDOW: usize = 2048;
+/// Pitch shift of the chipmunk and deep voices at full amount.
+const MAX_VOICE_SHIFT_SEMITONES: f64 = 12.0;
+/// Frequency the robot voice is modulated with.
+const ROBOT_CARRIER_HZ: f64 = 50.0;
+
+/// Applies a voice effect to live mono audio, a block at a time, keeping
+/// what it needs between blocks.
+pub struct VoiceProcessor {
+    sample_rate: f64,
+    /// Recent input, read back by the pitch shifter at a sweeping delay.
+    delay_line: Vec<f32>,
+    write_index: usize,
+    /// Where the first of the pitch shifter's two taps is in its sweep,
+    /// from 0.0 to 1.0. The second is half a sweep behind.
+    sweep: f64,
+    carrier_phase: f64,
+}
+
+impl VoiceProcessor {
+    pub fn new(sample_rate: u32) -> Self {
+        VoiceProcessor {
+            sample_rate: sample_rate.max(1) as f64,
+            delay_line: vec![0.0; PITCH_WINDOW],
+            write_index: 0,
+            sweep: 0.0,
+            carrier_phase: 0.0,
+        }
+    }
+
+    /// Applies `effect` to `samples` in place. `amount` runs from 0.0
+    /// (no change) to 1.0 (the full effect).
+    pub fn process(&mut self, samples: &mut [f32], effect: VoiceEffect, amount: f64) {
+        let amount = amount.clamp(0.0, 1.0);
+        match effect {
+            VoiceEffect::None => {}
+            VoiceEffect::Chipmunk => self.shift_pitch(samples, amount * MAX_VOICE_SHIFT_SEMITONES),
+            VoiceEffect::Deep => self.shift_pitch(samples, -amount * MAX_VOICE_SHIFT_SEMITONES),
+            VoiceEffect::Robot => self.ring_modulate(samples, amount),
+        }
+    }
+
+    /// Reads a delayed sample, interpolating between neighbours.
+    fn read_delayed(&self, delay: f64) -> f32 {
+        let len = self.delay_line.len();
+        let position = (self.write_index as f64 - delay).rem_euclid(len as f64);
+        let index = position as usize % len;
+        let next = (index + 1) % len;
+        let fraction = (position - position.floor()) as f32;
+        self.delay_line[index] * (1.0 - fraction) + self.delay_line[next] * fraction
+    }
+
+    /// Shifts the pitch with two taps sweeping through a delay line at a
+    /// rate set by the ratio, crossfaded so that each tap is silent as it
+    /// jumps back to the start of its sweep.
+    fn shift_pitch(&mut self, samples: &mut [f32], semitones: f64) {
+        let ratio = 2.0_f64.powf(semitones / 12.0);
+        let window = PITCH_WINDOW as f64 - 2.0;
+        let sweep_step = (1.0 - ratio) / window;
+        for sample in samples.iter_mut() {
+            self.delay_line[self.write_index] = *sample;
+            let first = self.sweep;
+            let second = (self.sweep + 0.5) % 1.0;
+            // Sine windows half a sweep apart add up to constant power
+            let first_gain = (std::f64::consts::PI * first).sin() as f32;
+            let second_gain = (std::f64::consts::PI * second).sin() as f32;
+            *sample = self.read_delayed(first * window) * first_gain
+                + self.read_delayed(second * window) * second_gain;
+            self.sweep = (self.sweep + sweep_step).rem_euclid(1.0);
+            self.write_index = (self.write_index + 1) % self.delay_line.len();
+        }
+    }
+
+    /// Mixes in the input multiplied by a low sine, the classic robot.
+    fn ring_modulate(&mut self, samples: &mut [f32], amount: f64) {
+        let phase_step = 2.0 * std::f64::consts::PI * ROBOT_CARRIER_HZ / self.sample_rate;
+        for sample in samples.iter_mut() {
+            let carrier = self.carrier_phase.sin();
+            let modulated = *sample as f64 * carrier;
+            *sample = (*sample as f64 * (1.0 - amount) + modulated * amount) as f32;
+            self.carrier_phase = (self.carrier_phase + phase_step) % (2.0 * std::f64::consts::PI);
+        }
+    }
+}
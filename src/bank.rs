@@ -0,0 +1,165 @@
+use crate::error::{Error, Result};
+use crate::get_bank_path;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the per-bank settings file inside the bank's directory.
+const MANIFEST_FILE: &str = "bank.toml";
+
+/// Settings for one key of a bank.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct KeySettings {
+    /// A library file the key plays instead of its own recording.
+    pub file: Option<PathBuf>,
+    /// Playback volume, 1.0 being unchanged. Unset means the default.
+    pub volume: Option<f64>,
+    /// Pitch shift in semitones. Unset means the default.
+    pub pitch: Option<f64>,
+    /// Text drawn along the bottom of the key.
+    pub label: Option<String>,
+    /// An image shown on the key instead of the play icon.
+    pub icon: Option<PathBuf>,
+    /// Output routes the key plays through instead of the enabled ones.
+    pub routes: Option<Vec<String>>,
+}
+
+impl KeySettings {
+    /// Whether nothing is set, so the key can be left out of the manifest.
+    pub fn is_empty(&self) -> bool {
+        *self == KeySettings::default()
+    }
+}
+
+/// Step counts a pattern can have.
+pub const PATTERN_STEP_COUNTS: [usize; 2] = [8, 16];
+/// Tempo range of a pattern, in beats per minute.
+pub const MIN_BPM: f64 = 40.0;
+pub const MAX_BPM: f64 = 300.0;
+/// The most swing a pattern can have.
+pub const MAX_SWING: f64 = 0.5;
+
+/// A bank's step sequencer pattern: which steps each key's sample plays
+/// on, and how fast.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Pattern {
+    pub bpm: f64,
+    /// How far every second step is pushed back, as a fraction of a step
+    /// from 0.0 (straight) to 0.5.
+    pub swing: f64,
+    /// Steps in the pattern, 8 or 16. Each step is a sixteenth note.
+    pub steps: usize,
+    /// Steps each key's sample plays on, keyed by key letter. Each step
+    /// is `x` for a hit or `.` for a rest, e.g. `"x...x...x...x..."`.
+    pub tracks: BTreeMap<String, String>,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern {
+            bpm: 120.0,
+            swing: 0.0,
+            steps: 16,
+            tracks: BTreeMap::new(),
+        }
+    }
+}
+
+impl Pattern {
+    /// Every step stored for `key`, which can run past the end of the
+    /// pattern if it was shortened.
+    fn stored_track(&self, key: u8) -> Vec<bool> {
+        self.tracks
+            .get(&key_name(key))
+            .map(|hits| hits.chars().map(|step| step == 'x').collect())
+            .unwrap_or_default()
+    }
+
+    /// The steps `key` plays on, as long as the pattern.
+    pub fn track(&self, key: u8) -> Vec<bool> {
+        let mut steps = self.stored_track(key);
+        steps.resize(self.steps, false);
+        steps
+    }
+
+    /// Turns `step` of `key`'s track on or off. Steps past the end of a
+    /// shortened pattern are kept for when it is lengthened again.
+    pub fn set_step(&mut self, key: u8, step: usize, on: bool) {
+        if step >= self.steps {
+            return;
+        }
+        let mut steps = self.stored_track(key);
+        steps.resize(steps.len().max(self.steps), false);
+        steps[step] = on;
+        if steps.contains(&true) {
+            let hits = steps.iter().map(|&on| if on { 'x' } else { '.' }).collect();
+            self.tracks.insert(key_name(key), hits);
+        } else {
+            self.tracks.remove(&key_name(key));
+        }
+    }
+}
+
+/// Per-key settings of a bank, stored as `bank.toml` in its directory and
+/// keyed by key letter (`A`, `B`, ...).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct BankManifest {
+    pub keys: BTreeMap<String, KeySettings>,
+    /// The step sequencer pattern, if one was made for the bank.
+    pub pattern: Option<Pattern>,
+}
+
+impl BankManifest {
+    pub fn key(&self, key: u8) -> Option<&KeySettings> {
+        self.keys.get(&key_name(key))
+    }
+
+    pub fn key_mut(&mut self, key: u8) -> &mut KeySettings {
+        self.keys.entry(key_name(key)).or_default()
+    }
+
+    /// Drops keys that have nothing set, keeping the file tidy.
+    fn prune(&mut self) {
+        self.keys.retain(|_, settings| !settings.is_empty());
+    }
+}
+
+/// The letter a key is known by in file names and manifests.
+pub fn key_name(key: u8) -> String {
+    ((b'A' + key) as char).to_string()
+}
+
+pub fn get_manifest_path(storage_path: &Path, bank: usize) -> PathBuf {
+    get_bank_path(storage_path, bank).join(MANIFEST_FILE)
+}
+
+/// Loads a bank's manifest. A bank without one has default settings.
+pub fn load_bank_manifest(storage_path: &Path, bank: usize) -> Result<BankManifest> {
+    let path = get_manifest_path(storage_path, bank);
+    if !path.exists() {
+        return Ok(BankManifest::default());
+    }
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| Error::io(format!("reading {}", path.display()), e))?;
+    toml::from_str(&contents).map_err(|e| Error::config(format!("parsing {}", path.display()), e))
+}
+
+pub fn save_bank_manifest(storage_path: &Path, bank: usize, manifest: &BankManifest) -> Result<()> {
+    let path = get_manifest_path(storage_path, bank);
+    let mut manifest = manifest.clone();
+    manifest.prune();
+    let contents = toml::to_string_pretty(&manifest).map_err(|e| {
+        Error::io(
+            format!("serializing {}", path.display()),
+            std::io::Error::other(e),
+        )
+    })?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| Error::io(format!("creating {}", parent.display()), e))?;
+    }
+    std::fs::write(&path, contents).map_err(|e| Error::io(format!("writing {}", path.display()), e))
+}
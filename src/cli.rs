@@ -0,0 +1,728 @@
+use crate::audio_capture;
+use crate::audio_player::play_sample;
+use crate::routes::{find_route_node, list_audio_sinks, resolve_routes};
+use crate::{DEFAULT_PITCH, DEFAULT_VOLUME};
+use elgato_streamdeck::{StreamDeckError, list_devices, new_hidapi};
+use soundboard::analysis::{analyze_wav, wav_duration};
+use soundboard::archive::{Conflict, export_bank, import_bank};
+use soundboard::bank::{BankManifest, key_name, load_bank_manifest, save_bank_manifest};
+use soundboard::config::{Config, load_config};
+use soundboard::error::Error;
+use soundboard::history::{self, ArchiveReason};
+use soundboard::library::is_audio_file;
+use soundboard::schedule::{
+    Clock, Schedule, ScheduleTarget, Scheduler, SystemClock, load_schedules, parse_interval,
+    parse_time_of_day, save_schedules,
+};
+use soundboard::{
+    AudioCommand, AudioRequest, AudioResponse, CaptureHealth, KEY_COUNT, TakeLevels,
+    get_key_file_path,
+};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const USAGE: &str = "\
+Usage:
+  soundboard                       Run the soundboard on every connected deck
+  soundboard list-devices          List connected Stream Decks
+  soundboard list-keys [--bank N]  Show each key's file, length, volume and pitch
+  soundboard assign <key> <file> [--bank N]
+                                   Make <key> play <file> instead of its recording
+  soundboard clear <key> [--bank N]
+                                   Unassign <key>, or move its recording to the history
+  soundboard play <key> [--bank N] [--routes NAME,...]
+                                   Play <key> as the deck would, or through the
+                                   given output routes or PipeWire nodes
+  soundboard list-routes           Show the output routes and the PipeWire sinks
+                                   they can play into
+  soundboard record <key> --seconds N [--bank N]
+                                   Record <key> from the capture input for N seconds
+  soundboard analyze [<key>] [--bank N]
+                                   Report levels, clipping and silence of recordings
+  soundboard export <file> [--bank N]
+                                   Pack a bank's samples, key settings and pattern into <file>
+  soundboard import <file> [--bank N] [--on-conflict abort|skip|replace]
+                                   Unpack an exported bank, by default refusing
+                                   to touch keys or a pattern already in use
+  soundboard list-schedules        Show the schedules and when each is next due
+  soundboard add-schedule <name> (--at HH:MM | --every INTERVAL) (--key K | --macro NAME)
+                                   Play <K> or run a macro every day at a time,
+                                   every hour at *:MM, or on an interval like 50m
+  soundboard remove-schedule <name>
+  soundboard enable-schedule <name>
+  soundboard disable-schedule <name>
+
+Keys are named by letter, A to H. Banks count from 1 and default to the first.";
+
+/// Printed after changing a bank, since a running soundboard only reads
+/// the bank when it switches to it.
+const RELOAD_HINT: &str = "A running soundboard picks up the changes when it switches to the bank.";
+
+/// Printed after changing the schedules.
+const SCHEDULES_HINT: &str =
+    "A running soundboard picks up the change within a second if [scheduler] is enabled.";
+
+/// How long `record` waits for the capture stream to come up.
+const CAPTURE_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a subcommand did not complete.
+enum CliError {
+    /// The command line was wrong; the usage is printed with it.
+    Usage(String),
+    /// The command ran and failed.
+    Failed(Error),
+}
+
+impl From<String> for CliError {
+    fn from(message: String) -> Self {
+        CliError::Usage(message)
+    }
+}
+
+impl From<Error> for CliError {
+    fn from(e: Error) -> Self {
+        CliError::Failed(e)
+    }
+}
+
+/// Arguments after the subcommand: positional values, plus the value of
+/// every `--flag value` pair.
+struct Args {
+    positional: Vec<String>,
+    flags: Vec<(String, String)>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args, String> {
+        let mut positional = Vec::new();
+        let mut flags = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if let Some(flag) = arg.strip_prefix("--") {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| format!("--{} needs a value", flag))?;
+                flags.push((flag.to_string(), value.clone()));
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+        Ok(Args { positional, flags })
+    }
+
+    fn flag(&self, name: &str) -> Option<&str> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|(flag, _)| flag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Fails on flags the subcommand does not know, so typos are not
+    /// silently ignored.
+    fn expect_flags(&self, known: &[&str]) -> Result<(), String> {
+        match self
+            .flags
+            .iter()
+            .find(|(flag, _)| !known.contains(&flag.as_str()))
+        {
+            Some((flag, _)) => Err(format!("unknown option --{}", flag)),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the positional arguments, which must be exactly `names`.
+    fn expect_positional<const N: usize>(&self, names: [&str; N]) -> Result<[&str; N], String> {
+        if let Some(extra) = self.positional.get(N) {
+            return Err(format!("unexpected argument '{}'", extra));
+        }
+        if let Some(missing) = names.get(self.positional.len()) {
+            return Err(format!("missing <{}>", missing));
+        }
+        Ok(std::array::from_fn(|i| self.positional[i].as_str()))
+    }
+
+    /// The bank given with `--bank` (counted from 1 as on the deck),
+    /// zero-based. Defaults to the first bank.
+    fn bank(&self, config: &Config) -> Result<usize, String> {
+        let Some(value) = self.flag("bank") else {
+            return Ok(0);
+        };
+        match value.parse::<usize>() {
+            Ok(bank) if (1..=config.banks.max(1)).contains(&bank) => Ok(bank - 1),
+            _ => Err(format!(
+                "--bank must be between 1 and {}, got '{}'",
+                config.banks.max(1),
+                value
+            )),
+        }
+    }
+}
+
+/// Parses a key letter, `A` for the first key.
+fn parse_key(value: &str) -> Result<u8, String> {
+    (0..KEY_COUNT)
+        .find(|&key| key_name(key).eq_ignore_ascii_case(value))
+        .ok_or_else(|| {
+            format!(
+                "unknown key '{}', expected A to {}",
+                value,
+                key_name(KEY_COUNT - 1)
+            )
+        })
+}
+
+/// The file `key` plays in a bank, and whether it is an assigned library
+/// file rather than the key's own recording.
+fn key_file(storage_path: &Path, manifest: &BankManifest, bank: usize, key: u8) -> (PathBuf, bool) {
+    match manifest.key(key).and_then(|settings| settings.file.clone()) {
+        Some(file) => (file, true),
+        None => (get_key_file_path(storage_path, bank, key), false),
+    }
+}
+
+/// Runs the subcommand in `args` and returns the process exit code.
+pub async fn run(args: &[String], storage_path: &Path) -> i32 {
+    let config = load_config().unwrap_or_else(|e| {
+        eprintln!("Failed to load config, using defaults: {}", e);
+        Config::default()
+    });
+    let Some((command, rest)) = args.split_first() else {
+        println!("{}", USAGE);
+        return 0;
+    };
+    let result = match Args::parse(rest) {
+        Ok(args) => match command.as_str() {
+            "list-devices" => list_decks(&args, &config),
+            "list-keys" => list_keys(&args, &config, storage_path),
+            "assign" => assign(&args, &config, storage_path),
+            "clear" => clear(&args, &config, storage_path),
+            "play" => play(&args, &config, storage_path).await,
+            "list-routes" => list_routes(&args, &config).await,
+            "record" => record(&args, &config, storage_path).await,
+            "analyze" => analyze(&args, &config, storage_path),
+            "export" => export(&args, &config, storage_path),
+            "import" => import(&args, &config, storage_path),
+            "list-schedules" => list_schedules(&args, &config),
+            "add-schedule" => add_schedule(&args),
+            "remove-schedule" => remove_schedule(&args),
+            "enable-schedule" => set_schedule_enabled(&args, true),
+            "disable-schedule" => set_schedule_enabled(&args, false),
+            "help" | "--help" | "-h" => {
+                println!("{}", USAGE);
+                Ok(())
+            }
+            other => Err(CliError::Usage(format!("unknown command '{}'", other))),
+        },
+        Err(message) => Err(CliError::Usage(message)),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(CliError::Usage(message)) => {
+            eprintln!("Error: {}\n\n{}", message, USAGE);
+            2
+        }
+        Err(CliError::Failed(e)) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn list_decks(args: &Args, config: &Config) -> Result<(), CliError> {
+    args.expect_flags(&[])?;
+    args.expect_positional([])?;
+    let hid = new_hidapi()
+        .map_err(|e| Error::device("initializing HID", StreamDeckError::HidError(e)))?;
+    let decks = list_devices(&hid);
+    if decks.is_empty() {
+        println!("No Stream Deck found.");
+        return Ok(());
+    }
+    for (kind, serial) in decks {
+        let configured = if config.decks.contains_key(&serial) {
+            " (configured)"
+        } else {
+            ""
+        };
+        println!(
+            "{:?}  serial {}  product id {:#06x}{}",
+            kind,
+            serial,
+            kind.product_id(),
+            configured
+        );
+    }
+    Ok(())
+}
+
+fn list_keys(args: &Args, config: &Config, storage_path: &Path) -> Result<(), CliError> {
+    args.expect_flags(&["bank"])?;
+    args.expect_positional([])?;
+    let bank = args.bank(config)?;
+    let manifest = load_bank_manifest(storage_path, bank)?;
+    println!("Bank {}:", bank + 1);
+    for key in 0..KEY_COUNT {
+        let settings = manifest.key(key).cloned().unwrap_or_default();
+        let (file, assigned) = key_file(storage_path, &manifest, bank, key);
+        let mut line = format!("  {}  ", key_name(key));
+        if !file.exists() {
+            line.push_str(if assigned { "missing " } else { "empty" });
+            if assigned {
+                line.push_str(&file.display().to_string());
+            }
+            println!("{}", line);
+            continue;
+        }
+        line.push_str(&file.display().to_string());
+        if assigned {
+            line.push_str(" (library)");
+        }
+        match wav_duration(&file) {
+            Ok(duration) => line.push_str(&format!("  {:.1}s", duration.as_secs_f64())),
+            Err(_) => line.push_str("  length unknown"),
+        }
+        line.push_str(&format!(
+            "  volume {:.0}%  pitch {:+.2}",
+            settings.volume.unwrap_or(DEFAULT_VOLUME) * 100.0,
+            settings.pitch.unwrap_or(DEFAULT_PITCH)
+        ));
+        if let Some(label) = &settings.label {
+            line.push_str(&format!("  label \"{}\"", label));
+        }
+        if let Some(routes) = &settings.routes {
+            line.push_str(&format!("  routes {}", routes.join(",")));
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn assign(args: &Args, config: &Config, storage_path: &Path) -> Result<(), CliError> {
+    args.expect_flags(&["bank"])?;
+    let [key, file] = args.expect_positional(["key", "file"])?;
+    let key = parse_key(key)?;
+    let bank = args.bank(config)?;
+    let file =
+        std::fs::canonicalize(file).map_err(|e| Error::io(format!("opening {}", file), e))?;
+    if !is_audio_file(&file) {
+        return Err(CliError::Usage(format!(
+            "{} is not a supported audio file",
+            file.display()
+        )));
+    }
+    let mut manifest = load_bank_manifest(storage_path, bank)?;
+    manifest.key_mut(key).file = Some(file.clone());
+    save_bank_manifest(storage_path, bank, &manifest)?;
+    println!(
+        "Assigned {} to key {} of bank {}.",
+        file.display(),
+        key_name(key),
+        bank + 1
+    );
+    println!("{}", RELOAD_HINT);
+    Ok(())
+}
+
+fn clear(args: &Args, config: &Config, storage_path: &Path) -> Result<(), CliError> {
+    args.expect_flags(&["bank"])?;
+    let [key] = args.expect_positional(["key"])?;
+    let key = parse_key(key)?;
+    let bank = args.bank(config)?;
+    let mut manifest = load_bank_manifest(storage_path, bank)?;
+    let (file, assigned) = key_file(storage_path, &manifest, bank, key);
+    let settings = manifest.key_mut(key);
+    if assigned {
+        // Library files are never deleted, only unassigned
+        settings.file = None;
+        println!("Unassigned {} from key {}.", file.display(), key_name(key));
+    } else {
+        match history::archive(storage_path, &file, ArchiveReason::Deleted)
+            .map_err(|e| Error::io(format!("deleting {}", file.display()), e))?
+        {
+            Some(target) => println!("Moved {} to {}.", file.display(), target.display()),
+            None => println!("Key {} has no recording to clear.", key_name(key)),
+        }
+        settings.volume = None;
+        settings.pitch = None;
+    }
+    save_bank_manifest(storage_path, bank, &manifest)?;
+    println!("{}", RELOAD_HINT);
+    Ok(())
+}
+
+async fn play(args: &Args, config: &Config, storage_path: &Path) -> Result<(), CliError> {
+    args.expect_flags(&["bank", "routes"])?;
+    let [key] = args.expect_positional(["key"])?;
+    let key = parse_key(key)?;
+    let bank = args.bank(config)?;
+    let manifest = load_bank_manifest(storage_path, bank)?;
+    let settings = manifest.key(key).cloned().unwrap_or_default();
+    let routes = config.output_routes();
+    // As on the deck: the key's own routes, or else the enabled ones
+    let route_names: Vec<String> = match args.flag("routes") {
+        Some(names) => names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => settings.routes.clone().unwrap_or_else(|| {
+            routes
+                .iter()
+                .filter(|route| route.enabled)
+                .map(|route| route.name.clone())
+                .collect()
+        }),
+    };
+    let (file, _) = key_file(storage_path, &manifest, bank, key);
+    if !file.exists() {
+        return Err(CliError::Usage(format!(
+            "key {} of bank {} has nothing to play",
+            key_name(key),
+            bank + 1
+        )));
+    }
+    play_sample(
+        &file,
+        &resolve_routes(&route_names, &routes).await,
+        settings.volume.unwrap_or(DEFAULT_VOLUME),
+        settings.pitch.unwrap_or(DEFAULT_PITCH),
+    )
+    .await
+    .map_err(|e| Error::audio(format!("playing {}", file.display()), e))?;
+    Ok(())
+}
+
+async fn list_routes(args: &Args, config: &Config) -> Result<(), CliError> {
+    args.expect_flags(&[])?;
+    args.expect_positional([])?;
+    let sinks = list_audio_sinks().await?;
+    println!("Output routes:");
+    for route in config.output_routes() {
+        let mut line = format!("  {}", route.name);
+        if route.enabled {
+            line.push_str(" (enabled)");
+        }
+        match find_route_node(&route, &sinks) {
+            Some(Some(node)) if sinks.iter().any(|sink| sink.name == node) => {
+                line.push_str(&format!(" -> {}", node))
+            }
+            Some(Some(node)) => line.push_str(&format!(" -> {} (not present)", node)),
+            Some(None) => line.push_str(" -> default output"),
+            None => line.push_str(" -> no matching node"),
+        }
+        if route.gain != 1.0 {
+            line.push_str(&format!("  gain {:.2}", route.gain));
+        }
+        println!("{}", line);
+    }
+    println!("PipeWire sinks:");
+    if sinks.is_empty() {
+        println!("  none");
+    }
+    for sink in sinks {
+        match &sink.description {
+            Some(description) => println!("  {} ({})", sink.name, description),
+            None => println!("  {}", sink.name),
+        }
+    }
+    Ok(())
+}
+
+/// Sends `command` to the capture thread and waits for its answer.
+async fn send_capture_command(
+    audio_tx: &mpsc::Sender<AudioRequest>,
+    command: AudioCommand,
+) -> Result<AudioResponse, Error> {
+    let (request, reply) = AudioRequest::new(command);
+    audio_tx
+        .send(request)
+        .map_err(|_| Error::audio("sending capture command", "audio capture has stopped"))?;
+    reply
+        .await
+        .map_err(|_| Error::audio("waiting for capture", "audio capture has stopped"))
+}
+
+async fn record(args: &Args, config: &Config, storage_path: &Path) -> Result<(), CliError> {
+    args.expect_flags(&["bank", "seconds"])?;
+    let [key] = args.expect_positional(["key"])?;
+    let key = parse_key(key)?;
+    let bank = args.bank(config)?;
+    let seconds = args
+        .flag("seconds")
+        .ok_or_else(|| "record needs --seconds".to_string())?;
+    let length = seconds
+        .parse::<f64>()
+        .ok()
+        .filter(|s| s.is_finite() && *s > 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| format!("--seconds must be a positive number, got '{}'", seconds))?;
+    let manifest = load_bank_manifest(storage_path, bank)?;
+    if manifest
+        .key(key)
+        .is_some_and(|settings| settings.file.is_some())
+    {
+        println!(
+            "Key {} plays a library file; the recording is kept but not played until it is cleared.",
+            key_name(key)
+        );
+    }
+    let path = get_key_file_path(storage_path, bank, key);
+
+    // 1. Start the capture stream the same way the deck does
+    let (audio_tx, audio_rx) = mpsc::channel();
+    let (level_tx, _level_rx) = watch::channel(TakeLevels::new());
+    let (health_tx, mut health_rx) = watch::channel(CaptureHealth::default());
+    let capture_storage_path = storage_path.to_path_buf();
+    let capture_history = config.history.clone();
+    std::thread::spawn(move || {
+        audio_capture::run_capture_supervisor(
+            audio_rx,
+            level_tx,
+            health_tx,
+            capture_storage_path,
+            capture_history,
+        );
+    });
+    let running = tokio::time::timeout(
+        CAPTURE_START_TIMEOUT,
+        health_rx.wait_for(CaptureHealth::is_running),
+    )
+    .await
+    .is_ok_and(|health| health.is_ok());
+    if !running {
+        let health = health_rx.borrow().to_string();
+        return Err(Error::audio("starting audio capture", health).into());
+    }
+
+    // 2. Record for the requested time, or until interrupted
+    match send_capture_command(&audio_tx, AudioCommand::Start(path.clone())).await? {
+        AudioResponse::Started => {}
+        other => return Err(Error::audio("starting recording", other.to_string()).into()),
+    }
+    println!(
+        "Recording key {} for {:.1}s. Press Ctrl+C to stop early.",
+        key_name(key),
+        length.as_secs_f64()
+    );
+    tokio::select! {
+        _ = tokio::time::sleep(length) => {}
+        _ = tokio::signal::ctrl_c() => println!("Stopping early."),
+    }
+
+    // 3. Stop and report what was saved
+    match send_capture_command(&audio_tx, AudioCommand::Stop(path)).await? {
+        AudioResponse::Saved { path, duration, .. } => {
+            println!(
+                "Saved {:.1}s to {}.",
+                duration.as_secs_f64(),
+                path.display()
+            );
+            println!("{}", RELOAD_HINT);
+            Ok(())
+        }
+        other => Err(Error::audio("saving recording", other.to_string()).into()),
+    }
+}
+
+fn analyze(args: &Args, config: &Config, storage_path: &Path) -> Result<(), CliError> {
+    args.expect_flags(&["bank"])?;
+    // Every key unless one is given
+    let keys = match args.positional.as_slice() {
+        [] => (0..KEY_COUNT).collect(),
+        [key] => vec![parse_key(key)?],
+        [_, extra, ..] => return Err(CliError::Usage(format!("unexpected argument '{}'", extra))),
+    };
+    let bank = args.bank(config)?;
+    let manifest = load_bank_manifest(storage_path, bank)?;
+    println!("Bank {}:", bank + 1);
+    for key in keys {
+        let (file, _) = key_file(storage_path, &manifest, bank, key);
+        if !file.exists() {
+            continue;
+        }
+        let analysis = match analyze_wav(&file) {
+            Ok(analysis) => analysis,
+            Err(e) => {
+                println!(
+                    "  {}  {}: not analyzed ({})",
+                    key_name(key),
+                    file.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        println!(
+            "  {}  {}  {:.2}s  {} Hz x{}",
+            key_name(key),
+            file.display(),
+            analysis.duration.as_secs_f64(),
+            analysis.sample_rate,
+            analysis.channels
+        );
+        println!(
+            "     peak {:.1} dBFS  rms {:.1} dBFS  silence {:.2}s before, {:.2}s after",
+            analysis.peak_db,
+            analysis.rms_db,
+            analysis.leading_silence.as_secs_f64(),
+            analysis.trailing_silence.as_secs_f64()
+        );
+        if analysis.clipped_samples > 0 {
+            println!(
+                "     CLIPPED: {} samples at full scale",
+                analysis.clipped_samples
+            );
+        }
+    }
+    Ok(())
+}
+
+fn export(args: &Args, config: &Config, storage_path: &Path) -> Result<(), CliError> {
+    args.expect_flags(&["bank"])?;
+    let [file] = args.expect_positional(["file"])?;
+    let file = PathBuf::from(file);
+    let bank = args.bank(config)?;
+    let keys = export_bank(storage_path, bank, &file)?;
+    println!(
+        "Exported {} keys of bank {} to {}.",
+        keys,
+        bank + 1,
+        file.display()
+    );
+    Ok(())
+}
+
+fn import(args: &Args, config: &Config, storage_path: &Path) -> Result<(), CliError> {
+    args.expect_flags(&["bank", "on-conflict"])?;
+    let [file] = args.expect_positional(["file"])?;
+    let file = PathBuf::from(file);
+    let bank = args.bank(config)?;
+    let conflict = match args.flag("on-conflict") {
+        Some(value) => value.parse::<Conflict>()?,
+        None => Conflict::default(),
+    };
+    let summary = import_bank(storage_path, bank, &file, conflict)?;
+    println!("Bank {} from {}: {}.", bank + 1, file.display(), summary);
+    println!("{}", RELOAD_HINT);
+    Ok(())
+}
+
+fn list_schedules(args: &Args, config: &Config) -> Result<(), CliError> {
+    args.expect_flags(&[])?;
+    args.expect_positional([])?;
+    let schedules = load_schedules()?;
+    if !config.scheduler.enabled {
+        println!("The scheduler is disabled in the config, so none of these run.");
+    }
+    if schedules.is_empty() {
+        println!("No schedules.");
+        return Ok(());
+    }
+    let mut scheduler = Scheduler::default();
+    scheduler.set_schedules(schedules.clone(), SystemClock.now());
+    for schedule in &schedules {
+        let mut line = format!("  {}  ", schedule.name);
+        match (&schedule.at, &schedule.every) {
+            (Some(at), _) => line.push_str(&format!("at {}", at)),
+            (None, Some(every)) => line.push_str(&format!("every {}", every)),
+            (None, None) => {}
+        }
+        match schedule.target() {
+            Ok(ScheduleTarget::Key(key)) => line.push_str(&format!("  plays {}", key_name(key))),
+            Ok(ScheduleTarget::Macro(name)) => line.push_str(&format!("  runs macro {}", name)),
+            Err(e) => line.push_str(&format!("  invalid: {}", e)),
+        }
+        let next = scheduler
+            .next_due()
+            .find(|(due, _)| due.name == schedule.name)
+            .map(|(_, next)| next);
+        match next {
+            _ if !schedule.enabled => line.push_str("  (disabled)"),
+            // Intervals count from when the soundboard starts
+            Some(next) if schedule.every.is_none() => {
+                line.push_str(&format!("  next {}", next.format("%Y-%m-%d %H:%M")))
+            }
+            _ => {}
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn add_schedule(args: &Args) -> Result<(), CliError> {
+    args.expect_flags(&["at", "every", "key", "macro"])?;
+    let [name] = args.expect_positional(["name"])?;
+    let schedule = Schedule {
+        name: name.to_string(),
+        enabled: true,
+        at: args.flag("at").map(str::to_string),
+        every: args.flag("every").map(str::to_string),
+        key: args
+            .flag("key")
+            .map(parse_key)
+            .transpose()?
+            .map(|key| key + 1),
+        run_macro: args.flag("macro").map(str::to_string),
+    };
+    match (&schedule.at, &schedule.every) {
+        (Some(at), None) => parse_time_of_day(at).map(|_| ())?,
+        (None, Some(every)) => parse_interval(every).map(|_| ())?,
+        _ => return Err(CliError::Usage("give one of --at and --every".to_string())),
+    }
+    if schedule.key.is_some() == schedule.run_macro.is_some() {
+        return Err(CliError::Usage("give one of --key and --macro".to_string()));
+    }
+    let mut schedules = load_schedules()?;
+    if schedules.iter().any(|existing| existing.name == name) {
+        return Err(CliError::Usage(format!(
+            "there is already a schedule called '{}'",
+            name
+        )));
+    }
+    schedules.push(schedule);
+    save_schedules(&schedules)?;
+    println!("Added schedule '{}'.", name);
+    println!("{}", SCHEDULES_HINT);
+    Ok(())
+}
+
+fn remove_schedule(args: &Args) -> Result<(), CliError> {
+    args.expect_flags(&[])?;
+    let [name] = args.expect_positional(["name"])?;
+    let mut schedules = load_schedules()?;
+    let count = schedules.len();
+    schedules.retain(|schedule| schedule.name != name);
+    if schedules.len() == count {
+        return Err(CliError::Usage(format!(
+            "there is no schedule called '{}'",
+            name
+        )));
+    }
+    save_schedules(&schedules)?;
+    println!("Removed schedule '{}'.", name);
+    println!("{}", SCHEDULES_HINT);
+    Ok(())
+}
+
+fn set_schedule_enabled(args: &Args, enabled: bool) -> Result<(), CliError> {
+    args.expect_flags(&[])?;
+    let [name] = args.expect_positional(["name"])?;
+    let mut schedules = load_schedules()?;
+    let schedule = schedules
+        .iter_mut()
+        .find(|schedule| schedule.name == name)
+        .ok_or_else(|| format!("there is no schedule called '{}'", name))?;
+    schedule.enabled = enabled;
+    save_schedules(&schedules)?;
+    println!(
+        "{} schedule '{}'.",
+        if enabled { "Enabled" } else { "Disabled" },
+        name
+    );
+    println!("{}", SCHEDULES_HINT);
+    Ok(())
+}
@@ -0,0 +1,744 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// Something the Stream Deck Plus touch strip can be told to do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TouchAction {
+    /// Do nothing.
+    None,
+    /// Reset the value controlled by the dial under the touched panel.
+    ResetDial,
+    /// Switch to the next mode: Playback, Edit, then Looper and Pattern if
+    /// they are enabled.
+    ToggleMode,
+    /// Cycle through combinations of the configured output routes.
+    #[serde(alias = "cycle-sink")]
+    CycleRoutes,
+    /// Move to the next bank of samples.
+    NextBank,
+    /// Move to the previous bank of samples.
+    PreviousBank,
+    /// Restore the most recently deleted or replaced sample.
+    Undo,
+    /// Pause or resume every scheduled trigger.
+    ToggleSchedules,
+}
+
+/// Actions bound to gestures on the touch strip.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TouchConfig {
+    pub tap: TouchAction,
+    pub long_press: TouchAction,
+    pub swipe_left: TouchAction,
+    pub swipe_right: TouchAction,
+}
+
+impl Default for TouchConfig {
+    fn default() -> Self {
+        TouchConfig {
+            tap: TouchAction::ResetDial,
+            long_press: TouchAction::ToggleMode,
+            swipe_left: TouchAction::NextBank,
+            swipe_right: TouchAction::PreviousBank,
+        }
+    }
+}
+
+/// Per-deck settings, looked up by the deck's serial number.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DeckConfig {
+    /// Bank the deck starts on, counting from 1 as shown on the LCD.
+    pub bank: Option<usize>,
+    /// Key brightness in percent.
+    pub brightness: Option<u8>,
+}
+
+/// How many old takes are kept in the history. A limit of 0 disables it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Old takes kept per sample; older ones are deleted first.
+    pub max_takes: usize,
+    /// Old takes are deleted once they are this many days old.
+    pub max_age_days: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_takes: 20,
+            max_age_days: 30,
+        }
+    }
+}
+
+/// Where the sample library browser looks for audio files.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LibraryConfig {
+    /// Directories searched recursively. A leading `~/` is expanded.
+    pub dirs: Vec<PathBuf>,
+}
+
+/// What a MIDI control change does when it arrives.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MidiControl {
+    /// Set the volume of the selected key, 0 to 127 covering 0% to 150%.
+    Volume,
+    /// Set the pitch of the selected key, 0 to 127 covering -12 to +12
+    /// semitones with 64 unchanged.
+    Pitch,
+    /// Switch between Playback and Edit mode.
+    ToggleMode,
+    /// Cycle through combinations of the configured output routes.
+    #[serde(alias = "cycle-sink")]
+    CycleRoutes,
+    /// Move to the next bank of samples.
+    NextBank,
+    /// Move to the previous bank of samples.
+    PreviousBank,
+}
+
+/// Binds a MIDI controller number to an action.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ControlMapping {
+    pub cc: u8,
+    pub action: MidiControl,
+}
+
+/// What triggered samples are lined up with when following a MIDI clock.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Quantize {
+    /// Play straight away.
+    #[default]
+    Off,
+    /// Wait for the next beat.
+    Beat,
+    /// Wait for the start of the next bar.
+    Bar,
+}
+
+/// MIDI through the ALSA sequencer: input so pad controllers can play the
+/// board, and output so lighting rigs and DAWs can follow it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MidiConfig {
+    pub enabled: bool,
+    /// Name of the sequencer client and port other devices connect to.
+    pub port_name: String,
+    /// Source ports to connect to at startup, matched by client or port
+    /// name. Others can be connected by hand, e.g. with `aconnect`.
+    pub connect: Vec<String>,
+    /// Only listen on this channel (1 to 16). Unset listens on all.
+    #[serde(deserialize_with = "deserialize_optional_channel")]
+    pub channel: Option<u8>,
+    /// The note that plays key A; the following notes play the next keys.
+    pub base_note: u8,
+    /// Scale playback volume by note velocity.
+    pub velocity: bool,
+    pub controls: Vec<ControlMapping>,
+    /// Serial number of the deck MIDI drives. Unset follows the first deck
+    /// attached, or the board while none is.
+    pub deck: Option<String>,
+    /// Send a note when a key plays (the note that would trigger it) and
+    /// a control change when the mode changes.
+    pub output: bool,
+    /// Destination ports to connect the output to at startup, matched by
+    /// client or port name.
+    pub output_connect: Vec<String>,
+    /// Channel the output is sent on (1 to 16).
+    #[serde(deserialize_with = "deserialize_channel")]
+    pub output_channel: u8,
+    /// Control change sent when the mode changes, with a value for each
+    /// mode as listed in `config.example.toml`. Unset sends nothing.
+    pub mode_cc: Option<u8>,
+    /// Hold triggered samples until the next beat or bar of the MIDI clock
+    /// coming in on the input. Plays straight away while no clock runs:
+    /// before the first pulse, after a Stop until Start or Continue, and
+    /// once pulses stop arriving.
+    pub quantize: Quantize,
+    pub beats_per_bar: u32,
+}
+
+/// Reads a MIDI channel, refusing any outside 1 to 16.
+fn deserialize_channel<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u8, D::Error> {
+    let channel = u8::deserialize(deserializer)?;
+    if !(1..=16).contains(&channel) {
+        return Err(serde::de::Error::custom(format!(
+            "MIDI channel {} is not between 1 and 16",
+            channel
+        )));
+    }
+    Ok(channel)
+}
+
+fn deserialize_optional_channel<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u8>, D::Error> {
+    deserialize_channel(deserializer).map(Some)
+}
+
+impl Default for MidiConfig {
+    fn default() -> Self {
+        MidiConfig {
+            enabled: false,
+            port_name: "Soundboard".to_string(),
+            connect: Vec::new(),
+            channel: None,
+            base_note: 36,
+            velocity: true,
+            controls: vec![
+                ControlMapping {
+                    cc: 7,
+                    action: MidiControl::Volume,
+                },
+                ControlMapping {
+                    cc: 1,
+                    action: MidiControl::Pitch,
+                },
+            ],
+            deck: None,
+            output: false,
+            output_connect: Vec::new(),
+            output_channel: 1,
+            mode_cc: None,
+            quantize: Quantize::Off,
+            beats_per_bar: 4,
+        }
+    }
+}
+
+/// Node name of the sink behind the managed virtual microphone, which the
+/// `mixer` route plays into.
+pub const VIRTUAL_MIC_SINK: &str = "soundboard-mic-sink";
+/// Node name of the managed virtual microphone voice chat apps record from.
+pub const VIRTUAL_MIC_SOURCE: &str = "soundboard-mic";
+
+/// The virtual microphone: a sink the `mixer` route plays into, paired with
+/// a source voice chat apps can pick as their microphone.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VirtualMicConfig {
+    /// Create the virtual microphone at startup. It is removed again when
+    /// the soundboard exits.
+    pub enabled: bool,
+    /// Name voice chat apps show for it.
+    pub description: String,
+    /// Loop the real microphone into it, so one input carries both voice
+    /// and samples.
+    pub loopback: bool,
+    /// Microphone to loop in, by node name. Unset uses the default source.
+    pub microphone: Option<String>,
+    /// Sink the `mixer` route plays into while `enabled` is off, e.g. one
+    /// set up by hand.
+    pub external_sink: String,
+}
+
+impl VirtualMicConfig {
+    /// The node the `mixer` route plays into.
+    pub fn mixer_target(&self) -> &str {
+        if self.enabled {
+            VIRTUAL_MIC_SINK
+        } else {
+            &self.external_sink
+        }
+    }
+}
+
+impl Default for VirtualMicConfig {
+    fn default() -> Self {
+        VirtualMicConfig {
+            enabled: true,
+            description: "Soundboard Mic".to_string(),
+            loopback: false,
+            microphone: None,
+            external_sink: "MyMixer".to_string(),
+        }
+    }
+}
+
+/// A named place samples can play to: a PipeWire node picked by name or by
+/// its properties, with a gain of its own.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OutputRoute {
+    pub name: String,
+    /// `node.name` of the node to play into. Unset, with no `match`
+    /// either, plays to the default output.
+    pub target: Option<String>,
+    /// Properties the node must have instead, e.g.
+    /// `{ "node.description" = "USB Headset" }`, for nodes whose name
+    /// changes. The first node that has them all is used.
+    #[serde(default, rename = "match", skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+    /// Volume multiplier on top of the key's volume.
+    #[serde(default = "default_route_gain")]
+    pub gain: f64,
+    /// Whether the route is on at startup.
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+fn default_route_gain() -> f64 {
+    1.0
+}
+
+impl OutputRoute {
+    /// A route to `target`, or to the default output if `None`.
+    pub fn new(name: &str, target: Option<&str>, enabled: bool) -> Self {
+        OutputRoute {
+            name: name.to_string(),
+            target: target.map(str::to_string),
+            properties: BTreeMap::new(),
+            gain: default_route_gain(),
+            enabled,
+        }
+    }
+}
+
+/// Lowering other audio while samples play, so they are not buried under
+/// music or a game.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DuckingConfig {
+    pub enabled: bool,
+    /// How far other streams are lowered, in dB.
+    pub amount_db: f64,
+    /// How long lowering them takes once a sample starts, in milliseconds.
+    pub attack_ms: u64,
+    /// How long bringing them back takes once the last sample ends.
+    pub release_ms: u64,
+    /// Apps to lower, by `application.name` or process binary. Empty
+    /// lowers every other playback stream.
+    pub apps: Vec<String>,
+}
+
+impl Default for DuckingConfig {
+    fn default() -> Self {
+        DuckingConfig {
+            enabled: false,
+            amount_db: 12.0,
+            attack_ms: 50,
+            release_ms: 600,
+            apps: Vec::new(),
+        }
+    }
+}
+
+/// A live effect on the microphone passthrough.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum VoiceEffect {
+    #[default]
+    None,
+    /// Pitched up, by up to an octave.
+    Chipmunk,
+    /// Pitched down, by up to an octave.
+    Deep,
+    /// Ring modulated.
+    Robot,
+}
+
+impl VoiceEffect {
+    /// The effect after this one, for cycling with a dial.
+    pub fn next(self) -> Self {
+        match self {
+            VoiceEffect::None => VoiceEffect::Chipmunk,
+            VoiceEffect::Chipmunk => VoiceEffect::Deep,
+            VoiceEffect::Deep => VoiceEffect::Robot,
+            VoiceEffect::Robot => VoiceEffect::None,
+        }
+    }
+}
+
+/// How the microphone key works.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum TalkMode {
+    /// The microphone is on while the key is held.
+    PushToTalk,
+    /// Each press mutes or unmutes the microphone.
+    #[default]
+    Toggle,
+}
+
+/// Routing the microphone through the soundboard into the virtual
+/// microphone, with live voice effects.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VoiceConfig {
+    pub enabled: bool,
+    /// Microphone by node name. Unset uses the default source.
+    pub microphone: Option<String>,
+    /// Board key, counting from 1, that turns the microphone on and off.
+    /// It no longer plays or records. Unset leaves the microphone on.
+    pub key: Option<u8>,
+    pub key_mode: TalkMode,
+    /// Effect at startup. Pressing dial 2 in Playback mode cycles through
+    /// them.
+    pub effect: VoiceEffect,
+    /// How strong the effect is, from 0.0 to 1.0. Twisting dial 2 in
+    /// Playback mode changes it.
+    pub amount: f64,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        VoiceConfig {
+            enabled: false,
+            microphone: None,
+            key: None,
+            key_mode: TalkMode::Toggle,
+            effect: VoiceEffect::None,
+            amount: 0.5,
+        }
+    }
+}
+
+/// The looper: loops recorded live from an input and played back in sync.
+/// The first loop sets the length of every other one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LooperConfig {
+    /// Adds Looper mode after Edit mode when switching modes.
+    pub enabled: bool,
+    /// Source to record loops from, by node name. Unset uses the default
+    /// source.
+    pub input: Option<String>,
+    /// Node loops play into, by name. Unset plays to the default output.
+    pub target: Option<String>,
+    /// Longest first loop, in seconds. Recording stops there.
+    pub max_seconds: f64,
+    /// Time from hearing the loop to the input reaching the looper, in
+    /// milliseconds. Overdubs are moved back by it to land on the beat.
+    pub latency_ms: f64,
+}
+
+impl Default for LooperConfig {
+    fn default() -> Self {
+        LooperConfig {
+            enabled: false,
+            input: None,
+            target: None,
+            max_seconds: 60.0,
+            latency_ms: 0.0,
+        }
+    }
+}
+
+/// The step sequencer behind Pattern mode. Patterns themselves are saved
+/// with each bank.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SequencerConfig {
+    /// Adds Pattern mode after the others when switching modes.
+    pub enabled: bool,
+    /// Node patterns play into, by name. Unset plays to the default
+    /// output.
+    pub target: Option<String>,
+}
+
+/// Runs the schedules in `schedules.toml`, which `soundboard
+/// add-schedule` and friends manage.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    pub enabled: bool,
+    /// Serial number of the deck whose bank schedules play from. Unset
+    /// follows the first deck attached. Schedules play from the board's
+    /// first bank while no such deck is attached.
+    pub deck: Option<String>,
+}
+
+/// One step of a macro, e.g. `{ do = "play", key = 5, pitch = 3 }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "do", rename_all = "kebab-case")]
+pub enum MacroStep {
+    /// Plays a key, counting from 1, moved by `pitch` semitones and with
+    /// its volume scaled by `volume`, for this playback only.
+    Play {
+        key: u8,
+        #[serde(default)]
+        pitch: f64,
+        #[serde(default = "default_macro_volume")]
+        volume: f64,
+    },
+    /// Waits before the next step.
+    Wait { ms: u64 },
+    /// Plays keys without routes of their own through exactly these
+    /// routes, e.g. to switch from the speakers to the mixer.
+    Routes { routes: Vec<String> },
+    /// Turns one output route on or off.
+    Route { name: String, enabled: bool },
+    /// Switches to a bank, counting from 1.
+    Bank { bank: usize },
+    /// Runs a touch strip action, e.g. `"next-bank"`.
+    Action { action: TouchAction },
+}
+
+fn default_macro_volume() -> f64 {
+    1.0
+}
+
+/// A named sequence of steps, run from its key or by remote controls.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MacroConfig {
+    pub name: String,
+    /// Board key, counting from 1, that runs the macro, or stops it while
+    /// it runs. It no longer plays or records.
+    pub key: Option<u8>,
+    pub steps: Vec<MacroStep>,
+}
+
+/// A keyboard combination and what it does. Set either `key` or `action`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HotkeyBinding {
+    /// Keys held together, e.g. `"ctrl+alt+1"`. The last one triggers the
+    /// binding.
+    pub combo: String,
+    /// Board key the combination presses, counting from 1. It plays on
+    /// release and records while held, like the key on the deck.
+    pub key: Option<u8>,
+    /// Touch strip action the combination runs instead.
+    pub action: Option<TouchAction>,
+}
+
+/// Global keyboard hotkeys, read from evdev so they work whichever window
+/// has focus.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HotkeyConfig {
+    pub enabled: bool,
+    /// Keyboards to read, by name or `/dev/input` path. Empty reads every
+    /// keyboard.
+    pub devices: Vec<String>,
+    /// Serial number of the deck hotkeys drive. Unset follows the first
+    /// deck attached, or the board while none is.
+    pub deck: Option<String>,
+    pub bindings: Vec<HotkeyBinding>,
+}
+
+/// The OSC server, for remote controls like TouchOSC or a lighting desk.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OscConfig {
+    pub enabled: bool,
+    /// Address and UDP port to listen on. Bind to `0.0.0.0` to accept
+    /// controls from other machines.
+    pub bind: String,
+    /// Clients sent the board's state from startup, as `host:port`. Others
+    /// can ask for it by sending `/subscribe`.
+    pub feedback: Vec<String>,
+}
+
+impl Default for OscConfig {
+    fn default() -> Self {
+        OscConfig {
+            enabled: false,
+            bind: "127.0.0.1:9000".to_string(),
+            feedback: Vec::new(),
+        }
+    }
+}
+
+/// The browser control panel. It has no login, so anyone who can reach it
+/// can run the board.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebConfig {
+    pub enabled: bool,
+    /// Address and TCP port to serve the panel on.
+    pub bind: String,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            enabled: false,
+            bind: "127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+/// Settings read from `config.toml` at startup. Every field is optional
+/// in the file; anything missing falls back to its default.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Number of banks the keys can be switched between.
+    pub banks: usize,
+    pub touch: TouchConfig,
+    pub history: HistoryConfig,
+    pub library: LibraryConfig,
+    pub virtual_mic: VirtualMicConfig,
+    /// Where samples can play, as `[[routes]]` tables. Empty means the
+    /// built-in `default` and `mixer` routes.
+    pub routes: Vec<OutputRoute>,
+    pub ducking: DuckingConfig,
+    pub voice: VoiceConfig,
+    pub looper: LooperConfig,
+    pub sequencer: SequencerConfig,
+    /// Sequences of actions, as `[[macros]]` tables.
+    pub macros: Vec<MacroConfig>,
+    pub scheduler: SchedulerConfig,
+    pub midi: MidiConfig,
+    pub hotkeys: HotkeyConfig,
+    pub osc: OscConfig,
+    pub web: WebConfig,
+    /// Serial number of the deck remote controls (OSC and the web panel)
+    /// drive and report on. Unset follows the first deck attached, or the
+    /// board while none is.
+    pub remote_deck: Option<String>,
+    /// Settings for specific decks, keyed by serial number, e.g.
+    /// `[decks.CL12345678]`. Decks not listed use the defaults.
+    pub decks: HashMap<String, DeckConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            banks: 4,
+            touch: TouchConfig::default(),
+            history: HistoryConfig::default(),
+            library: LibraryConfig::default(),
+            virtual_mic: VirtualMicConfig::default(),
+            routes: Vec::new(),
+            ducking: DuckingConfig::default(),
+            voice: VoiceConfig::default(),
+            looper: LooperConfig::default(),
+            sequencer: SequencerConfig::default(),
+            macros: Vec::new(),
+            scheduler: SchedulerConfig::default(),
+            midi: MidiConfig::default(),
+            hotkeys: HotkeyConfig::default(),
+            osc: OscConfig::default(),
+            web: WebConfig::default(),
+            remote_deck: None,
+            decks: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The configured output routes, or if there are none, `default` (the
+    /// default output, enabled) and `mixer` (the virtual microphone).
+    pub fn output_routes(&self) -> Vec<OutputRoute> {
+        if !self.routes.is_empty() {
+            return self.routes.clone();
+        }
+        vec![
+            OutputRoute::new("default", None, true),
+            OutputRoute::new("mixer", Some(self.virtual_mic.mixer_target()), false),
+        ]
+    }
+
+    /// Routes that macro steps name but are not configured, as (macro,
+    /// route) pairs. Names are matched exactly, and an unknown one is
+    /// taken as a PipeWire node name, which is rarely what was meant.
+    pub fn unknown_macro_routes(&self) -> Vec<(String, String)> {
+        let routes = self.output_routes();
+        let known = |name: &String| routes.iter().any(|route| &route.name == name);
+        let mut unknown = Vec::new();
+        for config in &self.macros {
+            for step in &config.steps {
+                let names = match step {
+                    MacroStep::Routes { routes } => routes.as_slice(),
+                    MacroStep::Route { name, .. } => std::slice::from_ref(name),
+                    _ => continue,
+                };
+                for name in names.iter().filter(|name| !known(name)) {
+                    unknown.push((config.name.clone(), name.clone()));
+                }
+            }
+        }
+        unknown
+    }
+}
+
+/// Returns `~/.config/soundboard/config.toml` (or the platform equivalent).
+pub fn get_config_path() -> std::io::Result<PathBuf> {
+    match dirs::config_dir() {
+        Some(mut path) => {
+            path.push("soundboard");
+            path.push("config.toml");
+            Ok(path)
+        }
+        None => Err(std::io::Error::other("Could not find config directory")),
+    }
+}
+
+/// Loads the config file, falling back to defaults if it does not exist.
+pub fn load_config() -> Result<Config> {
+    let path = get_config_path().map_err(|e| Error::io("finding config file", e))?;
+    if !path.exists() {
+        println!("No config file at {}, using defaults.", path.display());
+        return Ok(Config::default());
+    }
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| Error::io(format!("reading {}", path.display()), e))?;
+    let config: Config = toml::from_str(&contents)
+        .map_err(|e| Error::config(format!("parsing {}", path.display()), e))?;
+    println!("Loaded config from {}", path.display());
+    for (name, route) in config.unknown_macro_routes() {
+        eprintln!(
+            "Warning: macro '{}' names route '{}', which is not configured. It will play into a PipeWire node of that name.",
+            name, route
+        );
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_midi(toml: &str) -> std::result::Result<MidiConfig, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn midi_channels_must_be_1_to_16() {
+        assert_eq!(parse_midi("channel = 1").unwrap().channel, Some(1));
+        assert_eq!(parse_midi("channel = 16").unwrap().channel, Some(16));
+        assert_eq!(parse_midi("").unwrap().channel, None);
+        assert!(parse_midi("channel = 0").is_err());
+        assert!(parse_midi("channel = 17").is_err());
+        assert_eq!(
+            parse_midi("output_channel = 10").unwrap().output_channel,
+            10
+        );
+        assert!(parse_midi("output_channel = 0").is_err());
+        assert!(parse_midi("output_channel = 17").is_err());
+    }
+
+    #[test]
+    fn macros_naming_unconfigured_routes_are_reported() {
+        let config: Config = toml::from_str(
+            r#"
+            [[macros]]
+            name = "fanfare"
+            steps = [
+              { do = "routes", routes = ["mixer", "Mixer"] },
+              { do = "route", name = "speakers", enabled = true },
+              { do = "route", name = "default", enabled = false },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.unknown_macro_routes(),
+            vec![
+                ("fanfare".to_string(), "Mixer".to_string()),
+                ("fanfare".to_string(), "speakers".to_string()),
+            ]
+        );
+    }
+}
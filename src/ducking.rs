@@ -0,0 +1,208 @@
+//! Ducking: lowering other playback streams while any sample plays, and
+//! bringing them back once the last one ends.
+
+use crate::routes::{PipeWireNode, list_nodes};
+use soundboard::config::DuckingConfig;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::watch;
+
+/// How often the volume of ducked streams is stepped during a fade.
+const FADE_STEP: Duration = Duration::from_millis(25);
+/// Processes whose streams are never ducked: the soundboard's own players.
+const OWN_BINARIES: [&str; 2] = ["pw-play", "pw-cat"];
+/// Node name prefix of the soundboard's own loopbacks.
+const OWN_NODE_PREFIX: &str = "soundboard-";
+
+/// Counts the samples playing on every deck, for ducking to follow.
+#[derive(Clone)]
+pub struct ActiveVoices(watch::Sender<usize>);
+
+/// One playing sample. It stops counting when dropped.
+pub struct Voice(watch::Sender<usize>);
+
+impl Default for ActiveVoices {
+    fn default() -> Self {
+        ActiveVoices(watch::Sender::new(0))
+    }
+}
+
+impl ActiveVoices {
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.0.subscribe()
+    }
+
+    /// Counts a sample as playing until the returned voice is dropped.
+    pub fn start(&self) -> Voice {
+        self.0.send_modify(|count| *count += 1);
+        Voice(self.0.clone())
+    }
+}
+
+impl Drop for Voice {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count = count.saturating_sub(1));
+    }
+}
+
+/// A stream that was lowered, with the volume to give back.
+struct DuckedStream {
+    id: u32,
+    volume: f64,
+}
+
+/// Whether the stream `node` should be lowered.
+fn should_duck(config: &DuckingConfig, node: &PipeWireNode) -> bool {
+    if node.media_class.as_deref() != Some("Stream/Output/Audio")
+        || node.name.starts_with(OWN_NODE_PREFIX)
+    {
+        return false;
+    }
+    let app_name = node.properties.get("application.name");
+    let binary = node.properties.get("application.process.binary");
+    if binary.is_some_and(|binary| OWN_BINARIES.contains(&binary.as_str())) {
+        return false;
+    }
+    config.apps.is_empty()
+        || config.apps.iter().any(|app| {
+            [app_name, binary]
+                .into_iter()
+                .flatten()
+                .any(|name| name.eq_ignore_ascii_case(app))
+        })
+}
+
+/// The volume `wpctl` reports for node `id`, e.g. `Volume: 0.40 [MUTED]`.
+async fn get_volume(id: u32) -> Option<f64> {
+    let output = Command::new("wpctl")
+        .arg("get-volume")
+        .arg(id.to_string())
+        .output()
+        .await
+        .ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    text.split_whitespace().nth(1)?.parse().ok()
+}
+
+async fn set_volume(id: u32, volume: f64) {
+    let result = Command::new("wpctl")
+        .arg("set-volume")
+        .arg(id.to_string())
+        .arg(format!("{:.4}", volume))
+        .status()
+        .await;
+    if let Err(e) = result {
+        eprintln!(
+            "Failed to set the volume of stream {} (is wpctl installed?): {}",
+            id, e
+        );
+    }
+}
+
+/// Finds the streams to lower and notes their volumes.
+async fn find_streams(config: &DuckingConfig) -> Vec<DuckedStream> {
+    let nodes = match tokio::task::spawn_blocking(list_nodes).await {
+        Ok(Ok(nodes)) => nodes,
+        Ok(Err(e)) => {
+            eprintln!("Failed to list streams to duck: {}", e);
+            return Vec::new();
+        }
+        Err(e) => {
+            eprintln!("Failed to list streams to duck: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut streams = Vec::new();
+    for node in nodes.iter().filter(|node| should_duck(config, node)) {
+        if let Some(volume) = get_volume(node.id).await {
+            streams.push(DuckedStream {
+                id: node.id,
+                volume,
+            });
+        }
+    }
+    streams
+}
+
+/// Sets every ducked stream to `gain` times its own volume. `wpctl`
+/// volumes are on a cubic scale, so the gain is converted to it.
+async fn apply_gain(streams: &[DuckedStream], gain: f64) {
+    let factor = gain.cbrt();
+    for stream in streams {
+        set_volume(stream.id, stream.volume * factor).await;
+    }
+}
+
+/// Puts back the volumes the streams had before they were lowered.
+async fn restore(streams: &mut Vec<DuckedStream>) {
+    if streams.is_empty() {
+        return;
+    }
+    for stream in streams.drain(..) {
+        set_volume(stream.id, stream.volume).await;
+    }
+    println!("Restored ducked streams.");
+}
+
+/// Lowers other streams while `voices_rx` counts a playing sample, fading
+/// over the attack and release times, and restores them once none play.
+/// Streams that start while ducked are left alone. Returns once shutdown
+/// is requested, with every stream restored.
+pub async fn run_ducking(
+    config: DuckingConfig,
+    mut voices_rx: watch::Receiver<usize>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    let ducked_gain = 10f64.powf(-config.amount_db.abs() / 20.0);
+    let attack = Duration::from_millis(config.attack_ms);
+    let release = Duration::from_millis(config.release_ms);
+    println!(
+        "Ducking other audio by {:.1} dB while samples play.",
+        config.amount_db.abs()
+    );
+
+    let mut streams: Vec<DuckedStream> = Vec::new();
+    let mut gain = 1.0;
+    while !*shutdown_rx.borrow() {
+        let active = *voices_rx.borrow_and_update() > 0;
+        let target = if active { ducked_gain } else { 1.0 };
+        if active && gain == 1.0 && streams.is_empty() {
+            streams = find_streams(&config).await;
+            if !streams.is_empty() {
+                println!("Ducking {} stream(s).", streams.len());
+            }
+        }
+
+        let wait = if gain == target {
+            if !active {
+                // Exact volumes rather than the faded ones
+                restore(&mut streams).await;
+            }
+            None
+        } else {
+            // 1. Step towards the target, a full fade taking the attack or
+            // release time
+            let fade = if active { attack } else { release };
+            let step = if fade.is_zero() {
+                1.0
+            } else {
+                (1.0 - ducked_gain) * FADE_STEP.as_secs_f64() / fade.as_secs_f64()
+            };
+            gain = if gain < target {
+                (gain + step).min(target)
+            } else {
+                (gain - step).max(target)
+            };
+            apply_gain(&streams, gain).await;
+            Some(FADE_STEP)
+        };
+
+        // 2. Wait for the next step, or for the voices to change
+        tokio::select! {
+            _ = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {}
+            changed = voices_rx.changed() => if changed.is_err() { break },
+            _ = shutdown_rx.changed() => {}
+        }
+    }
+    restore(&mut streams).await;
+}
@@ -0,0 +1,135 @@
+use elgato_streamdeck::StreamDeckError;
+use std::fmt;
+
+/// Everything that can go wrong in the soundboard, grouped by where it
+/// came from. Each variant carries a short description of what was being
+/// attempted, so logs read as "<context>: <cause>".
+#[derive(Debug)]
+pub enum Error {
+    /// Talking to a Stream Deck failed.
+    Device {
+        context: String,
+        source: StreamDeckError,
+    },
+    /// Capturing or playing audio failed, or the audio thread is gone.
+    Audio {
+        context: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Talking to the MIDI sequencer failed.
+    Midi {
+        context: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Reading or writing a file failed.
+    Io {
+        context: String,
+        source: std::io::Error,
+    },
+    /// The config file could not be parsed.
+    Config {
+        context: String,
+        source: toml::de::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// What the service does after an error, decided per class of error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// The deck can no longer be used. Drop the connection and let the
+    /// device supervisor reconnect when it comes back.
+    Reconnect,
+    /// Show the failure on the deck and keep going.
+    Notify,
+    /// Carry on with default settings.
+    UseDefaults,
+}
+
+impl Error {
+    pub fn device(context: impl Into<String>, source: StreamDeckError) -> Self {
+        Error::Device {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn audio(
+        context: impl Into<String>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Error::Audio {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn midi(
+        context: impl Into<String>,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Error::Midi {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub fn io(context: impl Into<String>, source: std::io::Error) -> Self {
+        Error::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn config(context: impl Into<String>, source: toml::de::Error) -> Self {
+        Error::Config {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// The recovery policy for this error.
+    ///
+    /// HID failures mean the deck has gone away, so the connection is
+    /// dropped and re-established. Other deck errors (a bad image, an
+    /// invalid key) as well as audio, MIDI and file errors only affect one
+    /// action, so they are reported on the deck. A broken config file
+    /// falls back to the defaults.
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Error::Device {
+                source: StreamDeckError::HidError(_),
+                ..
+            } => Recovery::Reconnect,
+            Error::Device { .. } | Error::Audio { .. } | Error::Midi { .. } | Error::Io { .. } => {
+                Recovery::Notify
+            }
+            Error::Config { .. } => Recovery::UseDefaults,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Device { context, source } => write!(f, "{}: {}", context, source),
+            Error::Audio { context, source } => write!(f, "{}: {}", context, source),
+            Error::Midi { context, source } => write!(f, "{}: {}", context, source),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::Config { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Device { source, .. } => Some(source),
+            Error::Audio { source, .. } => Some(source.as_ref()),
+            Error::Midi { source, .. } => Some(source.as_ref()),
+            Error::Io { source, .. } => Some(source),
+            Error::Config { source, .. } => Some(source),
+        }
+    }
+}
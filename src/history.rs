@@ -0,0 +1,426 @@
+use crate::config::HistoryConfig;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the directory under the storage path that holds old takes.
+const HISTORY_DIR: &str = ".history";
+
+/// Why a take was moved into the history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveReason {
+    /// The sample was deleted from its key.
+    Deleted,
+    /// A new recording or file was saved over it.
+    Replaced,
+    /// It was the current take when an older one was restored. Undo skips
+    /// these, so repeated undos keep going back in time.
+    Undone,
+}
+
+impl ArchiveReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ArchiveReason::Deleted => "deleted",
+            ArchiveReason::Replaced => "replaced",
+            ArchiveReason::Undone => "undone",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "deleted" => Some(ArchiveReason::Deleted),
+            "replaced" => Some(ArchiveReason::Replaced),
+            "undone" => Some(ArchiveReason::Undone),
+            _ => None,
+        }
+    }
+}
+
+/// A previous version of a sample, kept in the history directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Take {
+    /// Where the old take is stored now.
+    pub path: PathBuf,
+    pub archived_at: SystemTime,
+    pub reason: ArchiveReason,
+}
+
+impl Take {
+    /// Parses a history file named `<unix millis>-<reason>.<ext>`.
+    fn from_path(path: PathBuf) -> Option<Take> {
+        let stem = path.file_stem()?.to_str()?;
+        let (millis, reason) = stem.split_once('-')?;
+        let millis: u64 = millis.parse().ok()?;
+        Some(Take {
+            archived_at: UNIX_EPOCH + Duration::from_millis(millis),
+            reason: ArchiveReason::parse(reason)?,
+            path,
+        })
+    }
+
+    /// How long ago the take was moved into the history.
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.archived_at)
+            .unwrap_or_default()
+    }
+}
+
+pub fn get_history_path(storage_path: &Path) -> PathBuf {
+    storage_path.join(HISTORY_DIR)
+}
+
+/// Returns the directory holding the old takes of `file`, which mirrors
+/// the file's place under the storage path, e.g. `bank_2/recording_A.wav`
+/// keeps its takes in `.history/bank_2/recording_A/`.
+fn takes_dir(storage_path: &Path, file: &Path) -> PathBuf {
+    let relative = file.strip_prefix(storage_path).unwrap_or(file);
+    let relative = relative.with_extension("");
+    // Files from outside the storage path are filed under their name
+    let relative = if relative.is_absolute() {
+        PathBuf::from(relative.file_name().unwrap_or_default())
+    } else {
+        relative
+    };
+    get_history_path(storage_path).join(relative)
+}
+
+/// Moves `file` into the history. Returns where it went, or `None` if
+/// there was no file to archive.
+pub fn archive(
+    storage_path: &Path,
+    file: &Path,
+    reason: ArchiveReason,
+) -> io::Result<Option<PathBuf>> {
+    if !file.exists() {
+        return Ok(None);
+    }
+    let dir = takes_dir(storage_path, file);
+    fs::create_dir_all(&dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let extension = file.extension().and_then(|e| e.to_str()).unwrap_or("wav");
+    let mut target = dir.join(format!("{:013}-{}.{}", millis, reason.as_str(), extension));
+    // Two archives within the same millisecond must not overwrite each other
+    let mut bump = millis;
+    while target.exists() {
+        bump += 1;
+        target = dir.join(format!("{:013}-{}.{}", bump, reason.as_str(), extension));
+    }
+    fs::rename(file, &target)?;
+    Ok(Some(target))
+}
+
+/// Lists the old takes in `dir`, newest first.
+fn takes_in(dir: &Path) -> io::Result<Vec<Take>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut takes = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file()
+            && let Some(take) = Take::from_path(path)
+        {
+            takes.push(take);
+        }
+    }
+    takes.sort_by_key(|take| std::cmp::Reverse(take.archived_at));
+    Ok(takes)
+}
+
+/// Lists the previous takes of `file`, newest first.
+pub fn list_takes(storage_path: &Path, file: &Path) -> io::Result<Vec<Take>> {
+    takes_in(&takes_dir(storage_path, file))
+}
+
+/// Puts `take` back as `file`. The current file, if any, is archived
+/// first so the restore can itself be undone from the take browser.
+pub fn restore(storage_path: &Path, file: &Path, take: &Take) -> io::Result<()> {
+    archive(storage_path, file, ArchiveReason::Undone)?;
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&take.path, file)
+}
+
+/// Calls `visit` for every directory under `dir` that holds takes.
+fn visit_take_dirs(dir: &Path, visit: &mut dyn FnMut(&Path) -> io::Result<()>) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let mut has_files = false;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            visit_take_dirs(&path, visit)?;
+        } else {
+            has_files = true;
+        }
+    }
+    if has_files {
+        visit(dir)?;
+    }
+    Ok(())
+}
+
+/// Restores the most recently deleted or replaced take across every key
+/// and bank. Returns the file it was restored to and the take, or `None`
+/// if there is nothing to undo.
+pub fn undo_last(storage_path: &Path) -> io::Result<Option<(PathBuf, Take)>> {
+    let history_path = get_history_path(storage_path);
+    let mut latest: Option<(PathBuf, Take)> = None;
+    visit_take_dirs(&history_path, &mut |dir| {
+        let newest = takes_in(dir)?
+            .into_iter()
+            .find(|take| take.reason != ArchiveReason::Undone);
+        if let Some(take) = newest
+            && latest
+                .as_ref()
+                .is_none_or(|(_, current)| take.archived_at > current.archived_at)
+        {
+            latest = Some((dir.to_path_buf(), take));
+        }
+        Ok(())
+    })?;
+    let Some((dir, take)) = latest else {
+        return Ok(None);
+    };
+    // The take's directory mirrors the original file without extension
+    let relative = dir.strip_prefix(&history_path).unwrap_or(&dir);
+    let extension = take.path.extension().unwrap_or_default();
+    let file = storage_path.join(relative).with_extension(extension);
+    restore(storage_path, &file, &take)?;
+    Ok(Some((file, take)))
+}
+
+/// Deletes old takes beyond what `config` keeps: more than `max_takes`
+/// per sample, or older than `max_age_days`. A limit of 0 disables it.
+/// Returns how many takes were removed.
+pub fn apply_retention(storage_path: &Path, config: &HistoryConfig) -> io::Result<usize> {
+    let max_age = Duration::from_secs(config.max_age_days * 24 * 60 * 60);
+    let mut removed = 0;
+    visit_take_dirs(&get_history_path(storage_path), &mut |dir| {
+        for (index, take) in takes_in(dir)?.into_iter().enumerate() {
+            let too_many = config.max_takes > 0 && index >= config.max_takes;
+            let too_old = config.max_age_days > 0 && take.age() > max_age;
+            if too_many || too_old {
+                fs::remove_file(&take.path)?;
+                removed += 1;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A storage directory of its own under the system temp directory,
+    /// removed again when dropped.
+    struct TempStorage(PathBuf);
+
+    impl TempStorage {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "soundboard-history-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempStorage(path)
+        }
+
+        /// Writes `contents` to `relative` under the storage directory.
+        fn write(&self, relative: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path
+        }
+
+        /// Puts an old take of `file` in the history as if it had been
+        /// archived at `millis` since the epoch.
+        fn take(&self, file: &str, millis: u128, reason: ArchiveReason, contents: &str) {
+            let dir = takes_dir(&self.0, &self.0.join(file));
+            let extension = Path::new(file).extension().unwrap().to_str().unwrap();
+            let name = format!("{:013}-{}.{}", millis, reason.as_str(), extension);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        fn read(&self, relative: &str) -> String {
+            fs::read_to_string(self.0.join(relative)).unwrap()
+        }
+    }
+
+    impl Drop for TempStorage {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn now_millis() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    #[test]
+    fn takes_dir_mirrors_the_file_without_its_extension() {
+        let storage = Path::new("/storage");
+        let history = storage.join(HISTORY_DIR);
+        assert_eq!(
+            takes_dir(storage, &storage.join("recording_A.wav")),
+            history.join("recording_A")
+        );
+        assert_eq!(
+            takes_dir(storage, &storage.join("bank_2/recording_B.wav")),
+            history.join("bank_2/recording_B")
+        );
+        assert_eq!(
+            takes_dir(storage, Path::new("/elsewhere/horn.mp3")),
+            history.join("horn")
+        );
+    }
+
+    #[test]
+    fn archive_and_undo_restore_the_same_file() {
+        let storage = TempStorage::new("round-trip");
+        let file = storage.write("bank_2/imported/horn.mp3", "old");
+        let archived = archive(&storage.0, &file, ArchiveReason::Deleted)
+            .unwrap()
+            .unwrap();
+        assert!(!file.exists());
+        assert!(archived.starts_with(storage.0.join(".history/bank_2/imported/horn")));
+        assert_eq!(archived.extension().unwrap(), "mp3");
+
+        let (restored, take) = undo_last(&storage.0).unwrap().unwrap();
+        assert_eq!(restored, file);
+        assert_eq!(take.reason, ArchiveReason::Deleted);
+        assert_eq!(storage.read("bank_2/imported/horn.mp3"), "old");
+        assert_eq!(undo_last(&storage.0).unwrap(), None);
+    }
+
+    #[test]
+    fn archiving_nothing_is_not_an_error() {
+        let storage = TempStorage::new("missing");
+        let file = storage.0.join("recording_A.wav");
+        assert_eq!(
+            archive(&storage.0, &file, ArchiveReason::Replaced).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn undo_goes_back_in_time_across_banks() {
+        let storage = TempStorage::new("order");
+        storage.write("recording_A.wav", "A now");
+        storage.write("bank_2/recording_B.wav", "B now");
+        storage.take("recording_A.wav", 1_000, ArchiveReason::Replaced, "A first");
+        storage.take(
+            "bank_2/recording_B.wav",
+            2_000,
+            ArchiveReason::Deleted,
+            "B first",
+        );
+        storage.take(
+            "recording_A.wav",
+            3_000,
+            ArchiveReason::Replaced,
+            "A second",
+        );
+
+        let (file, _) = undo_last(&storage.0).unwrap().unwrap();
+        assert_eq!(file, storage.0.join("recording_A.wav"));
+        assert_eq!(storage.read("recording_A.wav"), "A second");
+
+        // The take put aside by the first undo is skipped
+        let (file, _) = undo_last(&storage.0).unwrap().unwrap();
+        assert_eq!(file, storage.0.join("bank_2/recording_B.wav"));
+        assert_eq!(storage.read("bank_2/recording_B.wav"), "B first");
+
+        let (file, _) = undo_last(&storage.0).unwrap().unwrap();
+        assert_eq!(file, storage.0.join("recording_A.wav"));
+        assert_eq!(storage.read("recording_A.wav"), "A first");
+        assert_eq!(undo_last(&storage.0).unwrap(), None);
+    }
+
+    #[test]
+    fn undo_skips_undone_takes() {
+        let storage = TempStorage::new("undone");
+        storage.take("recording_C.wav", 1_000, ArchiveReason::Replaced, "kept");
+        storage.take("recording_C.wav", 2_000, ArchiveReason::Undone, "put aside");
+        let (_, take) = undo_last(&storage.0).unwrap().unwrap();
+        assert_eq!(take.reason, ArchiveReason::Replaced);
+        assert_eq!(storage.read("recording_C.wav"), "kept");
+    }
+
+    #[test]
+    fn retention_keeps_the_newest_takes() {
+        let storage = TempStorage::new("max-takes");
+        let now = now_millis();
+        for i in 0..5 {
+            storage.take("recording_A.wav", now - i, ArchiveReason::Replaced, "");
+        }
+        storage.take("bank_2/recording_A.wav", now, ArchiveReason::Replaced, "");
+        let config = HistoryConfig {
+            max_takes: 2,
+            max_age_days: 0,
+        };
+        assert_eq!(apply_retention(&storage.0, &config).unwrap(), 3);
+        let file = storage.0.join("recording_A.wav");
+        let kept: Vec<u128> = list_takes(&storage.0, &file)
+            .unwrap()
+            .iter()
+            .map(|take| {
+                take.archived_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis()
+            })
+            .collect();
+        assert_eq!(kept, vec![now, now - 1]);
+        let other = storage.0.join("bank_2/recording_A.wav");
+        assert_eq!(list_takes(&storage.0, &other).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retention_removes_takes_past_the_age_limit() {
+        let storage = TempStorage::new("max-age");
+        let day = 24 * 60 * 60 * 1000;
+        let now = now_millis();
+        storage.take("recording_A.wav", now - 3 * day, ArchiveReason::Deleted, "");
+        storage.take("recording_A.wav", now - day / 2, ArchiveReason::Deleted, "");
+        let config = HistoryConfig {
+            max_takes: 0,
+            max_age_days: 2,
+        };
+        assert_eq!(apply_retention(&storage.0, &config).unwrap(), 1);
+        let file = storage.0.join("recording_A.wav");
+        assert_eq!(list_takes(&storage.0, &file).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn zero_limits_keep_takes_forever() {
+        let storage = TempStorage::new("forever");
+        for i in 0..30 {
+            storage.take("recording_A.wav", 1_000 + i, ArchiveReason::Replaced, "");
+        }
+        let config = HistoryConfig {
+            max_takes: 0,
+            max_age_days: 0,
+        };
+        assert_eq!(apply_retention(&storage.0, &config).unwrap(), 0);
+        let file = storage.0.join("recording_A.wav");
+        assert_eq!(list_takes(&storage.0, &file).unwrap().len(), 30);
+    }
+}
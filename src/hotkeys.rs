@@ -0,0 +1,336 @@
+use evdev::{Device, EventType, KeyCode};
+use soundboard::KEY_COUNT;
+use soundboard::config::{HotkeyBinding, HotkeyConfig, TouchAction};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// How often to look for keyboards that were plugged in.
+const HOTKEY_RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+/// evdev key event values.
+const KEY_RELEASED: i32 = 0;
+const KEY_PRESSED: i32 = 1;
+
+/// Something a hotkey asked the board to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotkeyEvent {
+    /// The combination for `key` was pressed.
+    KeyDown(u8),
+    /// The last key of the combination for `key` was released.
+    KeyUp(u8),
+    Action(TouchAction),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Key(u8),
+    Action(TouchAction),
+}
+
+/// A parsed binding. Each step of the combination accepts any of its
+/// codes, so that `ctrl` matches either Ctrl key.
+#[derive(Debug)]
+pub struct Hotkey {
+    keys: Vec<Vec<KeyCode>>,
+    target: Target,
+}
+
+/// Looks up a key by evdev name, with or without `KEY_`, e.g. `f13` or
+/// `KEY_KP1`. `ctrl`, `shift`, `alt` and `meta` match either side.
+fn parse_key_name(name: &str) -> Result<Vec<KeyCode>, String> {
+    let name = name.trim().to_ascii_uppercase();
+    let either = |left, right| Ok(vec![left, right]);
+    match name.as_str() {
+        "CTRL" | "CONTROL" => either(KeyCode::KEY_LEFTCTRL, KeyCode::KEY_RIGHTCTRL),
+        "SHIFT" => either(KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT),
+        "ALT" => either(KeyCode::KEY_LEFTALT, KeyCode::KEY_RIGHTALT),
+        "META" | "SUPER" => either(KeyCode::KEY_LEFTMETA, KeyCode::KEY_RIGHTMETA),
+        _ => {
+            let full = if name.starts_with("KEY_") {
+                name.clone()
+            } else {
+                format!("KEY_{}", name)
+            };
+            KeyCode::from_str(&full)
+                .map(|code| vec![code])
+                .map_err(|_| format!("unknown key '{}'", name))
+        }
+    }
+}
+
+fn parse_binding(binding: &HotkeyBinding) -> Result<Hotkey, String> {
+    let keys = binding
+        .combo
+        .split('+')
+        .map(parse_key_name)
+        .collect::<Result<Vec<_>, _>>()?;
+    let target = match (binding.key, binding.action) {
+        (Some(key), None) if (1..=KEY_COUNT).contains(&key) => Target::Key(key - 1),
+        (Some(key), None) => return Err(format!("no key {}, expected 1 to {}", key, KEY_COUNT)),
+        // There is no dial under a keyboard
+        (None, Some(TouchAction::ResetDial)) => {
+            return Err("reset-dial only works on the touch strip".to_string());
+        }
+        (None, Some(action)) => Target::Action(action),
+        _ => return Err("set either key or action".to_string()),
+    };
+    Ok(Hotkey { keys, target })
+}
+
+/// Parses the configured bindings, skipping (and reporting) broken ones.
+pub fn parse_bindings(bindings: &[HotkeyBinding]) -> Vec<Hotkey> {
+    bindings
+        .iter()
+        .filter_map(|binding| match parse_binding(binding) {
+            Ok(hotkey) => Some(hotkey),
+            Err(e) => {
+                eprintln!("Ignoring hotkey '{}': {}", binding.combo, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Follows the keys held on one keyboard and turns completed combinations
+/// into hotkey events.
+pub struct ComboMatcher {
+    hotkeys: Arc<Vec<Hotkey>>,
+    held: HashSet<KeyCode>,
+    /// What each held trigger key fired, to release it again.
+    active: HashMap<KeyCode, Target>,
+}
+
+impl ComboMatcher {
+    pub fn new(hotkeys: Arc<Vec<Hotkey>>) -> Self {
+        ComboMatcher {
+            hotkeys,
+            held: HashSet::new(),
+            active: HashMap::new(),
+        }
+    }
+
+    /// Fires the most specific combination `code` completes, so that
+    /// `ctrl+shift+1` wins over `ctrl+1` while Shift is held.
+    pub fn key_down(&mut self, code: KeyCode) -> Option<HotkeyEvent> {
+        self.held.insert(code);
+        let held = &self.held;
+        let hotkey = self
+            .hotkeys
+            .iter()
+            .filter(|hotkey| hotkey.keys.last().is_some_and(|last| last.contains(&code)))
+            .filter(|hotkey| {
+                hotkey
+                    .keys
+                    .iter()
+                    .all(|step| step.iter().any(|key| held.contains(key)))
+            })
+            .max_by_key(|hotkey| hotkey.keys.len())?;
+        self.active.insert(code, hotkey.target);
+        Some(match hotkey.target {
+            Target::Key(key) => HotkeyEvent::KeyDown(key),
+            Target::Action(action) => HotkeyEvent::Action(action),
+        })
+    }
+
+    pub fn key_up(&mut self, code: KeyCode) -> Option<HotkeyEvent> {
+        self.held.remove(&code);
+        match self.active.remove(&code)? {
+            Target::Key(key) => Some(HotkeyEvent::KeyUp(key)),
+            Target::Action(_) => None,
+        }
+    }
+}
+
+/// Whether hotkeys should be read from `device`: one of the configured
+/// devices, or any keyboard if none are configured.
+fn is_hotkey_device(config: &HotkeyConfig, path: &Path, device: &Device) -> bool {
+    if config.devices.is_empty() {
+        return device
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(KeyCode::KEY_A));
+    }
+    config
+        .devices
+        .iter()
+        .any(|wanted| Path::new(wanted) == path || device.name() == Some(wanted.as_str()))
+}
+
+/// Reads key events from `device` until it goes away.
+async fn read_keyboard(
+    device: Device,
+    hotkeys: Arc<Vec<Hotkey>>,
+    event_tx: broadcast::Sender<HotkeyEvent>,
+) -> io::Result<()> {
+    let mut events = device.into_event_stream()?;
+    let mut matcher = ComboMatcher::new(hotkeys);
+    loop {
+        let event = events.next_event().await?;
+        if event.event_type() != EventType::KEY {
+            continue;
+        }
+        let code = KeyCode::new(event.code());
+        // Auto-repeat is ignored, a held combination fires once
+        let hotkey_event = match event.value() {
+            KEY_PRESSED => matcher.key_down(code),
+            KEY_RELEASED => matcher.key_up(code),
+            _ => None,
+        };
+        if let Some(hotkey_event) = hotkey_event {
+            // Fails only while the role moves between loops
+            let _ = event_tx.send(hotkey_event);
+        }
+    }
+}
+
+/// Reads hotkeys from the configured keyboards and sends what they do to
+/// the deck with the hotkeys role, or the board while no deck has it.
+/// Keyboards that are unplugged are picked up again when they come back.
+/// Runs until the process exits.
+pub async fn run_hotkeys(config: HotkeyConfig, event_tx: broadcast::Sender<HotkeyEvent>) {
+    let hotkeys = Arc::new(parse_bindings(&config.bindings));
+    if hotkeys.is_empty() {
+        println!("No hotkeys configured.");
+        return;
+    }
+    let mut reading: HashMap<PathBuf, JoinHandle<()>> = HashMap::new();
+    let mut missing_logged = false;
+    loop {
+        reading.retain(|_, handle| !handle.is_finished());
+        // Opening every input device blocks, so keep it off the runtime
+        let devices = tokio::task::spawn_blocking(|| evdev::enumerate().collect::<Vec<_>>())
+            .await
+            .unwrap_or_default();
+        for (path, device) in devices {
+            if reading.contains_key(&path) || !is_hotkey_device(&config, &path, &device) {
+                continue;
+            }
+            println!(
+                "Reading hotkeys from {} ({})",
+                device.name().unwrap_or("unnamed keyboard"),
+                path.display()
+            );
+            let hotkeys = hotkeys.clone();
+            let event_tx = event_tx.clone();
+            let device_path = path.clone();
+            let handle = tokio::spawn(async move {
+                if let Err(e) = read_keyboard(device, hotkeys, event_tx).await {
+                    eprintln!(
+                        "Stopped reading hotkeys from {}: {}",
+                        device_path.display(),
+                        e
+                    );
+                }
+            });
+            reading.insert(path, handle);
+        }
+
+        if reading.is_empty() {
+            if !missing_logged {
+                println!(
+                    "No keyboard found for hotkeys. Reading /dev/input needs the 'input' group."
+                );
+                missing_logged = true;
+            }
+        } else {
+            missing_logged = false;
+        }
+        tokio::time::sleep(HOTKEY_RESCAN_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(bindings: &[(&str, Option<u8>, Option<TouchAction>)]) -> ComboMatcher {
+        let bindings: Vec<HotkeyBinding> = bindings
+            .iter()
+            .map(|&(combo, key, action)| HotkeyBinding {
+                combo: combo.to_string(),
+                key,
+                action,
+            })
+            .collect();
+        let hotkeys = parse_bindings(&bindings);
+        assert_eq!(hotkeys.len(), bindings.len());
+        ComboMatcher::new(Arc::new(hotkeys))
+    }
+
+    #[test]
+    fn fires_the_most_specific_combination() {
+        let mut matcher = matcher(&[
+            ("ctrl+1", Some(1), None),
+            ("ctrl+shift+1", Some(2), None),
+            ("1", Some(3), None),
+        ]);
+        matcher.key_down(KeyCode::KEY_LEFTCTRL);
+        matcher.key_down(KeyCode::KEY_LEFTSHIFT);
+        assert_eq!(
+            matcher.key_down(KeyCode::KEY_1),
+            Some(HotkeyEvent::KeyDown(1))
+        );
+        assert_eq!(matcher.key_up(KeyCode::KEY_1), Some(HotkeyEvent::KeyUp(1)));
+        matcher.key_up(KeyCode::KEY_LEFTSHIFT);
+        assert_eq!(
+            matcher.key_down(KeyCode::KEY_1),
+            Some(HotkeyEvent::KeyDown(0))
+        );
+        assert_eq!(matcher.key_up(KeyCode::KEY_1), Some(HotkeyEvent::KeyUp(0)));
+        matcher.key_up(KeyCode::KEY_LEFTCTRL);
+        assert_eq!(
+            matcher.key_down(KeyCode::KEY_1),
+            Some(HotkeyEvent::KeyDown(2))
+        );
+    }
+
+    #[test]
+    fn modifiers_match_either_side() {
+        let mut matcher = matcher(&[("ctrl+alt+f13", None, Some(TouchAction::ToggleMode))]);
+        matcher.key_down(KeyCode::KEY_RIGHTCTRL);
+        matcher.key_down(KeyCode::KEY_LEFTALT);
+        assert_eq!(
+            matcher.key_down(KeyCode::KEY_F13),
+            Some(HotkeyEvent::Action(TouchAction::ToggleMode))
+        );
+        // Actions have nothing to release
+        assert_eq!(matcher.key_up(KeyCode::KEY_F13), None);
+        matcher.key_up(KeyCode::KEY_LEFTALT);
+        assert_eq!(matcher.key_down(KeyCode::KEY_F13), None);
+    }
+
+    #[test]
+    fn releasing_the_trigger_releases_the_key() {
+        let mut matcher = matcher(&[("ctrl+1", Some(4), None)]);
+        matcher.key_down(KeyCode::KEY_LEFTCTRL);
+        assert_eq!(
+            matcher.key_down(KeyCode::KEY_1),
+            Some(HotkeyEvent::KeyDown(3))
+        );
+        // Letting go of the modifier first keeps the key held
+        assert_eq!(matcher.key_up(KeyCode::KEY_LEFTCTRL), None);
+        assert_eq!(matcher.key_up(KeyCode::KEY_1), Some(HotkeyEvent::KeyUp(3)));
+        assert_eq!(matcher.key_up(KeyCode::KEY_1), None);
+        // Pressing the trigger before the modifier does not complete it
+        assert_eq!(matcher.key_down(KeyCode::KEY_1), None);
+        assert_eq!(matcher.key_down(KeyCode::KEY_LEFTCTRL), None);
+    }
+
+    #[test]
+    fn rejects_bad_bindings() {
+        let binding = |combo: &str, key, action| HotkeyBinding {
+            combo: combo.to_string(),
+            key,
+            action,
+        };
+        assert!(parse_binding(&binding("1", Some(0), None)).is_err());
+        assert!(parse_binding(&binding("1", Some(KEY_COUNT + 1), None)).is_err());
+        assert!(parse_binding(&binding("1", Some(1), Some(TouchAction::ToggleMode))).is_err());
+        assert!(parse_binding(&binding("1", None, Some(TouchAction::ResetDial))).is_err());
+        assert!(parse_binding(&binding("ctrl+nosuchkey", Some(1), None)).is_err());
+    }
+}
@@ -1,18 +1,84 @@
 use crate::Mode;
-use elgato_streamdeck::AsyncStreamDeck;
+use crate::font::{GLYPH_HEIGHT, draw_text, text_width};
 use elgato_streamdeck::images::convert_image_with_format;
-use image::{DynamicImage, Rgb};
+use elgato_streamdeck::info::ImageFormat;
+use elgato_streamdeck::{AsyncStreamDeck, StreamDeckError};
+use image::{DynamicImage, Rgb, RgbImage};
+use soundboard::error::{Error, Result};
+use soundboard::{InputLevel, amplitude_to_dbfs};
+use std::time::Duration;
 
-pub async fn update_lcd_mode(
+/// How long a key or the LCD strip stays red after an error.
+const ERROR_FLASH_DURATION: Duration = Duration::from_millis(400);
+const COLOR_ERROR: Rgb<u8> = Rgb([200, 0, 0]);
+
+/// The quietest level shown on the meters; anything below reads as empty.
+const METER_FLOOR_DB: f32 = -60.0;
+/// Levels above this are drawn yellow.
+const METER_WARN_DB: f32 = -12.0;
+/// Levels above this are drawn red.
+const METER_HOT_DB: f32 = -3.0;
+
+const COLOR_METER_OK: Rgb<u8> = Rgb([0, 200, 0]);
+const COLOR_METER_WARN: Rgb<u8> = Rgb([230, 200, 0]);
+const COLOR_METER_HOT: Rgb<u8> = Rgb([255, 0, 0]);
+const COLOR_METER_TRACK: Rgb<u8> = Rgb([40, 40, 40]);
+const COLOR_TEXT: Rgb<u8> = Rgb([255, 255, 255]);
+const COLOR_LCD_BACKGROUND: Rgb<u8> = Rgb([10, 10, 10]);
+
+/// Sets the image on `key`, adding the key to the error context. Does
+/// nothing without a deck, as when the board runs with none connected.
+pub async fn set_key_image(
+    device: Option<&AsyncStreamDeck>,
+    key: u8,
+    img: DynamicImage,
+) -> Result<()> {
+    let Some(device) = device else {
+        return Ok(());
+    };
+    device
+        .set_button_image(key, img)
+        .await
+        .map_err(|e| Error::device(format!("setting image for key {}", key), e))
+}
+
+/// Sends any queued key images to the deck, if there is one.
+pub async fn flush_device(device: Option<&AsyncStreamDeck>) -> Result<()> {
+    let Some(device) = device else {
+        return Ok(());
+    };
+    device
+        .flush()
+        .await
+        .map_err(|e| Error::device("flushing Stream Deck", e))
+}
+
+/// Converts `img` for the LCD strip and writes it across the whole strip.
+async fn write_lcd_image(
     device: &AsyncStreamDeck,
+    format: ImageFormat,
+    img: DynamicImage,
+) -> Result<()> {
+    let converted_image = convert_image_with_format(format, img)
+        .map_err(|e| Error::device("converting LCD image", StreamDeckError::ImageError(e)))?;
+    device
+        .write_lcd_fill(&converted_image)
+        .await
+        .map_err(|e| Error::device("writing LCD image", e))
+}
+
+pub async fn update_lcd_mode(
+    device: Option<&AsyncStreamDeck>,
     mode: Mode,
     img_playback: &DynamicImage,
     img_edit: &DynamicImage,
-) {
-    // ... (This function is unchanged)
+) -> Result<()> {
+    let Some(device) = device else {
+        return Ok(());
+    };
     println!("Setting LCD mode to: {:?}", mode);
     let img_to_use = match mode {
-        Mode::Playback => img_playback,
+        Mode::Playback | Mode::Looper | Mode::Pattern => img_playback,
         Mode::Edit => img_edit,
     };
     if let Some(format) = device.kind().lcd_image_format() {
@@ -21,11 +87,128 @@ pub async fn update_lcd_mode(
             format.size.1 as u32,
             image::imageops::FilterType::Nearest,
         );
-        let converted_image = convert_image_with_format(format, scaled_image).unwrap();
-        let _ = device.write_lcd_fill(&converted_image).await;
+        write_lcd_image(device, format, scaled_image).await?;
     } else {
         eprintln!("Failed to set LCD image (is this a Stream Deck Plus?)");
     }
+    Ok(())
+}
+
+/// A red key with an exclamation mark, shown briefly when an action fails.
+fn render_error_key(device: &AsyncStreamDeck) -> DynamicImage {
+    let (width, height) = device.kind().key_image_format().size;
+    let (width, height) = (width as u32, height as u32);
+    let mut img = RgbImage::from_pixel(width, height, COLOR_ERROR);
+    let scale = (height / 12).max(1);
+    let x = width.saturating_sub(text_width("!", scale)) / 2;
+    let y = height.saturating_sub(GLYPH_HEIGHT * scale) / 2;
+    draw_text(&mut img, x, y, "!", scale, COLOR_TEXT);
+    DynamicImage::ImageRgb8(img)
+}
+
+/// Darkens `base` and writes "OFFLINE" across it, for empty keys while
+/// the audio capture is down and nothing can be recorded.
+pub fn render_offline_key(base: &DynamicImage) -> DynamicImage {
+    let mut img = base.brighten(-60).into_rgb8();
+    let (width, height) = img.dimensions();
+    let scale = (width / 60).max(1);
+    let x = width.saturating_sub(text_width("OFFLINE", scale)) / 2;
+    let y = height.saturating_sub(GLYPH_HEIGHT * scale) / 2;
+    draw_text(&mut img, x, y, "OFFLINE", scale, COLOR_TEXT);
+    DynamicImage::ImageRgb8(img)
+}
+
+/// Writes `label` along the bottom of `base` on a dark band, cutting it
+/// short if it does not fit the key.
+pub fn render_key_label(base: &DynamicImage, label: &str) -> DynamicImage {
+    let mut img = base.to_rgb8();
+    let (width, height) = img.dimensions();
+    let scale = (width / 60).max(1);
+    let mut text: String = label.chars().collect();
+    while !text.is_empty() && text_width(&text, scale) > width {
+        text.pop();
+    }
+    let band = (GLYPH_HEIGHT + 4) * scale;
+    for y in height.saturating_sub(band)..height {
+        for x in 0..width {
+            img.put_pixel(x, y, COLOR_LCD_BACKGROUND);
+        }
+    }
+    let x = width.saturating_sub(text_width(&text, scale)) / 2;
+    let y = height.saturating_sub(band) + 2 * scale;
+    draw_text(&mut img, x, y, &text, scale, COLOR_TEXT);
+    DynamicImage::ImageRgb8(img)
+}
+
+/// Flashes `key` red, then puts `restore` back. Runs in the background so
+/// the event loop is not held up; failures are only logged, since this is
+/// already the error path.
+pub fn flash_key_error(device: Option<&AsyncStreamDeck>, key: u8, restore: DynamicImage) {
+    let Some(device) = device.cloned() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let error_key = render_error_key(&device);
+        let device = Some(&device);
+        let flash = async {
+            set_key_image(device, key, error_key).await?;
+            flush_device(device).await?;
+            tokio::time::sleep(ERROR_FLASH_DURATION).await;
+            set_key_image(device, key, restore).await?;
+            flush_device(device).await
+        };
+        if let Err(e) = flash.await {
+            eprintln!("Failed to flash error on key {}: {}", key, e);
+        }
+    });
+}
+
+/// Flashes the LCD strip red, then shows `restore` again. Used for errors
+/// that do not belong to a key, such as a failed dial action.
+pub fn flash_lcd_error(device: Option<&AsyncStreamDeck>, restore: DynamicImage) {
+    let Some(device) = device else {
+        return;
+    };
+    let Some(format) = device.kind().lcd_image_format() else {
+        return;
+    };
+    let device = device.clone();
+    tokio::spawn(async move {
+        let (width, height) = (format.size.0 as u32, format.size.1 as u32);
+        let flash = async {
+            let red = RgbImage::from_pixel(width, height, COLOR_ERROR);
+            write_lcd_image(&device, format, DynamicImage::ImageRgb8(red)).await?;
+            tokio::time::sleep(ERROR_FLASH_DURATION).await;
+            let restore =
+                restore.resize_to_fill(width, height, image::imageops::FilterType::Nearest);
+            write_lcd_image(&device, format, restore).await
+        };
+        if let Err(e) = flash.await {
+            eprintln!("Failed to flash error on LCD: {}", e);
+        }
+    });
+}
+
+/// Shows `img` on the LCD strip with `text` drawn across the top-left,
+/// e.g. to announce a bank change.
+pub async fn update_lcd_banner(
+    device: Option<&AsyncStreamDeck>,
+    img: &DynamicImage,
+    text: &str,
+) -> Result<()> {
+    let Some(device) = device else {
+        return Ok(());
+    };
+    let Some(format) = device.kind().lcd_image_format() else {
+        return Ok(());
+    };
+    let (width, height) = (format.size.0 as u32, format.size.1 as u32);
+    let mut banner = img
+        .resize_to_fill(width, height, image::imageops::FilterType::Nearest)
+        .into_rgb8();
+    let scale = (height / 20).max(1);
+    draw_text(&mut banner, 10, 10, text, scale, COLOR_TEXT);
+    write_lcd_image(device, format, DynamicImage::ImageRgb8(banner)).await
 }
 
 pub fn create_fallback_image(color: Rgb<u8>) -> DynamicImage {
@@ -35,3 +218,181 @@ pub fn create_fallback_image(color: Rgb<u8>) -> DynamicImage {
 pub fn create_fallback_lcd_image(color: Rgb<u8>) -> DynamicImage {
     DynamicImage::ImageRgb8(image::RgbImage::from_fn(800, 100, move |_, _| color))
 }
+
+/// Formats a take duration as `M:SS.t`.
+fn format_elapsed(elapsed: Duration) -> String {
+    let tenths = elapsed.as_millis() / 100;
+    format!("{}:{:02}.{}", tenths / 600, (tenths / 10) % 60, tenths % 10)
+}
+
+/// Maps a linear amplitude onto 0.0..=1.0 of the meter's length.
+fn meter_fraction(amplitude: f32) -> f32 {
+    let db = amplitude_to_dbfs(amplitude);
+    ((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0)
+}
+
+/// Picks the meter colour for the portion of the scale at `fraction`.
+fn meter_color(fraction: f32) -> Rgb<u8> {
+    let db = METER_FLOOR_DB + fraction * -METER_FLOOR_DB;
+    if db >= METER_HOT_DB {
+        COLOR_METER_HOT
+    } else if db >= METER_WARN_DB {
+        COLOR_METER_WARN
+    } else {
+        COLOR_METER_OK
+    }
+}
+
+fn fill_rect(img: &mut RgbImage, x: u32, y: u32, w: u32, h: u32, color: Rgb<u8>) {
+    let (width, height) = img.dimensions();
+    for py in y..(y + h).min(height) {
+        for px in x..(x + w).min(width) {
+            img.put_pixel(px, py, color);
+        }
+    }
+}
+
+/// Draws a border of `thickness` pixels around the whole image.
+fn draw_border(img: &mut RgbImage, thickness: u32, color: Rgb<u8>) {
+    let (width, height) = img.dimensions();
+    fill_rect(img, 0, 0, width, thickness, color);
+    fill_rect(
+        img,
+        0,
+        height.saturating_sub(thickness),
+        width,
+        thickness,
+        color,
+    );
+    fill_rect(img, 0, 0, thickness, height, color);
+    fill_rect(
+        img,
+        width.saturating_sub(thickness),
+        0,
+        thickness,
+        height,
+        color,
+    );
+}
+
+/// Renders the recording key: the `rec_on` image with a vertical level
+/// meter on the right, the take length along the bottom, and a red
+/// border once the input has clipped.
+pub fn render_key_meter(
+    device: &AsyncStreamDeck,
+    base: &DynamicImage,
+    level: InputLevel,
+) -> DynamicImage {
+    let (width, height) = device.kind().key_image_format().size;
+    let mut img = base
+        .resize_to_fill(
+            width as u32,
+            height as u32,
+            image::imageops::FilterType::Nearest,
+        )
+        .into_rgb8();
+    let (width, height) = img.dimensions();
+
+    // Vertical meter, filling from the bottom up.
+    let bar_width = (width / 8).max(4);
+    let bar_x = width - bar_width - 2;
+    let bar_height = height - 4;
+    fill_rect(&mut img, bar_x, 2, bar_width, bar_height, COLOR_METER_TRACK);
+    let filled = (meter_fraction(level.rms) * bar_height as f32) as u32;
+    for step in 0..filled {
+        let y = 2 + bar_height - 1 - step;
+        let color = meter_color(step as f32 / bar_height as f32);
+        fill_rect(&mut img, bar_x, y, bar_width, 1, color);
+    }
+    let peak_y = 2 + bar_height - (meter_fraction(level.peak) * bar_height as f32) as u32;
+    fill_rect(
+        &mut img,
+        bar_x,
+        peak_y.min(height - 3),
+        bar_width,
+        1,
+        COLOR_TEXT,
+    );
+
+    // Timer along the bottom, on a dark band so it stays readable.
+    let scale = (width / 48).max(1);
+    let timer = format_elapsed(level.elapsed);
+    let text_height = GLYPH_HEIGHT * scale;
+    let band_y = height - text_height - 4;
+    fill_rect(
+        &mut img,
+        0,
+        band_y,
+        bar_x,
+        text_height + 4,
+        COLOR_METER_TRACK,
+    );
+    draw_text(&mut img, 2, band_y + 2, &timer, scale, COLOR_TEXT);
+
+    if level.clipped {
+        draw_border(&mut img, (width / 24).max(2), COLOR_METER_HOT);
+    }
+    DynamicImage::ImageRgb8(img)
+}
+
+/// Draws a horizontal RMS/peak meter and the take length across the LCD
+/// strip while `key` is recording. Does nothing on decks without a strip.
+pub async fn update_lcd_meter(
+    device: Option<&AsyncStreamDeck>,
+    key: u8,
+    level: InputLevel,
+) -> Result<()> {
+    let Some(device) = device else {
+        return Ok(());
+    };
+    let Some(format) = device.kind().lcd_image_format() else {
+        return Ok(());
+    };
+    let (width, height) = (format.size.0 as u32, format.size.1 as u32);
+    let mut img = RgbImage::from_pixel(width, height, COLOR_LCD_BACKGROUND);
+
+    let scale = (height / 25).max(1);
+    let text_height = GLYPH_HEIGHT * scale;
+    let label = format!("REC {} {}", key, format_elapsed(level.elapsed));
+    draw_text(&mut img, 10, 10, &label, scale, COLOR_TEXT);
+    if level.clipped {
+        let clip_width = text_width("CLIP", scale);
+        draw_text(
+            &mut img,
+            width.saturating_sub(clip_width + 10),
+            10,
+            "CLIP",
+            scale,
+            COLOR_METER_HOT,
+        );
+    }
+
+    let bar_x = 10;
+    let bar_y = 10 + text_height + 10;
+    let bar_width = width - 20;
+    let bar_height = height.saturating_sub(bar_y + 10).max(4);
+    fill_rect(
+        &mut img,
+        bar_x,
+        bar_y,
+        bar_width,
+        bar_height,
+        COLOR_METER_TRACK,
+    );
+    let filled = (meter_fraction(level.rms) * bar_width as f32) as u32;
+    for step in 0..filled {
+        let color = meter_color(step as f32 / bar_width as f32);
+        fill_rect(&mut img, bar_x + step, bar_y, 1, bar_height, color);
+    }
+    let peak_x = bar_x + (meter_fraction(level.peak) * bar_width as f32) as u32;
+    fill_rect(
+        &mut img,
+        peak_x.min(bar_x + bar_width - 2),
+        bar_y,
+        2,
+        bar_height,
+        COLOR_TEXT,
+    );
+
+    write_lcd_image(device, format, DynamicImage::ImageRgb8(img)).await
+}
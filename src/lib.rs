@@ -1,10 +1,142 @@
+pub mod analysis;
+pub mod archive;
+pub mod bank;
+pub mod config;
+pub mod error;
+pub mod history;
+pub mod library;
+pub mod osc;
+pub mod schedule;
+pub mod websocket;
+
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::oneshot;
 
+/// Commands for the capture thread. Several takes can record at once,
+/// each identified by the file it is saved to.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum AudioCommand {
     Start(PathBuf),
-    Stop,
+    Stop(PathBuf),
+}
+
+/// What became of an `AudioCommand`, sent back by the capture thread.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AudioResponse {
+    /// Recording has started.
+    Started,
+    /// The command was not carried out, e.g. START while the capture is
+    /// down or STOP for a file that is not recording.
+    Refused(String),
+    /// The take was written to `path`.
+    Saved {
+        path: PathBuf,
+        duration: Duration,
+        samples: usize,
+    },
+    /// The command was accepted but could not be completed, e.g. the WAV
+    /// file could not be written.
+    Failed(String),
+}
+
+impl std::fmt::Display for AudioResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioResponse::Started => write!(f, "started"),
+            AudioResponse::Refused(reason) => write!(f, "refused: {}", reason),
+            AudioResponse::Saved {
+                path,
+                duration,
+                samples,
+            } => write!(
+                f,
+                "saved {} ({:.1}s, {} samples)",
+                path.display(),
+                duration.as_secs_f64(),
+                samples
+            ),
+            AudioResponse::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// An `AudioCommand` together with the channel its response is sent on.
+#[derive(Debug)]
+pub struct AudioRequest {
+    pub command: AudioCommand,
+    pub reply: oneshot::Sender<AudioResponse>,
+}
+
+impl AudioRequest {
+    /// Wraps `command`, returning the receiver for its response. The
+    /// receiver can be dropped if the outcome does not matter.
+    pub fn new(command: AudioCommand) -> (Self, oneshot::Receiver<AudioResponse>) {
+        let (reply, response_rx) = oneshot::channel();
+        (AudioRequest { command, reply }, response_rx)
+    }
+}
+
+/// A snapshot of the input signal for one take, published by the capture
+/// thread while it is recording.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct InputLevel {
+    /// Highest absolute sample value in the last processed block.
+    pub peak: f32,
+    /// Root-mean-square of the last processed block.
+    pub rms: f32,
+    /// Length of the take so far, derived from the number of captured frames.
+    pub elapsed: Duration,
+    /// Latched once any sample of the take exceeds 0 dBFS.
+    pub clipped: bool,
+}
+
+/// The level of every take in progress, keyed by the file it records to.
+pub type TakeLevels = HashMap<PathBuf, InputLevel>;
+
+/// Whether the capture thread can take recordings, published by its
+/// supervisor so the deck can show when recording is unavailable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureHealth {
+    /// Connecting to PipeWire and waiting for the stream format.
+    #[default]
+    Starting,
+    /// Capturing; recordings can be started.
+    Running,
+    /// The capture stream failed and is restarted after `retry_in`.
+    Restarting { attempt: u32, retry_in: Duration },
+    /// Capture has ended for good because the command channel closed.
+    Stopped,
+}
+
+impl CaptureHealth {
+    pub fn is_running(&self) -> bool {
+        *self == CaptureHealth::Running
+    }
+}
+
+impl std::fmt::Display for CaptureHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureHealth::Starting => write!(f, "audio starting"),
+            CaptureHealth::Running => write!(f, "audio ok"),
+            CaptureHealth::Restarting { attempt, .. } => {
+                write!(f, "audio down - retry {}", attempt)
+            }
+            CaptureHealth::Stopped => write!(f, "audio stopped"),
+        }
+    }
+}
+
+/// Converts a linear amplitude to dBFS, flooring silence at -120 dB.
+pub fn amplitude_to_dbfs(amplitude: f32) -> f32 {
+    if amplitude <= 1e-6 {
+        -120.0
+    } else {
+        20.0 * amplitude.log10()
+    }
 }
 
 pub fn get_audio_storage_path() -> std::io::Result<PathBuf> {
@@ -17,3 +149,26 @@ pub fn get_audio_storage_path() -> std::io::Result<PathBuf> {
         None => Err(std::io::Error::other("Could not find audio directory")),
     }
 }
+
+/// Number of keys on the deck, and so per bank.
+pub const KEY_COUNT: u8 = 8;
+
+/// The loudest a key can be set to play, 1.0 being unchanged.
+pub const MAX_VOLUME: f64 = 1.5;
+
+/// Returns the directory holding the recordings for `bank` (zero-based).
+/// The first bank lives directly in the storage directory so recordings
+/// made before banks existed keep working.
+pub fn get_bank_path(storage_path: &Path, bank: usize) -> PathBuf {
+    if bank == 0 {
+        storage_path.to_path_buf()
+    } else {
+        storage_path.join(format!("bank_{}", bank + 1))
+    }
+}
+
+/// Returns the recording file for `key` within `bank`.
+pub fn get_key_file_path(storage_path: &Path, bank: usize, key: u8) -> PathBuf {
+    let file_name = format!("recording_{}.wav", (b'A' + key) as char);
+    get_bank_path(storage_path, bank).join(file_name)
+}
@@ -0,0 +1,84 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// File extensions the library picks up, matched case-insensitively.
+const AUDIO_EXTENSIONS: &[&str] = &["wav", "flac", "ogg", "oga", "aif", "aiff", "mp3"];
+
+/// An audio file found in one of the library directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryEntry {
+    pub path: PathBuf,
+    /// File name without extension, as shown on the LCD strip.
+    pub name: String,
+}
+
+/// Expands a leading `~/` to the home directory.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Whether `path` has the extension of an audio file the player handles.
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Whether `path` is a WAV file, the only format that can be pitch
+/// shifted or loaded into the sequencer.
+pub fn is_wav_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("wav"))
+}
+
+/// Adds every audio file under `dir` to `entries`. Hidden files and
+/// directories, such as the take history, are skipped.
+fn scan_dir(dir: &Path, entries: &mut Vec<LibraryEntry>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            if let Err(e) = scan_dir(&path, entries) {
+                eprintln!("Failed to scan {}: {}", path.display(), e);
+            }
+        } else if is_audio_file(&path) {
+            let name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            entries.push(LibraryEntry { path, name });
+        }
+    }
+    Ok(())
+}
+
+/// Indexes every audio file in `dirs` and below, sorted by name.
+/// Directories that cannot be read are logged and skipped.
+pub fn scan_library(dirs: &[PathBuf]) -> Vec<LibraryEntry> {
+    let mut entries = Vec::new();
+    for dir in dirs {
+        let dir = expand_home(dir);
+        if let Err(e) = scan_dir(&dir, &mut entries) {
+            eprintln!("Failed to scan library directory {}: {}", dir.display(), e);
+        }
+    }
+    entries.sort_by(|a, b| {
+        a.name
+            .to_lowercase()
+            .cmp(&b.name.to_lowercase())
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    entries.dedup_by(|a, b| a.path == b.path);
+    entries
+}
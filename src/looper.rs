@@ -0,0 +1,442 @@
+//! The looper: loops recorded live from an input and played back in sync.
+//! The first loop sets the length, every later one is recorded for
+//! exactly that long, and all of them are laid over each other by sample.
+
+use crate::audio_capture::{
+    PipeWireSession, f32_format_pod, keep_running, lock_ignoring_poison, read_f32_buffer,
+    write_f32_buffer,
+};
+use pipewire as pw;
+use pw::{properties::properties, spa};
+use soundboard::config::LooperConfig;
+use soundboard::error::{Error, Result};
+use spa::pod::Pod;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Loops are recorded and played mono at a fixed rate, so that their
+/// positions count samples on both streams alike.
+const LOOPER_SAMPLE_RATE: u32 = 48000;
+/// How long to wait before connecting again after the looper failed.
+const LOOPER_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// What a key shows in Looper mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopKeyState {
+    /// No loop yet; pressing records one.
+    Empty,
+    Recording,
+    Playing,
+    Muted,
+}
+
+/// One recorded loop, as long as the first.
+struct Layer {
+    key: u8,
+    samples: Vec<f32>,
+    muted: bool,
+}
+
+/// A loop being recorded.
+struct Recording {
+    key: u8,
+    samples: Vec<f32>,
+    /// Loop position the first sample was heard at. The first loop
+    /// starts the loop, so it is always 0.
+    start: usize,
+}
+
+struct LoopState {
+    /// Samples in one loop, set once the first loop is recorded.
+    length: Option<usize>,
+    /// Loop position of the next sample played.
+    position: usize,
+    /// Loops in the order they were recorded. The first set the length.
+    layers: Vec<Layer>,
+    recording: Option<Recording>,
+    /// Whether the streams are connected, so loops can be recorded.
+    running: bool,
+    /// Longest first loop, in samples.
+    max_length: usize,
+    /// Samples the input lags behind what is heard.
+    latency: usize,
+    /// Bumped on every change, for decks to redraw their keys.
+    changed_tx: watch::Sender<u64>,
+}
+
+impl LoopState {
+    fn changed(&self) {
+        self.changed_tx.send_modify(|version| *version += 1);
+    }
+
+    /// Ends the first loop, which sets the loop length. Playback starts
+    /// over from its beginning.
+    fn finish_first_loop(&mut self) {
+        let Some(recording) = self.recording.take() else {
+            return;
+        };
+        if recording.samples.is_empty() {
+            println!("Loop on key {} was empty, dropping it.", recording.key);
+        } else {
+            self.length = Some(recording.samples.len());
+            self.position = 0;
+            println!(
+                "Loop length set by key {}: {:.2}s.",
+                recording.key,
+                recording.samples.len() as f64 / LOOPER_SAMPLE_RATE as f64
+            );
+            self.layers.push(Layer {
+                key: recording.key,
+                samples: recording.samples,
+                muted: false,
+            });
+        }
+        self.changed();
+    }
+
+    /// Ends an overdub once a whole loop has been recorded, laying each
+    /// sample on the loop position it was played along to.
+    fn finish_overdub(&mut self, length: usize) {
+        let Some(recording) = self.recording.take_if(|r| r.samples.len() >= length) else {
+            return;
+        };
+        let mut samples = vec![0.0; length];
+        let offset = recording.start + length - self.latency % length;
+        for (i, sample) in recording.samples.into_iter().take(length).enumerate() {
+            samples[(offset + i) % length] = sample;
+        }
+        println!("Overdub on key {} recorded.", recording.key);
+        self.layers.push(Layer {
+            key: recording.key,
+            samples,
+            muted: false,
+        });
+        self.changed();
+    }
+
+    /// Adds captured input to the recording in progress.
+    fn record(&mut self, samples: &[f32]) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+        recording.samples.extend_from_slice(samples);
+        match self.length {
+            Some(length) => self.finish_overdub(length),
+            None if recording.samples.len() >= self.max_length => {
+                recording.samples.truncate(self.max_length);
+                println!("Loop reached the longest allowed, stopping it.");
+                self.finish_first_loop();
+            }
+            None => {}
+        }
+    }
+
+    /// Mixes the next `count` samples of every unmuted loop.
+    fn play(&mut self, count: usize) -> Vec<f32> {
+        let Some(length) = self.length else {
+            return vec![0.0; count];
+        };
+        let mut samples = vec![0.0; count];
+        for layer in self.layers.iter().filter(|layer| !layer.muted) {
+            for (i, out) in samples.iter_mut().enumerate() {
+                *out += layer.samples[(self.position + i) % length];
+            }
+        }
+        for sample in &mut samples {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+        self.position = (self.position + count) % length;
+        samples
+    }
+}
+
+/// The looper as decks see it. Every deck shares the same loops.
+#[derive(Clone)]
+pub struct Looper(Arc<Mutex<LoopState>>);
+
+impl Looper {
+    pub fn new(config: &LooperConfig) -> Self {
+        let rate = LOOPER_SAMPLE_RATE as f64;
+        Looper(Arc::new(Mutex::new(LoopState {
+            length: None,
+            position: 0,
+            layers: Vec::new(),
+            recording: None,
+            running: false,
+            max_length: (config.max_seconds.max(0.1) * rate) as usize,
+            latency: (config.latency_ms.max(0.0) / 1000.0 * rate) as usize,
+            changed_tx: watch::Sender::new(0),
+        })))
+    }
+
+    /// Locks the loops, ignoring poison like the capture does.
+    fn lock(&self) -> MutexGuard<'_, LoopState> {
+        lock_ignoring_poison(&self.0)
+    }
+
+    /// Changes whenever a loop is recorded, muted or undone.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.lock().changed_tx.subscribe()
+    }
+
+    pub fn key_state(&self, key: u8) -> LoopKeyState {
+        let state = self.lock();
+        if state.recording.as_ref().is_some_and(|r| r.key == key) {
+            return LoopKeyState::Recording;
+        }
+        match state.layers.iter().find(|layer| layer.key == key) {
+            Some(layer) if layer.muted => LoopKeyState::Muted,
+            Some(_) => LoopKeyState::Playing,
+            None => LoopKeyState::Empty,
+        }
+    }
+
+    /// Whether loops can be recorded right now.
+    pub fn is_running(&self) -> bool {
+        self.lock().running
+    }
+
+    /// A key went down: mutes or unmutes its loop, or starts recording
+    /// one. The first loop records until the key is released, later ones
+    /// for exactly one loop from now.
+    pub fn press(&self, key: u8) -> std::result::Result<(), String> {
+        let mut state = self.lock();
+        if let Some(layer) = state.layers.iter_mut().find(|layer| layer.key == key) {
+            layer.muted = !layer.muted;
+            println!(
+                "Loop on key {} {}.",
+                key,
+                if layer.muted { "muted" } else { "unmuted" }
+            );
+            state.changed();
+            return Ok(());
+        }
+        if !state.running {
+            return Err("the looper is not running".to_string());
+        }
+        if let Some(recording) = &state.recording {
+            return Err(format!("already recording a loop on key {}", recording.key));
+        }
+        let start = if state.length.is_some() {
+            state.position
+        } else {
+            0
+        };
+        println!("Recording loop on key {}.", key);
+        state.recording = Some(Recording {
+            key,
+            samples: Vec::new(),
+            start,
+        });
+        state.changed();
+        Ok(())
+    }
+
+    /// A key came up, which ends the first loop if it is recording it.
+    pub fn release(&self, key: u8) {
+        let mut state = self.lock();
+        if state.length.is_none() && state.recording.as_ref().is_some_and(|r| r.key == key) {
+            state.finish_first_loop();
+        }
+    }
+
+    /// Drops the overdub in progress, or else the last loop recorded.
+    /// Undoing the first loop clears the length for a new one.
+    pub fn undo(&self) {
+        let mut state = self.lock();
+        if let Some(recording) = state.recording.take() {
+            println!("Dropped the loop recording on key {}.", recording.key);
+        } else if let Some(layer) = state.layers.pop() {
+            println!("Undid the loop on key {}.", layer.key);
+            if state.layers.is_empty() {
+                state.length = None;
+                state.position = 0;
+            }
+        } else {
+            println!("No loop to undo.");
+            return;
+        }
+        state.changed();
+    }
+
+    /// A short summary for the LCD strip.
+    pub fn status(&self) -> String {
+        let state = self.lock();
+        if !state.running {
+            return "LOOPER OFFLINE".to_string();
+        }
+        match state.length {
+            Some(length) => format!(
+                "LOOP {:.1}S  {} LAYERS",
+                length as f64 / LOOPER_SAMPLE_RATE as f64,
+                state.layers.len()
+            ),
+            None => "LOOPER".to_string(),
+        }
+    }
+
+    fn set_running(&self, running: bool) {
+        let mut state = self.lock();
+        state.running = running;
+        if !running && state.recording.take().is_some() {
+            println!("Looper stopped mid-recording, dropping the loop.");
+        }
+        state.changed();
+    }
+}
+
+/// Records from the configured input and plays the loops into the target.
+/// Connects again whenever PipeWire drops it, until the process exits.
+pub fn run_looper(config: LooperConfig, looper: Looper) {
+    keep_running("Looper", LOOPER_RESTART_DELAY, || {
+        let result = run_looper_loop(&config, &looper);
+        looper.set_running(false);
+        result
+    });
+}
+
+/// Runs the input and output streams until either fails, returning why.
+fn run_looper_loop(config: &LooperConfig, looper: &Looper) -> Result<()> {
+    let session = PipeWireSession::connect()?;
+
+    // 1. Record the input into the loop in progress
+    let mut input_props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Capture",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::NODE_NAME => "soundboard-looper-in",
+    };
+    if let Some(input) = &config.input {
+        input_props.insert(*pw::keys::TARGET_OBJECT, input.as_str());
+    }
+    let input = pw::stream::StreamBox::new(&session.core, "looper-input", input_props)
+        .map_err(|e| Error::audio("creating looper input stream", e))?;
+    let _input_listener = input
+        .add_local_listener_with_user_data(looper.clone())
+        .state_changed(session.report_state("Looper input"))
+        .process(|stream, looper| {
+            if let Some(samples) = read_f32_buffer(stream) {
+                looper.lock().record(&samples);
+            }
+        })
+        .register()
+        .map_err(|e| Error::audio("registering looper input listener", e))?;
+
+    // 2. Play every loop from the shared position, which is the clock
+    // overdubs are laid against
+    let mut output_props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Playback",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::NODE_NAME => "soundboard-looper-out",
+    };
+    if let Some(target) = &config.target {
+        output_props.insert(*pw::keys::TARGET_OBJECT, target.as_str());
+    }
+    let output = pw::stream::StreamBox::new(&session.core, "looper-output", output_props)
+        .map_err(|e| Error::audio("creating looper output stream", e))?;
+    let _output_listener = output
+        .add_local_listener_with_user_data(looper.clone())
+        .state_changed(session.report_state("Looper output"))
+        .process(|stream, looper| {
+            write_f32_buffer(stream, |capacity| looper.lock().play(capacity));
+        })
+        .register()
+        .map_err(|e| Error::audio("registering looper output listener", e))?;
+
+    // 3. Both streams use the same mono format
+    let values = f32_format_pod(Some((LOOPER_SAMPLE_RATE, 1)))?;
+    let flags = pw::stream::StreamFlags::AUTOCONNECT
+        | pw::stream::StreamFlags::MAP_BUFFERS
+        | pw::stream::StreamFlags::RT_PROCESS;
+    for (stream, direction, name) in [
+        (&input, spa::utils::Direction::Input, "looper input"),
+        (&output, spa::utils::Direction::Output, "looper output"),
+    ] {
+        let pod = Pod::from_bytes(&values)
+            .ok_or_else(|| Error::audio("building looper format", "invalid POD"))?;
+        let mut params = [pod];
+        stream
+            .connect(direction, None, flags, &mut params)
+            .map_err(|e| Error::audio(format!("connecting {} stream", name), e))?;
+    }
+    looper.set_running(true);
+    println!("Looper running.");
+
+    session.run("running the looper")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loop state with a first loop `length` samples long already laid.
+    fn looping(length: usize, latency: usize) -> LoopState {
+        LoopState {
+            length: Some(length),
+            position: 0,
+            layers: vec![Layer {
+                key: 0,
+                samples: vec![0.0; length],
+                muted: false,
+            }],
+            recording: None,
+            running: true,
+            max_length: length,
+            latency,
+            changed_tx: watch::Sender::new(0),
+        }
+    }
+
+    /// Records the ramp 1, 2, 3, ... as an overdub on key 1, started at
+    /// loop position `start`, and returns the layer it made.
+    fn overdub(state: &mut LoopState, start: usize) -> Vec<f32> {
+        let length = state.length.unwrap();
+        state.recording = Some(Recording {
+            key: 1,
+            samples: Vec::new(),
+            start,
+        });
+        let ramp: Vec<f32> = (1..=length).map(|i| i as f32).collect();
+        // Captured in two buffers, the overdub ends with the second
+        state.record(&ramp[..length / 2]);
+        assert!(state.recording.is_some());
+        state.record(&ramp[length / 2..]);
+        assert!(state.recording.is_none());
+        state.layers.last().unwrap().samples.clone()
+    }
+
+    #[test]
+    fn overdub_lands_where_it_was_played() {
+        let mut state = looping(4, 0);
+        assert_eq!(overdub(&mut state, 0), vec![1.0, 2.0, 3.0, 4.0]);
+        let mut state = looping(4, 0);
+        assert_eq!(overdub(&mut state, 3), vec![2.0, 3.0, 4.0, 1.0]);
+    }
+
+    #[test]
+    fn overdub_is_moved_back_by_the_latency() {
+        // Heard one sample earlier than it was captured
+        let mut state = looping(4, 1);
+        assert_eq!(overdub(&mut state, 0), vec![2.0, 3.0, 4.0, 1.0]);
+        // Wrapping back past the start of the loop
+        let mut state = looping(4, 3);
+        assert_eq!(overdub(&mut state, 1), vec![3.0, 4.0, 1.0, 2.0]);
+        // A latency longer than the loop wraps around it
+        let mut state = looping(4, 6);
+        assert_eq!(overdub(&mut state, 0), vec![3.0, 4.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn overdub_keeps_only_one_loop() {
+        let mut state = looping(4, 0);
+        state.recording = Some(Recording {
+            key: 1,
+            samples: Vec::new(),
+            start: 0,
+        });
+        state.record(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(state.layers[1].samples, vec![1.0, 2.0, 3.0, 4.0]);
+    }
+}
@@ -1,219 +1,1967 @@
-use soundboard::{AudioCommand, get_audio_storage_path};
+use soundboard::bank::{
+    BankManifest, MAX_BPM, MAX_SWING, MIN_BPM, PATTERN_STEP_COUNTS, Pattern, key_name,
+    load_bank_manifest, save_bank_manifest,
+};
+use soundboard::config::{
+    Config, DeckConfig, HistoryConfig, MacroConfig, MidiConfig, MidiControl, OutputRoute, TalkMode,
+    TouchAction, TouchConfig, load_config,
+};
+use soundboard::error::{Error, Recovery, Result};
+use soundboard::history::{self, ArchiveReason, Take};
+use soundboard::library::{LibraryEntry, is_wav_file, scan_library};
+use soundboard::schedule::{Schedule, ScheduleTarget, SystemClock};
+use soundboard::{
+    AudioCommand, AudioRequest, AudioResponse, CaptureHealth, KEY_COUNT, MAX_VOLUME, TakeLevels,
+    get_audio_storage_path, get_key_file_path,
+};
+mod actions;
 mod audio_player;
-use crate::audio_player::{PlaybackSink, play_audio_file};
+mod cli;
+mod ducking;
+mod hotkeys;
+mod looper;
+mod midi;
+mod osc_server;
+mod remote;
+mod routes;
+mod scheduler;
+mod sequencer;
+mod virtual_mic;
+mod voice;
+mod web;
+use crate::actions::{Action, MacroEvent, RunningMacro};
+use crate::audio_player::{PlaybackRoute, play_audio_file, play_sample};
+use crate::ducking::ActiveVoices;
+use crate::hotkeys::HotkeyEvent;
+use crate::looper::{LoopKeyState, Looper};
+use crate::midi::{MIDI_MAX, MidiClock, MidiEvent, MidiMessage, key_to_note};
+use crate::remote::{BoardState, KeyState, MacroState, RemoteCommand, RouteState};
+use crate::routes::{next_route_combination, resolve_routes};
+use crate::sequencer::{SEQUENCER_SAMPLE_RATE, Sequencer, TrackSample};
+use crate::voice::VoiceControl;
+mod font;
 mod lcd;
-use crate::lcd::{create_fallback_image, create_fallback_lcd_image, update_lcd_mode};
+use crate::lcd::{
+    create_fallback_image, create_fallback_lcd_image, flash_key_error, flash_lcd_error,
+    flush_device, render_key_label, render_key_meter, render_offline_key, set_key_image,
+    update_lcd_banner, update_lcd_meter, update_lcd_mode,
+};
 mod audio_processor;
+use crate::audio_processor::decode_wav_stereo;
 
 mod audio_capture;
-use elgato_streamdeck::{AsyncStreamDeck, DeviceStateUpdate, list_devices, new_hidapi};
+use elgato_streamdeck::{
+    AsyncDeviceStateReader, AsyncStreamDeck, DeviceStateUpdate, StreamDeckError, list_devices,
+    new_hidapi, refresh_device_list,
+};
 use image::open;
 use image::{DynamicImage, Rgb};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::mpsc;
-use tokio::fs as tokio_fs;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc as tokio_mpsc, watch};
+use tokio::task::JoinHandle;
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// How often the recording key and LCD meter are redrawn while recording.
+const METER_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+/// How long volume and pitch must stay unchanged before they are saved,
+/// so a dial turned through many ticks writes the bank manifest once.
+const SETTINGS_SAVE_DELAY: Duration = Duration::from_secs(1);
+/// How often the HID bus is rescanned while no deck is connected.
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const DEFAULT_BRIGHTNESS: u8 = 50;
+/// Volume change per dial tick, and while the dial is held down.
+const VOLUME_STEP: f64 = 0.05;
+const VOLUME_STEP_FINE: f64 = 0.01;
+/// Pitch change in semitones per dial tick, and while the dial is held down.
+const PITCH_STEP: f64 = 0.1;
+const PITCH_STEP_FINE: f64 = 0.01;
+const DEFAULT_VOLUME: f64 = 1.0;
+/// How far a key's pitch can be shifted either way, in semitones.
+const MAX_PITCH: f64 = 24.0;
+/// The pitch range a MIDI control covers, either side of unchanged.
+const MIDI_PITCH_RANGE: f64 = 12.0;
+/// MIDI events queued per deck before the oldest are dropped.
+const MIDI_QUEUE_LENGTH: usize = 64;
+/// Hotkey events buffered per deck before the oldest are dropped.
+const HOTKEY_QUEUE_LENGTH: usize = 64;
+/// Remote commands buffered per deck before the oldest are dropped.
+const REMOTE_QUEUE_LENGTH: usize = 64;
+/// Due schedules buffered per deck before the oldest are dropped.
+const SCHEDULE_QUEUE_LENGTH: usize = 16;
+const DEFAULT_PITCH: f64 = 0.0;
+/// Voice effect amount change per dial tick, coarse and fine.
+const VOICE_AMOUNT_STEP: f64 = 0.05;
+const VOICE_AMOUNT_STEP_FINE: f64 = 0.01;
+/// Tempo change per dial tick in Pattern mode, coarse and fine.
+const BPM_STEP: f64 = 1.0;
+const BPM_STEP_FINE: f64 = 0.1;
+/// Swing change per dial tick in Pattern mode, coarse and fine.
+const SWING_STEP: f64 = 0.05;
+const SWING_STEP_FINE: f64 = 0.01;
+/// Minimum horizontal travel for a touch strip swipe to count as left/right.
+const SWIPE_MIN_DISTANCE: i32 = 40;
+
+/// Identifies a sample slot: a key within a bank.
+type KeySlot = (usize, u8);
+
+/// The response to an audio command, tagged with the key that sent it.
+type KeyResponse = (u8, AudioResponse);
+
+/// Why a deck's event loop returned.
+enum DeviceExit {
+    /// Reading from the deck failed, most likely because it was unplugged.
+    Disconnected,
+    /// The process was asked to shut down.
+    Shutdown,
+}
+
+/// The inputs shared by every deck that one event loop handles. Each goes
+/// to a single loop, so it acts once however many decks are connected,
+/// and to the board's own loop while no deck takes it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Roles {
+    hotkeys: bool,
+    midi: bool,
+    /// Runs remote commands and publishes its state for remote controls.
+    remote: bool,
+    /// Plays schedules that come due, from its own bank.
+    schedules: bool,
+}
+
+/// Serial numbers of the decks configured to take each role. Unset gives
+/// the role to the first deck attached.
+#[derive(Debug, Clone, Default)]
+struct RoleDecks {
+    hotkeys: Option<String>,
+    midi: Option<String>,
+    remote: Option<String>,
+    schedules: Option<String>,
+}
+
+impl RoleDecks {
+    /// The roles of the deck with `serial`, or of the board for `None`,
+    /// while the decks in `attached` are connected, in the order they
+    /// were attached.
+    fn roles_for(&self, serial: Option<&str>, attached: &[String]) -> Roles {
+        Roles {
+            hotkeys: role_holder(self.hotkeys.as_deref(), attached) == serial,
+            midi: role_holder(self.midi.as_deref(), attached) == serial,
+            remote: role_holder(self.remote.as_deref(), attached) == serial,
+            schedules: role_holder(self.schedules.as_deref(), attached) == serial,
+        }
+    }
+}
+
+/// The deck that takes a role configured for `configured`: that deck if
+/// it is attached, or with none configured, the first deck attached.
+/// `None` leaves the role to the board.
+fn role_holder<'a>(configured: Option<&str>, attached: &'a [String]) -> Option<&'a str> {
+    match configured {
+        Some(serial) => attached
+            .iter()
+            .map(String::as_str)
+            .find(|attached| *attached == serial),
+        None => attached.first().map(String::as_str),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
 enum Mode {
     Playback,
     Edit,
+    /// Keys record and mute loops. Only offered while the looper runs.
+    Looper,
+    /// Keys are the steps of the selected track's pattern. Only offered
+    /// while the sequencer runs.
+    Pattern,
 }
 
+impl Mode {
+    /// Every mode, in the order the deck switches through them.
+    const ALL: [Mode; 4] = [Mode::Playback, Mode::Edit, Mode::Looper, Mode::Pattern];
+
+    /// The control change value sent on `midi.mode_cc` when switching to
+    /// this mode. `config.example.toml` lists these.
+    fn midi_value(self) -> u8 {
+        match self {
+            Mode::Playback => 0,
+            Mode::Edit => MIDI_MAX,
+            Mode::Looper => 64,
+            Mode::Pattern => 96,
+        }
+    }
+
+    /// The name used by remote controls.
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Playback => "playback",
+            Mode::Edit => "edit",
+            Mode::Looper => "looper",
+            Mode::Pattern => "pattern",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "playback" => Ok(Mode::Playback),
+            "edit" => Ok(Mode::Edit),
+            "looper" => Ok(Mode::Looper),
+            "pattern" => Ok(Mode::Pattern),
+            other => Err(format!(
+                "unknown mode '{}', expected playback, edit, looper or pattern",
+                other
+            )),
+        }
+    }
+}
+
+/// The UI state of one Stream Deck. Every connected deck gets its own
+/// copy, while the audio command channel behind it is shared. One more
+/// copy is the board itself, which handles the inputs no deck takes.
+#[derive(Clone)]
 struct AppState {
     mode: Mode,
-    playback_sink: PlaybackSink,
-    playback_volume: HashMap<u8, f64>,
+    /// The configured output routes.
+    routes: Vec<OutputRoute>,
+    /// Routes keys without routes of their own play through, in the order
+    /// they were enabled.
+    enabled_routes: Vec<String>,
+    playback_volume: HashMap<KeySlot, f64>,
     button_files: HashMap<u8, PathBuf>,
-    active_recording_key: Option<u8>,
+    /// Keys recording right now with the file each records to, in the
+    /// order they started.
+    recording_keys: Vec<(u8, PathBuf)>,
     selected_for_delete: Option<u8>,
-    pitch_shift_semitones: HashMap<u8, f64>,
+    /// Previous takes of the selected key, loaded when dial 3 starts
+    /// browsing them in Edit mode.
+    history_takes: Vec<Take>,
+    /// Index into `history_takes` of the take dial 3 is on, or `None` for
+    /// the current file.
+    browsed_take: Option<usize>,
+    library_dirs: Vec<PathBuf>,
+    /// Audio files in `library_dirs`, indexed when the browser opens.
+    library: Vec<LibraryEntry>,
+    /// The library file dial 1 is on, or `None` while the browser is closed.
+    library_index: Option<usize>,
+    pitch_shift_semitones: HashMap<KeySlot, f64>,
+    /// Keys whose volume or pitch changed since they were last saved, and
+    /// when the latest change was.
+    unsaved_settings: HashSet<KeySlot>,
+    settings_changed_at: Option<Instant>,
+    storage_path: PathBuf,
+    bank: usize,
+    bank_count: usize,
+    touch: TouchConfig,
+    history: HistoryConfig,
+    held_encoder: Option<u8>,
+    held_encoder_twisted: bool,
+    brightness: u8,
     img_rec_off: DynamicImage,
+    img_rec_offline: DynamicImage,
     img_rec_on: DynamicImage,
     img_play: DynamicImage,
+    /// Icons and labels from the bank manifest, shown instead of
+    /// `img_play` on keys that have them.
+    key_faces: HashMap<u8, DynamicImage>,
+    key_labels: HashMap<u8, String>,
+    key_icons: HashMap<u8, PathBuf>,
+    /// Routes from the bank manifest for keys that have their own.
+    key_routes: HashMap<u8, Vec<String>>,
     img_lcd_playback: DynamicImage,
     img_lcd_edit: DynamicImage,
 
-    audio_cmd_tx: mpsc::Sender<AudioCommand>,
+    /// MIDI input, shared by every deck but only followed by the loop with
+    /// the MIDI role.
+    midi_tx: broadcast::Sender<MidiEvent>,
+    midi: MidiConfig,
+    /// The clock coming in on the MIDI input, for quantized playback.
+    midi_clock: MidiClock,
+    /// Notes and control changes for the MIDI output, if it is enabled.
+    midi_out_tx: Option<mpsc::Sender<MidiMessage>>,
+    /// Velocity of the MIDI note holding a key, scaling its next playback.
+    key_velocity: HashMap<u8, f64>,
+    /// The last value of each MIDI controller, so switch-like controls
+    /// fire once as they go past halfway.
+    control_values: HashMap<u8, u8>,
+
+    /// Keyboard hotkeys, shared by every deck but only followed by the
+    /// loop with the hotkeys role.
+    hotkey_tx: broadcast::Sender<HotkeyEvent>,
+    /// The inputs this state's event loop handles, set by the supervisor.
+    roles: watch::Receiver<Roles>,
+
+    /// Commands from remote controls, shared by every deck but only
+    /// followed by the loop with the remote role.
+    remote_tx: broadcast::Sender<RemoteCommand>,
+    /// Schedules that came due, shared by every deck but only followed by
+    /// the loop with the schedules role.
+    schedule_tx: broadcast::Sender<Schedule>,
+    /// Whether schedules play, shared by every deck so any of them can
+    /// pause them.
+    schedules_on: watch::Sender<bool>,
+    /// Where the loop with the remote role publishes its state for remote
+    /// controls to show.
+    board_tx: watch::Sender<Option<BoardState>>,
+    /// Samples playing (or waiting for the beat) on each slot.
+    playing: HashMap<KeySlot, usize>,
+    /// Samples playing on every deck, which ducking follows.
+    voices: ActiveVoices,
+    /// The mic passthrough's controls, shared by every deck, if it runs.
+    voice_tx: Option<watch::Sender<VoiceControl>>,
+    /// The key that turns the passthrough microphone on and off.
+    voice_key: Option<u8>,
+    voice_key_mode: TalkMode,
+    /// The looper, shared by every deck, if it is enabled.
+    looper: Option<Looper>,
+    /// The step sequencer, shared by every deck, if it is enabled.
+    sequencer: Option<Sequencer>,
+    /// The bank's pattern, loaded with its files.
+    pattern: Pattern,
+    /// The key whose steps Pattern mode shows and edits.
+    pattern_track: u8,
+    /// Which eight steps the keys show in Pattern mode.
+    pattern_page: usize,
+    /// The step the sequencer is on, lit in Pattern mode.
+    shown_step: Option<usize>,
+    /// The configured macros.
+    macros: Vec<MacroConfig>,
+    /// Macros running on this deck, by name.
+    running_macros: HashMap<String, RunningMacro>,
+    /// Counts macro runs, so each run can be told apart.
+    next_macro_run: u64,
+    /// Where running macros send their steps while the deck is connected.
+    macro_tx: Option<tokio_mpsc::UnboundedSender<MacroEvent>>,
+    /// Where playback tasks report the slot they finished on while the
+    /// deck is connected.
+    playback_done_tx: Option<tokio_mpsc::UnboundedSender<KeySlot>>,
+
+    audio_cmd_tx: mpsc::Sender<AudioRequest>,
+    /// Where responses to this deck's audio commands are delivered while
+    /// the deck is connected.
+    audio_response_tx: Option<tokio_mpsc::UnboundedSender<KeyResponse>>,
+    level_rx: watch::Receiver<TakeLevels>,
+    health_rx: watch::Receiver<CaptureHealth>,
 }
 
 impl AppState {
-    async fn handle_encoder_twist(&mut self, dial: u8, ticks: i32, device: &AsyncStreamDeck) {
-        if dial == 0 {
-            self.mode = match self.mode {
-                Mode::Playback => Mode::Edit,
-                Mode::Edit => Mode::Playback,
+    /// The state every deck and the board start from, with nothing
+    /// connected yet: the services `config` enables are added by `main`.
+    fn new(
+        config: &Config,
+        storage_path: PathBuf,
+        audio_cmd_tx: mpsc::Sender<AudioRequest>,
+        level_rx: watch::Receiver<TakeLevels>,
+        health_rx: watch::Receiver<CaptureHealth>,
+    ) -> AppState {
+        let img_rec_off =
+            open("assets/rec_off.png").unwrap_or_else(|_| create_fallback_image(Rgb([80, 80, 80])));
+        let img_rec_offline = render_offline_key(&img_rec_off);
+        let img_rec_on =
+            open("assets/rec_on.png").unwrap_or_else(|_| create_fallback_image(Rgb([255, 0, 0])));
+        let img_play =
+            open("assets/play.png").unwrap_or_else(|_| create_fallback_image(Rgb([0, 255, 0])));
+        let img_lcd_playback = open("assets/lcd_strip.png")
+            .unwrap_or_else(|_| create_fallback_lcd_image(Rgb([10, 50, 10])));
+        let img_lcd_edit = open("assets/lcd_edit.png")
+            .unwrap_or_else(|_| create_fallback_lcd_image(Rgb([50, 10, 10])));
+
+        let output_routes = config.output_routes();
+        AppState {
+            mode: Mode::Playback,
+            routes: output_routes.clone(),
+            enabled_routes: output_routes
+                .iter()
+                .filter(|route| route.enabled)
+                .map(|route| route.name.clone())
+                .collect(),
+            playback_volume: HashMap::new(),
+            button_files: HashMap::new(),
+            recording_keys: Vec::new(),
+            selected_for_delete: None,
+            history_takes: Vec::new(),
+            browsed_take: None,
+            library_dirs: config.library.dirs.clone(),
+            library: Vec::new(),
+            library_index: None,
+            pitch_shift_semitones: HashMap::new(),
+            unsaved_settings: HashSet::new(),
+            settings_changed_at: None,
+            storage_path,
+            bank: 0,
+            bank_count: config.banks,
+            touch: config.touch.clone(),
+            history: config.history.clone(),
+            held_encoder: None,
+            held_encoder_twisted: false,
+            brightness: DEFAULT_BRIGHTNESS,
+            img_rec_off,
+            img_rec_offline,
+            img_rec_on,
+            img_play,
+            key_faces: HashMap::new(),
+            key_labels: HashMap::new(),
+            key_icons: HashMap::new(),
+            key_routes: HashMap::new(),
+            img_lcd_playback,
+            img_lcd_edit,
+
+            midi_tx: broadcast::channel(MIDI_QUEUE_LENGTH).0,
+            midi: config.midi.clone(),
+            midi_clock: MidiClock::default(),
+            midi_out_tx: None,
+            key_velocity: HashMap::new(),
+            control_values: HashMap::new(),
+
+            hotkey_tx: broadcast::channel(HOTKEY_QUEUE_LENGTH).0,
+            roles: watch::channel(Roles::default()).1,
+
+            remote_tx: broadcast::channel(REMOTE_QUEUE_LENGTH).0,
+            schedule_tx: broadcast::channel(SCHEDULE_QUEUE_LENGTH).0,
+            schedules_on: watch::channel(true).0,
+            board_tx: watch::channel(None).0,
+            playing: HashMap::new(),
+            voices: ActiveVoices::default(),
+            voice_tx: None,
+            voice_key: config
+                .voice
+                .key
+                .filter(|key| (1..=KEY_COUNT).contains(key))
+                .map(|key| key - 1),
+            voice_key_mode: config.voice.key_mode,
+            looper: None,
+            sequencer: None,
+            pattern: Pattern::default(),
+            pattern_track: 0,
+            pattern_page: 0,
+            shown_step: None,
+            macros: config.macros.clone(),
+            running_macros: HashMap::new(),
+            next_macro_run: 0,
+            macro_tx: None,
+            playback_done_tx: None,
+
+            audio_cmd_tx,
+            audio_response_tx: None,
+            level_rx,
+            health_rx,
+        }
+    }
+
+    /// Makes the state for a newly seen deck from `self`, applying any
+    /// settings configured for its serial number.
+    fn for_deck(&self, serial: &str, deck_config: Option<&DeckConfig>) -> AppState {
+        let mut state = self.clone();
+        if let Some(deck_config) = deck_config {
+            if let Some(bank) = deck_config.bank {
+                state.bank = bank
+                    .saturating_sub(1)
+                    .min(self.bank_count.saturating_sub(1));
+            }
+            if let Some(brightness) = deck_config.brightness {
+                state.brightness = brightness.min(100);
+            }
+            println!(
+                "Using config for Stream Deck {}: bank {}, brightness {}%",
+                serial,
+                state.bank + 1,
+                state.brightness
+            );
+        }
+        state.load_bank_files();
+        state
+    }
+
+    /// Makes the state for the board's own loop from `self`. It has no
+    /// deck and handles the `roles` no deck takes.
+    fn for_board(&self, roles: watch::Receiver<Roles>) -> AppState {
+        let mut state = self.clone();
+        state.roles = roles;
+        state.load_bank_files();
+        state
+    }
+
+    fn slot(&self, key: u8) -> KeySlot {
+        (self.bank, key)
+    }
+
+    fn is_recording(&self, key: u8) -> bool {
+        self.recording_keys.iter().any(|(k, _)| *k == key)
+    }
+
+    /// Forgets that `key` is recording, returning the file it recorded to.
+    fn take_recording(&mut self, key: u8) -> Option<PathBuf> {
+        let index = self.recording_keys.iter().position(|(k, _)| *k == key)?;
+        Some(self.recording_keys.remove(index).1)
+    }
+
+    /// Points every key at its file in the current bank: a library file
+    /// assigned in the bank manifest, or else its own recording. Also
+    /// picks up the volume, pitch, icon, label and routes saved for each
+    /// key.
+    fn load_bank_files(&mut self) {
+        let manifest = load_bank_manifest(&self.storage_path, self.bank).unwrap_or_else(|e| {
+            eprintln!("Failed to load bank manifest, ignoring key settings: {}", e);
+            BankManifest::default()
+        });
+        self.button_files.clear();
+        self.key_faces.clear();
+        self.key_labels.clear();
+        self.key_icons.clear();
+        self.key_routes.clear();
+        self.pattern = manifest.pattern.clone().unwrap_or_default();
+        for key in 0..KEY_COUNT {
+            let settings = manifest.key(key).cloned().unwrap_or_default();
+            let path = settings
+                .file
+                .clone()
+                .unwrap_or_else(|| get_key_file_path(&self.storage_path, self.bank, key));
+            self.button_files.insert(key, path);
+            let slot = self.slot(key);
+            if let Some(volume) = settings.volume {
+                self.playback_volume.insert(slot, volume);
+            }
+            if let Some(pitch) = settings.pitch {
+                self.pitch_shift_semitones
+                    .insert(slot, pitch.clamp(-MAX_PITCH, MAX_PITCH));
+            }
+            if let Some(face) =
+                self.render_key_face(key, settings.icon.as_deref(), settings.label.as_deref())
+            {
+                self.key_faces.insert(key, face);
+            }
+            if let Some(label) = settings.label {
+                self.key_labels.insert(key, label);
+            }
+            if let Some(icon) = settings.icon {
+                self.key_icons.insert(key, icon);
+            }
+            if let Some(routes) = settings.routes {
+                self.key_routes.insert(key, routes);
+            }
+        }
+    }
+
+    /// Builds the image for a key with an icon or label, or `None` if it
+    /// has neither and shows the plain play image.
+    fn render_key_face(
+        &self,
+        key: u8,
+        icon: Option<&Path>,
+        label: Option<&str>,
+    ) -> Option<DynamicImage> {
+        if icon.is_none() && label.is_none() {
+            return None;
+        }
+        let base = match icon.map(open) {
+            Some(Ok(img)) => img,
+            Some(Err(e)) => {
+                eprintln!("Failed to load icon for key {}: {}", key, e);
+                self.img_play.clone()
+            }
+            None => self.img_play.clone(),
+        };
+        Some(match label {
+            Some(label) => render_key_label(&base, label),
+            None => base,
+        })
+    }
+
+    fn key_volume(&self, key: u8) -> f64 {
+        self.playback_volume
+            .get(&self.slot(key))
+            .copied()
+            .unwrap_or(DEFAULT_VOLUME)
+    }
+
+    fn key_pitch(&self, key: u8) -> f64 {
+        self.pitch_shift_semitones
+            .get(&self.slot(key))
+            .copied()
+            .unwrap_or(DEFAULT_PITCH)
+    }
+
+    /// Sets the playback volume of `key`, from 0% to 150%. It is saved
+    /// once it stops changing.
+    fn set_key_volume(&mut self, key: u8, volume: f64) {
+        let volume = volume.clamp(0.0, MAX_VOLUME);
+        let slot = self.slot(key);
+        self.playback_volume.insert(slot, volume);
+        println!("Set volume for key {} to {:.0}%", key, volume * 100.0);
+        self.settings_changed(slot);
+    }
+
+    /// Sets the pitch shift of `key` in semitones. It is saved once it
+    /// stops changing. Keys playing anything but WAV keep their pitch.
+    fn set_key_pitch(&mut self, key: u8, pitch: f64) {
+        if !self.can_pitch(key) {
+            println!(
+                "Key {} does not play a WAV file, so its pitch cannot change.",
+                key
+            );
+            return;
+        }
+        let pitch = pitch.clamp(-MAX_PITCH, MAX_PITCH);
+        let slot = self.slot(key);
+        self.pitch_shift_semitones.insert(slot, pitch);
+        println!("Set pitch for key {} to {:.2} semitones", key, pitch);
+        self.settings_changed(slot);
+    }
+
+    /// Notes that the volume or pitch of `slot` changed, to be saved by
+    /// [`Self::save_changed_settings`].
+    fn settings_changed(&mut self, slot: KeySlot) {
+        self.unsaved_settings.insert(slot);
+        self.settings_changed_at = Some(Instant::now());
+    }
+
+    /// Saves volume and pitch that changed, once they have been left
+    /// alone for `SETTINGS_SAVE_DELAY`, or straight away with `now`.
+    fn save_changed_settings(&mut self, now: bool) {
+        let Some(changed_at) = self.settings_changed_at else {
+            return;
+        };
+        if !now && changed_at.elapsed() < SETTINGS_SAVE_DELAY {
+            return;
+        }
+        self.settings_changed_at = None;
+        for slot in std::mem::take(&mut self.unsaved_settings) {
+            if let Err(e) = self.save_slot_settings(slot) {
+                eprintln!("Failed to save settings of key {}: {}", slot.1, e);
+            }
+        }
+    }
+
+    /// Saves the volume and pitch of `key` to the bank manifest, so they
+    /// are kept across restarts and exported with the bank.
+    fn save_key_settings(&self, key: u8) -> Result<()> {
+        self.save_slot_settings(self.slot(key))
+    }
+
+    fn save_slot_settings(&self, slot: KeySlot) -> Result<()> {
+        let (bank, key) = slot;
+        let mut manifest = load_bank_manifest(&self.storage_path, bank)?;
+        let settings = manifest.key_mut(key);
+        settings.volume = self
+            .playback_volume
+            .get(&slot)
+            .copied()
+            .filter(|volume| *volume != DEFAULT_VOLUME);
+        settings.pitch = self
+            .pitch_shift_semitones
+            .get(&slot)
+            .copied()
+            .filter(|pitch| *pitch != DEFAULT_PITCH);
+        save_bank_manifest(&self.storage_path, bank, &manifest)
+    }
+
+    /// Whether the pitch of `key` can be shifted: recordings are WAV, but
+    /// library files can be in formats only the player reads.
+    fn can_pitch(&self, key: u8) -> bool {
+        self.button_files
+            .get(&key)
+            .is_none_or(|path| is_wav_file(path))
+    }
+
+    /// Whether `key` plays a library file rather than its own recording.
+    fn is_assigned(&self, key: u8) -> bool {
+        self.button_files
+            .get(&key)
+            .is_some_and(|path| *path != get_key_file_path(&self.storage_path, self.bank, key))
+    }
+
+    /// The image a key should show: lit while recording or selected, play
+    /// if its file exists, otherwise ready to record (or offline while the
+    /// audio capture is down).
+    fn key_image(&self, key: u8) -> DynamicImage {
+        if let Some(open) = self.voice_key_open(key) {
+            return if open {
+                render_key_label(&self.img_rec_on, "MIC")
+            } else {
+                render_key_label(&self.img_rec_off, "MUTED")
             };
-            println!("Mode switched to: {:?}", self.mode);
-            if self.mode == Mode::Playback {
-                if let Some(selected_key) = self.selected_for_delete.take() {
-                    println!(
-                        "Mode switched away from Edit. Deselecting key {}.",
-                        selected_key
-                    );
-                    // Reset the button's image
-                    if let Some(path) = self.button_files.get(&selected_key) {
-                        let img = if path.exists() {
-                            self.img_play.clone()
-                        } else {
-                            self.img_rec_off.clone()
-                        };
-                        device.set_button_image(selected_key, img).await.unwrap();
-                    }
-                }
+        }
+        if let Some(name) = self.key_macro(key) {
+            return self.macro_key_image(name);
+        }
+        if self.mode == Mode::Looper
+            && let Some(looper) = &self.looper
+        {
+            return match looper.key_state(key) {
+                LoopKeyState::Empty if !looper.is_running() => self.img_rec_offline.clone(),
+                LoopKeyState::Empty => self.img_rec_off.clone(),
+                LoopKeyState::Recording => self.img_rec_on.clone(),
+                LoopKeyState::Playing => render_key_label(&self.img_play, "LOOP"),
+                LoopKeyState::Muted => render_key_label(&self.img_play, "MUTED"),
+            };
+        }
+        if self.mode == Mode::Pattern {
+            let Some(step) = self.key_step(key) else {
+                return self.img_rec_offline.clone();
+            };
+            if self.shown_step == Some(step) {
+                return self.img_rec_on.clone();
             }
-            // Update the LCD strip to reflect the new mode
+            return if self.pattern.track(self.pattern_track)[step] {
+                self.key_faces
+                    .get(&self.pattern_track)
+                    .cloned()
+                    .unwrap_or_else(|| self.img_play.clone())
+            } else {
+                self.img_rec_off.clone()
+            };
+        }
+        if self.is_recording(key) || self.selected_for_delete == Some(key) {
+            return self.img_rec_on.clone();
+        }
+        match self.button_files.get(&key) {
+            Some(path) if path.exists() => self
+                .key_faces
+                .get(&key)
+                .cloned()
+                .unwrap_or_else(|| self.img_play.clone()),
+            _ if !self.health_rx.borrow().is_running() => self.img_rec_offline.clone(),
+            _ => self.img_rec_off.clone(),
+        }
+    }
+
+    /// The LCD strip image for the current mode.
+    fn lcd_image(&self) -> &DynamicImage {
+        match self.mode {
+            Mode::Playback | Mode::Looper | Mode::Pattern => &self.img_lcd_playback,
+            Mode::Edit => &self.img_lcd_edit,
+        }
+    }
+
+    /// Shows the mode image on the LCD strip, with a banner on top while
+    /// the audio capture is down. Looper mode shows the loops instead,
+    /// which do not need the capture.
+    async fn update_lcd_status(&self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        if self.mode == Mode::Looper
+            && let Some(looper) = &self.looper
+        {
+            return update_lcd_banner(device, self.lcd_image(), &looper.status()).await;
+        }
+        if self.mode == Mode::Pattern {
+            return update_lcd_banner(device, self.lcd_image(), &self.pattern_status()).await;
+        }
+        let health = *self.health_rx.borrow();
+        if health.is_running() {
             update_lcd_mode(
                 device,
                 self.mode,
                 &self.img_lcd_playback,
                 &self.img_lcd_edit,
             )
-            .await;
-            device.flush().await.unwrap();
+            .await
+        } else {
+            update_lcd_banner(device, self.lcd_image(), &health.to_string()).await
+        }
+    }
+
+    /// Brings the deck up to date after the capture health changed. Takes
+    /// in progress have already been saved by the capture supervisor when
+    /// the stream went down, so only their keys need resetting.
+    async fn handle_capture_health(&mut self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let health = *self.health_rx.borrow_and_update();
+        println!("Audio capture health: {}", health);
+        if !health.is_running() {
+            for (key, _) in self.recording_keys.drain(..) {
+                println!("Recording on key {} ended by capture failure.", key);
+            }
+        }
+        self.redraw_keys(device).await?;
+        self.update_lcd_status(device).await?;
+        flush_device(device).await
+    }
+
+    /// Redraws the keys and LCD strip after the loops changed.
+    async fn refresh_looper(&self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        self.redraw_keys(device).await?;
+        self.update_lcd_status(device).await?;
+        flush_device(device).await
+    }
+
+    /// Sets every key's image from its current state.
+    async fn redraw_keys(&self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        for key in self.button_files.keys() {
+            set_key_image(device, *key, self.key_image(*key)).await?;
+        }
+        flush_device(device).await
+    }
+
+    /// Moves `delta` banks forward or back, wrapping around at either end.
+    async fn switch_bank(&mut self, delta: isize, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        if !self.recording_keys.is_empty() {
+            println!("Refusing to switch bank while recording.");
+            return Ok(());
+        }
+        let count = self.bank_count.max(1) as isize;
+        self.bank = (self.bank as isize + delta).rem_euclid(count) as usize;
+        self.selected_for_delete = None;
+        self.close_take_browser();
+        println!("Switched to bank {}.", self.bank + 1);
+        self.load_bank_files();
+        if self.mode == Mode::Pattern {
+            self.load_pattern();
+        }
+        self.redraw_keys(device).await?;
+        update_lcd_banner(device, self.lcd_image(), &format!("BANK {}", self.bank + 1)).await?;
+        flush_device(device).await
+    }
+
+    /// Puts the value controlled by `dial` back to its default.
+    async fn reset_dial(&mut self, dial: u8, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        match dial {
+            0 => self.set_mode(Mode::Playback, device).await?,
+            1 if self.mode == Mode::Pattern => {
+                self.pattern.bpm = Pattern::default().bpm;
+                println!("Reset pattern tempo to {:.0} BPM", self.pattern.bpm);
+                self.save_pattern()?;
+                self.update_lcd_status(device).await?;
+            }
+            2 if self.mode == Mode::Pattern => {
+                self.pattern.swing = 0.0;
+                println!("Reset pattern swing.");
+                self.save_pattern()?;
+                self.update_lcd_status(device).await?;
+            }
+            1 | 2 => {
+                let Some(key) = self.selected_for_delete else {
+                    println!("Dial {} reset requested, but no sample is selected.", dial);
+                    return Ok(());
+                };
+                let slot = self.slot(key);
+                if dial == 1 {
+                    self.playback_volume.insert(slot, DEFAULT_VOLUME);
+                    println!("Reset volume for key {} to 100%", key);
+                } else {
+                    self.pitch_shift_semitones.insert(slot, DEFAULT_PITCH);
+                    println!("Reset pitch for key {} to 0 semitones", key);
+                }
+                self.save_key_settings(key)?;
+            }
+            _ => println!("Dial {} has no value to reset.", dial),
+        }
+        Ok(())
+    }
+
+    /// Runs a configured touch strip action. `x` is where the strip was
+    /// touched, used to find the dial under the finger.
+    async fn run_touch_action(
+        &mut self,
+        action: TouchAction,
+        x: u16,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        println!("Touch action: {:?}", action);
+        match Action::from_touch(action, dial_under_touch(device, x)) {
+            Some(action) => self.run_action(action, device).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_touch_press(&mut self, x: u16, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        self.run_touch_action(self.touch.tap, x, device).await
+    }
+
+    async fn handle_touch_long_press(
+        &mut self,
+        x: u16,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        self.run_touch_action(self.touch.long_press, x, device)
+            .await
+    }
+
+    async fn handle_touch_swipe(
+        &mut self,
+        from: (u16, u16),
+        to: (u16, u16),
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let distance = to.0 as i32 - from.0 as i32;
+        if distance <= -SWIPE_MIN_DISTANCE {
+            self.run_touch_action(self.touch.swipe_left, from.0, device)
+                .await
+        } else if distance >= SWIPE_MIN_DISTANCE {
+            self.run_touch_action(self.touch.swipe_right, from.0, device)
+                .await
+        } else {
+            println!("Swipe too short ({} px), ignoring.", distance);
+            Ok(())
+        }
+    }
+
+    /// Redraws the live meter and timer on every recording key. The LCD
+    /// strip follows the most recently started take. Does nothing unless a
+    /// recording is in progress.
+    async fn refresh_recording_meter(&mut self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(deck) = device else {
+            return Ok(());
+        };
+        let Some((latest_key, _)) = self.recording_keys.last() else {
+            return Ok(());
+        };
+        let latest_key = *latest_key;
+        let levels = self.level_rx.borrow_and_update().clone();
+        for (key, path) in &self.recording_keys {
+            let level = levels.get(path).copied().unwrap_or_default();
+            let img = render_key_meter(deck, &self.img_rec_on, level);
+            set_key_image(device, *key, img).await?;
+            if *key == latest_key {
+                update_lcd_meter(device, *key, level).await?;
+            }
+        }
+        flush_device(device).await
+    }
+
+    /// Whether `mode` can be switched to: Looper and Pattern mode need
+    /// the looper and sequencer to be enabled.
+    fn mode_available(&self, mode: Mode) -> bool {
+        match mode {
+            Mode::Playback | Mode::Edit => true,
+            Mode::Looper => self.looper.is_some(),
+            Mode::Pattern => self.sequencer.is_some(),
+        }
+    }
+
+    /// Moves on to the next mode: Playback, Edit, then Looper and Pattern
+    /// if they are enabled.
+    async fn toggle_mode(&mut self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let current = Mode::ALL
+            .iter()
+            .position(|mode| *mode == self.mode)
+            .unwrap_or(0);
+        let next = (1..Mode::ALL.len())
+            .map(|offset| Mode::ALL[(current + offset) % Mode::ALL.len()])
+            .find(|mode| self.mode_available(*mode))
+            .unwrap_or(Mode::Playback);
+        self.set_mode(next, device).await
+    }
+
+    async fn set_mode(&mut self, mode: Mode, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        if mode == self.mode {
+            return Ok(());
+        }
+        if !self.mode_available(mode) {
+            println!("{:?} mode requested, but it is not enabled.", mode);
+            return Ok(());
+        }
+        let previous = std::mem::replace(&mut self.mode, mode);
+        println!("Mode switched to: {:?}", self.mode);
+        if let Some(cc) = self.midi.mode_cc {
+            let value = self.mode.midi_value();
+            self.send_midi(MidiMessage::Control { cc, value });
+        }
+        self.close_take_browser();
+        self.close_library();
+        if self.mode != Mode::Edit
+            && let Some(selected_key) = self.selected_for_delete.take()
+        {
+            println!(
+                "Mode switched away from Edit. Deselecting key {}.",
+                selected_key
+            );
+            // Reset the button's image
+            set_key_image(device, selected_key, self.key_image(selected_key)).await?;
+        }
+        if self.mode == Mode::Pattern {
+            self.load_pattern();
+        }
+        if [previous, self.mode]
+            .iter()
+            .any(|mode| matches!(mode, Mode::Looper | Mode::Pattern))
+        {
+            // Keys show loops or steps in those modes and samples otherwise
+            self.redraw_keys(device).await?;
+        }
+        // Update the LCD strip to reflect the new mode
+        self.update_lcd_status(device).await?;
+        flush_device(device).await
+    }
+
+    /// Moves on to the next combination of the configured routes.
+    fn cycle_routes(&mut self) {
+        self.enabled_routes = next_route_combination(&self.enabled_routes, &self.routes);
+        println!("Output routes set to: {}", self.enabled_routes.join(", "));
+    }
+
+    /// Pauses every schedule, or resumes them.
+    async fn toggle_schedules(&mut self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let on = !*self.schedules_on.borrow();
+        self.schedules_on.send_replace(on);
+        let status = if on { "SCHEDULES ON" } else { "SCHEDULES OFF" };
+        println!("{}", status);
+        update_lcd_banner(device, self.lcd_image(), status).await?;
+        flush_device(device).await
+    }
+
+    /// Runs a schedule that came due: plays its key as a remote trigger
+    /// would, or runs its macro.
+    async fn run_schedule(
+        &mut self,
+        schedule: Schedule,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let action = match schedule.target() {
+            Ok(ScheduleTarget::Key(key)) if key < KEY_COUNT => Action::play(key),
+            Ok(ScheduleTarget::Key(key)) => {
+                println!(
+                    "Schedule '{}' plays key {}, which is not on the deck.",
+                    schedule.name,
+                    key + 1
+                );
+                return Ok(());
+            }
+            Ok(ScheduleTarget::Macro(name)) => Action::RunMacro(name),
+            Err(e) => {
+                eprintln!("Skipping schedule: {}", e);
+                return Ok(());
+            }
+        };
+        println!("Running schedule '{}'.", schedule.name);
+        self.run_action(action, device).await
+    }
+
+    /// Turns the output route `name` on or off for keys without routes of
+    /// their own.
+    fn set_route_enabled(&mut self, name: &str, enabled: bool) {
+        let position = self.enabled_routes.iter().position(|route| route == name);
+        match (enabled, position) {
+            (true, None) => self.enabled_routes.push(name.to_string()),
+            (false, Some(index)) => {
+                self.enabled_routes.remove(index);
+            }
+            _ => return,
+        }
+        println!("Output routes set to: {}", self.enabled_routes.join(", "));
+    }
+
+    /// The routes `key` plays through: its own, or else the enabled ones.
+    fn key_route_names(&self, key: u8) -> &[String] {
+        self.key_routes.get(&key).unwrap_or(&self.enabled_routes)
+    }
+
+    /// Makes `key` play through `routes`, or the enabled routes if `None`,
+    /// and saves it in the bank manifest.
+    fn set_key_routes(&mut self, key: u8, routes: Option<Vec<String>>) -> Result<()> {
+        let mut manifest = load_bank_manifest(&self.storage_path, self.bank)?;
+        manifest.key_mut(key).routes = routes.clone();
+        save_bank_manifest(&self.storage_path, self.bank, &manifest)?;
+        match routes {
+            Some(routes) => {
+                println!("Key {} plays through: {}", key, routes.join(", "));
+                self.key_routes.insert(key, routes);
+            }
+            None => {
+                println!("Key {} plays through the enabled routes.", key);
+                self.key_routes.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_encoder_twist(
+        &mut self,
+        dial: u8,
+        ticks: i32,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        // Twisting a dial while it is held down makes finer adjustments,
+        // and stops the press action from firing on release.
+        let fine = self.held_encoder == Some(dial);
+        if fine {
+            self.held_encoder_twisted = true;
+        }
+        if dial == 0 {
+            self.toggle_mode(device).await?;
+        } else if self.mode == Mode::Pattern {
+            self.adjust_pattern(dial, ticks, fine, device).await?;
         } else if dial == 1 {
-            if self.mode == Mode::Edit {
+            if self.mode == Mode::Edit && self.library_index.is_some() {
+                self.scroll_library(ticks, device).await?;
+            } else if self.mode == Mode::Edit {
                 if let Some(key) = self.selected_for_delete {
                     // A key is selected, so adjust its volume
-                    let current_volume = self.playback_volume.entry(key).or_insert(1.0);
-                    *current_volume += ticks as f64 * 0.05; // 5% per tick
-                    *current_volume = current_volume.clamp(0.0, 1.5); // 0% to 150%
-                    println!(
-                        "Set volume for key {} to {:.0}%",
-                        key,
-                        *current_volume * 100.0
-                    );
+                    let step = if fine { VOLUME_STEP_FINE } else { VOLUME_STEP };
+                    let volume = self.key_volume(key);
+                    self.set_key_volume(key, volume + ticks as f64 * step);
                 } else {
                     println!("Dial 1 (Volume) turned in Edit mode, but no sample is selected.");
                 }
             }
-        } else if dial == 2 {
-            if self.mode == Mode::Edit {
-                if let Some(key) = self.selected_for_delete {
-                    // A key is selected, so adjust its pitch
-                    let current_pitch = self.pitch_shift_semitones.entry(key).or_insert(0.0);
-                    // Adjust by 0.1 semitones per "tick" of the dial
-                    *current_pitch += ticks as f64 * 0.1;
+        } else if dial == 3 && self.mode == Mode::Edit {
+            self.browse_takes(ticks, device).await?;
+        } else if dial == 2 && self.mode == Mode::Playback {
+            self.adjust_voice_amount(ticks, fine);
+        } else if dial == 2 && self.mode == Mode::Edit {
+            if let Some(key) = self.selected_for_delete {
+                if !self.can_pitch(key) {
                     println!(
-                        "Set pitch for key {} to {:.2} semitones",
-                        key, *current_pitch
+                        "Key {} does not play a WAV file, so its pitch cannot change.",
+                        key
                     );
-                } else {
-                    println!("Dial 2 turned in Edit mode, but no sample is selected.");
+                    update_lcd_banner(device, self.lcd_image(), "PITCH NEEDS WAV").await?;
+                    return flush_device(device).await;
                 }
+                // A key is selected, so adjust its pitch
+                let step = if fine { PITCH_STEP_FINE } else { PITCH_STEP };
+                let pitch = self.key_pitch(key);
+                self.set_key_pitch(key, pitch + ticks as f64 * step);
+            } else {
+                println!("Dial 2 turned in Edit mode, but no sample is selected.");
             }
         }
+        Ok(())
+    }
+
+    fn handle_encoder_down(&mut self, dial: u8) {
+        // The press action runs on release, so that twisting a held dial
+        // can be used for fine adjustment instead.
+        self.held_encoder = Some(dial);
+        self.held_encoder_twisted = false;
+    }
+
+    async fn handle_encoder_up(
+        &mut self,
+        dial: u8,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let was_twisted = self.held_encoder == Some(dial) && self.held_encoder_twisted;
+        self.held_encoder = None;
+        self.held_encoder_twisted = false;
+        if was_twisted {
+            println!("Dial {} released after fine adjustment.", dial);
+            Ok(())
+        } else {
+            self.handle_encoder_press(dial, device).await
+        }
     }
 
-    async fn handle_encoder_down(&mut self, dial: u8, device: &AsyncStreamDeck) {
+    async fn handle_encoder_press(
+        &mut self,
+        dial: u8,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
         if dial == 0 {
-            self.playback_sink = match self.playback_sink {
-                PlaybackSink::Default => PlaybackSink::Mixer,
-                PlaybackSink::Mixer => PlaybackSink::Both,
-                PlaybackSink::Both => PlaybackSink::Default,
-            };
-            println!("Playback sink set to: {:?}", self.playback_sink);
+            self.cycle_routes();
+        } else if self.mode == Mode::Pattern {
+            self.press_pattern_dial(dial, device).await?;
+        } else if dial == 2 && self.mode == Mode::Playback {
+            self.cycle_voice_effect();
+        } else if dial == 1 && self.mode == Mode::Edit {
+            if self.library_index.is_some() {
+                self.audition_library_file();
+            } else {
+                self.open_library(device).await?;
+            }
         } else if dial == 3 {
             if self.mode == Mode::Edit {
+                if self.library_index.is_some() {
+                    return self.assign_library_file(device).await;
+                }
+                if self.browsed_take.is_some() {
+                    return self.restore_browsed_take(device).await;
+                }
                 if let Some(key_to_delete) = self.selected_for_delete.take() {
                     println!(
                         "Encoder 3 pressed in Edit mode. Deleting selected key: {}",
                         key_to_delete
                     );
-                    if let Some(path) = self.button_files.get(&key_to_delete) {
-                        match tokio_fs::remove_file(path).await {
-                            Ok(_) => {
-                                println!("...File {} deleted.", path.display());
-                                self.pitch_shift_semitones.remove(&key_to_delete);
-                                self.playback_volume.remove(&key_to_delete);
-                                device
-                                    .set_button_image(key_to_delete, self.img_rec_off.clone())
-                                    .await
-                                    .unwrap();
-                            }
-                            Err(e) => {
-                                eprintln!("...Failed to delete file {}: {}", path.display(), e);
-                                // Set image back to 'play' even if delete failed
-                                device
-                                    .set_button_image(key_to_delete, self.img_play.clone())
-                                    .await
-                                    .unwrap();
-                            }
-                        }
-                        device.flush().await.unwrap();
-                    }
+                    self.delete_key(key_to_delete, device).await?;
                 } else {
                     println!("Encoder 3 pressed in Edit mode, but no sample is selected.");
                 }
+            } else if self.mode == Mode::Looper {
+                println!("Encoder 3 pressed in Looper mode. Undoing last loop.");
+                self.undo_last(device).await?;
             } else {
-                println!("Encoder 3 pressed (not in Edit mode). No action.");
+                // Undo is bound to a gesture or hotkey instead, so a stray
+                // press cannot bring back a deleted sample
+                println!("Encoder 3 pressed in Playback mode. No action.");
             }
         }
+        Ok(())
     }
 
-    async fn handle_button_down(&mut self, key: u8, device: &AsyncStreamDeck) {
-        match self.mode {
-            Mode::Playback => {
-                if let Some(path) = self.button_files.get(&key) {
-                    if path.exists() {
-                        device
-                            .set_button_image(key, self.img_rec_on.clone())
-                            .await
-                            .unwrap();
-                        device.flush().await.unwrap();
-                    } else {
-                        println!(
-                            "Button {} down (Playback Mode, no file). Sending START.",
-                            key
-                        );
+    /// Clears `key`: a library file is unassigned, and a recording is moved
+    /// to the history (so it can be undone) along with its settings.
+    async fn delete_key(&mut self, key: u8, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let slot = self.slot(key);
+        if self.is_assigned(key) {
+            // Library files are never deleted, only unassigned
+            return self.unassign_key(key, device).await;
+        }
+        let Some(path) = self.button_files.get(&key) else {
+            return Ok(());
+        };
+        // Deleted samples go to the history so they can be undone
+        let archived = history::archive(&self.storage_path, path, ArchiveReason::Deleted);
+        match &archived {
+            Ok(Some(target)) => {
+                println!("...File {} moved to {}.", path.display(), target.display());
+                self.pitch_shift_semitones.remove(&slot);
+                self.playback_volume.remove(&slot);
+                self.save_key_settings(key)?;
+            }
+            Ok(None) => println!("...Key {} has no file to delete.", key),
+            Err(_) => {}
+        }
+        // Shows 'play' again if the delete failed
+        set_key_image(device, key, self.key_image(key)).await?;
+        flush_device(device).await?;
+        archived.map_err(|e| Error::io(format!("deleting {}", path.display()), e))?;
+        self.prune_history();
+        Ok(())
+    }
 
-                        // This is a sync send, but it's non-blocking (just
-                        // drops the command in a queue) so it's fine in async.
-                        let cmd = AudioCommand::Start(path.clone());
-                        if let Err(e) = self.audio_cmd_tx.send(cmd) {
-                            eprintln!("Failed to send START command: {}", e);
-                        } else {
-                            // The audio thread will handle logic.
-                            self.active_recording_key = Some(key);
-                            device
-                                .set_button_image(key, self.img_rec_on.clone())
-                                .await
-                                .unwrap();
-                            device.flush().await.unwrap();
-                            println!("...START sent.");
-                        }
-                    }
-                }
+    /// Opens the library browser on dial 1, indexing the library
+    /// directories afresh so newly added files show up.
+    async fn open_library(&mut self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let dirs = self.library_dirs.clone();
+        self.library = tokio::task::spawn_blocking(move || scan_library(&dirs))
+            .await
+            .map_err(|e| Error::audio("indexing sample library", e))?;
+        if self.library.is_empty() {
+            println!("Sample library is empty. Add directories under [library] in the config.");
+            update_lcd_banner(device, self.lcd_image(), "LIBRARY EMPTY").await?;
+            return flush_device(device).await;
+        }
+        println!("Opened sample library with {} files.", self.library.len());
+        self.library_index = Some(0);
+        self.show_library_entry(device).await
+    }
+
+    fn close_library(&mut self) {
+        self.library.clear();
+        self.library_index = None;
+    }
+
+    /// Shows the library file dial 1 is on, e.g. `12/340 KICK_01`.
+    async fn show_library_entry(&self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(index) = self.library_index else {
+            return Ok(());
+        };
+        let entry = &self.library[index];
+        let text = format!("{}/{} {}", index + 1, self.library.len(), entry.name);
+        println!("Library: {} ({})", text, entry.path.display());
+        update_lcd_banner(device, self.lcd_image(), &text).await?;
+        flush_device(device).await
+    }
+
+    async fn scroll_library(&mut self, ticks: i32, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(index) = self.library_index else {
+            return Ok(());
+        };
+        let last = self.library.len().saturating_sub(1) as i32;
+        self.library_index = Some((index as i32 + ticks).clamp(0, last) as usize);
+        self.show_library_entry(device).await
+    }
+
+    /// Plays the library file dial 1 is on through the default output,
+    /// so it can be checked without going out on the mixer.
+    fn audition_library_file(&self) {
+        let Some(entry) = self.library_index.and_then(|i| self.library.get(i)) else {
+            return;
+        };
+        println!("Auditioning {}", entry.path.display());
+        let path = entry.path.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                play_audio_file(&path, &[PlaybackRoute::default_output()], DEFAULT_VOLUME).await
+            {
+                eprintln!("Audition failed: {}", e);
             }
-            Mode::Edit => {
-                if let Some(path) = self.button_files.get(&key) {
-                    if path.exists() {
-                        if let Some(prev_selected_key) = self.selected_for_delete {
-                            // A key is already selected
-                            if prev_selected_key == key {
-                                // This key was already selected. Toggle it OFF.
-                                println!("Button {} down (Edit Mode). Deselecting {}.", key, key);
-                                device
-                                    .set_button_image(key, self.img_play.clone())
-                                    .await
-                                    .unwrap();
-                                self.selected_for_delete = None;
+        });
+    }
+
+    /// Assigns the library file dial 1 is on to the selected key, saving
+    /// it in the bank manifest. The key's own recording, if any, is kept
+    /// and comes back when the file is unassigned.
+    async fn assign_library_file(&mut self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(entry) = self
+            .library_index
+            .and_then(|i| self.library.get(i).cloned())
+        else {
+            return Ok(());
+        };
+        let Some(key) = self.selected_for_delete else {
+            println!("Select a key to assign {} to.", entry.name);
+            update_lcd_banner(device, self.lcd_image(), "SELECT A KEY FIRST").await?;
+            return flush_device(device).await;
+        };
+        self.assign_file(key, &entry.path, device).await?;
+        self.close_library();
+        update_lcd_banner(
+            device,
+            self.lcd_image(),
+            &format!("ASSIGNED {}", entry.name),
+        )
+        .await?;
+        flush_device(device).await
+    }
+
+    /// Makes `key` play `path`, saving it in the bank manifest.
+    async fn assign_file(
+        &mut self,
+        key: u8,
+        path: &Path,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let mut manifest = load_bank_manifest(&self.storage_path, self.bank)?;
+        manifest.key_mut(key).file = Some(path.to_path_buf());
+        save_bank_manifest(&self.storage_path, self.bank, &manifest)?;
+        self.button_files.insert(key, path.to_path_buf());
+        println!("Assigned {} to key {}.", path.display(), key);
+        set_key_image(device, key, self.key_image(key)).await?;
+        flush_device(device).await
+    }
+
+    /// Sets or removes the label shown on `key`, saving it in the bank
+    /// manifest.
+    async fn set_key_label(
+        &mut self,
+        key: u8,
+        label: Option<String>,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let label = label.filter(|label| !label.trim().is_empty());
+        let mut manifest = load_bank_manifest(&self.storage_path, self.bank)?;
+        let settings = manifest.key_mut(key);
+        settings.label = label.clone();
+        let icon = settings.icon.clone();
+        save_bank_manifest(&self.storage_path, self.bank, &manifest)?;
+        match self.render_key_face(key, icon.as_deref(), label.as_deref()) {
+            Some(face) => self.key_faces.insert(key, face),
+            None => self.key_faces.remove(&key),
+        };
+        match &label {
+            Some(label) => {
+                println!("Set label for key {} to '{}'", key, label);
+                self.key_labels.insert(key, label.clone());
+            }
+            None => {
+                println!("Removed label from key {}", key);
+                self.key_labels.remove(&key);
+            }
+        }
+        set_key_image(device, key, self.key_image(key)).await?;
+        flush_device(device).await
+    }
+
+    /// Removes a library file from `key`, which goes back to its own
+    /// recording.
+    async fn unassign_key(&mut self, key: u8, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let mut manifest = load_bank_manifest(&self.storage_path, self.bank)?;
+        manifest.key_mut(key).file = None;
+        save_bank_manifest(&self.storage_path, self.bank, &manifest)?;
+        let path = get_key_file_path(&self.storage_path, self.bank, key);
+        self.button_files.insert(key, path);
+        println!("Unassigned library file from key {}.", key);
+        set_key_image(device, key, self.key_image(key)).await?;
+        flush_device(device).await
+    }
+
+    /// Whether `path` has previous takes in the history.
+    fn has_history(&self, path: &Path) -> bool {
+        history::list_takes(&self.storage_path, path).is_ok_and(|takes| !takes.is_empty())
+    }
+
+    /// Closes the take browser, e.g. when the selection changes.
+    fn close_take_browser(&mut self) {
+        self.history_takes.clear();
+        self.browsed_take = None;
+    }
+
+    /// Deletes old takes the history config no longer keeps.
+    fn prune_history(&self) {
+        match history::apply_retention(&self.storage_path, &self.history) {
+            Ok(0) => {}
+            Ok(removed) => println!("Removed {} old takes from the history.", removed),
+            Err(e) => eprintln!("Failed to prune the take history: {}", e),
+        }
+    }
+
+    /// Moves through the previous takes of the selected key with dial 3,
+    /// showing the take on the LCD strip. Position 0 is the current file.
+    async fn browse_takes(&mut self, ticks: i32, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(key) = self.selected_for_delete else {
+            println!("Dial 3 turned in Edit mode, but no sample is selected.");
+            return Ok(());
+        };
+        if self.is_assigned(key) {
+            // Only the key's own recordings have takes
+            println!("Key {} plays a library file, which has no takes.", key);
+            update_lcd_banner(device, self.lcd_image(), "LIBRARY FILE").await?;
+            return flush_device(device).await;
+        }
+        let Some(path) = self.button_files.get(&key) else {
+            return Ok(());
+        };
+        if self.browsed_take.is_none() {
+            // Reload when entering the browser so new takes show up
+            self.history_takes = history::list_takes(&self.storage_path, path)
+                .map_err(|e| Error::io(format!("listing takes of {}", path.display()), e))?;
+        }
+        let count = self.history_takes.len() as i32;
+        let position = self.browsed_take.map_or(0, |i| i as i32 + 1) + ticks;
+        let position = position.clamp(0, count);
+        self.browsed_take = (position > 0).then(|| position as usize - 1);
+        let text = match self.browsed_take {
+            None if count == 0 => "NO OLD TAKES".to_string(),
+            None => "CURRENT TAKE".to_string(),
+            Some(i) => format!(
+                "TAKE {}/{}, {} AGO",
+                i + 1,
+                count,
+                format_age(self.history_takes[i].age())
+            ),
+        };
+        println!("Key {}: {}", key, text);
+        update_lcd_banner(device, self.lcd_image(), &text).await?;
+        flush_device(device).await
+    }
+
+    /// Puts the take being browsed back on the selected key. The file it
+    /// replaces goes to the history, so this can be undone by browsing.
+    async fn restore_browsed_take(&mut self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(take) = self
+            .browsed_take
+            .and_then(|i| self.history_takes.get(i).cloned())
+        else {
+            return Ok(());
+        };
+        self.close_take_browser();
+        let Some(key) = self.selected_for_delete else {
+            return Ok(());
+        };
+        // A take never goes over a library file
+        if self.is_assigned(key) {
+            return Ok(());
+        }
+        let Some(path) = self.button_files.get(&key) else {
+            return Ok(());
+        };
+        history::restore(&self.storage_path, path, &take)
+            .map_err(|e| Error::io(format!("restoring {}", take.path.display()), e))?;
+        println!(
+            "Restored take from {} ago to key {}.",
+            format_age(take.age()),
+            key
+        );
+        update_lcd_banner(device, self.lcd_image(), "TAKE RESTORED").await?;
+        flush_device(device).await
+    }
+
+    /// Restores the most recently deleted or replaced sample, in any bank.
+    /// In Looper mode, undoes the last loop instead.
+    async fn undo_last(&mut self, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        if self.mode == Mode::Looper
+            && let Some(looper) = &self.looper
+        {
+            // The deck redraws once the looper reports the change
+            looper.undo();
+            return Ok(());
+        }
+        let undone = history::undo_last(&self.storage_path)
+            .map_err(|e| Error::io("undoing last delete", e))?;
+        let text = match undone {
+            Some((file, take)) => {
+                println!(
+                    "Restored {} from {} ago.",
+                    file.display(),
+                    format_age(take.age())
+                );
+                "UNDONE"
+            }
+            None => {
+                println!("Nothing to undo.");
+                "NOTHING TO UNDO"
+            }
+        };
+        self.redraw_keys(device).await?;
+        update_lcd_banner(device, self.lcd_image(), text).await?;
+        flush_device(device).await
+    }
+
+    /// Brings a newly connected deck in line with the current state:
+    /// brightness, the LCD strip and every key image.
+    async fn attach_device(&self, device: &AsyncStreamDeck) -> Result<()> {
+        device
+            .set_brightness(self.brightness)
+            .await
+            .map_err(|e| Error::device("setting brightness", e))?;
+        device
+            .clear_all_button_images()
+            .await
+            .map_err(|e| Error::device("clearing button images", e))?;
+        self.update_lcd_status(Some(device)).await?;
+        self.redraw_keys(Some(device)).await
+    }
+
+    /// Forgets input that was in progress on a deck that went away.
+    /// Recordings whose keys can no longer be released are stopped and
+    /// saved.
+    fn detach_device(&mut self) {
+        self.held_encoder = None;
+        self.held_encoder_twisted = false;
+        // Playback carries on, but its end can no longer be reported
+        self.playing.clear();
+        for (key, path) in self.recording_keys.drain(..) {
+            println!(
+                "Deck lost while key {} was recording. Sending STOP to keep the take.",
+                key
+            );
+            // Nobody is left to show the outcome, so it is only logged
+            // by the audio thread.
+            let (request, _) = AudioRequest::new(AudioCommand::Stop(path));
+            if let Err(e) = self.audio_cmd_tx.send(request) {
+                eprintln!("Failed to send STOP command: {}", e);
+            }
+        }
+    }
+
+    /// Sends `command` to the audio thread on behalf of `key`. Its response
+    /// comes back through `audio_response_tx` and is handled by
+    /// `handle_audio_response`, so the event loop never waits on a save.
+    fn send_audio_command(&self, key: u8, command: AudioCommand) -> Result<()> {
+        let (request, response_rx) = AudioRequest::new(command);
+        self.audio_cmd_tx
+            .send(request)
+            .map_err(|e| Error::audio("sending audio command", e.to_string()))?;
+        if let Some(response_tx) = self.audio_response_tx.clone() {
+            tokio::spawn(async move {
+                // A dropped reply means the audio thread went away mid-command
+                let response = response_rx
+                    .await
+                    .unwrap_or_else(|_| AudioResponse::Failed("audio thread stopped".to_string()));
+                let _ = response_tx.send((key, response));
+            });
+        }
+        Ok(())
+    }
+
+    /// Updates the deck with what the audio thread actually did for `key`.
+    /// Refused and failed commands leave the key as it was before and are
+    /// returned as errors so the key flashes.
+    async fn handle_audio_response(
+        &mut self,
+        key: u8,
+        response: AudioResponse,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        match response {
+            AudioResponse::Started => {
+                println!("Key {} is recording.", key);
+                Ok(())
+            }
+            AudioResponse::Saved { .. } => {
+                println!("Key {} {}", key, response);
+                set_key_image(device, key, self.key_image(key)).await?;
+                flush_device(device).await
+            }
+            AudioResponse::Refused(reason) | AudioResponse::Failed(reason) => {
+                if self.take_recording(key).is_some() && self.recording_keys.is_empty() {
+                    self.update_lcd_status(device).await?;
+                }
+                set_key_image(device, key, self.key_image(key)).await?;
+                flush_device(device).await?;
+                Err(Error::audio(format!("recording on key {}", key), reason))
+            }
+        }
+    }
+
+    /// Applies the recovery policy for an error raised while handling input
+    /// on `device`. `key` is the key the failed action belongs to, if any,
+    /// so it can be flashed red; otherwise the LCD strip is. Returns `true`
+    /// if the deck should be dropped and reconnected.
+    fn recover(&self, error: Error, key: Option<u8>, device: Option<&AsyncStreamDeck>) -> bool {
+        eprintln!("{}", error);
+        match error.recovery() {
+            Recovery::Reconnect => {
+                eprintln!("Lost contact with the Stream Deck, reconnecting.");
+                true
+            }
+            Recovery::Notify => {
+                match key {
+                    Some(key) => flash_key_error(device, key, self.key_image(key)),
+                    None => flash_lcd_error(device, self.lcd_image().clone()),
+                }
+                false
+            }
+            Recovery::UseDefaults => false,
+        }
+    }
+
+    /// Drives `device` until it is disconnected or shutdown is requested.
+    /// Without a device this is the board's own loop, which handles the
+    /// roles no deck takes and runs until shutdown.
+    async fn run_device(
+        &mut self,
+        device: Option<&AsyncStreamDeck>,
+        shutdown_rx: &mut watch::Receiver<bool>,
+    ) -> DeviceExit {
+        if let Some(deck) = device
+            && let Err(e) = self.attach_device(deck).await
+            && self.recover(e, None, device)
+        {
+            self.detach_device();
+            return DeviceExit::Disconnected;
+        }
+
+        let (audio_response_tx, mut audio_response_rx) = tokio_mpsc::unbounded_channel();
+        self.audio_response_tx = Some(audio_response_tx);
+        let (playback_done_tx, mut playback_done_rx) = tokio_mpsc::unbounded_channel();
+        self.playback_done_tx = Some(playback_done_tx);
+        let (macro_tx, mut macro_rx) = tokio_mpsc::unbounded_channel();
+        self.macro_tx = Some(macro_tx);
+
+        // Subscribed only while the loop has the role
+        let mut midi_rx: Option<broadcast::Receiver<MidiEvent>> = None;
+        let mut remote_rx: Option<broadcast::Receiver<RemoteCommand>> = None;
+        let mut schedule_rx: Option<broadcast::Receiver<Schedule>> = None;
+        let mut hotkey_rx: Option<broadcast::Receiver<HotkeyEvent>> = None;
+        let mut looper_rx = self.looper.as_ref().map(Looper::subscribe);
+        let mut step_rx = self.sequencer.as_ref().map(Sequencer::subscribe);
+        let reader = device.map(AsyncStreamDeck::get_reader);
+        let mut meter_interval = tokio::time::interval(METER_REFRESH_INTERVAL);
+        let exit = 'events: loop {
+            // Whatever the last event changed, remote controls see it
+            self.publish_state();
+            let roles = *self.roles.borrow_and_update();
+            if roles.midi != midi_rx.is_some() {
+                midi_rx = roles.midi.then(|| self.midi_tx.subscribe());
+            }
+            if roles.remote != remote_rx.is_some() {
+                remote_rx = roles.remote.then(|| self.remote_tx.subscribe());
+            }
+            if roles.schedules != schedule_rx.is_some() {
+                schedule_rx = roles.schedules.then(|| self.schedule_tx.subscribe());
+            }
+            if roles.hotkeys != hotkey_rx.is_some() {
+                hotkey_rx = roles.hotkeys.then(|| self.hotkey_tx.subscribe());
+            }
+            let updates = tokio::select! {
+                result = read_deck(reader.as_deref()) => match result {
+                    Ok(updates) => updates,
+                    Err(e) => {
+                        eprintln!("Failed to read from Stream Deck: {}", e);
+                        break DeviceExit::Disconnected;
+                    }
+                },
+                _ = meter_interval.tick() => {
+                    self.save_changed_settings(false);
+                    let key = self.recording_keys.last().map(|(key, _)| *key);
+                    if let Err(e) = self.refresh_recording_meter(device).await
+                        && self.recover(e, key, device)
+                    {
+                        break DeviceExit::Disconnected;
+                    }
+                    continue;
+                }
+                _ = capture_health_changed(&mut self.health_rx) => {
+                    if let Err(e) = self.handle_capture_health(device).await
+                        && self.recover(e, None, device)
+                    {
+                        break DeviceExit::Disconnected;
+                    }
+                    continue;
+                }
+                _ = looper_changed(&mut looper_rx) => {
+                    if self.mode == Mode::Looper
+                        && let Err(e) = self.refresh_looper(device).await
+                        && self.recover(e, None, device)
+                    {
+                        break DeviceExit::Disconnected;
+                    }
+                    continue;
+                }
+                step = sequencer_step_changed(&mut step_rx) => {
+                    if let Err(e) = self.show_step(step, device).await
+                        && self.recover(e, None, device)
+                    {
+                        break DeviceExit::Disconnected;
+                    }
+                    continue;
+                }
+                Some((key, response)) = audio_response_rx.recv() => {
+                    if let Err(e) = self.handle_audio_response(key, response, device).await
+                        && self.recover(e, Some(key), device)
+                    {
+                        break DeviceExit::Disconnected;
+                    }
+                    continue;
+                }
+                event = next_event(&mut midi_rx) => {
+                    match event {
+                        Ok(event) => {
+                            if let Err(e) = self.handle_midi_event(event, device).await
+                                && self.recover(e, event.key(), device)
+                            {
+                                break DeviceExit::Disconnected;
+                            }
+                        }
+                        Err(RecvError::Lagged(missed)) => {
+                            eprintln!("Dropped {} MIDI events while busy.", missed);
+                        }
+                        // Every deck holds a sender, so this cannot happen
+                        Err(RecvError::Closed) => {}
+                    }
+                    continue;
+                }
+                _ = roles_changed(&mut self.roles) => continue,
+                // Hotkeys for keys go through the same dispatch as the deck's
+                // own buttons
+                event = next_event(&mut hotkey_rx) => match event {
+                    Ok(HotkeyEvent::KeyDown(key)) => vec![DeviceStateUpdate::ButtonDown(key)],
+                    Ok(HotkeyEvent::KeyUp(key)) => vec![DeviceStateUpdate::ButtonUp(key)],
+                    Ok(HotkeyEvent::Action(action)) => {
+                        if let Err(e) = self.run_touch_action(action, 0, device).await
+                            && self.recover(e, None, device)
+                        {
+                            break DeviceExit::Disconnected;
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        eprintln!("Dropped {} hotkeys while busy.", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => continue,
+                },
+                command = next_event(&mut remote_rx) => {
+                    match command {
+                        Ok(command) => {
+                            let key = command.key();
+                            if let Err(e) = self.handle_remote_command(command, device).await
+                                && self.recover(e, key, device)
+                            {
+                                break DeviceExit::Disconnected;
+                            }
+                        }
+                        Err(RecvError::Lagged(missed)) => {
+                            eprintln!("Dropped {} remote commands while busy.", missed);
+                        }
+                        Err(RecvError::Closed) => {}
+                    }
+                    continue;
+                }
+                schedule = next_event(&mut schedule_rx) => {
+                    match schedule {
+                        Ok(schedule) => {
+                            if let Err(e) = self.run_schedule(schedule, device).await
+                                && self.recover(e, None, device)
+                            {
+                                break DeviceExit::Disconnected;
+                            }
+                        }
+                        Err(RecvError::Lagged(missed)) => {
+                            eprintln!("Dropped {} schedules while busy.", missed);
+                        }
+                        Err(RecvError::Closed) => {}
+                    }
+                    continue;
+                }
+                Some(slot) = playback_done_rx.recv() => {
+                    self.finish_playback(slot);
+                    continue;
+                }
+                Some(event) = macro_rx.recv() => {
+                    if let Err(e) = self.handle_macro_event(event, device).await
+                        && self.recover(e, None, device)
+                    {
+                        break DeviceExit::Disconnected;
+                    }
+                    continue;
+                }
+                _ = shutdown_requested(shutdown_rx) => break DeviceExit::Shutdown,
+            };
+            for update in updates {
+                let (result, key) = match update {
+                    DeviceStateUpdate::EncoderTwist(dial, ticks) => (
+                        self.handle_encoder_twist(dial, ticks as i32, device).await,
+                        None,
+                    ),
+                    DeviceStateUpdate::EncoderDown(dial) => {
+                        self.handle_encoder_down(dial);
+                        (Ok(()), None)
+                    }
+                    DeviceStateUpdate::EncoderUp(dial) => {
+                        (self.handle_encoder_up(dial, device).await, None)
+                    }
+                    DeviceStateUpdate::TouchScreenPress(x, _) => {
+                        (self.handle_touch_press(x, device).await, None)
+                    }
+                    DeviceStateUpdate::TouchScreenLongPress(x, _) => {
+                        (self.handle_touch_long_press(x, device).await, None)
+                    }
+                    DeviceStateUpdate::TouchScreenSwipe(from, to) => {
+                        (self.handle_touch_swipe(from, to, device).await, None)
+                    }
+                    DeviceStateUpdate::ButtonDown(key) => {
+                        (self.handle_button_down(key, device).await, Some(key))
+                    }
+                    DeviceStateUpdate::ButtonUp(key) => {
+                        (self.handle_button_up(key, device).await, Some(key))
+                    }
+                    _ => (Ok(()), None),
+                };
+                if let Err(e) = result
+                    && self.recover(e, key, device)
+                {
+                    break 'events DeviceExit::Disconnected;
+                }
+            }
+        };
+        drop(reader);
+        self.save_changed_settings(true);
+        self.audio_response_tx = None;
+        self.playback_done_tx = None;
+        self.macro_tx = None;
+        self.cancel_all_macros();
+
+        match exit {
+            DeviceExit::Disconnected => self.detach_device(),
+            DeviceExit::Shutdown => {
+                self.detach_device();
+                if let Some(device) = device {
+                    println!("Cleaning up buttons...");
+                    if let Err(e) = device.clear_all_button_images().await {
+                        eprintln!("Failed to clear button images: {}", e);
+                    }
+                    if let Err(e) = device.flush().await {
+                        eprintln!("Failed to flush Stream Deck: {}", e);
+                    }
+                }
+            }
+        }
+        // Stopped recordings and forgotten playback
+        self.publish_state();
+        exit
+    }
+
+    /// Queues a message for the MIDI output. Does nothing if the output is
+    /// disabled; a stopped output thread only loses the message.
+    fn send_midi(&self, message: MidiMessage) {
+        if let Some(tx) = &self.midi_out_tx
+            && tx.send(message).is_err()
+        {
+            eprintln!("MIDI output has stopped, dropping {:?}.", message);
+        }
+    }
+
+    /// Handles a note or control change from a MIDI controller like the
+    /// matching key or dial on the deck.
+    async fn handle_midi_event(
+        &mut self,
+        event: MidiEvent,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        match event {
+            MidiEvent::KeyDown { key, velocity } => {
+                self.key_velocity.insert(key, velocity);
+                self.handle_button_down(key, device).await
+            }
+            MidiEvent::KeyUp { key } => {
+                let result = self.handle_button_up(key, device).await;
+                self.key_velocity.remove(&key);
+                result
+            }
+            MidiEvent::Control { action, cc, value } => {
+                let position = value as f64 / MIDI_MAX as f64;
+                // Switch-like actions fire when the control goes past
+                // halfway, not on every value above it
+                let previous = self.control_values.insert(cc, value).unwrap_or(0);
+                let pressed = previous <= MIDI_MAX / 2 && value > MIDI_MAX / 2;
+                match action {
+                    MidiControl::Volume | MidiControl::Pitch => {
+                        let Some(key) = self.selected_for_delete else {
+                            println!("MIDI {:?} changed, but no sample is selected.", action);
+                            return Ok(());
+                        };
+                        if action == MidiControl::Volume {
+                            self.set_key_volume(key, position * MAX_VOLUME);
+                        } else {
+                            let pitch = (position * 2.0 - 1.0) * MIDI_PITCH_RANGE;
+                            self.set_key_pitch(key, (pitch * 100.0).round() / 100.0);
+                        }
+                        Ok(())
+                    }
+                    _ if !pressed => Ok(()),
+                    MidiControl::ToggleMode => self.run_action(Action::ToggleMode, device).await,
+                    MidiControl::CycleRoutes => self.run_action(Action::CycleRoutes, device).await,
+                    MidiControl::NextBank => self.run_action(Action::SwitchBank(1), device).await,
+                    MidiControl::PreviousBank => {
+                        self.run_action(Action::SwitchBank(-1), device).await
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_button_down(
+        &mut self,
+        key: u8,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        if self.voice_key_open(key).is_some() {
+            return self.set_voice_key(key, true, device).await;
+        }
+        if self.key_macro(key).is_some() {
+            return self.toggle_macro(key, device).await;
+        }
+        match self.mode {
+            Mode::Playback => {
+                if let Some(path) = self.button_files.get(&key) {
+                    if path.exists() {
+                        set_key_image(device, key, self.img_rec_on.clone()).await?;
+                        flush_device(device).await?;
+                    } else {
+                        println!(
+                            "Button {} down (Playback Mode, no file). Sending START.",
+                            key
+                        );
+                        self.start_recording(key, device).await?;
+                    }
+                }
+            }
+            Mode::Edit => {
+                if let Some(path) = self.button_files.get(&key) {
+                    // Empty keys with old takes can be selected to browse them
+                    if path.exists() || self.has_history(path) {
+                        self.close_take_browser();
+                        if let Some(prev_selected_key) = self.selected_for_delete {
+                            // A key is already selected
+                            if prev_selected_key == key {
+                                // This key was already selected. Toggle it OFF.
+                                println!("Button {} down (Edit Mode). Deselecting {}.", key, key);
+                                self.selected_for_delete = None;
+                                set_key_image(device, key, self.key_image(key)).await?;
                             } else {
                                 // A different key was selected. Deselect old, select new.
                                 println!(
                                     "Button {} down (Edit Mode). Deselecting old key {}.",
                                     key, prev_selected_key
                                 );
-                                device
-                                    .set_button_image(prev_selected_key, self.img_play.clone())
-                                    .await
-                                    .unwrap();
-                                println!("...Selecting new key {}.", key);
-                                device
-                                    .set_button_image(key, self.img_rec_on.clone())
-                                    .await
-                                    .unwrap();
                                 self.selected_for_delete = Some(key);
+                                set_key_image(
+                                    device,
+                                    prev_selected_key,
+                                    self.key_image(prev_selected_key),
+                                )
+                                .await?;
+                                println!("...Selecting new key {}.", key);
+                                set_key_image(device, key, self.img_rec_on.clone()).await?;
                             }
                         } else {
                             // Nothing was selected. Select this key.
@@ -221,135 +1969,855 @@ impl AppState {
                                 "Button {} down (Edit Mode). Selecting key {} for deletion.",
                                 key, key
                             );
-                            device
-                                .set_button_image(key, self.img_rec_on.clone())
-                                .await
-                                .unwrap();
+                            set_key_image(device, key, self.img_rec_on.clone()).await?;
                             self.selected_for_delete = Some(key);
                         }
-                        device.flush().await.unwrap();
+                        flush_device(device).await?;
                     } else {
                         println!("Button {} down (Edit Mode, no file). No action.", key);
                     }
                 }
             }
+            Mode::Looper => {
+                if let Some(looper) = &self.looper
+                    && let Err(reason) = looper.press(key)
+                {
+                    println!("Button {} down (Looper Mode). Refused: {}", key, reason);
+                    flash_key_error(device, key, self.key_image(key));
+                }
+            }
+            Mode::Pattern => {
+                if let Some(step) = self.key_step(key) {
+                    let on = !self.pattern.track(self.pattern_track)[step];
+                    println!(
+                        "Button {} down (Pattern Mode). Step {} of track {} {}.",
+                        key,
+                        step + 1,
+                        key_name(self.pattern_track),
+                        if on { "on" } else { "off" }
+                    );
+                    self.pattern.set_step(self.pattern_track, step, on);
+                    self.save_pattern()?;
+                    set_key_image(device, key, self.key_image(key)).await?;
+                    flush_device(device).await?;
+                }
+            }
         }
+        Ok(())
     }
 
-    async fn handle_button_up(&mut self, key: u8, device: &AsyncStreamDeck) {
+    async fn handle_button_up(&mut self, key: u8, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        if self.voice_key_open(key).is_some() {
+            return self.set_voice_key(key, false, device).await;
+        }
+        if self.key_macro(key).is_some() {
+            // Macros run on ButtonDown
+            return Ok(());
+        }
         match self.mode {
             Mode::Playback => {
-                if self.active_recording_key == Some(key) {
+                if self.is_recording(key) {
                     println!(
                         "Button {} up (Playback Mode, was recording), sending STOP",
                         key
                     );
+                    self.stop_recording(key, device).await?;
+                } else if self.has_sample(key) {
+                    println!("Button {} up (Playback Mode). Triggering playback.", key);
+                    self.play_key(key, device).await?;
+                }
+            }
+            Mode::Edit => {
+                // ButtonUp does nothing in Edit mode
+            }
+            Mode::Looper => {
+                if let Some(looper) = &self.looper {
+                    looper.release(key);
+                }
+            }
+            Mode::Pattern => {
+                // Steps toggle on ButtonDown
+            }
+        }
+        Ok(())
+    }
 
-                    if let Err(e) = self.audio_cmd_tx.send(AudioCommand::Stop) {
-                        eprintln!("Failed to send STOP command: {}", e);
-                    } else {
-                        println!("...STOP sent.");
-                    }
+    /// The pattern step `key` stands for on the current page, if the
+    /// pattern has that many steps.
+    fn key_step(&self, key: u8) -> Option<usize> {
+        let step = self.pattern_page * KEY_COUNT as usize + key as usize;
+        (step < self.pattern.steps).then_some(step)
+    }
 
-                    self.active_recording_key = None;
-                    device
-                        .set_button_image(key, self.img_play.clone())
-                        .await
-                        .unwrap();
-                    device.flush().await.unwrap();
-                } else if let Some(path) = self.button_files.get(&key) {
-                    if path.exists() {
-                        println!("Button {} up (Playback Mode). Triggering playback.", key);
-
-                        let pitch_shift =
-                            self.pitch_shift_semitones.get(&key).cloned().unwrap_or(0.0);
-                        let path_clone = path.clone();
-                        let sink_clone = self.playback_sink;
-                        let volume_clone = self.playback_volume.get(&key).cloned().unwrap_or(1.0);
-
-                        // This task will create a temp file if needed, play it,
-                        // and then clean up the temp file.
-                        tokio::spawn(async move {
-                            let mut temp_path: Option<PathBuf> = None;
-                            // 1. Check if we need to apply pitch shift
-                            // We use an epsilon (0.01) to avoid floating point issues
-                            let path_to_play = if pitch_shift.abs() > 0.01 {
-                                println!("...Applying pitch shift: {:.2} semitones", pitch_shift);
-
-                                let path_for_blocking = path_clone.clone();
-                                // 2. Run the synchronous file I/O in a blocking thread
-                                // This prevents blocking the main async runtime
-                                match tokio::task::spawn_blocking(move || {
-                                    audio_processor::create_pitched_copy_sync(
-                                        &path_for_blocking,
-                                        pitch_shift,
-                                    )
-                                })
-                                .await
-                                {
-                                    Ok(Ok(new_path)) => {
-                                        // Successfully created temp file
-                                        temp_path = Some(new_path.clone());
-                                        new_path
-                                    }
-                                    Ok(Err(e)) => {
-                                        // Failed to create, play original
-                                        eprintln!(
-                                            "Failed to create pitched copy: {}. Playing original.",
-                                            e
-                                        );
-
-                                        path_clone
-                                    }
-                                    Err(e) => {
-                                        // Task itself failed, play original
-                                        eprintln!(
-                                            "Task join error for pitched copy: {}. Playing original.",
-                                            e
-                                        );
-
-                                        path_clone
-                                    }
-                                }
-                            } else {
-                                // No pitch shift, play original
-                                path_clone
-                            };
-                            // 3. Play the chosen file (original or temp)
-                            if let Err(e) =
-                                play_audio_file(&path_to_play, sink_clone, volume_clone).await
-                            {
-                                eprintln!("Playback failed: {}", e);
-                            }
-                            // 4. Clean up the temp file if one was created
-                            if let Some(p) = temp_path {
-                                if let Err(e) = tokio_fs::remove_file(&p).await {
-                                    eprintln!(
-                                        "Failed to clean up temp file {}: {}",
-                                        p.display(),
-                                        e
-                                    );
-                                } else {
-                                    println!("Cleaned up temp file: {}", p.display());
-                                }
-                            }
-                        });
-                        // Set image back to "play" immediately
-                        device
-                            .set_button_image(key, self.img_play.clone())
-                            .await
-                            .unwrap();
-                        device.flush().await.unwrap();
+    /// What the LCD strip shows in Pattern mode.
+    fn pattern_status(&self) -> String {
+        let playing = self.sequencer.as_ref().is_some_and(Sequencer::is_playing);
+        let first = self.pattern_page * KEY_COUNT as usize;
+        let last = (first + KEY_COUNT as usize).min(self.pattern.steps);
+        format!(
+            "{} {:.1} BPM  SWING {:.0}%  TRACK {}  STEPS {}-{}/{}",
+            if playing { "PLAYING" } else { "STOPPED" },
+            self.pattern.bpm,
+            self.pattern.swing * 100.0,
+            key_name(self.pattern_track),
+            first + 1,
+            last,
+            self.pattern.steps
+        )
+    }
+
+    /// Hands the bank's pattern and samples to the sequencer, and picks a
+    /// track with a sample if the selected one has none.
+    fn load_pattern(&mut self) {
+        let Some(sequencer) = self.sequencer.clone() else {
+            return;
+        };
+        if !self.has_sample(self.pattern_track)
+            && let Some(key) = (0..KEY_COUNT).find(|key| self.has_sample(*key))
+        {
+            self.pattern_track = key;
+        }
+        if self.key_step(0).is_none() {
+            self.pattern_page = 0;
+        }
+        sequencer.set_pattern(&self.pattern);
+
+        // Decoding is slow enough to keep off the runtime
+        let keys: Vec<(u8, PathBuf, f64, f64)> = (0..KEY_COUNT)
+            .filter(|key| self.has_sample(*key))
+            .filter_map(|key| {
+                let path = self.button_files.get(&key)?.clone();
+                if !is_wav_file(&path) {
+                    println!(
+                        "Key {} does not play a WAV file, so the sequencer leaves it out.",
+                        key
+                    );
+                    return None;
+                }
+                Some((key, path, self.key_volume(key), self.key_pitch(key)))
+            })
+            .collect();
+        tokio::task::spawn_blocking(move || {
+            let mut samples = HashMap::new();
+            for (key, path, volume, pitch) in keys {
+                match decode_wav_stereo(&path, SEQUENCER_SAMPLE_RATE, pitch) {
+                    Ok(decoded) => {
+                        samples.insert(
+                            key,
+                            TrackSample {
+                                samples: decoded,
+                                gain: volume as f32,
+                            },
+                        );
                     }
+                    Err(e) => eprintln!("Failed to load key {} for the sequencer: {}", key, e),
                 }
             }
-            Mode::Edit => {
-                // ButtonUp does nothing in Edit mode
+            println!("Loaded {} track(s) for the sequencer.", samples.len());
+            sequencer.set_samples(samples);
+        });
+    }
+
+    /// Saves the pattern with the bank and hands it to the sequencer.
+    fn save_pattern(&self) -> Result<()> {
+        if let Some(sequencer) = &self.sequencer {
+            sequencer.set_pattern(&self.pattern);
+        }
+        let mut manifest = load_bank_manifest(&self.storage_path, self.bank)?;
+        manifest.pattern = (self.pattern != Pattern::default()).then(|| self.pattern.clone());
+        save_bank_manifest(&self.storage_path, self.bank, &manifest)
+    }
+
+    /// Twisting a dial in Pattern mode: dial 1 sets the tempo, dial 2 the
+    /// swing and dial 3 picks the track among the keys with samples.
+    async fn adjust_pattern(
+        &mut self,
+        dial: u8,
+        ticks: i32,
+        fine: bool,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        match dial {
+            1 => {
+                let step = if fine { BPM_STEP_FINE } else { BPM_STEP };
+                self.pattern.bpm = (self.pattern.bpm + ticks as f64 * step).clamp(MIN_BPM, MAX_BPM);
+                println!("Pattern tempo set to {:.1} BPM", self.pattern.bpm);
+                self.save_pattern()?;
+            }
+            2 => {
+                let step = if fine { SWING_STEP_FINE } else { SWING_STEP };
+                self.pattern.swing =
+                    (self.pattern.swing + ticks as f64 * step).clamp(0.0, MAX_SWING);
+                println!("Pattern swing set to {:.0}%", self.pattern.swing * 100.0);
+                self.save_pattern()?;
+            }
+            3 => {
+                let tracks: Vec<u8> = (0..KEY_COUNT).filter(|key| self.has_sample(*key)).collect();
+                if tracks.is_empty() {
+                    println!("Dial 3 turned in Pattern mode, but no key has a sample.");
+                    return Ok(());
+                }
+                let current = tracks
+                    .iter()
+                    .position(|key| *key == self.pattern_track)
+                    .unwrap_or(0);
+                let index = (current as i64 + ticks as i64).rem_euclid(tracks.len() as i64);
+                self.pattern_track = tracks[index as usize];
+                println!("Pattern track set to key {}.", self.pattern_track);
+                self.redraw_keys(device).await?;
+            }
+            _ => return Ok(()),
+        }
+        self.update_lcd_status(device).await?;
+        flush_device(device).await
+    }
+
+    /// Pressing a dial in Pattern mode: dial 1 starts and stops the
+    /// pattern, dial 2 flips between the pages of a 16-step pattern and
+    /// dial 3 switches between 8 and 16 steps.
+    async fn press_pattern_dial(
+        &mut self,
+        dial: u8,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        match dial {
+            1 => {
+                let Some(sequencer) = &self.sequencer else {
+                    return Ok(());
+                };
+                let playing = !sequencer.is_playing();
+                sequencer.set_playing(playing);
+                println!("Pattern {}.", if playing { "started" } else { "stopped" });
+            }
+            2 => {
+                let pages = self.pattern.steps.div_ceil(KEY_COUNT as usize).max(1);
+                self.pattern_page = (self.pattern_page + 1) % pages;
+                println!(
+                    "Showing pattern page {} of {}.",
+                    self.pattern_page + 1,
+                    pages
+                );
+                self.redraw_keys(device).await?;
+            }
+            3 => {
+                let current = PATTERN_STEP_COUNTS
+                    .iter()
+                    .position(|steps| *steps == self.pattern.steps)
+                    .unwrap_or(0);
+                self.pattern.steps = PATTERN_STEP_COUNTS[(current + 1) % PATTERN_STEP_COUNTS.len()];
+                if self.key_step(0).is_none() {
+                    self.pattern_page = 0;
+                }
+                println!("Pattern set to {} steps.", self.pattern.steps);
+                self.save_pattern()?;
+                self.redraw_keys(device).await?;
             }
+            _ => return Ok(()),
         }
+        self.update_lcd_status(device).await?;
+        flush_device(device).await
+    }
+
+    /// Lights the step the sequencer has moved on to, and puts back the
+    /// one it left.
+    async fn show_step(
+        &mut self,
+        step: Option<usize>,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let previous = std::mem::replace(&mut self.shown_step, step);
+        if self.mode != Mode::Pattern || previous == step {
+            return Ok(());
+        }
+        let page_start = self.pattern_page * KEY_COUNT as usize;
+        for step in [previous, step].into_iter().flatten() {
+            if let Some(key) = step
+                .checked_sub(page_start)
+                .filter(|key| *key < KEY_COUNT as usize)
+            {
+                set_key_image(device, key as u8, self.key_image(key as u8)).await?;
+            }
+        }
+        flush_device(device).await
+    }
+
+    /// Whether the passthrough microphone is on, if `key` is its key.
+    fn voice_key_open(&self, key: u8) -> Option<bool> {
+        let voice_tx = self.voice_tx.as_ref()?;
+        (self.voice_key == Some(key)).then(|| voice_tx.borrow().open)
+    }
+
+    /// Handles the microphone key going down or up: push-to-talk follows
+    /// the key, while toggling flips on each press.
+    async fn set_voice_key(
+        &self,
+        key: u8,
+        down: bool,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let Some(voice_tx) = &self.voice_tx else {
+            return Ok(());
+        };
+        let changed = voice_tx.send_if_modified(|control| {
+            let open = match self.voice_key_mode {
+                TalkMode::PushToTalk => down,
+                TalkMode::Toggle if down => !control.open,
+                TalkMode::Toggle => control.open,
+            };
+            let changed = open != control.open;
+            control.open = open;
+            changed
+        });
+        if !changed {
+            return Ok(());
+        }
+        let open = voice_tx.borrow().open;
+        println!("Microphone {}.", if open { "on" } else { "muted" });
+        set_key_image(device, key, self.key_image(key)).await?;
+        flush_device(device).await
+    }
+
+    /// Changes the strength of the voice effect by `ticks` dial steps.
+    fn adjust_voice_amount(&self, ticks: i32, fine: bool) {
+        let Some(voice_tx) = &self.voice_tx else {
+            return;
+        };
+        let step = if fine {
+            VOICE_AMOUNT_STEP_FINE
+        } else {
+            VOICE_AMOUNT_STEP
+        };
+        voice_tx.send_modify(|control| {
+            control.amount = (control.amount + ticks as f64 * step).clamp(0.0, 1.0);
+            println!(
+                "Voice effect {:?} at {:.0}%",
+                control.effect,
+                control.amount * 100.0
+            );
+        });
+    }
+
+    fn cycle_voice_effect(&self) {
+        let Some(voice_tx) = &self.voice_tx else {
+            return;
+        };
+        voice_tx.send_modify(|control| {
+            control.effect = control.effect.next();
+            println!("Voice effect set to: {:?}", control.effect);
+        });
+    }
+
+    /// Whether `key` has a file to play.
+    fn has_sample(&self, key: u8) -> bool {
+        self.button_files
+            .get(&key)
+            .is_some_and(|path| path.exists())
+    }
+
+    /// Starts recording `key` into its file. The key lights up straight
+    /// away and is put back by `handle_audio_response` if the audio thread
+    /// refuses.
+    async fn start_recording(&mut self, key: u8, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(path) = self.button_files.get(&key).cloned() else {
+            return Ok(());
+        };
+        let health = *self.health_rx.borrow();
+        if !health.is_running() {
+            return Err(Error::audio("starting recording", health.to_string()));
+        }
+
+        // This is a sync send, but it's non-blocking (just drops the
+        // command in a queue) so it's fine in async.
+        self.send_audio_command(key, AudioCommand::Start(path.clone()))?;
+        // Undone in `handle_audio_response` if the audio thread refuses to
+        // start.
+        self.recording_keys.push((key, path));
+        set_key_image(device, key, self.img_rec_on.clone()).await?;
+        flush_device(device).await?;
+        println!("...START sent.");
+        Ok(())
+    }
+
+    /// Stops the recording on `key`, if there is one. The key stays lit
+    /// until the take is saved.
+    async fn stop_recording(&mut self, key: u8, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        let Some(path) = self.take_recording(key) else {
+            return Ok(());
+        };
+        self.send_audio_command(key, AudioCommand::Stop(path))?;
+        println!("...STOP sent.");
+
+        set_key_image(device, key, self.img_rec_on.clone()).await?;
+        if self.recording_keys.is_empty() {
+            // Replace the live meter with the mode image again
+            self.update_lcd_status(device).await?;
+        }
+        flush_device(device).await
+    }
+
+    /// Plays the sample on `key` with its volume and pitch, on the next
+    /// beat if quantizing to a MIDI clock. Playback runs in the background;
+    /// the key flashes if it fails.
+    async fn play_key(&mut self, key: u8, device: Option<&AsyncStreamDeck>) -> Result<()> {
+        self.play_key_adjusted(key, 0.0, 1.0, device).await
+    }
+
+    /// Plays `key` moved by `pitch` semitones and with its volume scaled by
+    /// `volume`, for this playback only.
+    async fn play_key_adjusted(
+        &mut self,
+        key: u8,
+        pitch: f64,
+        volume: f64,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        let Some(path) = self.button_files.get(&key).filter(|path| path.exists()) else {
+            return Ok(());
+        };
+        let path_clone = path.clone();
+        let pitch_shift = self.key_pitch(key) + pitch;
+        let route_names = self.key_route_names(key).to_vec();
+        let routes = self.routes.clone();
+        // A MIDI note plays at the volume of its velocity
+        let velocity = self.key_velocity.remove(&key).unwrap_or(1.0);
+        let volume_clone = self.key_volume(key) * velocity * volume;
+        let device_clone = device.cloned();
+        let img_play = self.key_image(key);
+        let note = key_to_note(&self.midi, key);
+        let note_velocity = (velocity * MIDI_MAX as f64).round().max(1.0) as u8;
+        let midi_out_tx = self.midi_out_tx.clone();
+        // Following a MIDI clock, the sample waits for the beat
+        let delay = self
+            .midi_clock
+            .delay_until_next(self.midi.quantize, self.midi.beats_per_bar);
+        let quantize = self.midi.quantize;
+        let slot = self.slot(key);
+        *self.playing.entry(slot).or_default() += 1;
+        let playback_done_tx = self.playback_done_tx.clone();
+        let voices = self.voices.clone();
+
+        tokio::spawn(async move {
+            if let Some(delay) = delay.filter(|delay| !delay.is_zero()) {
+                println!("...Waiting {:?} for the next {:?}.", delay, quantize);
+                tokio::time::sleep(delay).await;
+            }
+            let send = |message| {
+                if let Some(tx) = &midi_out_tx {
+                    let _ = tx.send(message);
+                }
+            };
+            send(MidiMessage::NoteOn {
+                note,
+                velocity: note_velocity,
+            });
+            let routes = resolve_routes(&route_names, &routes).await;
+            let voice = voices.start();
+            let result = play_sample(&path_clone, &routes, volume_clone, pitch_shift).await;
+            drop(voice);
+            send(MidiMessage::NoteOff { note });
+            if let Some(tx) = playback_done_tx {
+                let _ = tx.send(slot);
+            }
+            if let Err(e) = result {
+                eprintln!("Playback failed: {}", e);
+                flash_key_error(device_clone.as_ref(), key, img_play);
+            }
+        });
+        // Set image back to "play" immediately
+        set_key_image(device, key, self.key_image(key)).await?;
+        flush_device(device).await
+    }
+
+    /// Forgets one playback on `slot` once it has finished.
+    fn finish_playback(&mut self, slot: KeySlot) {
+        if let Some(count) = self.playing.get_mut(&slot) {
+            *count -= 1;
+            if *count == 0 {
+                self.playing.remove(&slot);
+            }
+        }
+    }
+
+    /// Carries out a command from a remote control. Keys play and record
+    /// in either mode, as the command says exactly what to do.
+    async fn handle_remote_command(
+        &mut self,
+        command: RemoteCommand,
+        device: Option<&AsyncStreamDeck>,
+    ) -> Result<()> {
+        match command {
+            RemoteCommand::Trigger { key } => {
+                if !self.has_sample(key) {
+                    println!("Remote trigger for key {}, which has no sample.", key);
+                    return Ok(());
+                }
+                println!("Remote trigger for key {}. Triggering playback.", key);
+                self.run_action(Action::play(key), device).await
+            }
+            RemoteCommand::SetVolume { key, volume } => {
+                self.set_key_volume(key, volume);
+                Ok(())
+            }
+            RemoteCommand::SetPitch { key, pitch } => {
+                self.set_key_pitch(key, pitch);
+                Ok(())
+            }
+            RemoteCommand::SetLabel { key, label } => self.set_key_label(key, label, device).await,
+            RemoteCommand::Assign { key, path } => self.assign_file(key, &path, device).await,
+            RemoteCommand::Clear { key } => {
+                if self.is_recording(key) {
+                    return Ok(());
+                }
+                println!("Remote clear for key {}.", key);
+                if self.selected_for_delete == Some(key) {
+                    self.selected_for_delete = None;
+                    self.close_take_browser();
+                }
+                self.delete_key(key, device).await
+            }
+            RemoteCommand::SetMode(mode) => self.run_action(Action::SetMode(mode), device).await,
+            RemoteCommand::SetRoute { name, enabled } => {
+                self.run_action(Action::SetRoute { name, enabled }, device)
+                    .await
+            }
+            RemoteCommand::RunMacro { name } => {
+                self.run_action(Action::RunMacro(name), device).await
+            }
+            RemoteCommand::CancelMacro { name } => {
+                self.run_action(Action::CancelMacro(name), device).await
+            }
+            RemoteCommand::SetKeyRoutes { key, routes } => self.set_key_routes(key, routes),
+            RemoteCommand::StartRecording { key } => {
+                if self.is_recording(key) {
+                    return Ok(());
+                }
+                // Like the deck, only empty keys record, so nothing is
+                // overwritten from afar.
+                if self.has_sample(key) {
+                    println!(
+                        "Remote recording for key {}, which already has a sample.",
+                        key
+                    );
+                    return Ok(());
+                }
+                println!("Remote recording for key {}. Sending START.", key);
+                self.start_recording(key, device).await
+            }
+            RemoteCommand::StopRecording { key } => {
+                if self.is_recording(key) {
+                    println!("Remote stop for key {}, sending STOP", key);
+                }
+                self.stop_recording(key, device).await
+            }
+        }
+    }
+
+    /// The deck as remote controls see it.
+    fn board_state(&self) -> BoardState {
+        BoardState {
+            mode: self.mode,
+            routes: self.route_states(),
+            macros: self
+                .macros
+                .iter()
+                .map(|config| MacroState {
+                    name: config.name.clone(),
+                    running: self.running_macros.contains_key(&config.name),
+                })
+                .collect(),
+            bank: self.bank,
+            keys: (0..KEY_COUNT)
+                .map(|key| KeyState {
+                    has_sample: self.has_sample(key),
+                    playing: self.playing.contains_key(&self.slot(key)),
+                    recording: self.is_recording(key),
+                    volume: self.key_volume(key),
+                    pitch: self.key_pitch(key),
+                    label: self.key_labels.get(&key).cloned(),
+                    icon: self.key_icons.get(&key).cloned(),
+                    routes: self.key_routes.get(&key).cloned(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The configured routes, then any other enabled ones, e.g. nodes
+    /// picked in the web panel.
+    fn route_states(&self) -> Vec<RouteState> {
+        let configured = self.routes.iter().map(|route| &route.name);
+        let others = self
+            .enabled_routes
+            .iter()
+            .filter(|name| !self.routes.iter().any(|route| &route.name == *name));
+        configured
+            .chain(others)
+            .map(|name| RouteState {
+                name: name.clone(),
+                enabled: self.enabled_routes.contains(name),
+            })
+            .collect()
+    }
+
+    /// Publishes the deck's state for remote controls if it changed.
+    fn publish_state(&self) {
+        if !self.roles.borrow().remote {
+            return;
+        }
+        let state = self.board_state();
+        self.board_tx.send_if_modified(|current| {
+            if current.as_ref() == Some(&state) {
+                return false;
+            }
+            *current = Some(state);
+            true
+        });
     }
 }
 
+/// Returns which dial sits under touch strip position `x`. The strip is
+/// divided into equal panels, one above each dial. Without a deck, as for
+/// hotkeys on a board with none connected, it is the first dial.
+fn dial_under_touch(device: Option<&AsyncStreamDeck>, x: u16) -> u8 {
+    let Some(device) = device else {
+        return 0;
+    };
+    let kind = device.kind();
+    let dials = kind.encoder_count().max(1);
+    let width = kind.lcd_strip_size().map(|(w, _)| w).unwrap_or(800).max(1);
+    let dial = (x as usize * dials as usize / width) as u8;
+    dial.min(dials - 1)
+}
+
+/// Formats how long ago something happened, e.g. `45S`, `3M`, `2H` or `4D`.
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{}S", secs),
+        60..3600 => format!("{}M", secs / 60),
+        3600..86400 => format!("{}H", secs / 3600),
+        _ => format!("{}D", secs / 86400),
+    }
+}
+
+/// Resolves on Ctrl+C, or on SIGTERM when running as a service.
+async fn wait_for_shutdown_signal() {
+    let mut terminate =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                eprintln!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Resolves once shutdown has been requested through `shutdown_rx`.
+async fn shutdown_requested(shutdown_rx: &mut watch::Receiver<bool>) {
+    let _ = shutdown_rx.wait_for(|&shutdown| shutdown).await;
+}
+
+/// Resolves when the capture health changes. Never resolves once the
+/// capture supervisor is gone, so a closed channel does not spin the loop.
+async fn capture_health_changed(health_rx: &mut watch::Receiver<CaptureHealth>) {
+    if health_rx.changed().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Reads the next input from a deck. Never resolves without one, as in
+/// the board's own loop.
+async fn read_deck(
+    reader: Option<&AsyncDeviceStateReader>,
+) -> std::result::Result<Vec<DeviceStateUpdate>, StreamDeckError> {
+    let Some(reader) = reader else {
+        return std::future::pending().await;
+    };
+    reader.read(100.0).await
+}
+
+/// Resolves when the supervisor hands the loop different roles. Never
+/// resolves once the supervisor is gone.
+async fn roles_changed(roles: &mut watch::Receiver<Roles>) {
+    if roles.changed().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Receives the next event shared by every deck. Never resolves without a
+/// receiver, while the loop does not have the role for it.
+async fn next_event<T: Clone>(
+    rx: &mut Option<broadcast::Receiver<T>>,
+) -> std::result::Result<T, RecvError> {
+    let Some(rx) = rx else {
+        return std::future::pending().await;
+    };
+    rx.recv().await
+}
+
+/// Resolves when the loops change. Never resolves without a looper.
+async fn looper_changed(looper_rx: &mut Option<watch::Receiver<u64>>) {
+    let Some(looper_rx) = looper_rx else {
+        return std::future::pending().await;
+    };
+    if looper_rx.changed().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Resolves with the sequencer's new step when it moves on. Never
+/// resolves without a sequencer.
+async fn sequencer_step_changed(
+    step_rx: &mut Option<watch::Receiver<Option<usize>>>,
+) -> Option<usize> {
+    let Some(step_rx) = step_rx else {
+        return std::future::pending().await;
+    };
+    if step_rx.changed().await.is_err() {
+        return std::future::pending().await;
+    }
+    *step_rx.borrow_and_update()
+}
+
+/// Keeps every connected Stream Deck attached for as long as the process
+/// runs, each driven by its own task and `AppState`.
+///
+/// HID has no hotplug notifications we can rely on here, so the device
+/// list is rescanned every `DEVICE_POLL_INTERVAL`. Decks that show up are
+/// connected; decks that go away have their state parked by serial number
+/// so it is restored when they come back. The audio side keeps running
+/// throughout, and so does the board's own loop, which takes the roles in
+/// `role_decks` while no deck does.
+async fn run_device_supervisor(
+    template: AppState,
+    decks: HashMap<String, DeckConfig>,
+    role_decks: RoleDecks,
+    shutdown_rx: watch::Receiver<bool>,
+) {
+    let mut hid = match new_hidapi() {
+        Ok(hid) => hid,
+        Err(e) => {
+            eprintln!("Failed to create HidApi instance: {}", e);
+            return;
+        }
+    };
+    // Serial numbers of the connected decks, in the order they were attached
+    let mut attached: Vec<String> = Vec::new();
+    let (board_roles_tx, board_roles_rx) = watch::channel(role_decks.roles_for(None, &attached));
+    let mut board = template.for_board(board_roles_rx);
+    let mut board_shutdown_rx = shutdown_rx.clone();
+    let board_task = tokio::spawn(async move {
+        board.run_device(None, &mut board_shutdown_rx).await;
+    });
+
+    let mut running: HashMap<String, JoinHandle<(AppState, DeviceExit)>> = HashMap::new();
+    let mut roles_txs: HashMap<String, watch::Sender<Roles>> = HashMap::new();
+    let mut parked: HashMap<String, AppState> = HashMap::new();
+    let mut waiting_logged = false;
+    let mut shutdown = shutdown_rx.clone();
+    loop {
+        // Collect decks whose event loop has ended since the last scan.
+        let finished: Vec<String> = running
+            .iter()
+            .filter(|(_, handle)| handle.is_finished())
+            .map(|(serial, _)| serial.clone())
+            .collect();
+        for serial in finished {
+            let Some(handle) = running.remove(&serial) else {
+                continue;
+            };
+            attached.retain(|attached| *attached != serial);
+            roles_txs.remove(&serial);
+            match handle.await {
+                Ok((state, DeviceExit::Disconnected)) => {
+                    println!("Stream Deck {} disconnected.", serial);
+                    parked.insert(serial, state);
+                }
+                Ok((_, DeviceExit::Shutdown)) => {}
+                Err(e) => eprintln!("Task for Stream Deck {} failed: {}", serial, e),
+            }
+        }
+
+        if let Err(e) = refresh_device_list(&mut hid) {
+            eprintln!("Failed to refresh HID device list: {}", e);
+        }
+        for (kind, serial) in list_devices(&hid) {
+            if running.contains_key(&serial) {
+                continue;
+            }
+            println!(
+                "Found Stream Deck: {:?} {} {}",
+                kind,
+                serial,
+                kind.product_id()
+            );
+            match AsyncStreamDeck::connect(&hid, kind, &serial) {
+                Ok(device) => {
+                    let mut state = match parked.remove(&serial) {
+                        Some(state) => state,
+                        None => template.for_deck(&serial, decks.get(&serial)),
+                    };
+                    attached.push(serial.clone());
+                    let (roles_tx, roles_rx) =
+                        watch::channel(role_decks.roles_for(Some(&serial), &attached));
+                    state.roles = roles_rx;
+                    roles_txs.insert(serial.clone(), roles_tx);
+                    let mut shutdown_rx = shutdown_rx.clone();
+                    let handle = tokio::spawn(async move {
+                        let exit = state.run_device(Some(&device), &mut shutdown_rx).await;
+                        (state, exit)
+                    });
+                    running.insert(serial, handle);
+                }
+                Err(e) => eprintln!("Failed to connect to Stream Deck {}: {}", serial, e),
+            }
+        }
+
+        // Roles move on as decks come and go
+        for (serial, roles_tx) in &roles_txs {
+            update_roles(roles_tx, role_decks.roles_for(Some(serial), &attached));
+        }
+        update_roles(&board_roles_tx, role_decks.roles_for(None, &attached));
+
+        if running.is_empty() {
+            if !waiting_logged {
+                println!("No Stream Deck connected. Waiting for one to be plugged in...");
+                waiting_logged = true;
+            }
+        } else {
+            waiting_logged = false;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(DEVICE_POLL_INTERVAL) => {}
+            _ = shutdown_requested(&mut shutdown) => break,
+        }
+    }
+
+    // Let every deck clear its keys before the process exits.
+    for (serial, handle) in running {
+        if let Err(e) = handle.await {
+            eprintln!("Task for Stream Deck {} failed: {}", serial, e);
+        }
+    }
+    if let Err(e) = board_task.await {
+        eprintln!("Task for the board failed: {}", e);
+    }
+}
+
+/// Hands a loop `roles`, waking it only if they changed.
+fn update_roles(roles_tx: &watch::Sender<Roles>, roles: Roles) {
+    roles_tx.send_if_modified(|current| {
+        let changed = *current != roles;
+        *current = roles;
+        changed
+    });
+}
+
 #[tokio::main]
 async fn main() {
     let audio_storage_path = match get_audio_storage_path() {
@@ -360,124 +2828,377 @@ async fn main() {
         }
     };
 
+    // Subcommands do their job and exit without touching the deck
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args, &audio_storage_path).await);
+    }
+
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load config, using defaults: {}", e);
+            Config::default()
+        }
+    };
+
     let (audio_tx, audio_rx) = mpsc::channel();
+    let (level_tx, level_rx) = watch::channel(TakeLevels::new());
+    let (health_tx, health_rx) = watch::channel(CaptureHealth::default());
 
-    // This thread will block on the pipewire mainloop, which is perfect.
+    // The supervisor blocks while the capture stream runs, restarting it
+    // whenever it fails.
+    let capture_storage_path = audio_storage_path.clone();
+    let capture_history = config.history.clone();
     std::thread::spawn(move || {
         println!("Audio capture thread started...");
-        if let Err(e) = audio_capture::run_capture_loop(audio_rx) {
-            eprintln!("Audio capture thread failed: {}", e);
-        } else {
-            println!("Audio capture thread exited cleanly.");
+        audio_capture::run_capture_supervisor(
+            audio_rx,
+            level_tx,
+            health_tx,
+            capture_storage_path,
+            capture_history,
+        );
+        println!("Audio capture thread exited.");
+    });
+
+    // Each deck starts from a copy of this state. A deck's state outlives
+    // its connection, so the mode, bank and per-key settings survive it
+    // being unplugged and plugged back in.
+    let mut app_state = AppState::new(
+        &config,
+        audio_storage_path.clone(),
+        audio_tx,
+        level_rx,
+        health_rx,
+    );
+
+    if config.virtual_mic.enabled {
+        tokio::spawn(virtual_mic::run_virtual_mic(config.virtual_mic.clone()));
+    }
+    app_state.voice_tx = config.voice.enabled.then(|| {
+        if config.virtual_mic.loopback {
+            println!(
+                "Both the mic loopback and the mic passthrough are on; the mic is heard twice."
+            );
         }
+        let (voice_tx, voice_rx) = watch::channel(VoiceControl::from_config(&config.voice));
+        let voice_config = config.voice.clone();
+        let target = config.virtual_mic.mixer_target().to_string();
+        std::thread::spawn(move || {
+            println!("Mic passthrough thread started...");
+            voice::run_voice_passthrough(voice_config, target, voice_rx);
+        });
+        voice_tx
     });
 
-    let img_rec_off =
-        open("assets/rec_off.png").unwrap_or_else(|_| create_fallback_image(Rgb([80, 80, 80])));
-    let img_rec_on =
-        open("assets/rec_on.png").unwrap_or_else(|_| create_fallback_image(Rgb([255, 0, 0])));
-    let img_play =
-        open("assets/play.png").unwrap_or_else(|_| create_fallback_image(Rgb([0, 255, 0])));
-    let img_lcd_playback = open("assets/lcd_strip.png")
-        .unwrap_or_else(|_| create_fallback_lcd_image(Rgb([10, 50, 10])));
-    let img_lcd_edit = open("assets/lcd_edit.png")
-        .unwrap_or_else(|_| create_fallback_lcd_image(Rgb([50, 10, 10])));
-
-    match new_hidapi() {
-        Ok(hid) => {
-            for (kind, serial) in list_devices(&hid) {
-                println!(
-                    "Found Stream Deck: {:?} {} {}",
-                    kind,
-                    serial,
-                    kind.product_id()
-                );
-                let device =
-                    AsyncStreamDeck::connect(&hid, kind, &serial).expect("Failed to connect");
-                device.set_brightness(50).await.unwrap();
-                device.clear_all_button_images().await.unwrap();
-
-                let mut app_state = AppState {
-                    mode: Mode::Playback,
-                    playback_sink: PlaybackSink::Default,
-                    playback_volume: HashMap::new(),
-                    button_files: HashMap::new(),
-                    active_recording_key: None,
-                    selected_for_delete: None,
-                    pitch_shift_semitones: HashMap::new(),
-                    img_rec_off: img_rec_off.clone(),
-                    img_rec_on: img_rec_on.clone(),
-                    img_play: img_play.clone(),
-                    img_lcd_playback: img_lcd_playback.clone(),
-                    img_lcd_edit: img_lcd_edit.clone(),
-
-                    audio_cmd_tx: audio_tx.clone(),
-                };
+    app_state.looper = config.looper.enabled.then(|| {
+        let looper = Looper::new(&config.looper);
+        let looper_config = config.looper.clone();
+        let engine = looper.clone();
+        std::thread::spawn(move || {
+            println!("Looper thread started...");
+            looper::run_looper(looper_config, engine);
+        });
+        looper
+    });
 
-                println!("Starting in {:?} mode.", app_state.mode);
-                println!("Playback sink set to: {:?}", app_state.playback_sink);
-                update_lcd_mode(
-                    &device,
-                    app_state.mode,
-                    &app_state.img_lcd_playback,
-                    &app_state.img_lcd_edit,
-                )
-                .await;
-
-                for i in 0..8 {
-                    let file_name = format!("recording_{}.wav", (b'A' + i) as char);
-                    let mut file_path = audio_storage_path.clone();
-                    file_path.push(file_name);
-                    app_state.button_files.insert(i, file_path);
-                }
-                for (key, path) in &app_state.button_files {
-                    let initial_image = if path.exists() {
-                        app_state.img_play.clone()
-                    } else {
-                        app_state.img_rec_off.clone()
-                    };
-                    device.set_button_image(*key, initial_image).await.unwrap();
-                }
-                device.flush().await.unwrap();
+    app_state.sequencer = config.sequencer.enabled.then(|| {
+        let sequencer = Sequencer::default();
+        let sequencer_config = config.sequencer.clone();
+        let engine = sequencer.clone();
+        std::thread::spawn(move || {
+            println!("Sequencer thread started...");
+            sequencer::run_sequencer(sequencer_config, engine);
+        });
+        sequencer
+    });
 
-                let reader = device.get_reader();
-                loop {
-                    let updates = match reader.read(100.0).await {
-                        Ok(updates) => updates,
-                        Err(_) => break,
-                    };
-                    for update in updates {
-                        match update {
-                            DeviceStateUpdate::EncoderTwist(dial, ticks) => {
-                                app_state
-                                    .handle_encoder_twist(dial, ticks as i32, &device)
-                                    .await;
-                            }
-                            DeviceStateUpdate::EncoderDown(dial) => {
-                                app_state.handle_encoder_down(dial, &device).await;
-                            }
-                            DeviceStateUpdate::ButtonDown(key) => {
-                                app_state.handle_button_down(key, &device).await;
-                            }
-                            DeviceStateUpdate::ButtonUp(key) => {
-                                app_state.handle_button_up(key, &device).await;
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-                drop(reader);
-                println!("Cleaning up buttons...");
-                device.clear_all_button_images().await.unwrap();
-                device.flush().await.unwrap();
-            }
-        }
-        Err(e) => eprintln!("Failed to create HidApi instance: {}", e),
+    if config.midi.enabled {
+        let midi_config = config.midi.clone();
+        let midi_input_tx = app_state.midi_tx.clone();
+        let input_clock = app_state.midi_clock.clone();
+        std::thread::spawn(move || {
+            println!("MIDI input thread started...");
+            midi::run_midi_input(midi_config, midi_input_tx, input_clock);
+        });
+    }
+    if config.midi.output {
+        let (midi_out_tx, midi_out_rx) = mpsc::channel();
+        let midi_config = config.midi.clone();
+        std::thread::spawn(move || {
+            println!("MIDI output thread started...");
+            midi::run_midi_output(midi_config, midi_out_rx);
+        });
+        app_state.midi_out_tx = Some(midi_out_tx);
+    }
+
+    if config.hotkeys.enabled {
+        tokio::spawn(hotkeys::run_hotkeys(
+            config.hotkeys.clone(),
+            app_state.hotkey_tx.clone(),
+        ));
+    }
+
+    if config.osc.enabled {
+        tokio::spawn(osc_server::run_osc_server(
+            config.osc.clone(),
+            app_state.remote_tx.clone(),
+            app_state.board_tx.subscribe(),
+        ));
+    }
+    if config.web.enabled {
+        tokio::spawn(web::run_web_server(
+            config.web.clone(),
+            audio_storage_path.clone(),
+            app_state.remote_tx.clone(),
+            app_state.board_tx.subscribe(),
+        ));
+    }
+
+    if config.scheduler.enabled {
+        tokio::spawn(scheduler::run_scheduler(
+            SystemClock,
+            app_state.schedule_tx.clone(),
+            app_state.schedules_on.subscribe(),
+        ));
+    }
+
+    println!("Starting in {:?} mode.", app_state.mode);
+    println!(
+        "Output routes set to: {}",
+        app_state.enabled_routes.join(", ")
+    );
+    app_state.prune_history();
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        println!("Shutdown requested.");
+        let _ = shutdown_tx.send(true);
+    });
+
+    // Started last to get the shutdown signal, so that ducked streams are
+    // restored before exiting
+    let ducking = config.ducking.enabled.then(|| {
+        tokio::spawn(ducking::run_ducking(
+            config.ducking.clone(),
+            app_state.voices.subscribe(),
+            shutdown_rx.clone(),
+        ))
+    });
+
+    let role_decks = RoleDecks {
+        hotkeys: config.hotkeys.deck.clone(),
+        midi: config.midi.deck.clone(),
+        remote: config.remote_deck.clone(),
+        schedules: config.scheduler.deck.clone(),
+    };
+    run_device_supervisor(app_state, config.decks, role_decks, shutdown_rx).await;
+    if let Some(ducking) = ducking {
+        let _ = ducking.await;
     }
 
     println!("Main function exiting. Audio thread will exit when sender is dropped.");
 
-    // When `audio_tx` (inside `app_state`) is dropped here,
+    // When `audio_tx` (inside every `AppState`) is dropped here,
     // the `rx.recv()` loop in `handle_audio_commands` will
     // end, and the audio thread will clean itself up.
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hotkeys::{ComboMatcher, parse_bindings};
+    use evdev::KeyCode;
+    use soundboard::config::HotkeyBinding;
+    use std::sync::Arc;
+
+    #[test]
+    fn example_config_lists_the_mode_cc_values() {
+        let values: Vec<String> = Mode::ALL
+            .iter()
+            .map(|mode| format!("{} {:?}", mode.midi_value(), mode))
+            .collect();
+        let line = format!("# Mode values: {}.", values.join(", "));
+        let example = include_str!("../config.example.toml");
+        assert!(
+            example.lines().any(|l| l == line),
+            "config.example.toml should have the line: {}",
+            line
+        );
+    }
+
+    #[test]
+    fn one_deck_takes_a_role_and_hands_it_on() {
+        let decks = RoleDecks::default();
+        let attached = vec!["A".to_string(), "B".to_string()];
+        assert!(decks.roles_for(Some("A"), &attached).hotkeys);
+        assert!(!decks.roles_for(Some("B"), &attached).hotkeys);
+        assert!(!decks.roles_for(None, &attached).hotkeys);
+
+        // Once A detaches, B takes over, and the board once B does too
+        let attached = vec!["B".to_string()];
+        assert!(decks.roles_for(Some("B"), &attached).hotkeys);
+        assert!(decks.roles_for(None, &[]).hotkeys);
+
+        // A configured deck that is not attached leaves the role to the
+        // board rather than another deck
+        let decks = RoleDecks {
+            hotkeys: Some("C".to_string()),
+            midi: None,
+            remote: None,
+            schedules: None,
+        };
+        assert!(!decks.roles_for(Some("B"), &attached).hotkeys);
+        assert!(decks.roles_for(None, &attached).hotkeys);
+        // Each role is handed out on its own
+        assert!(decks.roles_for(Some("B"), &attached).midi);
+        let attached = vec!["B".to_string(), "C".to_string()];
+        assert!(decks.roles_for(Some("C"), &attached).hotkeys);
+        assert!(!decks.roles_for(None, &attached).hotkeys);
+    }
+
+    /// The state decks start from, storing under a temp directory named
+    /// for `name`, with a recording on key B of the first bank.
+    fn board_template(name: &str) -> (AppState, PathBuf) {
+        let storage_path =
+            std::env::temp_dir().join(format!("soundboard-board-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&storage_path);
+        let key_file = get_key_file_path(&storage_path, 0, 1);
+        std::fs::create_dir_all(key_file.parent().unwrap()).unwrap();
+        std::fs::write(&key_file, "").unwrap();
+
+        let (audio_cmd_tx, _) = mpsc::channel();
+        let (_, level_rx) = watch::channel(TakeLevels::default());
+        let (_, health_rx) = watch::channel(CaptureHealth::default());
+        let template = AppState::new(
+            &Config::default(),
+            storage_path.clone(),
+            audio_cmd_tx,
+            level_rx,
+            health_rx,
+        );
+        (template, storage_path)
+    }
+
+    #[tokio::test]
+    async fn hotkeys_play_keys_with_no_deck_attached() {
+        let (mut template, storage_path) = board_template("hotkeys");
+        let (midi_out_tx, midi_out_rx) = mpsc::channel();
+        template.midi_out_tx = Some(midi_out_tx);
+        let hotkey_tx = template.hotkey_tx.clone();
+        let (_roles_tx, roles_rx) = watch::channel(RoleDecks::default().roles_for(None, &[]));
+        let mut board = template.for_board(roles_rx);
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let board_task = tokio::spawn(async move {
+            board.run_device(None, &mut shutdown_rx).await;
+        });
+        while hotkey_tx.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let bindings = [HotkeyBinding {
+            combo: "ctrl+2".to_string(),
+            key: Some(2),
+            action: None,
+        }];
+        let mut matcher = ComboMatcher::new(Arc::new(parse_bindings(&bindings)));
+        assert_eq!(matcher.key_down(KeyCode::KEY_LEFTCTRL), None);
+        for event in [
+            matcher.key_down(KeyCode::KEY_2),
+            matcher.key_up(KeyCode::KEY_2),
+        ] {
+            hotkey_tx.send(event.unwrap()).unwrap();
+        }
+
+        let message =
+            tokio::task::spawn_blocking(move || midi_out_rx.recv_timeout(Duration::from_secs(5)))
+                .await
+                .unwrap();
+        assert!(matches!(
+            message,
+            Ok(MidiMessage::NoteOn { note, .. }) if note == key_to_note(&template.midi, 1)
+        ));
+
+        shutdown_tx.send(true).unwrap();
+        board_task.await.unwrap();
+        let _ = std::fs::remove_dir_all(&storage_path);
+    }
+
+    #[tokio::test]
+    async fn remote_commands_apply_with_no_deck_attached() {
+        let (template, storage_path) = board_template("remote");
+        let remote_tx = template.remote_tx.clone();
+        let mut board_rx = template.board_tx.subscribe();
+        let (_roles_tx, roles_rx) = watch::channel(RoleDecks::default().roles_for(None, &[]));
+        let mut board = template.for_board(roles_rx);
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let board_task = tokio::spawn(async move {
+            board.run_device(None, &mut shutdown_rx).await;
+        });
+        while remote_tx.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        remote_tx
+            .send(RemoteCommand::SetLabel {
+                key: 1,
+                label: Some("Intro".to_string()),
+            })
+            .unwrap();
+        let labelled = board_rx.wait_for(|state| {
+            state
+                .as_ref()
+                .is_some_and(|state| state.keys[1].label.as_deref() == Some("Intro"))
+        });
+        tokio::time::timeout(Duration::from_secs(5), labelled)
+            .await
+            .expect("the board should publish the new label")
+            .unwrap();
+
+        shutdown_tx.send(true).unwrap();
+        board_task.await.unwrap();
+        let _ = std::fs::remove_dir_all(&storage_path);
+    }
+
+    #[tokio::test]
+    async fn schedules_play_with_no_deck_attached() {
+        let (mut template, storage_path) = board_template("schedules");
+        let (midi_out_tx, midi_out_rx) = mpsc::channel();
+        template.midi_out_tx = Some(midi_out_tx);
+        let schedule_tx = template.schedule_tx.clone();
+        let (_roles_tx, roles_rx) = watch::channel(RoleDecks::default().roles_for(None, &[]));
+        let mut board = template.for_board(roles_rx);
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let board_task = tokio::spawn(async move {
+            board.run_device(None, &mut shutdown_rx).await;
+        });
+        while schedule_tx.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        schedule_tx
+            .send(Schedule {
+                name: "intro".to_string(),
+                at: Some("09:00".to_string()),
+                every: None,
+                key: Some(2),
+                enabled: true,
+                run_macro: None,
+            })
+            .unwrap();
+        let message =
+            tokio::task::spawn_blocking(move || midi_out_rx.recv_timeout(Duration::from_secs(5)))
+                .await
+                .unwrap();
+        assert!(matches!(
+            message,
+            Ok(MidiMessage::NoteOn { note, .. }) if note == key_to_note(&template.midi, 1)
+        ));
+
+        shutdown_tx.send(true).unwrap();
+        board_task.await.unwrap();
+        let _ = std::fs::remove_dir_all(&storage_path);
+    }
+}
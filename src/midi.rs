@@ -0,0 +1,696 @@
+use alsa::seq::{
+    Addr, ClientIter, EvCtrl, EvNote, Event, EventType, PortCap, PortIter, PortSubscribe, PortType,
+    Seq,
+};
+use soundboard::KEY_COUNT;
+use soundboard::config::{MidiConfig, MidiControl, Quantize};
+use soundboard::error::{Error, Result};
+use std::ffi::CString;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// How long to wait before opening the sequencer again after it failed.
+const MIDI_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// The largest value a 7-bit MIDI data byte can have.
+pub const MIDI_MAX: u8 = 127;
+/// MIDI clock runs at 24 pulses per quarter note.
+const CLOCK_PPQN: u64 = 24;
+/// Clock pulses per song position unit (a sixteenth note).
+const CLOCK_PER_SONG_POSITION: u64 = 6;
+/// A clock whose last pulse is older than this has gone away, e.g. was
+/// unplugged without sending Stop.
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Something a MIDI controller asked the board to do, already mapped to
+/// keys and actions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiEvent {
+    /// A note for `key` started. `velocity` runs from 0.0 to 1.0.
+    KeyDown { key: u8, velocity: f64 },
+    /// The note for `key` ended.
+    KeyUp { key: u8 },
+    /// A mapped control change on controller `cc`, with its raw 0-127
+    /// value.
+    Control {
+        action: MidiControl,
+        cc: u8,
+        value: u8,
+    },
+}
+
+impl MidiEvent {
+    /// The key the event is for, if any.
+    pub fn key(&self) -> Option<u8> {
+        match self {
+            MidiEvent::KeyDown { key, .. } | MidiEvent::KeyUp { key } => Some(*key),
+            MidiEvent::Control { .. } => None,
+        }
+    }
+}
+
+/// A message for the MIDI output port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOn { note: u8, velocity: u8 },
+    NoteOff { note: u8 },
+    Control { cc: u8, value: u8 },
+}
+
+/// Position and tempo of the MIDI clock coming in on the input port.
+#[derive(Debug, Default)]
+struct ClockState {
+    /// Set by Start and Continue, and by pulses arriving unless a Stop
+    /// came after them. Some sources never send Start, only pulses.
+    running: bool,
+    /// A Stop was received. Sources that keep pulsing while stopped only
+    /// run again on Start or Continue.
+    stopped: bool,
+    /// Pulses since the clock was started.
+    ticks: u64,
+    last_tick: Option<Instant>,
+    /// Smoothed time between pulses.
+    tick_interval: Option<Duration>,
+}
+
+/// The incoming MIDI clock, shared between the input thread that follows
+/// it and the decks that quantize playback to it.
+#[derive(Debug, Clone, Default)]
+pub struct MidiClock {
+    state: Arc<Mutex<ClockState>>,
+}
+
+impl MidiClock {
+    /// Locks the clock, carrying on if a panicking thread poisoned it.
+    fn lock(&self) -> MutexGuard<'_, ClockState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Follows a clock, start, stop, continue or song position event.
+    fn handle(&self, event: &Event) {
+        let mut clock = self.lock();
+        match event.get_type() {
+            EventType::Start => {
+                clock.running = true;
+                clock.stopped = false;
+                clock.ticks = 0;
+            }
+            EventType::Continue => {
+                clock.running = true;
+                clock.stopped = false;
+            }
+            EventType::Stop => {
+                clock.running = false;
+                clock.stopped = true;
+            }
+            EventType::Songpos => {
+                if let Some(position) = event.get_data::<EvCtrl>() {
+                    clock.ticks = position.value.max(0) as u64 * CLOCK_PER_SONG_POSITION;
+                }
+            }
+            EventType::Clock => {
+                let now = Instant::now();
+                // A gap means the clock went away, not that it slowed down
+                if let Some(last) = clock.last_tick
+                    && now - last <= CLOCK_TIMEOUT
+                {
+                    let interval = now - last;
+                    // Average out jitter over the last few pulses
+                    clock.tick_interval = Some(match clock.tick_interval {
+                        Some(previous) => (previous * 7 + interval) / 8,
+                        None => interval,
+                    });
+                }
+                clock.last_tick = Some(now);
+                if !clock.stopped {
+                    clock.running = true;
+                }
+                if clock.running {
+                    clock.ticks += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// How long until the next beat or bar, or `None` if playback should
+    /// not wait: quantizing is off or no clock is running.
+    pub fn delay_until_next(&self, quantize: Quantize, beats_per_bar: u32) -> Option<Duration> {
+        let unit = match quantize {
+            Quantize::Off => return None,
+            Quantize::Beat => CLOCK_PPQN,
+            Quantize::Bar => CLOCK_PPQN * beats_per_bar.max(1) as u64,
+        };
+        let clock = self.lock();
+        if !clock.running {
+            return None;
+        }
+        let since_last = clock.last_tick?.elapsed();
+        if since_last > CLOCK_TIMEOUT {
+            return None;
+        }
+        let remaining = (unit - clock.ticks % unit) % unit;
+        let interval = clock.tick_interval?;
+        Some((interval * remaining as u32).saturating_sub(since_last))
+    }
+}
+
+/// Maps a note to a key, if it falls within the keys.
+fn note_to_key(config: &MidiConfig, note: u8) -> Option<u8> {
+    let key = note.checked_sub(config.base_note)?;
+    (key < KEY_COUNT).then_some(key)
+}
+
+/// The note that triggers `key`, which is also sent when it plays.
+pub fn key_to_note(config: &MidiConfig, key: u8) -> u8 {
+    config.base_note.saturating_add(key).min(MIDI_MAX)
+}
+
+/// Whether an event on `channel` (0-based, as ALSA reports it) should be
+/// listened to.
+fn on_channel(config: &MidiConfig, channel: u8) -> bool {
+    config.channel.is_none_or(|wanted| wanted == channel + 1)
+}
+
+/// Turns a sequencer event into what it means for the board, or `None`
+/// for events that are ignored.
+fn translate(config: &MidiConfig, event: &Event) -> Option<MidiEvent> {
+    match event.get_type() {
+        EventType::Noteon => {
+            let note: EvNote = event.get_data()?;
+            if !on_channel(config, note.channel) {
+                return None;
+            }
+            let key = note_to_key(config, note.note)?;
+            // A note-on with velocity 0 is a note-off
+            if note.velocity == 0 {
+                return Some(MidiEvent::KeyUp { key });
+            }
+            let velocity = if config.velocity {
+                note.velocity as f64 / MIDI_MAX as f64
+            } else {
+                1.0
+            };
+            Some(MidiEvent::KeyDown { key, velocity })
+        }
+        EventType::Noteoff => {
+            let note: EvNote = event.get_data()?;
+            if !on_channel(config, note.channel) {
+                return None;
+            }
+            Some(MidiEvent::KeyUp {
+                key: note_to_key(config, note.note)?,
+            })
+        }
+        EventType::Controller => {
+            let control: EvCtrl = event.get_data()?;
+            if !on_channel(config, control.channel) {
+                return None;
+            }
+            let mapping = config
+                .controls
+                .iter()
+                .find(|mapping| mapping.cc as u32 == control.param)?;
+            Some(MidiEvent::Control {
+                action: mapping.action,
+                cc: mapping.cc,
+                value: control.value.clamp(0, MIDI_MAX as i32) as u8,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Which way a connection made by [`connect_ports`] goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link {
+    /// From other ports into ours.
+    Input,
+    /// From our port out to others.
+    Output,
+}
+
+/// Connects our port with every other port whose client or port name
+/// contains one of `names`.
+fn connect_ports(seq: &Seq, port: i32, names: &[String], link: Link) -> Result<()> {
+    let ours = Addr {
+        client: seq
+            .client_id()
+            .map_err(|e| Error::midi("reading MIDI client id", e))?,
+        port,
+    };
+    let wanted_caps = match link {
+        Link::Input => PortCap::READ | PortCap::SUBS_READ,
+        Link::Output => PortCap::WRITE | PortCap::SUBS_WRITE,
+    };
+    for client in ClientIter::new(seq) {
+        if client.get_client() == ours.client {
+            continue;
+        }
+        let client_name = client.get_name().unwrap_or_default().to_string();
+        for other in PortIter::new(seq, client.get_client()) {
+            if !other.get_capability().contains(wanted_caps) {
+                continue;
+            }
+            let port_name = other.get_name().unwrap_or_default();
+            let wanted = names.iter().any(|name| {
+                client_name.contains(name.as_str()) || port_name.contains(name.as_str())
+            });
+            if !wanted {
+                continue;
+            }
+            let mut subscription =
+                PortSubscribe::empty().map_err(|e| Error::midi("creating MIDI subscription", e))?;
+            let (sender, dest) = match link {
+                Link::Input => (other.addr(), ours),
+                Link::Output => (ours, other.addr()),
+            };
+            subscription.set_sender(sender);
+            subscription.set_dest(dest);
+            match seq.subscribe_port(&subscription) {
+                Ok(()) => println!(
+                    "Connected MIDI {:?} with {} ({}).",
+                    link, client_name, port_name
+                ),
+                Err(e) => eprintln!(
+                    "Failed to connect MIDI {:?} with {} ({}): {}",
+                    link, client_name, port_name, e
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Opens the sequencer as a client named after the configured port and
+/// creates one port on it.
+fn open_port(
+    config: &MidiConfig,
+    direction: alsa::Direction,
+    port_name: &str,
+    caps: PortCap,
+) -> Result<(Seq, i32)> {
+    let client_name =
+        CString::new(config.port_name.as_str()).map_err(|e| Error::midi("MIDI client name", e))?;
+    let port_name = CString::new(port_name).map_err(|e| Error::midi("MIDI port name", e))?;
+    let seq = Seq::open(None, Some(direction), false)
+        .map_err(|e| Error::midi("opening ALSA sequencer", e))?;
+    seq.set_client_name(&client_name)
+        .map_err(|e| Error::midi("naming MIDI client", e))?;
+    let port = seq
+        .create_simple_port(
+            &port_name,
+            caps,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )
+        .map_err(|e| Error::midi("creating MIDI port", e))?;
+    Ok((seq, port))
+}
+
+/// Opens the input port and forwards events until reading fails. Clock
+/// events go to `clock` rather than to the decks.
+fn run_midi_session(
+    config: &MidiConfig,
+    tx: &broadcast::Sender<MidiEvent>,
+    clock: &MidiClock,
+) -> Result<()> {
+    let (seq, port) = open_port(
+        config,
+        alsa::Direction::Capture,
+        &config.port_name,
+        PortCap::WRITE | PortCap::SUBS_WRITE,
+    )?;
+    println!("MIDI input port '{}' ready.", config.port_name);
+    connect_ports(&seq, port, &config.connect, Link::Input)?;
+
+    let mut input = seq.input();
+    loop {
+        let event = input
+            .event_input()
+            .map_err(|e| Error::midi("reading MIDI input", e))?;
+        clock.handle(&event);
+        if let Some(event) = translate(config, &event) {
+            // Nobody listening just means no deck is connected yet
+            let _ = tx.send(event);
+        }
+    }
+}
+
+/// Runs the MIDI input for the lifetime of the process, opening the
+/// sequencer again whenever it fails. Blocks, so it gets its own thread.
+pub fn run_midi_input(config: MidiConfig, tx: broadcast::Sender<MidiEvent>, clock: MidiClock) {
+    loop {
+        if let Err(e) = run_midi_session(&config, &tx, &clock) {
+            eprintln!(
+                "MIDI input failed: {}. Retrying in {}s.",
+                e,
+                MIDI_RETRY_INTERVAL.as_secs()
+            );
+        }
+        std::thread::sleep(MIDI_RETRY_INTERVAL);
+    }
+}
+
+/// Sends one message out of our port to everything subscribed to it.
+fn send_message(seq: &Seq, port: i32, channel: u8, message: MidiMessage) -> Result<()> {
+    let mut event = match message {
+        MidiMessage::NoteOn { note, velocity } => Event::new(
+            EventType::Noteon,
+            &EvNote {
+                channel,
+                note,
+                velocity,
+                off_velocity: 0,
+                duration: 0,
+            },
+        ),
+        MidiMessage::NoteOff { note } => Event::new(
+            EventType::Noteoff,
+            &EvNote {
+                channel,
+                note,
+                velocity: 0,
+                off_velocity: 0,
+                duration: 0,
+            },
+        ),
+        MidiMessage::Control { cc, value } => Event::new(
+            EventType::Controller,
+            &EvCtrl {
+                channel,
+                param: cc as u32,
+                value: value as i32,
+            },
+        ),
+    };
+    event.set_source(port);
+    event.set_subs();
+    event.set_direct();
+    seq.event_output_direct(&mut event)
+        .map_err(|e| Error::midi("sending MIDI output", e))?;
+    Ok(())
+}
+
+/// Opens the output port and sends messages from `rx` until sending
+/// fails. Returns `Ok` once every sender is gone.
+fn run_output_session(config: &MidiConfig, rx: &Receiver<MidiMessage>) -> Result<()> {
+    let port_name = format!("{} Out", config.port_name);
+    let (seq, port) = open_port(
+        config,
+        alsa::Direction::Playback,
+        &port_name,
+        PortCap::READ | PortCap::SUBS_READ,
+    )?;
+    println!("MIDI output port '{}' ready.", port_name);
+    connect_ports(&seq, port, &config.output_connect, Link::Output)?;
+
+    let channel = config.output_channel.clamp(1, 16) - 1;
+    for message in rx {
+        send_message(&seq, port, channel, message)?;
+    }
+    Ok(())
+}
+
+/// Runs the MIDI output until the board shuts down, opening the sequencer
+/// again whenever it fails. Blocks, so it gets its own thread.
+pub fn run_midi_output(config: MidiConfig, rx: Receiver<MidiMessage>) {
+    loop {
+        match run_output_session(&config, &rx) {
+            Ok(()) => break,
+            Err(e) => eprintln!(
+                "MIDI output failed: {}. Retrying in {}s.",
+                e,
+                MIDI_RETRY_INTERVAL.as_secs()
+            ),
+        }
+        std::thread::sleep(MIDI_RETRY_INTERVAL);
+        // Whatever happened meanwhile is stale by now
+        let dropped = rx.try_iter().count();
+        if dropped > 0 {
+            println!(
+                "Dropped {} MIDI messages while the output was down.",
+                dropped
+            );
+        }
+    }
+    println!("MIDI output channel closed. Exiting output loop.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use soundboard::config::ControlMapping;
+
+    fn note(kind: EventType, channel: u8, note: u8, velocity: u8) -> Event<'static> {
+        Event::new(
+            kind,
+            &EvNote {
+                channel,
+                note,
+                velocity,
+                off_velocity: 0,
+                duration: 0,
+            },
+        )
+    }
+
+    fn control(channel: u8, cc: u32, value: i32) -> Event<'static> {
+        Event::new(
+            EventType::Controller,
+            &EvCtrl {
+                channel,
+                param: cc,
+                value,
+            },
+        )
+    }
+
+    #[test]
+    fn notes_map_onto_keys_from_the_base_note() {
+        let config = MidiConfig::default();
+        assert_eq!(note_to_key(&config, config.base_note - 1), None);
+        assert_eq!(note_to_key(&config, config.base_note), Some(0));
+        assert_eq!(
+            note_to_key(&config, config.base_note + KEY_COUNT - 1),
+            Some(KEY_COUNT - 1)
+        );
+        assert_eq!(note_to_key(&config, config.base_note + KEY_COUNT), None);
+        assert_eq!(key_to_note(&config, 1), config.base_note + 1);
+        let high = MidiConfig {
+            base_note: 125,
+            ..MidiConfig::default()
+        };
+        assert_eq!(key_to_note(&high, 7), MIDI_MAX);
+    }
+
+    #[test]
+    fn translates_notes() {
+        let config = MidiConfig::default();
+        let base = config.base_note;
+        assert_eq!(
+            translate(&config, &note(EventType::Noteon, 0, base + 2, MIDI_MAX)),
+            Some(MidiEvent::KeyDown {
+                key: 2,
+                velocity: 1.0
+            })
+        );
+        assert_eq!(
+            translate(&config, &note(EventType::Noteoff, 0, base + 2, 0)),
+            Some(MidiEvent::KeyUp { key: 2 })
+        );
+        // Out of range notes are ignored
+        assert_eq!(
+            translate(&config, &note(EventType::Noteon, 0, base - 1, 100)),
+            None
+        );
+    }
+
+    #[test]
+    fn note_on_without_velocity_is_a_note_off() {
+        let config = MidiConfig::default();
+        assert_eq!(
+            translate(&config, &note(EventType::Noteon, 0, config.base_note, 0)),
+            Some(MidiEvent::KeyUp { key: 0 })
+        );
+    }
+
+    #[test]
+    fn velocity_scales_only_when_enabled() {
+        let config = MidiConfig::default();
+        let soft = note(EventType::Noteon, 0, config.base_note, 64);
+        assert_eq!(
+            translate(&config, &soft),
+            Some(MidiEvent::KeyDown {
+                key: 0,
+                velocity: 64.0 / MIDI_MAX as f64
+            })
+        );
+        let flat = MidiConfig {
+            velocity: false,
+            ..MidiConfig::default()
+        };
+        assert_eq!(
+            translate(&flat, &soft),
+            Some(MidiEvent::KeyDown {
+                key: 0,
+                velocity: 1.0
+            })
+        );
+    }
+
+    #[test]
+    fn listens_only_on_the_configured_channel() {
+        let config = MidiConfig {
+            channel: Some(10),
+            ..MidiConfig::default()
+        };
+        let base = config.base_note;
+        // ALSA counts channels from 0
+        assert_eq!(
+            translate(&config, &note(EventType::Noteon, 9, base, 0)),
+            Some(MidiEvent::KeyUp { key: 0 })
+        );
+        assert_eq!(
+            translate(&config, &note(EventType::Noteon, 0, base, 100)),
+            None
+        );
+        assert_eq!(
+            translate(&config, &note(EventType::Noteoff, 10, base, 0)),
+            None
+        );
+        assert_eq!(translate(&config, &control(0, 7, 100)), None);
+        assert!(translate(&config, &control(9, 7, 100)).is_some());
+        // Unset listens on every channel
+        let all = MidiConfig::default();
+        assert!(translate(&all, &note(EventType::Noteon, 15, base, 100)).is_some());
+    }
+
+    #[test]
+    fn maps_control_changes() {
+        let config = MidiConfig {
+            controls: vec![
+                ControlMapping {
+                    cc: 7,
+                    action: MidiControl::Volume,
+                },
+                ControlMapping {
+                    cc: 64,
+                    action: MidiControl::NextBank,
+                },
+            ],
+            ..MidiConfig::default()
+        };
+        assert_eq!(
+            translate(&config, &control(0, 7, 100)),
+            Some(MidiEvent::Control {
+                action: MidiControl::Volume,
+                cc: 7,
+                value: 100
+            })
+        );
+        assert_eq!(
+            translate(&config, &control(0, 64, 200)),
+            Some(MidiEvent::Control {
+                action: MidiControl::NextBank,
+                cc: 64,
+                value: MIDI_MAX
+            })
+        );
+        assert_eq!(translate(&config, &control(0, 1, 100)), None);
+    }
+
+    fn pulse(clock: &MidiClock, kind: EventType) {
+        clock.handle(&Event::new(kind, &()));
+    }
+
+    #[test]
+    fn clock_runs_on_pulses_alone() {
+        let clock = MidiClock::default();
+        assert_eq!(clock.delay_until_next(Quantize::Beat, 4), None);
+        pulse(&clock, EventType::Clock);
+        pulse(&clock, EventType::Clock);
+        assert!(clock.lock().running);
+        assert_eq!(clock.lock().ticks, 2);
+        assert!(clock.delay_until_next(Quantize::Beat, 4).is_some());
+        assert_eq!(clock.delay_until_next(Quantize::Off, 4), None);
+    }
+
+    #[test]
+    fn clock_stays_stopped_until_started() {
+        let clock = MidiClock::default();
+        pulse(&clock, EventType::Clock);
+        pulse(&clock, EventType::Stop);
+        pulse(&clock, EventType::Clock);
+        pulse(&clock, EventType::Clock);
+        assert!(!clock.lock().running);
+        assert_eq!(clock.delay_until_next(Quantize::Bar, 4), None);
+        pulse(&clock, EventType::Continue);
+        pulse(&clock, EventType::Clock);
+        assert!(clock.lock().running);
+        assert_eq!(clock.lock().ticks, 2);
+        pulse(&clock, EventType::Start);
+        assert_eq!(clock.lock().ticks, 0);
+    }
+
+    #[test]
+    fn clock_times_out_without_pulses() {
+        let clock = MidiClock::default();
+        pulse(&clock, EventType::Clock);
+        pulse(&clock, EventType::Clock);
+        assert!(clock.delay_until_next(Quantize::Beat, 4).is_some());
+        clock.lock().last_tick = Some(Instant::now() - CLOCK_TIMEOUT * 2);
+        assert_eq!(clock.delay_until_next(Quantize::Beat, 4), None);
+    }
+
+    /// Plays a note into the input port through a second sequencer client.
+    #[test]
+    #[ignore = "needs the ALSA sequencer"]
+    fn receives_notes_from_a_virtual_port() {
+        let sender_config = MidiConfig {
+            port_name: "Soundboard Test Sender".to_string(),
+            ..MidiConfig::default()
+        };
+        let (seq, port) = open_port(
+            &sender_config,
+            alsa::Direction::Playback,
+            &sender_config.port_name,
+            PortCap::READ | PortCap::SUBS_READ,
+        )
+        .unwrap();
+        let config = MidiConfig {
+            port_name: "Soundboard Test".to_string(),
+            connect: vec![sender_config.port_name.clone()],
+            ..MidiConfig::default()
+        };
+        let (tx, mut rx) = broadcast::channel(8);
+        let note = key_to_note(&config, 3);
+        std::thread::spawn(move || run_midi_input(config, tx, MidiClock::default()));
+        // Give the input time to connect to the sender
+        std::thread::sleep(Duration::from_millis(500));
+        let message = MidiMessage::NoteOn {
+            note,
+            velocity: MIDI_MAX,
+        };
+        send_message(&seq, port, 0, message).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let event = loop {
+            match rx.try_recv() {
+                Ok(event) => break event,
+                Err(_) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("no MIDI event: {}", e),
+            }
+        };
+        assert_eq!(
+            event,
+            MidiEvent::KeyDown {
+                key: 3,
+                velocity: 1.0
+            }
+        );
+    }
+}
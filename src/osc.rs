@@ -0,0 +1,318 @@
+//! A small OSC 1.0 codec: just enough of the format for remote controls
+//! like TouchOSC to trigger keys and follow the board's state.
+
+use std::fmt;
+
+/// One argument of an OSC message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    /// The argument as a number, for controls that may send ints, floats
+    /// or booleans interchangeably.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            OscArg::Int(i) => Some(*i as f64),
+            OscArg::Float(f) => Some(*f as f64),
+            OscArg::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            OscArg::String(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            OscArg::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// An OSC message: an address such as `/key/1/trigger` and its arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: impl Into<String>, args: Vec<OscArg>) -> Self {
+        OscMessage {
+            address: address.into(),
+            args,
+        }
+    }
+}
+
+/// A packet that is not valid OSC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OscError(String);
+
+impl fmt::Display for OscError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid OSC packet: {}", self.0)
+    }
+}
+
+impl std::error::Error for OscError {}
+
+/// Reads OSC's big-endian, 4-byte aligned fields from a packet.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], OscError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| OscError("packet is truncated".to_string()))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<[u8; 4], OscError> {
+        let bytes = self.take(4)?;
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// A NUL-terminated string padded to a multiple of 4 bytes.
+    fn string(&mut self) -> Result<String, OscError> {
+        let rest = &self.buf[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| OscError("string is not terminated".to_string()))?;
+        let text = std::str::from_utf8(&rest[..len])
+            .map_err(|_| OscError("string is not UTF-8".to_string()))?
+            .to_string();
+        self.take(padded(len + 1))?;
+        Ok(text)
+    }
+}
+
+/// Rounds `len` up to the 4-byte alignment OSC uses.
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+fn decode_message(buf: &[u8]) -> Result<OscMessage, OscError> {
+    let mut reader = Reader { buf, pos: 0 };
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return Err(OscError(format!("bad address '{}'", address)));
+    }
+    // Very old senders leave out the type tags for messages without arguments
+    if reader.pos == buf.len() {
+        return Ok(OscMessage::new(address, Vec::new()));
+    }
+    let tags = reader.string()?;
+    let tags = tags
+        .strip_prefix(',')
+        .ok_or_else(|| OscError("type tags do not start with ','".to_string()))?;
+    let mut args = Vec::new();
+    for tag in tags.chars() {
+        let arg = match tag {
+            'i' => OscArg::Int(i32::from_be_bytes(reader.word()?)),
+            'f' => OscArg::Float(f32::from_be_bytes(reader.word()?)),
+            's' => OscArg::String(reader.string()?),
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            'h' => {
+                let bytes: [u8; 8] = reader
+                    .take(8)?
+                    .try_into()
+                    .map_err(|_| OscError("packet is truncated".to_string()))?;
+                OscArg::Int(
+                    i64::from_be_bytes(bytes).clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+                )
+            }
+            'd' => {
+                let bytes: [u8; 8] = reader
+                    .take(8)?
+                    .try_into()
+                    .map_err(|_| OscError("packet is truncated".to_string()))?;
+                OscArg::Float(f64::from_be_bytes(bytes) as f32)
+            }
+            other => return Err(OscError(format!("unsupported type tag '{}'", other))),
+        };
+        args.push(arg);
+    }
+    Ok(OscMessage::new(address, args))
+}
+
+/// Decodes a packet into its messages, flattening bundles. Bundle time
+/// tags are ignored; everything is handled on arrival.
+pub fn decode_packet(buf: &[u8]) -> Result<Vec<OscMessage>, OscError> {
+    if !buf.starts_with(b"#bundle\0") {
+        return Ok(vec![decode_message(buf)?]);
+    }
+    let mut reader = Reader { buf, pos: 16 };
+    if buf.len() < reader.pos {
+        return Err(OscError("bundle is truncated".to_string()));
+    }
+    let mut messages = Vec::new();
+    while reader.pos < buf.len() {
+        let len = i32::from_be_bytes(reader.word()?);
+        let len =
+            usize::try_from(len).map_err(|_| OscError("negative element size".to_string()))?;
+        messages.extend(decode_packet(reader.take(len)?)?);
+    }
+    Ok(messages)
+}
+
+fn push_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    let len = s.len() + 1;
+    out.resize(out.len() + 1 + (padded(len) - len), 0);
+}
+
+/// Encodes a single message.
+pub fn encode_message(message: &OscMessage) -> Vec<u8> {
+    let mut out = Vec::new();
+    push_string(&mut out, &message.address);
+    let mut tags = String::from(",");
+    for arg in &message.args {
+        tags.push(match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::String(_) => 's',
+            OscArg::Bool(true) => 'T',
+            OscArg::Bool(false) => 'F',
+        });
+    }
+    push_string(&mut out, &tags);
+    for arg in &message.args {
+        match arg {
+            OscArg::Int(i) => out.extend_from_slice(&i.to_be_bytes()),
+            OscArg::Float(f) => out.extend_from_slice(&f.to_be_bytes()),
+            OscArg::String(s) => push_string(&mut out, s),
+            OscArg::Bool(_) => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wraps `elements` in a bundle, each with its size in front.
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = b"#bundle\0".to_vec();
+        // Time tag 1, "immediately"
+        out.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            out.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            out.extend_from_slice(element);
+        }
+        out
+    }
+
+    #[test]
+    fn message_round_trips() {
+        let message = OscMessage::new(
+            "/key/1/label",
+            vec![
+                OscArg::Int(-7),
+                OscArg::Float(0.5),
+                OscArg::String("air horn".to_string()),
+                OscArg::Bool(true),
+                OscArg::Bool(false),
+            ],
+        );
+        let encoded = encode_message(&message);
+        assert_eq!(encoded.len() % 4, 0);
+        assert_eq!(decode_packet(&encoded), Ok(vec![message]));
+    }
+
+    #[test]
+    fn strings_are_padded_to_four_bytes() {
+        let encoded = encode_message(&OscMessage::new("/abc", Vec::new()));
+        // "/abc" needs a NUL and so a whole extra word, then "," and padding
+        assert_eq!(encoded, b"/abc\0\0\0\0,\0\0\0");
+    }
+
+    #[test]
+    fn decodes_message_without_type_tags() {
+        assert_eq!(
+            decode_packet(b"/subscribe\0\0"),
+            Ok(vec![OscMessage::new("/subscribe", Vec::new())])
+        );
+    }
+
+    #[test]
+    fn decodes_64_bit_arguments() {
+        let mut packet = b"/n\0\0,hd\0".to_vec();
+        packet.extend_from_slice(&(i64::MAX).to_be_bytes());
+        packet.extend_from_slice(&1.5f64.to_be_bytes());
+        assert_eq!(
+            decode_packet(&packet),
+            Ok(vec![OscMessage::new(
+                "/n",
+                vec![OscArg::Int(i32::MAX), OscArg::Float(1.5)]
+            )])
+        );
+    }
+
+    #[test]
+    fn flattens_nested_bundles() {
+        let first = OscMessage::new("/key/1/trigger", Vec::new());
+        let second = OscMessage::new("/key/2/volume", vec![OscArg::Float(1.0)]);
+        let third = OscMessage::new("/subscribe", vec![OscArg::Int(9000)]);
+        let packet = bundle(&[
+            encode_message(&first),
+            bundle(&[encode_message(&second), encode_message(&third)]),
+        ]);
+        assert_eq!(decode_packet(&packet), Ok(vec![first, second, third]));
+    }
+
+    #[test]
+    fn empty_bundle_has_no_messages() {
+        assert_eq!(decode_packet(&bundle(&[])), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let encoded = encode_message(&OscMessage::new("/v", vec![OscArg::Int(1)]));
+        // An argument cut short
+        assert!(decode_packet(&encoded[..encoded.len() - 2]).is_err());
+        // A string without its terminator
+        assert!(decode_packet(b"/key").is_err());
+        // A bundle shorter than its header
+        assert!(decode_packet(b"#bundle\0\0\0").is_err());
+        // An element size reaching past the end
+        let mut packet = bundle(std::slice::from_ref(&encoded));
+        packet.truncate(packet.len() - 4);
+        assert!(decode_packet(&packet).is_err());
+        // An element size cut short
+        let mut packet = bundle(&[]);
+        packet.extend_from_slice(&[0, 0]);
+        assert!(decode_packet(&packet).is_err());
+    }
+
+    #[test]
+    fn rejects_negative_element_size() {
+        let mut packet = bundle(&[]);
+        packet.extend_from_slice(&(-4i32).to_be_bytes());
+        packet.extend_from_slice(b"/a\0\0");
+        assert_eq!(
+            decode_packet(&packet),
+            Err(OscError("negative element size".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_addresses_and_tags() {
+        assert!(decode_packet(b"key\0,\0\0\0").is_err());
+        assert!(decode_packet(b"/a\0\0i\0\0\0\0\0\0\x01").is_err());
+        assert!(decode_packet(b"/a\0\0,b\0\0").is_err());
+    }
+}
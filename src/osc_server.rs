@@ -0,0 +1,457 @@
+//! OSC over UDP, so remote controls can play and record keys and follow
+//! the board's state.
+//!
+//! Keys are numbered from 1. Commands:
+//!
+//! - `/key/N/trigger` plays key N
+//! - `/key/N/volume f` sets its volume, 0.0 to 1.5
+//! - `/key/N/pitch f` sets its pitch shift in semitones
+//! - `/mode s` switches to `"playback"`, `"edit"`, `"looper"` or
+//!   `"pattern"` (or 0 for playback and 1 for edit)
+//! - `/route/NAME i` turns output route NAME on (1) or off (0). A name
+//!   that is not a configured route is a PipeWire node
+//! - `/key/N/routes s...` makes key N play through the given routes, or
+//!   with no arguments through the enabled ones again
+//! - `/record/N/start` and `/record/N/stop` record into key N
+//! - `/macro/NAME i` runs macro NAME (1) or cancels it (0)
+//! - `/subscribe [port]` and `/unsubscribe [port]` start and stop state
+//!   updates to the sender, or to another port on the sender's host
+//!
+//! Buttons that send 0 on release only act on press. Subscribers get
+//! `/mode`, `/bank`, `/route/NAME`, `/macro/NAME` and, per key, `/key/N/loaded`,
+//! `/key/N/playing`, `/key/N/recording`, `/key/N/volume`, `/key/N/pitch`
+//! and `/key/N/label`, all at once when they subscribe and then as they
+//! change.
+
+use crate::Mode;
+use crate::remote::{BoardState, RemoteCommand};
+use soundboard::KEY_COUNT;
+use soundboard::config::OscConfig;
+use soundboard::osc::{OscArg, OscMessage, decode_packet, encode_message};
+use std::net::SocketAddr;
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::sync::{broadcast, watch};
+
+/// Largest packet accepted, the most a UDP datagram can carry.
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// What an incoming message asks for.
+enum Request {
+    Command(RemoteCommand),
+    Subscribe(Option<u16>),
+    Unsubscribe(Option<u16>),
+    /// A button being released, which does nothing.
+    Ignore,
+}
+
+/// Parses the `N` of `/key/N/...` into a key index.
+fn parse_key(number: &str) -> Result<u8, String> {
+    number
+        .parse::<u8>()
+        .ok()
+        .filter(|n| (1..=KEY_COUNT).contains(n))
+        .map(|n| n - 1)
+        .ok_or_else(|| format!("no key {}, expected 1 to {}", number, KEY_COUNT))
+}
+
+fn number_arg(message: &OscMessage) -> Result<f64, String> {
+    message
+        .args
+        .first()
+        .and_then(OscArg::as_f64)
+        .filter(|value| value.is_finite())
+        .ok_or_else(|| "expected a number".to_string())
+}
+
+fn port_arg(message: &OscMessage) -> Result<Option<u16>, String> {
+    match message.args.first() {
+        None => Ok(None),
+        Some(arg) => arg
+            .as_f64()
+            .filter(|port| (1.0..=u16::MAX as f64).contains(port))
+            .map(|port| Some(port as u16))
+            .ok_or_else(|| "expected a port number".to_string()),
+    }
+}
+
+/// Whether a button message is a release, i.e. has a first argument of 0.
+fn is_release(message: &OscMessage) -> bool {
+    message
+        .args
+        .first()
+        .and_then(OscArg::as_f64)
+        .is_some_and(|value| value == 0.0)
+}
+
+fn parse_message(message: &OscMessage) -> Result<Request, String> {
+    let parts: Vec<&str> = message.address[1..].split('/').collect();
+    let press = |command: RemoteCommand| {
+        if is_release(message) {
+            Request::Ignore
+        } else {
+            Request::Command(command)
+        }
+    };
+    let request = match parts.as_slice() {
+        ["key", n, "trigger"] => press(RemoteCommand::Trigger { key: parse_key(n)? }),
+        ["key", n, "volume"] => Request::Command(RemoteCommand::SetVolume {
+            key: parse_key(n)?,
+            volume: number_arg(message)?,
+        }),
+        ["key", n, "pitch"] => Request::Command(RemoteCommand::SetPitch {
+            key: parse_key(n)?,
+            pitch: number_arg(message)?,
+        }),
+        ["record", n, "start"] => press(RemoteCommand::StartRecording { key: parse_key(n)? }),
+        ["record", n, "stop"] => press(RemoteCommand::StopRecording { key: parse_key(n)? }),
+        ["mode"] => {
+            let mode = match message.args.first().and_then(OscArg::as_str) {
+                Some(name) => name.parse()?,
+                None if number_arg(message)? == 0.0 => Mode::Playback,
+                None => Mode::Edit,
+            };
+            Request::Command(RemoteCommand::SetMode(mode))
+        }
+        ["route", name] if !name.is_empty() => Request::Command(RemoteCommand::SetRoute {
+            name: name.to_string(),
+            enabled: number_arg(message)? != 0.0,
+        }),
+        ["macro", name] if !name.is_empty() => {
+            let name = name.to_string();
+            Request::Command(if number_arg(message)? != 0.0 {
+                RemoteCommand::RunMacro { name }
+            } else {
+                RemoteCommand::CancelMacro { name }
+            })
+        }
+        ["key", n, "routes"] => {
+            let routes = message
+                .args
+                .iter()
+                .map(|arg| arg.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| "expected route names".to_string())?;
+            Request::Command(RemoteCommand::SetKeyRoutes {
+                key: parse_key(n)?,
+                routes: (!routes.is_empty()).then_some(routes),
+            })
+        }
+        ["subscribe"] => Request::Subscribe(port_arg(message)?),
+        ["unsubscribe"] => Request::Unsubscribe(port_arg(message)?),
+        _ => return Err("unknown address".to_string()),
+    };
+    Ok(request)
+}
+
+fn flag(value: bool) -> OscArg {
+    OscArg::Int(value as i32)
+}
+
+/// The messages that bring a subscriber from `previous` to `current`: only
+/// what changed, or everything if `previous` is `None`.
+fn state_messages(previous: Option<&BoardState>, current: &BoardState) -> Vec<OscMessage> {
+    let mut messages = Vec::new();
+    let mut push = |changed: bool, address: String, arg: OscArg| {
+        if changed {
+            messages.push(OscMessage::new(address, vec![arg]));
+        }
+    };
+    push(
+        previous.is_none_or(|p| p.mode != current.mode),
+        "/mode".to_string(),
+        OscArg::String(current.mode.name().to_string()),
+    );
+    for route in &current.routes {
+        let before = previous.and_then(|p| p.routes.iter().find(|r| r.name == route.name));
+        push(
+            before.is_none_or(|b| b.enabled != route.enabled),
+            format!("/route/{}", route.name),
+            flag(route.enabled),
+        );
+    }
+    // Routes that are no longer listed were nodes that got turned off
+    for route in previous.map(|p| p.routes.as_slice()).unwrap_or_default() {
+        push(
+            !current.routes.iter().any(|r| r.name == route.name),
+            format!("/route/{}", route.name),
+            flag(false),
+        );
+    }
+    for macro_state in &current.macros {
+        let before = previous.and_then(|p| p.macros.iter().find(|m| m.name == macro_state.name));
+        push(
+            before.is_none_or(|b| b.running != macro_state.running),
+            format!("/macro/{}", macro_state.name),
+            flag(macro_state.running),
+        );
+    }
+    push(
+        previous.is_none_or(|p| p.bank != current.bank),
+        "/bank".to_string(),
+        OscArg::Int(current.bank as i32 + 1),
+    );
+    for (index, key) in current.keys.iter().enumerate() {
+        let before = previous.and_then(|p| p.keys.get(index));
+        let number = index + 1;
+        push(
+            before.is_none_or(|b| b.has_sample != key.has_sample),
+            format!("/key/{}/loaded", number),
+            flag(key.has_sample),
+        );
+        push(
+            before.is_none_or(|b| b.playing != key.playing),
+            format!("/key/{}/playing", number),
+            flag(key.playing),
+        );
+        push(
+            before.is_none_or(|b| b.recording != key.recording),
+            format!("/key/{}/recording", number),
+            flag(key.recording),
+        );
+        push(
+            before.is_none_or(|b| b.volume != key.volume),
+            format!("/key/{}/volume", number),
+            OscArg::Float(key.volume as f32),
+        );
+        push(
+            before.is_none_or(|b| b.pitch != key.pitch),
+            format!("/key/{}/pitch", number),
+            OscArg::Float(key.pitch as f32),
+        );
+        push(
+            before.is_none_or(|b| b.label != key.label),
+            format!("/key/{}/label", number),
+            OscArg::String(key.label.clone().unwrap_or_default()),
+        );
+    }
+    messages
+}
+
+async fn send_messages(socket: &UdpSocket, to: SocketAddr, messages: &[OscMessage]) {
+    for message in messages {
+        if let Err(e) = socket.send_to(&encode_message(message), to).await {
+            eprintln!("Failed to send OSC to {}: {}", to, e);
+            return;
+        }
+    }
+}
+
+/// Listens for OSC on `config.bind`, passing commands to the decks through
+/// `remote_tx` and sending subscribers the state published on `board_rx`.
+/// Runs until the process exits.
+pub async fn run_osc_server(
+    config: OscConfig,
+    remote_tx: broadcast::Sender<RemoteCommand>,
+    board_rx: watch::Receiver<Option<BoardState>>,
+) {
+    let socket = match UdpSocket::bind(&config.bind).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Failed to start OSC server on {}: {}", config.bind, e);
+            return;
+        }
+    };
+    println!("OSC server listening on {}", config.bind);
+    serve(socket, &config.feedback, remote_tx, board_rx).await;
+}
+
+/// Serves OSC on a bound `socket`, sending state to the `feedback`
+/// addresses from the start.
+async fn serve(
+    socket: UdpSocket,
+    feedback: &[String],
+    remote_tx: broadcast::Sender<RemoteCommand>,
+    mut board_rx: watch::Receiver<Option<BoardState>>,
+) {
+    let mut subscribers: Vec<SocketAddr> = Vec::new();
+    for host in feedback {
+        match lookup_host(host.as_str())
+            .await
+            .map(|mut addrs| addrs.next())
+        {
+            Ok(Some(addr)) => subscribers.push(addr),
+            Ok(None) => eprintln!("OSC feedback address {} did not resolve.", host),
+            Err(e) => eprintln!("Failed to resolve OSC feedback address {}: {}", host, e),
+        }
+    }
+
+    // What subscribers have been sent so far
+    let mut sent = board_rx.borrow_and_update().clone();
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    loop {
+        tokio::select! {
+            result = socket.recv_from(&mut buf) => {
+                let (len, from) = match result {
+                    Ok(received) => received,
+                    // e.g. a subscriber that went away, reported on the next receive
+                    Err(e) => {
+                        eprintln!("Failed to receive OSC: {}", e);
+                        continue;
+                    }
+                };
+                let messages = match decode_packet(&buf[..len]) {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        eprintln!("Ignoring packet from {}: {}", from, e);
+                        continue;
+                    }
+                };
+                for message in messages {
+                    match parse_message(&message) {
+                        Ok(Request::Command(command)) => {
+                            if remote_tx.send(command).is_err() {
+                                println!("No Stream Deck connected, dropping OSC {}.", message.address);
+                            }
+                        }
+                        Ok(Request::Subscribe(port)) => {
+                            let addr = port.map_or(from, |port| SocketAddr::new(from.ip(), port));
+                            if !subscribers.contains(&addr) {
+                                println!("OSC client {} subscribed.", addr);
+                                subscribers.push(addr);
+                            }
+                            if let Some(board) = &sent {
+                                send_messages(&socket, addr, &state_messages(None, board)).await;
+                            }
+                        }
+                        Ok(Request::Unsubscribe(port)) => {
+                            let addr = port.map_or(from, |port| SocketAddr::new(from.ip(), port));
+                            subscribers.retain(|subscriber| *subscriber != addr);
+                            println!("OSC client {} unsubscribed.", addr);
+                        }
+                        Ok(Request::Ignore) => {}
+                        Err(reason) => {
+                            eprintln!("Ignoring OSC {} from {}: {}", message.address, from, reason);
+                        }
+                    }
+                }
+            }
+            result = board_rx.changed() => {
+                // The senders live as long as the decks do
+                if result.is_err() {
+                    return;
+                }
+                let board = board_rx.borrow_and_update().clone();
+                if let Some(current) = &board {
+                    let messages = state_messages(sent.as_ref(), current);
+                    for subscriber in &subscribers {
+                        send_messages(&socket, *subscriber, &messages).await;
+                    }
+                }
+                sent = board;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::{KeyState, RouteState};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn board() -> BoardState {
+        BoardState {
+            mode: Mode::Playback,
+            routes: vec![RouteState {
+                name: "default".to_string(),
+                enabled: true,
+            }],
+            macros: Vec::new(),
+            bank: 0,
+            keys: (0..KEY_COUNT)
+                .map(|key| KeyState {
+                    has_sample: key == 0,
+                    playing: false,
+                    recording: false,
+                    volume: 1.0,
+                    pitch: 0.0,
+                    label: None,
+                    icon: None,
+                    routes: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Receives one message sent to `client`.
+    async fn receive(client: &UdpSocket) -> OscMessage {
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let len = timeout(WAIT, client.recv(&mut buf))
+            .await
+            .expect("timed out waiting for OSC")
+            .unwrap();
+        let mut messages = decode_packet(&buf[..len]).unwrap();
+        assert_eq!(messages.len(), 1);
+        messages.remove(0)
+    }
+
+    #[tokio::test]
+    async fn serves_commands_and_state_over_a_local_socket() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        let (remote_tx, mut remote_rx) = broadcast::channel(8);
+        let (board_tx, board_rx) = watch::channel(Some(board()));
+        tokio::spawn(async move { serve(socket, &[], remote_tx, board_rx).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server).await.unwrap();
+
+        // A press triggers the key, its release does nothing
+        let trigger = |value| OscMessage::new("/key/1/trigger", vec![OscArg::Int(value)]);
+        client.send(&encode_message(&trigger(1))).await.unwrap();
+        client.send(&encode_message(&trigger(0))).await.unwrap();
+        let command = timeout(WAIT, remote_rx.recv()).await.unwrap().unwrap();
+        assert_eq!(command, RemoteCommand::Trigger { key: 0 });
+
+        // Subscribing sends the whole state
+        let subscribe = OscMessage::new("/subscribe", Vec::new());
+        client.send(&encode_message(&subscribe)).await.unwrap();
+        let expected = state_messages(None, &board());
+        let mut received = Vec::new();
+        for _ in 0..expected.len() {
+            received.push(receive(&client).await);
+        }
+        assert_eq!(received, expected);
+        assert_eq!(
+            received[0],
+            OscMessage::new("/mode", vec![OscArg::String("playback".to_string())])
+        );
+        assert!(received.contains(&OscMessage::new("/key/1/loaded", vec![OscArg::Int(1)])));
+
+        // Then only what changes
+        let mut playing = board();
+        playing.keys[0].playing = true;
+        board_tx.send_replace(Some(playing));
+        assert_eq!(
+            receive(&client).await,
+            OscMessage::new("/key/1/playing", vec![OscArg::Int(1)])
+        );
+        assert!(remote_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn parses_macro_and_route_commands() {
+        let parse = |address: &str, args| match parse_message(&OscMessage::new(address, args)) {
+            Ok(Request::Command(command)) => Some(command),
+            _ => None,
+        };
+        assert_eq!(
+            parse("/macro/fanfare", vec![OscArg::Int(1)]),
+            Some(RemoteCommand::RunMacro {
+                name: "fanfare".to_string()
+            })
+        );
+        assert_eq!(
+            parse("/route/Mixer", vec![OscArg::Float(0.0)]),
+            Some(RemoteCommand::SetRoute {
+                name: "Mixer".to_string(),
+                enabled: false
+            })
+        );
+        assert_eq!(parse("/key/9/trigger", Vec::new()), None);
+        assert_eq!(parse("/key/1/volume", vec![OscArg::Float(f32::NAN)]), None);
+    }
+}
@@ -0,0 +1,128 @@
+//! What remote controls can ask of the board, and the state they are shown.
+
+use crate::Mode;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// An action from a remote control, carried out by every deck that follows
+/// remote controls as if done on the deck itself.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteCommand {
+    /// Play the key's sample.
+    Trigger {
+        key: u8,
+    },
+    SetVolume {
+        key: u8,
+        volume: f64,
+    },
+    SetPitch {
+        key: u8,
+        pitch: f64,
+    },
+    /// Set or, with `None`, remove the key's label.
+    SetLabel {
+        key: u8,
+        label: Option<String>,
+    },
+    /// Make the key play `path`, like assigning a library file.
+    Assign {
+        key: u8,
+        path: PathBuf,
+    },
+    /// Unassign the key's file or delete its recording.
+    Clear {
+        key: u8,
+    },
+    SetMode(Mode),
+    /// Turn an output route on or off for keys without routes of their
+    /// own. A name that is not a configured route is a PipeWire node.
+    SetRoute {
+        name: String,
+        enabled: bool,
+    },
+    /// Make the key play through `routes`, or with `None` through the
+    /// enabled routes again.
+    SetKeyRoutes {
+        key: u8,
+        routes: Option<Vec<String>>,
+    },
+    /// Record into an empty key until `StopRecording`.
+    StartRecording {
+        key: u8,
+    },
+    StopRecording {
+        key: u8,
+    },
+    /// Run a configured macro.
+    RunMacro {
+        name: String,
+    },
+    /// Stop a running macro before its remaining steps.
+    CancelMacro {
+        name: String,
+    },
+}
+
+impl RemoteCommand {
+    /// The key the command acts on, if any.
+    pub fn key(&self) -> Option<u8> {
+        match *self {
+            RemoteCommand::Trigger { key }
+            | RemoteCommand::SetVolume { key, .. }
+            | RemoteCommand::SetPitch { key, .. }
+            | RemoteCommand::SetLabel { key, .. }
+            | RemoteCommand::Assign { key, .. }
+            | RemoteCommand::Clear { key }
+            | RemoteCommand::SetKeyRoutes { key, .. }
+            | RemoteCommand::StartRecording { key }
+            | RemoteCommand::StopRecording { key } => Some(key),
+            RemoteCommand::SetMode(_)
+            | RemoteCommand::SetRoute { .. }
+            | RemoteCommand::RunMacro { .. }
+            | RemoteCommand::CancelMacro { .. } => None,
+        }
+    }
+}
+
+/// One key as remote controls see it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyState {
+    pub has_sample: bool,
+    /// Playing, or waiting for the beat to play.
+    pub playing: bool,
+    pub recording: bool,
+    pub volume: f64,
+    pub pitch: f64,
+    pub label: Option<String>,
+    pub icon: Option<PathBuf>,
+    /// Routes the key plays through instead of the enabled ones.
+    pub routes: Option<Vec<String>>,
+}
+
+/// An output route and whether it is enabled.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteState {
+    pub name: String,
+    pub enabled: bool,
+}
+
+/// A configured macro and whether it is running.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MacroState {
+    pub name: String,
+    pub running: bool,
+}
+
+/// A snapshot of a deck, published whenever it changes so remote controls
+/// can mirror it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BoardState {
+    pub mode: Mode,
+    /// The configured routes, then any other enabled ones.
+    pub routes: Vec<RouteState>,
+    pub macros: Vec<MacroState>,
+    /// The current bank, counting from 0.
+    pub bank: usize,
+    pub keys: Vec<KeyState>,
+}
@@ -0,0 +1,206 @@
+//! Output routes: finding the PipeWire nodes they play into, and picking
+//! which of them are enabled.
+
+use crate::audio_player::PlaybackRoute;
+use pipewire as pw;
+use pw::types::ObjectType;
+use serde::Serialize;
+use soundboard::config::OutputRoute;
+use soundboard::error::{Error, Result};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// How many routes the deck cycles through combinations of. Every subset
+/// of more would take too many presses to get through.
+const MAX_CYCLED_ROUTES: usize = 4;
+
+/// A node PipeWire has, as announced by its registry.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PipeWireNode {
+    pub id: u32,
+    pub name: String,
+    pub description: Option<String>,
+    pub media_class: Option<String>,
+    #[serde(skip)]
+    pub properties: BTreeMap<String, String>,
+}
+
+impl PipeWireNode {
+    /// Whether audio can be played into the node.
+    pub fn is_audio_sink(&self) -> bool {
+        self.media_class
+            .as_deref()
+            .is_some_and(|class| class.starts_with("Audio/Sink") || class == "Audio/Duplex")
+    }
+
+    fn has_properties(&self, wanted: &BTreeMap<String, String>) -> bool {
+        wanted
+            .iter()
+            .all(|(key, value)| self.properties.get(key) == Some(value))
+    }
+}
+
+/// Lists the nodes PipeWire has right now. Connects to PipeWire and blocks
+/// until the registry has announced them all.
+pub fn list_nodes() -> Result<Vec<PipeWireNode>> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoopRc::new(None)
+        .map_err(|e| Error::audio("creating PipeWire main loop", e))?;
+    let context = pw::context::ContextRc::new(&mainloop, None)
+        .map_err(|e| Error::audio("creating PipeWire context", e))?;
+    let core = context
+        .connect_rc(None)
+        .map_err(|e| Error::audio("connecting to PipeWire", e))?;
+    let registry = core
+        .get_registry_rc()
+        .map_err(|e| Error::audio("getting the PipeWire registry", e))?;
+
+    // 1. Collect every node the registry announces
+    let nodes: Rc<RefCell<Vec<PipeWireNode>>> = Rc::default();
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let nodes = nodes.clone();
+            move |global| {
+                if global.type_ != ObjectType::Node {
+                    return;
+                }
+                let Some(props) = global.props else {
+                    return;
+                };
+                let properties: BTreeMap<String, String> = props
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect();
+                let Some(name) = properties.get("node.name").cloned() else {
+                    return;
+                };
+                nodes.borrow_mut().push(PipeWireNode {
+                    id: global.id,
+                    name,
+                    description: properties.get("node.description").cloned(),
+                    media_class: properties.get("media.class").cloned(),
+                    properties,
+                });
+            }
+        })
+        .register();
+
+    // 2. The server answers a sync after everything sent before it, so
+    // once it is done every existing node has been announced
+    let pending = core
+        .sync(0)
+        .map_err(|e| Error::audio("syncing with PipeWire", e))?;
+    let failure: Rc<RefCell<Option<String>>> = Rc::default();
+    let _core_listener = core
+        .add_listener_local()
+        .done({
+            let mainloop = mainloop.clone();
+            move |id, seq| {
+                if id == pw::core::PW_ID_CORE && seq == pending {
+                    mainloop.quit();
+                }
+            }
+        })
+        .error({
+            let mainloop = mainloop.clone();
+            let failure = failure.clone();
+            move |id, _seq, res, message| {
+                if id == pw::core::PW_ID_CORE {
+                    *failure.borrow_mut() = Some(format!("{} ({})", message, res));
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+    mainloop.run();
+
+    if let Some(failure) = failure.take() {
+        return Err(Error::audio("listing PipeWire nodes", failure));
+    }
+    let mut nodes = nodes.take();
+    nodes.sort_by_key(|node| node.id);
+    Ok(nodes)
+}
+
+/// Lists the nodes audio can be played into, without blocking the runtime.
+pub async fn list_audio_sinks() -> Result<Vec<PipeWireNode>> {
+    let nodes = tokio::task::spawn_blocking(list_nodes)
+        .await
+        .map_err(|e| Error::audio("listing PipeWire nodes", e))??;
+    Ok(nodes
+        .into_iter()
+        .filter(PipeWireNode::is_audio_sink)
+        .collect())
+}
+
+/// The node `route` plays into among `nodes`: its target, or the first
+/// node with its properties. `Some(None)` is the default output, and
+/// `None` means no node matches.
+pub fn find_route_node(route: &OutputRoute, nodes: &[PipeWireNode]) -> Option<Option<String>> {
+    if route.properties.is_empty() {
+        return Some(route.target.clone());
+    }
+    nodes
+        .iter()
+        .find(|node| node.has_properties(&route.properties))
+        .map(|node| Some(node.name.clone()))
+}
+
+/// Works out where each of the routes `names` plays. A name that is not a
+/// configured route is taken as the `node.name` of a node to play into.
+/// Routes whose node cannot be found are left out.
+pub async fn resolve_routes(names: &[String], routes: &[OutputRoute]) -> Vec<PlaybackRoute> {
+    // Nodes are only looked up if a route picks one by its properties
+    let mut nodes: Option<Vec<PipeWireNode>> = None;
+    let mut resolved = Vec::with_capacity(names.len());
+    for name in names {
+        let Some(route) = routes.iter().find(|route| &route.name == name) else {
+            resolved.push(PlaybackRoute {
+                name: name.clone(),
+                node: Some(name.clone()),
+                gain: 1.0,
+            });
+            continue;
+        };
+        if !route.properties.is_empty() && nodes.is_none() {
+            nodes = Some(list_audio_sinks().await.unwrap_or_else(|e| {
+                eprintln!("Failed to list PipeWire nodes: {}", e);
+                Vec::new()
+            }));
+        }
+        match find_route_node(route, nodes.as_deref().unwrap_or_default()) {
+            Some(node) => resolved.push(PlaybackRoute {
+                name: route.name.clone(),
+                node,
+                gain: route.gain,
+            }),
+            None => eprintln!("No PipeWire node matches route '{}', skipping it.", name),
+        }
+    }
+    resolved
+}
+
+/// The next combination of routes for the deck to cycle to. Each
+/// non-empty subset of the first routes comes up in turn, so `default`
+/// and `mixer` go default, mixer, both.
+pub fn next_route_combination(enabled: &[String], routes: &[OutputRoute]) -> Vec<String> {
+    let cycled = &routes[..routes.len().min(MAX_CYCLED_ROUTES)];
+    if cycled.is_empty() {
+        return Vec::new();
+    }
+    let current = cycled
+        .iter()
+        .enumerate()
+        .filter(|(_, route)| enabled.contains(&route.name))
+        .fold(0usize, |mask, (i, _)| mask | 1 << i);
+    let last = (1usize << cycled.len()) - 1;
+    let next = current % last + 1;
+    cycled
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| next & 1 << i != 0)
+        .map(|(_, route)| route.name.clone())
+        .collect()
+}
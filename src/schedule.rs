@@ -0,0 +1,527 @@
+//! Scheduled triggers: keys played and macros run at set times of day or
+//! on intervals. Schedules live in `schedules.toml` next to the config
+//! file, which the CLI edits and a running soundboard rereads when it
+//! changes.
+
+use crate::config::get_config_path;
+use crate::error::{Error, Result};
+use chrono::{Local, NaiveDateTime, NaiveTime, TimeDelta, Timelike};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The longest interval a schedule can have.
+const MAX_INTERVAL: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Where the scheduler reads the time. Schedules only ever see the time
+/// through a clock, so they can be driven by a fake one.
+pub trait Clock {
+    /// The local wall-clock time.
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The system's local time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/// A scheduled trigger. Set either `at` or `every`, and either `key` or
+/// `macro`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub name: String,
+    #[serde(default = "default_schedule_enabled")]
+    pub enabled: bool,
+    /// Time of day, `"HH:MM"`, or `"*:MM"` for that minute of every hour.
+    pub at: Option<String>,
+    /// Interval, e.g. `"50m"`, `"1h30m"` or `"90s"`, counted from when the
+    /// soundboard starts.
+    pub every: Option<String>,
+    /// Board key to play, counting from 1, in the deck's current bank.
+    pub key: Option<u8>,
+    /// Macro to run instead.
+    #[serde(rename = "macro")]
+    pub run_macro: Option<String>,
+}
+
+fn default_schedule_enabled() -> bool {
+    true
+}
+
+/// When a schedule comes due.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum When {
+    /// Every day at this time.
+    Daily(NaiveTime),
+    /// Every hour at this minute.
+    Hourly(u32),
+    /// On an interval.
+    Every(Duration),
+}
+
+impl When {
+    /// The first time after `now` a time of day comes due, or `None` for
+    /// intervals, which count from when they started instead.
+    pub fn next_after(self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let (due, period) = match self {
+            When::Daily(time) => (now.date().and_time(time), TimeDelta::days(1)),
+            When::Hourly(minute) => (
+                now.date().and_hms_opt(now.hour(), minute, 0)?,
+                TimeDelta::hours(1),
+            ),
+            When::Every(_) => return None,
+        };
+        if due > now {
+            Some(due)
+        } else {
+            due.checked_add_signed(period)
+        }
+    }
+}
+
+/// What a schedule does when it comes due.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleTarget {
+    /// Play a key, counting from 0.
+    Key(u8),
+    Macro(String),
+}
+
+impl Schedule {
+    pub fn when(&self) -> std::result::Result<When, String> {
+        match (&self.at, &self.every) {
+            (Some(at), None) => parse_time_of_day(at),
+            (None, Some(every)) => parse_interval(every).map(When::Every),
+            _ => Err(format!(
+                "schedule '{}' needs one of `at` and `every`",
+                self.name
+            )),
+        }
+    }
+
+    pub fn target(&self) -> std::result::Result<ScheduleTarget, String> {
+        match (self.key, &self.run_macro) {
+            (Some(key), None) => key
+                .checked_sub(1)
+                .map(ScheduleTarget::Key)
+                .ok_or_else(|| format!("schedule '{}' has no key 0, keys count from 1", self.name)),
+            (None, Some(name)) => Ok(ScheduleTarget::Macro(name.clone())),
+            _ => Err(format!(
+                "schedule '{}' needs one of `key` and `macro`",
+                self.name
+            )),
+        }
+    }
+}
+
+/// Parses `"HH:MM"` or `"*:MM"`.
+pub fn parse_time_of_day(value: &str) -> std::result::Result<When, String> {
+    let invalid = || format!("expected HH:MM or *:MM, got '{}'", value);
+    let (hour, minute) = value.trim().split_once(':').ok_or_else(invalid)?;
+    let minute: u32 = minute.parse().map_err(|_| invalid())?;
+    if hour == "*" {
+        return if minute < 60 {
+            Ok(When::Hourly(minute))
+        } else {
+            Err(invalid())
+        };
+    }
+    let hour: u32 = hour.parse().map_err(|_| invalid())?;
+    NaiveTime::from_hms_opt(hour, minute, 0)
+        .map(When::Daily)
+        .ok_or_else(invalid)
+}
+
+/// Parses an interval of hours, minutes and seconds, e.g. `"1h30m"`.
+pub fn parse_interval(value: &str) -> std::result::Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "expected an interval like 50m, 1h30m or 90s, got '{}'",
+            value
+        )
+    };
+    let mut seconds = 0u64;
+    let mut number = String::new();
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let count: u64 = number.parse().map_err(|_| invalid())?;
+        seconds = count
+            .checked_mul(unit)
+            .and_then(|part| seconds.checked_add(part))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return Err(invalid());
+    }
+    let interval = Duration::from_secs(seconds);
+    if interval > MAX_INTERVAL {
+        return Err(format!("intervals can be at most a week, got '{}'", value));
+    }
+    Ok(interval)
+}
+
+/// `time` plus `interval`, or the last time there is if that is out of
+/// range.
+fn add_interval(time: NaiveDateTime, interval: Duration) -> NaiveDateTime {
+    TimeDelta::from_std(interval)
+        .ok()
+        .and_then(|interval| time.checked_add_signed(interval))
+        .unwrap_or(NaiveDateTime::MAX)
+}
+
+/// A schedule and when it is next due.
+struct Timer {
+    schedule: Schedule,
+    when: When,
+    next: NaiveDateTime,
+}
+
+impl Timer {
+    fn new(schedule: Schedule, when: When, now: NaiveDateTime) -> Self {
+        let next = match when {
+            When::Every(interval) => add_interval(now, interval),
+            _ => when.next_after(now).unwrap_or(now),
+        };
+        Timer {
+            schedule,
+            when,
+            next,
+        }
+    }
+
+    /// Moves `next` past `now`. Times of day follow the clock, even when it
+    /// is set back; intervals skip what they missed.
+    fn advance(&mut self, now: NaiveDateTime) {
+        match self.when {
+            When::Every(interval) => {
+                let step = TimeDelta::from_std(interval).unwrap_or(TimeDelta::MAX);
+                if self.next - now > step {
+                    // The clock was set back
+                    self.next = add_interval(now, interval);
+                } else if self.next <= now {
+                    // Skip every interval that was missed at once
+                    let missed = (now - self.next).num_seconds() as u64 / interval.as_secs().max(1);
+                    let skip =
+                        interval.saturating_mul(u32::try_from(missed + 1).unwrap_or(u32::MAX));
+                    self.next = add_interval(self.next, skip);
+                }
+            }
+            when => {
+                if let Some(next) = when.next_after(now) {
+                    self.next = next;
+                }
+            }
+        }
+    }
+}
+
+/// Keeps track of when each schedule is next due. It is only told the
+/// time, so it runs the same off a real clock or a fake one.
+#[derive(Default)]
+pub struct Scheduler {
+    timers: Vec<Timer>,
+}
+
+impl Scheduler {
+    /// Replaces the schedules. Ones that are unchanged but for being
+    /// enabled keep when they are next due, so intervals carry on.
+    /// Invalid ones are reported and left out.
+    pub fn set_schedules(&mut self, schedules: Vec<Schedule>, now: NaiveDateTime) {
+        let mut old = std::mem::take(&mut self.timers);
+        for schedule in schedules {
+            let checked = schedule.target().and_then(|_| schedule.when());
+            let when = match checked {
+                Ok(when) => when,
+                Err(e) => {
+                    eprintln!("Ignoring schedule: {}", e);
+                    continue;
+                }
+            };
+            let kept = old
+                .iter()
+                .position(|timer| timer.schedule.name == schedule.name && timer.when == when);
+            let timer = match kept {
+                Some(index) => Timer {
+                    schedule,
+                    ..old.swap_remove(index)
+                },
+                None => Timer::new(schedule, when, now),
+            };
+            self.timers.push(timer);
+        }
+    }
+
+    /// The enabled schedules that came due by `now`, each once however
+    /// many times it was missed. Disabled schedules move on without coming
+    /// due.
+    pub fn poll(&mut self, now: NaiveDateTime) -> Vec<Schedule> {
+        let mut due = Vec::new();
+        for timer in &mut self.timers {
+            if timer.next <= now && timer.schedule.enabled {
+                due.push(timer.schedule.clone());
+            }
+            timer.advance(now);
+        }
+        due
+    }
+
+    /// Each schedule and when it is next due.
+    pub fn next_due(&self) -> impl Iterator<Item = (&Schedule, NaiveDateTime)> {
+        self.timers
+            .iter()
+            .map(|timer| (&timer.schedule, timer.next))
+    }
+}
+
+/// Returns `schedules.toml` in the config file's directory.
+pub fn get_schedules_path() -> std::io::Result<PathBuf> {
+    get_config_path().map(|path| path.with_file_name("schedules.toml"))
+}
+
+/// The file's layout: a `[[schedules]]` table per schedule.
+#[derive(Serialize, Deserialize, Default)]
+struct ScheduleFile {
+    #[serde(default)]
+    schedules: Vec<Schedule>,
+}
+
+/// Loads the schedules, none if the file does not exist.
+pub fn load_schedules() -> Result<Vec<Schedule>> {
+    let path = get_schedules_path().map_err(|e| Error::io("finding schedules file", e))?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| Error::io(format!("reading {}", path.display()), e))?;
+    let file: ScheduleFile = toml::from_str(&contents)
+        .map_err(|e| Error::config(format!("parsing {}", path.display()), e))?;
+    Ok(file.schedules)
+}
+
+pub fn save_schedules(schedules: &[Schedule]) -> Result<()> {
+    let path = get_schedules_path().map_err(|e| Error::io("finding schedules file", e))?;
+    let file = ScheduleFile {
+        schedules: schedules.to_vec(),
+    };
+    let contents = toml::to_string_pretty(&file).map_err(|e| {
+        Error::io(
+            format!("serializing {}", path.display()),
+            std::io::Error::other(e),
+        )
+    })?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| Error::io(format!("creating {}", parent.display()), e))?;
+    }
+    std::fs::write(&path, contents).map_err(|e| Error::io(format!("writing {}", path.display()), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::cell::Cell;
+
+    /// A clock that only moves when told to.
+    struct FakeClock(Cell<NaiveDateTime>);
+
+    impl FakeClock {
+        fn at(hour: u32, minute: u32, second: u32) -> Self {
+            FakeClock(Cell::new(time(hour, minute, second)))
+        }
+
+        fn set(&self, hour: u32, minute: u32, second: u32) {
+            self.0.set(time(hour, minute, second));
+        }
+
+        fn advance(&self, seconds: i64) {
+            self.0.set(self.0.get() + TimeDelta::seconds(seconds));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> NaiveDateTime {
+            self.0.get()
+        }
+    }
+
+    fn time(hour: u32, minute: u32, second: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, 18)
+            .unwrap()
+            .and_hms_opt(hour, minute, second)
+            .unwrap()
+    }
+
+    fn schedule(name: &str, at: Option<&str>, every: Option<&str>) -> Schedule {
+        Schedule {
+            name: name.to_string(),
+            enabled: true,
+            at: at.map(str::to_string),
+            every: every.map(str::to_string),
+            key: Some(1),
+            run_macro: None,
+        }
+    }
+
+    fn scheduler(clock: &FakeClock, schedules: Vec<Schedule>) -> Scheduler {
+        let mut scheduler = Scheduler::default();
+        scheduler.set_schedules(schedules, clock.now());
+        scheduler
+    }
+
+    fn due(scheduler: &mut Scheduler, clock: &FakeClock) -> Vec<String> {
+        scheduler
+            .poll(clock.now())
+            .into_iter()
+            .map(|schedule| schedule.name)
+            .collect()
+    }
+
+    #[test]
+    fn daily_schedule_comes_due_once_a_day() {
+        let clock = FakeClock::at(13, 29, 59);
+        let mut scheduler = scheduler(&clock, vec![schedule("lunch", Some("13:30"), None)]);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        clock.set(13, 30, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["lunch"]);
+        clock.set(23, 59, 59);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        clock.advance(24 * 3600 - 3600 * 10 - 29 * 60);
+        assert_eq!(due(&mut scheduler, &clock), ["lunch"]);
+    }
+
+    #[test]
+    fn hourly_schedule_comes_due_every_hour() {
+        let clock = FakeClock::at(12, 59, 58);
+        let mut scheduler = scheduler(&clock, vec![schedule("horn", Some("*:00"), None)]);
+        clock.advance(1);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        clock.advance(1);
+        assert_eq!(due(&mut scheduler, &clock), ["horn"]);
+        clock.advance(1);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        clock.set(14, 0, 5);
+        assert_eq!(due(&mut scheduler, &clock), ["horn"]);
+    }
+
+    #[test]
+    fn interval_counts_from_start_and_skips_missed_runs() {
+        let clock = FakeClock::at(9, 0, 0);
+        let mut scheduler = scheduler(&clock, vec![schedule("break", None, Some("50m"))]);
+        clock.set(9, 49, 59);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        clock.set(9, 50, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["break"]);
+        // Three intervals missed at once come due once
+        clock.set(12, 21, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["break"]);
+        // Next due at 13:10, keeping to the 50 minute grid
+        clock.set(13, 9, 59);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        clock.set(13, 10, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["break"]);
+    }
+
+    #[test]
+    fn reloading_keeps_interval_running() {
+        let clock = FakeClock::at(9, 0, 0);
+        let mut scheduler = scheduler(&clock, vec![schedule("break", None, Some("50m"))]);
+        clock.set(9, 30, 0);
+        scheduler.set_schedules(vec![schedule("break", None, Some("50m"))], clock.now());
+        clock.set(9, 50, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["break"]);
+    }
+
+    #[test]
+    fn disabled_schedule_moves_on_without_coming_due() {
+        let clock = FakeClock::at(12, 59, 0);
+        let mut disabled = schedule("horn", Some("*:00"), None);
+        disabled.enabled = false;
+        let mut scheduler = scheduler(&clock, vec![disabled]);
+        clock.set(13, 0, 0);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        // Enabling it again does not catch up on what it skipped
+        scheduler.set_schedules(vec![schedule("horn", Some("*:00"), None)], clock.now());
+        clock.set(13, 30, 0);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        clock.set(14, 0, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["horn"]);
+    }
+
+    #[test]
+    fn clock_set_back_follows_the_new_time() {
+        let clock = FakeClock::at(13, 59, 0);
+        let mut scheduler = scheduler(
+            &clock,
+            vec![
+                schedule("horn", Some("*:00"), None),
+                schedule("break", None, Some("10m")),
+            ],
+        );
+        clock.set(14, 0, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["horn"]);
+        // Back an hour, as when daylight saving time ends
+        clock.set(13, 0, 0);
+        assert!(due(&mut scheduler, &clock).is_empty());
+        // The interval starts over from the new time instead of waiting an
+        // hour
+        clock.set(13, 10, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["break"]);
+        clock.set(14, 0, 0);
+        assert_eq!(due(&mut scheduler, &clock), ["horn", "break"]);
+    }
+
+    #[test]
+    fn invalid_schedules_are_left_out() {
+        let clock = FakeClock::at(12, 0, 0);
+        let mut no_target = schedule("nothing", Some("13:00"), None);
+        no_target.key = None;
+        let scheduler = scheduler(
+            &clock,
+            vec![
+                schedule("both", Some("13:00"), Some("1h")),
+                schedule("late", Some("24:00"), None),
+                no_target,
+            ],
+        );
+        assert_eq!(scheduler.next_due().count(), 0);
+    }
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(parse_interval("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_interval("90s"), Ok(Duration::from_secs(90)));
+        assert!(parse_interval("50").is_err());
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("5x").is_err());
+        assert!(parse_interval("3000000000h").is_err());
+        assert!(parse_interval("99999999999999999999h").is_err());
+    }
+
+    #[test]
+    fn parses_times_of_day() {
+        assert_eq!(
+            parse_time_of_day("09:05"),
+            Ok(When::Daily(NaiveTime::from_hms_opt(9, 5, 0).unwrap()))
+        );
+        assert_eq!(parse_time_of_day("*:00"), Ok(When::Hourly(0)));
+        assert!(parse_time_of_day("24:00").is_err());
+        assert!(parse_time_of_day("*:60").is_err());
+        assert!(parse_time_of_day("noon").is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! Runs the schedules from `schedules.toml`, sending each one that comes
+//! due to the deck with the schedules role, or the board while no deck
+//! has it. The file is read again whenever it changes, so the
+//! CLI can manage schedules while the soundboard runs.
+
+use soundboard::schedule::{Clock, Schedule, Scheduler, get_schedules_path, load_schedules};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, watch};
+
+/// How often to check for schedules that came due and for changes to the
+/// file.
+const SCHEDULER_TICK: Duration = Duration::from_secs(1);
+
+/// Sends schedules to `schedule_tx` as they come due, reading the time
+/// from `clock`. While `enabled_rx` is false they pass without playing.
+/// Runs until the process exits.
+pub async fn run_scheduler(
+    clock: impl Clock,
+    schedule_tx: broadcast::Sender<Schedule>,
+    enabled_rx: watch::Receiver<bool>,
+) {
+    let path = match get_schedules_path() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Scheduler not started: finding schedules file: {}", e);
+            return;
+        }
+    };
+    let mut scheduler = Scheduler::default();
+    // When the file was last changed as of reading it; `None` while it
+    // does not exist
+    let mut loaded: Option<SystemTime> = None;
+    let mut tick = tokio::time::interval(SCHEDULER_TICK);
+    loop {
+        tick.tick().await;
+        let modified = tokio::fs::metadata(&path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified != loaded {
+            loaded = modified;
+            match load_schedules() {
+                Ok(schedules) => {
+                    println!(
+                        "Loaded {} schedules from {}",
+                        schedules.len(),
+                        path.display()
+                    );
+                    scheduler.set_schedules(schedules, clock.now());
+                }
+                // Keep the schedules there were until the file is fixed
+                Err(e) => eprintln!("Failed to load schedules: {}", e),
+            }
+        }
+        let due = scheduler.poll(clock.now());
+        let enabled = *enabled_rx.borrow();
+        for schedule in due {
+            if !enabled {
+                println!("Schedules are paused. Skipping '{}'.", schedule.name);
+                continue;
+            }
+            println!("Schedule '{}' is due.", schedule.name);
+            // No receivers just means the role is moving between loops
+            let _ = schedule_tx.send(schedule);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! The step sequencer: plays a bank's pattern on a BPM grid. Steps are
+//! timed by counting the frames the output stream plays, so every hit
+//! lands on the exact sample it is due.
+
+use crate::audio_capture::{
+    PipeWireSession, f32_format_pod, keep_running, lock_ignoring_poison, write_f32_buffer,
+};
+use pipewire as pw;
+use pw::{properties::properties, spa};
+use soundboard::KEY_COUNT;
+use soundboard::bank::{PATTERN_STEP_COUNTS, Pattern};
+use soundboard::config::SequencerConfig;
+use soundboard::error::{Error, Result};
+use spa::pod::Pod;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Samples are decoded to this rate, so that frames count time alike on
+/// every track.
+pub const SEQUENCER_SAMPLE_RATE: u32 = 48000;
+const SEQUENCER_CHANNELS: usize = 2;
+/// How long to wait before connecting again after the sequencer failed.
+const SEQUENCER_RESTART_DELAY: Duration = Duration::from_secs(5);
+/// Steps per beat: every step is a sixteenth note.
+const STEPS_PER_BEAT: f64 = 4.0;
+
+/// A key's sample, decoded to interleaved stereo at the sequencer's rate.
+pub struct TrackSample {
+    pub samples: Vec<f32>,
+    pub gain: f32,
+}
+
+/// A sample playing from one of the steps.
+struct Voice {
+    key: u8,
+    sample: Arc<TrackSample>,
+    /// The next frame of the sample to play.
+    frame: usize,
+}
+
+struct SequencerState {
+    bpm: f64,
+    swing: f64,
+    steps: usize,
+    /// Steps each key plays on.
+    tracks: HashMap<u8, Vec<bool>>,
+    samples: HashMap<u8, Arc<TrackSample>>,
+    playing: bool,
+    /// The step played next, and the frames until it is due.
+    next_step: usize,
+    frames_to_next: f64,
+    voices: Vec<Voice>,
+    /// The step played last while the pattern plays.
+    step_tx: watch::Sender<Option<usize>>,
+}
+
+impl SequencerState {
+    /// Frames from `step` to the one after it. Swing lengthens the gap
+    /// after even steps and shortens the one after odd steps as much, so
+    /// every odd step comes late and the bar keeps its length.
+    fn step_frames(&self, step: usize) -> f64 {
+        let frames = SEQUENCER_SAMPLE_RATE as f64 * 60.0 / self.bpm / STEPS_PER_BEAT;
+        if step.is_multiple_of(2) {
+            frames * (1.0 + self.swing)
+        } else {
+            frames * (1.0 - self.swing)
+        }
+    }
+
+    /// Starts every sample on `next_step`, cutting off any still playing
+    /// from an earlier step of the same track.
+    fn trigger_step(&mut self) {
+        let step = self.next_step;
+        for (key, steps) in &self.tracks {
+            if steps.get(step) != Some(&true) {
+                continue;
+            }
+            let Some(sample) = self.samples.get(key) else {
+                continue;
+            };
+            self.voices.retain(|voice| voice.key != *key);
+            self.voices.push(Voice {
+                key: *key,
+                sample: sample.clone(),
+                frame: 0,
+            });
+        }
+        self.step_tx.send_replace(Some(step));
+        self.frames_to_next += self.step_frames(step);
+        self.next_step = (step + 1) % self.steps.max(1);
+    }
+
+    /// Mixes the next `count` samples, starting steps on the frame they
+    /// are due.
+    fn play(&mut self, count: usize) -> Vec<f32> {
+        let frames = count / SEQUENCER_CHANNELS;
+        let mut out = vec![0.0; frames * SEQUENCER_CHANNELS];
+        for frame in out.chunks_exact_mut(SEQUENCER_CHANNELS) {
+            if self.playing {
+                while self.frames_to_next <= 0.0 {
+                    self.trigger_step();
+                }
+                self.frames_to_next -= 1.0;
+            }
+            for voice in &mut self.voices {
+                let start = voice.frame * SEQUENCER_CHANNELS;
+                if let Some(samples) = voice.sample.samples.get(start..start + SEQUENCER_CHANNELS) {
+                    for (out, sample) in frame.iter_mut().zip(samples) {
+                        *out += sample * voice.sample.gain;
+                    }
+                }
+                voice.frame += 1;
+            }
+        }
+        self.voices
+            .retain(|voice| voice.frame * SEQUENCER_CHANNELS < voice.sample.samples.len());
+        for sample in &mut out {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+        out
+    }
+}
+
+/// The step sequencer as decks see it. Every deck shares it, and plays
+/// whichever pattern was set last.
+#[derive(Clone)]
+pub struct Sequencer(Arc<Mutex<SequencerState>>);
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        let pattern = Pattern::default();
+        Sequencer(Arc::new(Mutex::new(SequencerState {
+            bpm: pattern.bpm,
+            swing: pattern.swing,
+            steps: pattern.steps,
+            tracks: HashMap::new(),
+            samples: HashMap::new(),
+            playing: false,
+            next_step: 0,
+            frames_to_next: 0.0,
+            voices: Vec::new(),
+            step_tx: watch::Sender::new(None),
+        })))
+    }
+}
+
+impl Sequencer {
+    /// Locks the sequencer, ignoring poison like the capture does.
+    fn lock(&self) -> MutexGuard<'_, SequencerState> {
+        lock_ignoring_poison(&self.0)
+    }
+
+    /// The step played last, or `None` while stopped.
+    pub fn subscribe(&self) -> watch::Receiver<Option<usize>> {
+        self.lock().step_tx.subscribe()
+    }
+
+    /// Plays `pattern` from now on. A running pattern carries on from the
+    /// step it is on.
+    pub fn set_pattern(&self, pattern: &Pattern) {
+        let mut state = self.lock();
+        state.bpm = pattern.bpm.max(1.0);
+        state.swing = pattern.swing.clamp(0.0, 0.5);
+        state.steps = if PATTERN_STEP_COUNTS.contains(&pattern.steps) {
+            pattern.steps
+        } else {
+            PATTERN_STEP_COUNTS[PATTERN_STEP_COUNTS.len() - 1]
+        };
+        state.tracks = (0..KEY_COUNT)
+            .map(|key| (key, pattern.track(key)))
+            .filter(|(_, steps)| steps.contains(&true))
+            .collect();
+        state.next_step %= state.steps;
+    }
+
+    /// Replaces the samples the tracks play.
+    pub fn set_samples(&self, samples: HashMap<u8, TrackSample>) {
+        self.lock().samples = samples
+            .into_iter()
+            .map(|(key, sample)| (key, Arc::new(sample)))
+            .collect();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.lock().playing
+    }
+
+    /// Starts the pattern from its first step, or stops it.
+    pub fn set_playing(&self, playing: bool) {
+        let mut state = self.lock();
+        state.playing = playing;
+        state.next_step = 0;
+        state.frames_to_next = 0.0;
+        if !playing {
+            state.step_tx.send_replace(None);
+        }
+    }
+}
+
+/// Plays the sequencer into the configured target. Connects again
+/// whenever PipeWire drops it, until the process exits.
+pub fn run_sequencer(config: SequencerConfig, sequencer: Sequencer) {
+    keep_running("Sequencer", SEQUENCER_RESTART_DELAY, || {
+        run_sequencer_loop(&config, &sequencer)
+    });
+}
+
+/// Runs the output stream until it fails, returning why.
+fn run_sequencer_loop(config: &SequencerConfig, sequencer: &Sequencer) -> Result<()> {
+    let session = PipeWireSession::connect()?;
+
+    let mut props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Playback",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::NODE_NAME => "soundboard-sequencer",
+    };
+    if let Some(target) = &config.target {
+        props.insert(*pw::keys::TARGET_OBJECT, target.as_str());
+    }
+    let stream = pw::stream::StreamBox::new(&session.core, "sequencer", props)
+        .map_err(|e| Error::audio("creating sequencer stream", e))?;
+    let _listener = stream
+        .add_local_listener_with_user_data(sequencer.clone())
+        .state_changed(session.report_state("Sequencer"))
+        .process(|stream, sequencer| {
+            write_f32_buffer(stream, |capacity| sequencer.lock().play(capacity));
+        })
+        .register()
+        .map_err(|e| Error::audio("registering sequencer listener", e))?;
+
+    let values = f32_format_pod(Some((SEQUENCER_SAMPLE_RATE, SEQUENCER_CHANNELS as u32)))?;
+    let pod = Pod::from_bytes(&values)
+        .ok_or_else(|| Error::audio("building sequencer format", "invalid POD"))?;
+    let mut params = [pod];
+    stream
+        .connect(
+            spa::utils::Direction::Output,
+            None,
+            pw::stream::StreamFlags::AUTOCONNECT
+                | pw::stream::StreamFlags::MAP_BUFFERS
+                | pw::stream::StreamFlags::RT_PROCESS,
+            &mut params,
+        )
+        .map_err(|e| Error::audio("connecting sequencer stream", e))?;
+    println!("Sequencer running.");
+
+    session.run("running the sequencer")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames per step at 120 BPM without swing.
+    const STEP: f64 = 6000.0;
+
+    /// A sequencer playing `hits` of key A's track, with a short sample.
+    fn sequencer(steps: usize, swing: f64, hits: &[usize]) -> Sequencer {
+        let mut pattern = Pattern {
+            bpm: 120.0,
+            swing,
+            steps,
+            ..Pattern::default()
+        };
+        for &step in hits {
+            pattern.set_step(0, step, true);
+        }
+        let sequencer = Sequencer::default();
+        sequencer.set_pattern(&pattern);
+        let sample = TrackSample {
+            samples: vec![0.5; 4 * SEQUENCER_CHANNELS],
+            gain: 1.0,
+        };
+        sequencer.set_samples(HashMap::from([(0, sample)]));
+        sequencer
+    }
+
+    #[test]
+    fn swing_delays_odd_steps_and_keeps_the_bar() {
+        let straight = sequencer(16, 0.0, &[]);
+        let straight = straight.lock();
+        assert_eq!(straight.step_frames(0), STEP);
+        assert_eq!(straight.step_frames(1), STEP);
+
+        let swung = sequencer(16, 0.5, &[]);
+        let swung = swung.lock();
+        assert_eq!(swung.step_frames(0), STEP * 1.5);
+        assert_eq!(swung.step_frames(1), STEP * 0.5);
+        let bar: f64 = (0..16).map(|step| swung.step_frames(step)).sum();
+        assert_eq!(bar, STEP * 16.0);
+    }
+
+    #[test]
+    fn steps_wrap_around_the_pattern() {
+        let sequencer = sequencer(8, 0.0, &[0, 7]);
+        let steps = sequencer.subscribe();
+        let mut state = sequencer.lock();
+        state.next_step = 7;
+        state.trigger_step();
+        assert_eq!(*steps.borrow(), Some(7));
+        assert_eq!(state.next_step, 0);
+        assert_eq!(state.voices.len(), 1);
+        // The same track cuts off its own sample
+        state.voices[0].frame = 2;
+        state.trigger_step();
+        assert_eq!(state.next_step, 1);
+        assert_eq!(state.voices.len(), 1);
+        assert_eq!(state.voices[0].frame, 0);
+        assert_eq!(state.frames_to_next, STEP * 2.0);
+    }
+
+    #[test]
+    fn steps_start_on_the_frame_they_are_due() {
+        let sequencer = sequencer(8, 0.0, &[1]);
+        sequencer.set_playing(true);
+        let mut state = sequencer.lock();
+        let frames = STEP as usize;
+        let first = state.play((frames + 1) * SEQUENCER_CHANNELS);
+        // Silent through step 0, then step 1 plays on its first frame
+        assert!(
+            first[..frames * SEQUENCER_CHANNELS]
+                .iter()
+                .all(|s| *s == 0.0)
+        );
+        assert_eq!(first[frames * SEQUENCER_CHANNELS..], [0.5, 0.5]);
+        assert_eq!(state.next_step, 2);
+    }
+}
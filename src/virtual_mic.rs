@@ -0,0 +1,93 @@
+use soundboard::config::{VIRTUAL_MIC_SINK, VIRTUAL_MIC_SOURCE, VirtualMicConfig};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// How long to wait before starting a loopback again after it exited.
+const LOOPBACK_RESTART_DELAY: Duration = Duration::from_secs(5);
+/// Head start the virtual microphone gets before the real one is looped
+/// into it, so the loop never falls back to the speakers.
+const MIC_LOOPBACK_DELAY: Duration = Duration::from_secs(1);
+
+/// `pw-loopback` between a sink the Mixer output plays into and a source
+/// voice chat apps record from. Both go away with the process.
+fn virtual_mic_command(config: &VirtualMicConfig) -> Command {
+    let mut command = Command::new("pw-loopback");
+    command
+        .arg("--name")
+        .arg(VIRTUAL_MIC_SOURCE)
+        .arg("--channels")
+        .arg("2")
+        .arg("--capture-props")
+        .arg(format!(
+            "media.class=Audio/Sink node.name={} node.description=\"{} Input\"",
+            VIRTUAL_MIC_SINK, config.description
+        ))
+        .arg("--playback-props")
+        .arg(format!(
+            "media.class=Audio/Source node.name={} node.description=\"{}\"",
+            VIRTUAL_MIC_SOURCE, config.description
+        ));
+    command
+}
+
+/// `pw-loopback` from the real microphone into the virtual one.
+fn mic_loopback_command(config: &VirtualMicConfig) -> Command {
+    let mut capture_props = "node.name=soundboard-mic-loopback".to_string();
+    if let Some(microphone) = &config.microphone {
+        capture_props.push_str(&format!(" target.object=\"{}\"", microphone));
+    }
+    let mut command = Command::new("pw-loopback");
+    command
+        .arg("--name")
+        .arg("soundboard-mic-loopback")
+        .arg("--capture-props")
+        .arg(capture_props)
+        .arg("--playback-props")
+        .arg(format!(
+            "target.object={} node.dont-fallback=true node.passive=true",
+            VIRTUAL_MIC_SINK
+        ));
+    command
+}
+
+/// Runs `make_command` and starts it again whenever it exits, until the
+/// process exits and the child is killed with it.
+async fn supervise(name: &str, make_command: impl Fn() -> Command) {
+    loop {
+        let mut command = make_command();
+        command
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        match command.spawn() {
+            Ok(mut child) => {
+                println!("Started {}.", name);
+                match child.wait().await {
+                    Ok(status) => eprintln!("{} exited with status: {}", name, status),
+                    Err(e) => eprintln!("Failed to wait for {}: {}", name, e),
+                }
+            }
+            Err(e) => eprintln!(
+                "Failed to start {} (is pw-loopback installed?): {}",
+                name, e
+            ),
+        }
+        tokio::time::sleep(LOOPBACK_RESTART_DELAY).await;
+    }
+}
+
+/// Creates the virtual microphone and, if configured, loops the real one
+/// into it. Both are restarted if they exit and are removed when the
+/// soundboard exits.
+pub async fn run_virtual_mic(config: VirtualMicConfig) {
+    let virtual_mic = supervise("virtual microphone", || virtual_mic_command(&config));
+    let loopback = async {
+        if !config.loopback {
+            return;
+        }
+        tokio::time::sleep(MIC_LOOPBACK_DELAY).await;
+        supervise("microphone loopback", || mic_loopback_command(&config)).await;
+    };
+    tokio::join!(virtual_mic, loopback);
+}
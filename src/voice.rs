@@ -0,0 +1,169 @@
+use crate::audio_capture::{
+    PipeWireSession, f32_format_pod, keep_running, lock_ignoring_poison, read_f32_buffer,
+    write_f32_buffer,
+};
+use crate::audio_processor::VoiceProcessor;
+use pipewire as pw;
+use pw::{properties::properties, spa};
+use soundboard::config::{TalkMode, VoiceConfig, VoiceEffect};
+use soundboard::error::{Error, Result};
+use spa::pod::Pod;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The passthrough runs mono at a fixed rate, so samples go from one
+/// stream to the other unconverted.
+const VOICE_SAMPLE_RATE: u32 = 48000;
+/// Most captured audio held back for the virtual microphone. Anything
+/// older is dropped, so the voice never lags far behind.
+const MAX_BUFFERED_SAMPLES: usize = VOICE_SAMPLE_RATE as usize / 10;
+/// Silence played while nothing has been captured yet.
+const UNDERRUN_SAMPLES: usize = 256;
+/// How long to wait before connecting again after the passthrough failed.
+const VOICE_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// How the passthrough should sound right now, set from the deck.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceControl {
+    /// Whether the microphone is heard at all.
+    pub open: bool,
+    pub effect: VoiceEffect,
+    /// From 0.0 to 1.0.
+    pub amount: f64,
+}
+
+impl VoiceControl {
+    /// The starting state: muted only while a push-to-talk key is not
+    /// held.
+    pub fn from_config(config: &VoiceConfig) -> Self {
+        VoiceControl {
+            open: config.key.is_none() || config.key_mode == TalkMode::Toggle,
+            effect: config.effect,
+            amount: config.amount.clamp(0.0, 1.0),
+        }
+    }
+}
+
+struct VoiceData {
+    /// Processed microphone audio waiting to be played.
+    buffer: VecDeque<f32>,
+    processor: VoiceProcessor,
+    control_rx: watch::Receiver<VoiceControl>,
+}
+
+/// Routes the microphone into `target` (the virtual microphone's sink)
+/// with the effect `control_rx` asks for. Connects again whenever
+/// PipeWire drops it, until the process exits.
+pub fn run_voice_passthrough(
+    config: VoiceConfig,
+    target: String,
+    control_rx: watch::Receiver<VoiceControl>,
+) {
+    keep_running("Mic passthrough", VOICE_RESTART_DELAY, || {
+        run_voice_loop(&config, &target, control_rx.clone())
+    });
+}
+
+/// Runs the capture and playback streams until either fails, returning
+/// why.
+fn run_voice_loop(
+    config: &VoiceConfig,
+    target: &str,
+    control_rx: watch::Receiver<VoiceControl>,
+) -> Result<()> {
+    let session = PipeWireSession::connect()?;
+
+    let data = Arc::new(Mutex::new(VoiceData {
+        buffer: VecDeque::with_capacity(MAX_BUFFERED_SAMPLES),
+        processor: VoiceProcessor::new(VOICE_SAMPLE_RATE),
+        control_rx,
+    }));
+
+    // 1. Capture the microphone, applying the effect as it comes in
+    let mut capture_props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Capture",
+        *pw::keys::MEDIA_ROLE => "Communication",
+        *pw::keys::NODE_NAME => "soundboard-voice-in",
+    };
+    if let Some(microphone) = &config.microphone {
+        capture_props.insert(*pw::keys::TARGET_OBJECT, microphone.as_str());
+    }
+    let capture = pw::stream::StreamBox::new(&session.core, "voice-capture", capture_props)
+        .map_err(|e| Error::audio("creating voice capture stream", e))?;
+    let _capture_listener = capture
+        .add_local_listener_with_user_data(data.clone())
+        .state_changed(session.report_state("Voice capture"))
+        .process(|stream, data| {
+            let Some(mut samples) = read_f32_buffer(stream) else {
+                return;
+            };
+            let mut voice = lock_ignoring_poison(data);
+            let control = *voice.control_rx.borrow();
+            if control.open {
+                voice
+                    .processor
+                    .process(&mut samples, control.effect, control.amount);
+            } else {
+                samples.fill(0.0);
+            }
+            voice.buffer.extend(samples);
+            let excess = voice.buffer.len().saturating_sub(MAX_BUFFERED_SAMPLES);
+            voice.buffer.drain(..excess);
+        })
+        .register()
+        .map_err(|e| Error::audio("registering voice capture listener", e))?;
+
+    // 2. Play what was captured into the virtual microphone
+    let playback_props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Playback",
+        *pw::keys::MEDIA_ROLE => "Communication",
+        *pw::keys::NODE_NAME => "soundboard-voice-out",
+        *pw::keys::TARGET_OBJECT => target,
+    };
+    let playback = pw::stream::StreamBox::new(&session.core, "voice-playback", playback_props)
+        .map_err(|e| Error::audio("creating voice playback stream", e))?;
+    let _playback_listener = playback
+        .add_local_listener_with_user_data(data.clone())
+        .state_changed(session.report_state("Voice playback"))
+        .process(|stream, data| {
+            write_f32_buffer(stream, |capacity| {
+                let mut voice = lock_ignoring_poison(data);
+                let count = match voice.buffer.len() {
+                    0 => UNDERRUN_SAMPLES,
+                    available => available,
+                }
+                .min(capacity);
+                let played = count.min(voice.buffer.len());
+                let mut samples: Vec<f32> = voice.buffer.drain(..played).collect();
+                // Silence for whatever has not been captured yet
+                samples.resize(count, 0.0);
+                samples
+            });
+        })
+        .register()
+        .map_err(|e| Error::audio("registering voice playback listener", e))?;
+
+    // 3. Both streams use the same mono format
+    let values = f32_format_pod(Some((VOICE_SAMPLE_RATE, 1)))?;
+    let flags = pw::stream::StreamFlags::AUTOCONNECT
+        | pw::stream::StreamFlags::MAP_BUFFERS
+        | pw::stream::StreamFlags::RT_PROCESS;
+    for (stream, direction, name) in [
+        (&capture, spa::utils::Direction::Input, "voice capture"),
+        (&playback, spa::utils::Direction::Output, "voice playback"),
+    ] {
+        let pod = Pod::from_bytes(&values)
+            .ok_or_else(|| Error::audio("building voice format", "invalid POD"))?;
+        let mut params = [pod];
+        stream
+            .connect(direction, None, flags, &mut params)
+            .map_err(|e| Error::audio(format!("connecting {} stream", name), e))?;
+    }
+    println!("Mic passthrough running into {}.", target);
+
+    session.run("running mic passthrough")
+}
@@ -0,0 +1,671 @@
+//! The browser control panel: a small HTTP server with a WebSocket that
+//! mirrors the deck and takes the same remote commands as OSC.
+//!
+//! - `GET /` serves the panel
+//! - `GET /state` returns the board state as JSON
+//! - `GET /nodes` lists the PipeWire sinks routes can play into
+//! - `GET /key/N/image` returns the icon of key N (numbered from 1)
+//! - `PUT /key/N/sample?name=file.wav` uploads a file and assigns it to key N
+//! - `POST /command` runs one JSON command, e.g. `{"action":"trigger","key":1}`
+//! - `GET /ws` sends `{"state":...}` on every change and runs JSON commands
+//!   sent to it, answering failures with `{"error":...}`
+
+use crate::remote::{BoardState, RemoteCommand};
+use crate::routes::list_audio_sinks;
+use serde::Deserialize;
+use serde_json::json;
+use soundboard::KEY_COUNT;
+use soundboard::config::WebConfig;
+use soundboard::library::is_audio_file;
+use soundboard::websocket::{Frame, accept_key, read_frame, write_frame};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
+
+const PANEL_HTML: &str = include_str!("../assets/panel.html");
+/// Names the panel answers to besides the address it is bound to.
+const LOCAL_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+/// Largest request line and headers accepted.
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+/// Largest sample that can be uploaded.
+const MAX_UPLOAD_SIZE: u64 = 100 * 1024 * 1024;
+/// Shown on keys with a sample but no icon, as on the deck.
+const PLAY_IMAGE_PATH: &str = "assets/play.png";
+
+/// A command from the panel. Keys are numbered from 1.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+enum PanelCommand {
+    Trigger {
+        key: u8,
+    },
+    Volume {
+        key: u8,
+        volume: f64,
+    },
+    Pitch {
+        key: u8,
+        pitch: f64,
+    },
+    Label {
+        key: u8,
+        label: Option<String>,
+    },
+    Clear {
+        key: u8,
+    },
+    RecordStart {
+        key: u8,
+    },
+    RecordStop {
+        key: u8,
+    },
+    Mode {
+        mode: String,
+    },
+    /// Turn an output route, or a PipeWire node by name, on or off.
+    Route {
+        name: String,
+        enabled: bool,
+    },
+    /// Set the routes a key plays through, or `null` for the enabled ones.
+    KeyRoutes {
+        key: u8,
+        routes: Option<Vec<String>>,
+    },
+    /// Run a macro, or cancel it with `run: false`.
+    Macro {
+        name: String,
+        run: bool,
+    },
+}
+
+/// What every connection needs: where uploads go, where commands are sent
+/// and the state to show.
+#[derive(Clone)]
+struct Panel {
+    /// The host names requests may be addressed to.
+    hosts: Vec<String>,
+    upload_dir: PathBuf,
+    remote_tx: broadcast::Sender<RemoteCommand>,
+    board_rx: watch::Receiver<Option<BoardState>>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: HashMap<String, String>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// Whether the request is addressed to one of `hosts`, ignoring the
+    /// port. A page on another site that got its name to resolve to this
+    /// machine (DNS rebinding) still sends its own name here.
+    fn is_for_host(&self, hosts: &[String]) -> bool {
+        let Some(host) = self.header("host") else {
+            return false;
+        };
+        let name = host_name(host);
+        hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(name))
+    }
+
+    /// Whether the request comes from the panel itself or a client that is
+    /// not a browser. Other sites open in the browser could otherwise run
+    /// the board through it.
+    fn is_same_origin(&self) -> bool {
+        let Some(origin) = self.header("origin") else {
+            return true;
+        };
+        let origin_host = origin
+            .strip_prefix("http://")
+            .or_else(|| origin.strip_prefix("https://"));
+        origin_host.is_some() && origin_host == self.header("host")
+    }
+
+    /// A query parameter, percent-decoded.
+    fn query_param(&self, name: &str) -> Option<String> {
+        self.query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            (key == name).then(|| percent_decode(value))
+        })
+    }
+}
+
+/// `host` without its port, keeping the brackets of an IPv6 address.
+fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.split_inclusive(']').next().unwrap_or(host);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn text(status: u16, message: impl Into<String>) -> Self {
+        Response::new(status, "text/plain; charset=utf-8", message.into())
+    }
+
+    fn json(value: serde_json::Value) -> Self {
+        Response::new(200, "application/json", value.to_string())
+    }
+
+    fn not_found() -> Self {
+        Response::text(404, "Not found")
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Decodes `%XX` escapes and `+` as a space, as browsers encode queries.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b'+' => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parses the `N` of `/key/N/...` into a key index.
+fn key_index(number: u8) -> Result<u8, String> {
+    if (1..=KEY_COUNT).contains(&number) {
+        Ok(number - 1)
+    } else {
+        Err(format!("no key {}, expected 1 to {}", number, KEY_COUNT))
+    }
+}
+
+fn parse_key(number: &str) -> Result<u8, String> {
+    key_index(
+        number
+            .parse()
+            .map_err(|_| format!("bad key '{}'", number))?,
+    )
+}
+
+fn finite(value: f64) -> Result<f64, String> {
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err("expected a number".to_string())
+    }
+}
+
+impl PanelCommand {
+    fn into_remote(self) -> Result<RemoteCommand, String> {
+        Ok(match self {
+            PanelCommand::Trigger { key } => RemoteCommand::Trigger {
+                key: key_index(key)?,
+            },
+            PanelCommand::Volume { key, volume } => RemoteCommand::SetVolume {
+                key: key_index(key)?,
+                volume: finite(volume)?,
+            },
+            PanelCommand::Pitch { key, pitch } => RemoteCommand::SetPitch {
+                key: key_index(key)?,
+                pitch: finite(pitch)?,
+            },
+            PanelCommand::Label { key, label } => RemoteCommand::SetLabel {
+                key: key_index(key)?,
+                label,
+            },
+            PanelCommand::Clear { key } => RemoteCommand::Clear {
+                key: key_index(key)?,
+            },
+            PanelCommand::RecordStart { key } => RemoteCommand::StartRecording {
+                key: key_index(key)?,
+            },
+            PanelCommand::RecordStop { key } => RemoteCommand::StopRecording {
+                key: key_index(key)?,
+            },
+            PanelCommand::Mode { mode } => RemoteCommand::SetMode(mode.parse()?),
+            PanelCommand::Route { name, enabled } => {
+                if name.is_empty() {
+                    return Err("expected a route name".to_string());
+                }
+                RemoteCommand::SetRoute { name, enabled }
+            }
+            PanelCommand::KeyRoutes { key, routes } => RemoteCommand::SetKeyRoutes {
+                key: key_index(key)?,
+                routes,
+            },
+            PanelCommand::Macro { name, run } => {
+                if name.is_empty() {
+                    return Err("expected a macro name".to_string());
+                }
+                if run {
+                    RemoteCommand::RunMacro { name }
+                } else {
+                    RemoteCommand::CancelMacro { name }
+                }
+            }
+        })
+    }
+}
+
+impl Panel {
+    fn send(&self, command: RemoteCommand) -> Result<(), String> {
+        self.remote_tx
+            .send(command)
+            .map(|_| ())
+            .map_err(|_| "no Stream Deck connected".to_string())
+    }
+
+    /// Parses and sends a JSON command.
+    fn run_command(&self, text: &str) -> Result<(), String> {
+        let command: PanelCommand =
+            serde_json::from_str(text).map_err(|e| format!("bad command: {}", e))?;
+        self.send(command.into_remote()?)
+    }
+
+    fn state_json(&self) -> serde_json::Value {
+        json!({ "state": *self.board_rx.borrow() })
+    }
+
+    /// The image key `key` shows: its icon, or the play image if it has a
+    /// sample. Empty keys have none and are drawn by the page.
+    async fn key_image(&self, key: u8) -> Response {
+        let path = {
+            let board = self.board_rx.borrow();
+            let Some(state) = board
+                .as_ref()
+                .and_then(|board| board.keys.get(key as usize))
+            else {
+                return Response::not_found();
+            };
+            match (&state.icon, state.has_sample) {
+                (Some(icon), _) => icon.clone(),
+                (None, true) => PathBuf::from(PLAY_IMAGE_PATH),
+                (None, false) => return Response::not_found(),
+            }
+        };
+        let content_type = match path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("png") => "image/png",
+            Some("jpg") | Some("jpeg") => "image/jpeg",
+            Some("gif") => "image/gif",
+            Some("bmp") => "image/bmp",
+            Some("webp") => "image/webp",
+            _ => "application/octet-stream",
+        };
+        match tokio::fs::read(&path).await {
+            Ok(bytes) => Response::new(200, content_type, bytes),
+            Err(_) => Response::not_found(),
+        }
+    }
+
+    /// Saves an uploaded sample under the upload directory and assigns it
+    /// to `key`. Existing files are never overwritten.
+    async fn upload_sample<R: AsyncRead + Unpin>(
+        &self,
+        key: u8,
+        request: &Request,
+        body: &mut R,
+    ) -> Response {
+        let Some(name) = request
+            .query_param("name")
+            .and_then(|name| sanitize_file_name(&name))
+        else {
+            return Response::text(400, "Missing or bad ?name= for the file");
+        };
+        if !is_audio_file(Path::new(&name)) {
+            return Response::text(400, format!("{} is not an audio file", name));
+        }
+        let Some(len) = request
+            .header("content-length")
+            .and_then(|len| len.parse::<u64>().ok())
+        else {
+            return Response::text(400, "Missing Content-Length");
+        };
+        if len > MAX_UPLOAD_SIZE {
+            return Response::text(413, "File is too large");
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        if let Err(e) = body.take(len).read_to_end(&mut data).await {
+            return Response::text(400, format!("Failed to read upload: {}", e));
+        }
+        if (data.len() as u64) < len {
+            return Response::text(400, "Upload was cut short");
+        }
+
+        if let Err(e) = tokio::fs::create_dir_all(&self.upload_dir).await {
+            eprintln!("Failed to create {}: {}", self.upload_dir.display(), e);
+            return Response::text(500, "Failed to save the file");
+        }
+        let path = unused_path(&self.upload_dir, &name);
+        if let Err(e) = tokio::fs::write(&path, &data).await {
+            eprintln!("Failed to save upload to {}: {}", path.display(), e);
+            return Response::text(500, "Failed to save the file");
+        }
+        println!("Saved upload {} for key {}.", path.display(), key);
+        match self.send(RemoteCommand::Assign { key, path }) {
+            Ok(()) => Response::json(json!({ "ok": true })),
+            Err(e) => Response::text(503, e),
+        }
+    }
+}
+
+/// Keeps only the file name of an upload, with characters that are safe
+/// everywhere. Leading dots are dropped so uploads are never hidden from
+/// the library.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?;
+    let clean: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '.' | '-' | '_' | ' ') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let clean = clean.trim_start_matches('.').trim().to_string();
+    (!clean.is_empty()).then_some(clean)
+}
+
+/// `dir/name`, or `dir/name-2.ext` and so on if that is taken.
+fn unused_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let name = Path::new(name);
+    let stem = name
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("upload");
+    let extension = name.extension().and_then(|e| e.to_str()).unwrap_or("");
+    (2..)
+        .map(|n| dir.join(format!("{}-{}.{}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap_or(path)
+}
+
+/// Reads the request line and headers.
+async fn read_head<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> io::Result<Request> {
+    let mut head = String::new();
+    let mut limited = reader.take(MAX_HEAD_SIZE);
+    loop {
+        let read = limited.read_line(&mut head).await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "request ended early",
+            ));
+        }
+        if head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+            break;
+        }
+    }
+
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad request line",
+        ));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+    })
+}
+
+async fn write_response(writer: &mut OwnedWriteHalf, response: Response) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&response.body).await?;
+    writer.flush().await
+}
+
+/// Serves one request. The connection is closed afterwards, unless it is
+/// upgraded to a WebSocket.
+async fn handle_connection(stream: TcpStream, panel: Panel) -> io::Result<()> {
+    // The address the client connected to, for clients on other machines
+    // that use it as the host
+    let mut hosts = panel.hosts.clone();
+    if let Ok(local) = stream.local_addr() {
+        hosts.push(match local.ip() {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        });
+    }
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let request = read_head(&mut reader).await?;
+    if !request.is_for_host(&hosts) {
+        return write_response(&mut writer, Response::text(403, "Unknown host refused")).await;
+    }
+
+    let parts: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let changes_state = request.method != "GET" || parts == ["ws"];
+    if changes_state && !request.is_same_origin() {
+        return write_response(
+            &mut writer,
+            Response::text(403, "Cross-origin request refused"),
+        )
+        .await;
+    }
+    let response = match (request.method.as_str(), parts.as_slice()) {
+        ("GET", [""]) => Response::new(200, "text/html; charset=utf-8", PANEL_HTML),
+        ("GET", ["state"]) => Response::json(panel.state_json()),
+        ("GET", ["nodes"]) => match list_audio_sinks().await {
+            Ok(nodes) => Response::json(json!({ "nodes": nodes })),
+            Err(e) => Response::text(503, e.to_string()),
+        },
+        ("GET", ["ws"]) => {
+            let Some(client_key) = request.header("sec-websocket-key") else {
+                return write_response(&mut writer, Response::text(400, "Expected a WebSocket"))
+                    .await;
+            };
+            let handshake = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(client_key)
+            );
+            writer.write_all(handshake.as_bytes()).await?;
+            return run_websocket(reader, writer, panel).await;
+        }
+        ("GET", ["key", n, "image"]) => match parse_key(n) {
+            Ok(key) => panel.key_image(key).await,
+            Err(e) => Response::text(404, e),
+        },
+        ("PUT", ["key", n, "sample"]) => match parse_key(n) {
+            Ok(key) => panel.upload_sample(key, &request, &mut reader).await,
+            Err(e) => Response::text(404, e),
+        },
+        ("POST", ["command"]) => {
+            let len = request
+                .header("content-length")
+                .and_then(|len| len.parse::<u64>().ok())
+                .unwrap_or(0)
+                .min(MAX_HEAD_SIZE);
+            let mut body = String::new();
+            (&mut reader).take(len).read_to_string(&mut body).await?;
+            match panel.run_command(&body) {
+                Ok(()) => Response::json(json!({ "ok": true })),
+                Err(e) => Response::text(400, e),
+            }
+        }
+        (
+            _,
+            [""] | ["state"] | ["nodes"] | ["ws"] | ["command"] | ["key", _, "image" | "sample"],
+        ) => Response::text(405, "Method not allowed"),
+        _ => Response::not_found(),
+    };
+    write_response(&mut writer, response).await
+}
+
+/// Sends the state on every change and runs the commands that come in,
+/// until either side closes.
+async fn run_websocket<R: AsyncRead + Unpin + Send + 'static>(
+    mut reader: R,
+    mut writer: OwnedWriteHalf,
+    mut panel: Panel,
+) -> io::Result<()> {
+    // Frames are read on their own task, as a read cut off by `select!`
+    // would lose part of a frame.
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+    let commands = panel.clone();
+    let read_task = tokio::spawn(async move {
+        loop {
+            let reply = match read_frame(&mut reader).await {
+                Ok(Frame::Text(text)) => match commands.run_command(&text) {
+                    Ok(()) => continue,
+                    Err(e) => Frame::Text(json!({ "error": e }).to_string()),
+                },
+                Ok(Frame::Ping(data)) => Frame::Pong(data),
+                Ok(Frame::Close) | Err(_) => break,
+                Ok(_) => continue,
+            };
+            if reply_tx.send(reply).is_err() {
+                break;
+            }
+        }
+    });
+
+    panel.board_rx.mark_changed();
+    let result = loop {
+        let frame = tokio::select! {
+            changed = panel.board_rx.changed() => {
+                if changed.is_err() {
+                    break Ok(());
+                }
+                panel.board_rx.borrow_and_update();
+                Frame::Text(panel.state_json().to_string())
+            }
+            reply = reply_rx.recv() => match reply {
+                Some(frame) => frame,
+                // The client closed the socket
+                None => break write_frame(&mut writer, &Frame::Close).await,
+            },
+        };
+        if let Err(e) = write_frame(&mut writer, &frame).await {
+            break Err(e);
+        }
+    };
+    read_task.abort();
+    result
+}
+
+/// Serves the control panel on `config.bind` until the process exits.
+/// Uploads are saved under `storage_path/uploads`.
+pub async fn run_web_server(
+    config: WebConfig,
+    storage_path: PathBuf,
+    remote_tx: broadcast::Sender<RemoteCommand>,
+    board_rx: watch::Receiver<Option<BoardState>>,
+) {
+    let listener = match TcpListener::bind(&config.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to start web panel on {}: {}", config.bind, e);
+            return;
+        }
+    };
+    println!("Web panel at http://{}/", config.bind);
+    let hosts = LOCAL_HOSTS
+        .iter()
+        .copied()
+        .chain([host_name(&config.bind)])
+        .map(str::to_string)
+        .collect();
+    let panel = Panel {
+        hosts,
+        upload_dir: storage_path.join("uploads"),
+        remote_tx,
+        board_rx,
+    };
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept web panel connection: {}", e);
+                continue;
+            }
+        };
+        let panel = panel.clone();
+        tokio::spawn(async move {
+            // Browsers often open connections they never use
+            if let Err(e) = handle_connection(stream, panel).await
+                && e.kind() != io::ErrorKind::UnexpectedEof
+            {
+                eprintln!("Web panel request failed: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,109 @@
+//! The server side of the WebSocket protocol (RFC 6455), for the browser
+//! control panel. Only what browsers send is supported: unfragmented text
+//! and binary messages, pings and close.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha1_smol::Sha1;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Appended to the client's key to prove the server speaks WebSocket.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest message accepted from a client.
+const MAX_MESSAGE_SIZE: u64 = 1 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// A WebSocket message or control frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// The `Sec-WebSocket-Accept` value answering a client's
+/// `Sec-WebSocket-Key`.
+pub fn accept_key(client_key: &str) -> String {
+    let digest = Sha1::from(format!("{}{}", client_key.trim(), HANDSHAKE_GUID)).digest();
+    STANDARD.encode(digest.bytes())
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// Reads one frame sent by a client, unmasking its payload.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[1] & 0x80 == 0 {
+        return Err(invalid("client frames must be masked"));
+    }
+    let len = match head[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid("message is too large"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    if !fin || opcode == OPCODE_CONTINUATION {
+        return Err(invalid("fragmented messages are not supported"));
+    }
+    match opcode {
+        OPCODE_TEXT => String::from_utf8(payload)
+            .map(Frame::Text)
+            .map_err(|_| invalid("text message is not UTF-8")),
+        OPCODE_BINARY => Ok(Frame::Binary(payload)),
+        OPCODE_CLOSE => Ok(Frame::Close),
+        OPCODE_PING => Ok(Frame::Ping(payload)),
+        OPCODE_PONG => Ok(Frame::Pong(payload)),
+        _ => Err(invalid("unknown opcode")),
+    }
+}
+
+/// Writes one unmasked frame, as servers send them.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    let (opcode, payload): (u8, &[u8]) = match frame {
+        Frame::Text(text) => (OPCODE_TEXT, text.as_bytes()),
+        Frame::Binary(data) => (OPCODE_BINARY, data),
+        Frame::Ping(data) => (OPCODE_PING, data),
+        Frame::Pong(data) => (OPCODE_PONG, data),
+        Frame::Close => (OPCODE_CLOSE, &[]),
+    };
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => out.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+    writer.write_all(&out).await?;
+    writer.flush().await
+}